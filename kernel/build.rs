@@ -1,6 +1,34 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() {
     // Tell cargo to pass the linker script to the linker..
     println!("cargo:rustc-link-arg=-Tlinker.ld");
     // ..and to re-run if it changes.
     println!("cargo:rerun-if-changed=linker.ld");
+
+    // Embed the commit and build time for `version::BUILD_INFO`, re-running when a commit is made or checked out.
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let git = |arguments: &[&str]| {
+        Command::new("git")
+            .args(arguments)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let hash = match (git(&["rev-parse", "--short=12", "HEAD"]), git(&["status", "--porcelain"])) {
+        (Some(hash), Some(status)) if !status.is_empty() => format!("{}-dirty", hash),
+        (Some(hash), _) => hash,
+        (None, _) => "unknown".to_string(),
+    };
+    println!("cargo:rustc-env=REX_GIT_HASH={}", hash);
+    // SOURCE_DATE_EPOCH makes the build reproducible
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|time| time.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()));
+    println!("cargo:rustc-env=REX_BUILD_TIME={}", build_time);
 }
@@ -0,0 +1,45 @@
+use core::mem::size_of;
+
+use super::bytes;
+use super::root::SDTHeader;
+use crate::acpi_signature;
+
+/// The Boot Graphics Resource Table, which describes the logo the firmware drew during boot.
+#[repr(packed)]
+#[derive(Debug)]
+pub struct BGRT {
+    header: SDTHeader,
+    version: u16,
+    status: u8,
+    image_type: u8,
+    image_address: u64,
+    image_offset_x: u32,
+    image_offset_y: u32,
+}
+
+impl BGRT {
+    /// Returns whether the logo is still on the screen, which is no longer true once anything else has been drawn.
+    pub fn displayed(&self) -> bool {
+        self.status & 1 != 0
+    }
+
+    /// Gets the type of the image, 0 is a BMP.
+    pub fn image_type(&self) -> u8 {
+        self.image_type
+    }
+
+    /// Gets the physical address of the image.
+    pub fn image_address(&self) -> u64 {
+        self.image_address
+    }
+
+    /// Gets the position of the logo's top left corner on the screen.
+    pub fn image_offset(&self) -> (u32, u32) {
+        (self.image_offset_x, self.image_offset_y)
+    }
+
+    /// Returns whether the checksum and signature of this table are valid
+    pub fn checksum(&self) -> bool {
+        bytes::table(self.header.bytes(), acpi_signature!('B', 'G', 'R', 'T'), size_of::<BGRT>()).is_ok()
+    }
+}
@@ -0,0 +1,67 @@
+//! Bounds checked reads of the little-endian values in ACPI tables. Parsers read tables through these rather than through
+//! pointers to packed structs, so a table that is shorter than it claims to be fails to parse instead of being read past its end.
+//! Nothing here depends on the rest of the kernel, so the parsers built on it can be compiled and fuzzed on the host.
+
+use core::mem::size_of;
+
+use super::root::SDTHeader;
+
+/// Reads of fixed size values at byte offsets, None if the value doesn't fit.
+pub trait TableBytes {
+    fn array_at<const N: usize>(&self, offset: usize) -> Option<[u8; N]>;
+
+    fn u8_at(&self, offset: usize) -> Option<u8> {
+        self.array_at::<1>(offset).map(|[byte]| byte)
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        self.array_at(offset).map(u16::from_le_bytes)
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        self.array_at(offset).map(u32::from_le_bytes)
+    }
+
+    fn u64_at(&self, offset: usize) -> Option<u64> {
+        self.array_at(offset).map(u64::from_le_bytes)
+    }
+}
+
+impl TableBytes for [u8] {
+    fn array_at<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.get(offset..offset.checked_add(N)?)?.try_into().ok()
+    }
+}
+
+/// Why some bytes aren't a valid table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableError {
+    /// The bytes end before the table does.
+    Truncated,
+    /// The table's length is too small for a table of its kind.
+    TooShort,
+    BadSignature,
+    BadChecksum,
+}
+
+/// Returns whether `bytes` sum to 0, as every table's bytes must.
+pub fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Checks that `bytes` starts with a table with signature `signature` that is at least `min_length` bytes long, is entirely in
+/// `bytes`, and has a valid checksum. Returns the bytes of the table, which may be fewer than `bytes`.
+pub fn table(bytes: &[u8], signature: [u8; 4], min_length: usize) -> Result<&[u8], TableError> {
+    let length = bytes.u32_at(4).ok_or(TableError::Truncated)? as usize;
+    if length < min_length.max(size_of::<SDTHeader>()) {
+        return Err(TableError::TooShort);
+    }
+    let table = bytes.get(..length).ok_or(TableError::Truncated)?;
+    if table.array_at(0) != Some(signature) {
+        return Err(TableError::BadSignature);
+    }
+    if !checksum(table) {
+        return Err(TableError::BadChecksum);
+    }
+    Ok(table)
+}
@@ -0,0 +1,97 @@
+use core::fmt::{self, Write};
+
+use super::bytes;
+use super::root::{SDTHeader, XSDT};
+
+/// The number of bytes printed on each line of a hexdump.
+const BYTES_PER_LINE: usize = 16;
+
+/// Writes the decoded header of an ACPI table to `writer`.
+pub fn dump_header<W: Write>(writer: &mut W, header: &SDTHeader) -> fmt::Result {
+    // copy the fields out of the packed struct so they can be referenced by the formatter
+    let length = header.length;
+    let oem_revision = header.oem_revision;
+    let creator_id = header.creator_id;
+    let creator_revision = header.creator_revision;
+    let checksum_valid = bytes::checksum(header.bytes());
+
+    writeln!(
+        writer,
+        "{} length: {:#x} revision: {} checksum: {:#04x} ({})",
+        AsciiBytes(&header.signature),
+        length,
+        header.revision,
+        header.checksum,
+        if checksum_valid { "valid" } else { "INVALID" }
+    )?;
+    writeln!(
+        writer,
+        "    oem id: \"{}\" oem table id: \"{}\" oem revision: {:#x}",
+        AsciiBytes(&header.oem_id),
+        AsciiBytes(&header.oem_table_id),
+        oem_revision
+    )?;
+    writeln!(
+        writer,
+        "    creator id: \"{}\" creator revision: {:#x}",
+        AsciiBytes(&creator_id.to_le_bytes()),
+        creator_revision
+    )
+}
+
+/// Writes the decoded header of an ACPI table followed by a hexdump of the entire table (including the header) to `writer`.
+pub fn dump_table<W: Write>(writer: &mut W, header: &SDTHeader) -> fmt::Result {
+    dump_header(writer, header)?;
+    hexdump(writer, header.bytes())
+}
+
+/// Writes the header of every table referenced by `xsdt` to `writer`, or the full contents of every table if `full` is set.
+pub fn dump_all<W: Write>(writer: &mut W, xsdt: &XSDT, full: bool) -> fmt::Result {
+    for i in 0..xsdt.length() {
+        // This is safe because `XSDT::get_pointer()` returns pointers into the direct map
+        let header = unsafe { &*xsdt.get_pointer(i) };
+        if full {
+            dump_table(writer, header)?;
+        } else {
+            dump_header(writer, header)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `bytes` to `writer` in the canonical hexdump format (offset, hex bytes, then printable ASCII).
+/// The output matches `hexdump -C` so dumps can be diffed against tables extracted on another OS.
+pub fn hexdump<W: Write>(writer: &mut W, bytes: &[u8]) -> fmt::Result {
+    for (line_number, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        write!(writer, "{:08x} ", line_number * BYTES_PER_LINE)?;
+        for i in 0..BYTES_PER_LINE {
+            // hexdump -C puts an extra space between the two groups of 8 bytes
+            if i == BYTES_PER_LINE / 2 {
+                write!(writer, " ")?;
+            }
+            match line.get(i) {
+                Some(byte) => write!(writer, " {:02x}", byte)?,
+                None => write!(writer, "   ")?,
+            }
+        }
+        writeln!(writer, "  |{}|", AsciiBytes(line))?;
+    }
+    writeln!(writer, "{:08x}", bytes.len())
+}
+
+/// Formats a byte string as ASCII, replacing non-printable characters with '.'.
+struct AsciiBytes<'a>(&'a [u8]);
+
+impl fmt::Display for AsciiBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &byte in self.0 {
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use core::hint::spin_loop;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bitflags::bitflags;
+
+use super::fadt::FADT;
+use crate::acpi_signature;
+
+/// Set in the global lock while the lock is owned.
+const GLOBAL_LOCK_OWNED: u32 = 1 << 1;
+/// Set in the global lock when another agent is waiting for it to be released.
+const GLOBAL_LOCK_PENDING: u32 = 1;
+
+/// The GBL_RLS bit in the PM1 control registers, used to tell the firmware that the global lock has been released.
+const PM1_GBL_RLS: u64 = 1 << 2;
+
+/// The Firmware ACPI Control Structure, which holds the waking vector and the global lock shared with the firmware.
+/// Unlike the other tables it lives in ACPI NVS memory and has no checksum.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FACS {
+    signature: [u8; 4],
+    length: u32,
+    hardware_signature: u32,
+    firmware_waking_vector: u32,
+    global_lock: AtomicU32,
+    flags: FacsFlags,
+    x_firmware_waking_vector: u64,
+    version: u8,
+    reserved: [u8; 3],
+    ospm_flags: u32,
+    reserved2: [u8; 24],
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct FacsFlags: u32 {
+        const S4BIOS = 1;
+        const WAKE_64BIT_SUPPORTED = 1 << 1;
+    }
+}
+
+impl FACS {
+    /// Returns whether the signature of this table is valid (the FACS has no checksum).
+    pub fn is_valid(&self) -> bool {
+        self.signature == acpi_signature!('F', 'A', 'C', 'S') && self.length as usize >= size_of::<FACS>()
+    }
+
+    /// Gets the hardware signature, which the firmware changes when the hardware configuration changes across a sleep.
+    pub fn hardware_signature(&self) -> u32 {
+        self.hardware_signature
+    }
+
+    pub fn flags(&self) -> FacsFlags {
+        self.flags
+    }
+
+    /// Tries to acquire the global lock once.
+    /// If it is owned by the firmware the pending bit is set, so the firmware will signal when it releases it.
+    pub fn try_acquire_global_lock(&self) -> bool {
+        let mut old = self.global_lock.load(Ordering::Relaxed);
+        loop {
+            let new = if old & GLOBAL_LOCK_OWNED == 0 {
+                (old & !GLOBAL_LOCK_PENDING) | GLOBAL_LOCK_OWNED
+            } else {
+                old | GLOBAL_LOCK_PENDING
+            };
+            match self.global_lock.compare_exchange_weak(
+                old,
+                new,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return old & GLOBAL_LOCK_OWNED == 0,
+                Err(current) => old = current,
+            }
+        }
+    }
+
+    /// Releases the global lock, returning true if the firmware is waiting for it (in which case GBL_RLS must be set).
+    fn release_global_lock(&self) -> bool {
+        let mut old = self.global_lock.load(Ordering::Relaxed);
+        loop {
+            let new = old & !(GLOBAL_LOCK_OWNED | GLOBAL_LOCK_PENDING);
+            match self.global_lock.compare_exchange_weak(
+                old,
+                new,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return old & GLOBAL_LOCK_PENDING != 0,
+                Err(current) => old = current,
+            }
+        }
+    }
+
+    /// Acquires the global lock, spinning until the firmware releases it.
+    /// The firmware signals release with an SCI, which isn't handled yet, so this polls the lock instead.
+    pub fn acquire_global_lock<'a>(&'a self, fadt: &'a FADT) -> GlobalLockGuard<'a> {
+        while !self.try_acquire_global_lock() {
+            spin_loop();
+        }
+        GlobalLockGuard { facs: self, fadt }
+    }
+
+    /// Checks the offsets of FACS fields. Panics if any are incorrect
+    pub fn check_offsets() {
+        assert_eq!(offset_of!(FACS, hardware_signature), 8);
+        assert_eq!(offset_of!(FACS, firmware_waking_vector), 12);
+        assert_eq!(offset_of!(FACS, global_lock), 16);
+        assert_eq!(offset_of!(FACS, flags), 20);
+        assert_eq!(offset_of!(FACS, x_firmware_waking_vector), 24);
+        assert_eq!(offset_of!(FACS, version), 32);
+        assert_eq!(offset_of!(FACS, ospm_flags), 36);
+        assert_eq!(size_of::<FACS>(), 64);
+    }
+}
+
+/// Holds the ACPI global lock, which is released when this is dropped.
+/// The embedded controller and AML interpreter must hold this while touching resources shared with SMM firmware.
+pub struct GlobalLockGuard<'a> {
+    facs: &'a FACS,
+    fadt: &'a FADT,
+}
+
+impl Drop for GlobalLockGuard<'_> {
+    fn drop(&mut self) {
+        if self.facs.release_global_lock() {
+            // The firmware is waiting for the lock, tell it that it has been released.
+            // GBL_RLS is write only, the rest of the register is preserved.
+            unsafe {
+                if let Some(pm1a_control) = self.fadt.pm1a_control_block() {
+                    pm1a_control.write(pm1a_control.read() | PM1_GBL_RLS);
+                }
+                if let Some(pm1b_control) = self.fadt.pm1b_control_block() {
+                    pm1b_control.write(pm1b_control.read() | PM1_GBL_RLS);
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,13 @@
 use core::mem::{offset_of, size_of};
 
+use bitflags::bitflags;
+
+use super::bytes;
+use super::facs::FACS;
 use super::root::SDTHeader;
+use crate::memory::{DirectMappedAddress, PhysicalAddress};
+use crate::x64::port::{inb, inl, inw, outb, outl, outw};
+use crate::{acpi_signature, DIRECT_MAP_START};
 
 #[repr(packed)]
 #[derive(Debug)]
@@ -11,7 +18,7 @@ pub struct FADT {
     /// The physical address of the DSDT. If `x_dsdt` is non-zero, it should be ignored.
     dsdt: u32,
     reserved: u8,
-    /// The preferred power management profile of the device. TODO: change to enum.
+    /// The preferred power management profile of the device, use `preferred_power_management_profile()` to decode it.
     preferred_power_management_profile: u8,
     sci_interrupt: u16,
     smi_command_port: u32,
@@ -44,9 +51,9 @@ pub struct FADT {
     day_alarm: u8,
     month_alarm: u8,
     century: u8,
-    boot_architecture_flags: u16,
+    boot_architecture_flags: BootArchitectureFlags,
     reserved2: u8,
-    flags: u32,
+    flags: FadtFlags,
     reset_register: GenericAddressStructure,
     reset_value: u8,
     reserved3: [u8 ; 3],
@@ -60,10 +67,97 @@ pub struct FADT {
     x_pm_timer_block: GenericAddressStructure,
     x_gpe0_block: GenericAddressStructure,
     x_gpe1_block: GenericAddressStructure,
+    /// Only present in ACPI 5.0 and later, use `sleep_control_register()`.
+    sleep_control_register: GenericAddressStructure,
+    /// Only present in ACPI 5.0 and later, use `sleep_status_register()`.
+    sleep_status_register: GenericAddressStructure,
+    /// Only present in ACPI 6.0 and later.
+    hypervisor_vendor_identity: u64,
 }
 
+/// The power management profile reported by the firmware, used to pick power management policies.
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerProfile {
+    Unspecified = 0,
+    Desktop = 1,
+    Mobile = 2,
+    Workstation = 3,
+    EnterpriseServer = 4,
+    SohoServer = 5,
+    AppliancePc = 6,
+    PerformanceServer = 7,
+    Tablet = 8,
+}
+
+impl PowerProfile {
+    /// Converts the raw FADT value to a `PowerProfile`, returning None for reserved values.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => PowerProfile::Unspecified,
+            1 => PowerProfile::Desktop,
+            2 => PowerProfile::Mobile,
+            3 => PowerProfile::Workstation,
+            4 => PowerProfile::EnterpriseServer,
+            5 => PowerProfile::SohoServer,
+            6 => PowerProfile::AppliancePc,
+            7 => PowerProfile::PerformanceServer,
+            8 => PowerProfile::Tablet,
+            _ => return None,
+        })
+    }
+}
+
+bitflags! {
+    /// The fixed feature flags of the FADT.
+    #[derive(Debug, Clone, Copy)]
+    pub struct FadtFlags: u32 {
+        const WBINVD = 1;
+        const WBINVD_FLUSH = 1 << 1;
+        const PROC_C1 = 1 << 2;
+        const P_LVL2_UP = 1 << 3;
+        /// Set if the power button is a control method device (clear if it is a fixed feature).
+        const PWR_BUTTON = 1 << 4;
+        /// Set if the sleep button is a control method device (clear if it is a fixed feature).
+        const SLP_BUTTON = 1 << 5;
+        const FIX_RTC = 1 << 6;
+        const RTC_S4 = 1 << 7;
+        /// Set if the PM timer is 32 bits wide, clear if it is 24 bits wide.
+        const TMR_VAL_EXT = 1 << 8;
+        const DCK_CAP = 1 << 9;
+        /// Set if the reset register (`reset_register` and `reset_value`) is supported.
+        const RESET_REG_SUP = 1 << 10;
+        const SEALED_CASE = 1 << 11;
+        const HEADLESS = 1 << 12;
+        const CPU_SW_SLP = 1 << 13;
+        const PCI_EXP_WAK = 1 << 14;
+        const USE_PLATFORM_CLOCK = 1 << 15;
+        const S4_RTC_STS_VALID = 1 << 16;
+        const REMOTE_POWER_ON_CAPABLE = 1 << 17;
+        const FORCE_APIC_CLUSTER_MODEL = 1 << 18;
+        const FORCE_APIC_PHYSICAL_DESTINATION_MODE = 1 << 19;
+        /// Set if the platform does not implement the ACPI fixed hardware (PM1 blocks, SCI, PM timer, etc).
+        const HW_REDUCED_ACPI = 1 << 20;
+        const LOW_POWER_S0_IDLE_CAPABLE = 1 << 21;
+    }
+}
+
+bitflags! {
+    /// The IA-PC boot architecture flags, describing which legacy devices are present.
+    #[derive(Debug, Clone, Copy)]
+    pub struct BootArchitectureFlags: u16 {
+        const LEGACY_DEVICES = 1;
+        /// Set if the motherboard contains an 8042 (PS/2) controller.
+        const PS2_CONTROLLER = 1 << 1;
+        const VGA_NOT_PRESENT = 1 << 2;
+        const MSI_NOT_SUPPORTED = 1 << 3;
+        const PCIE_ASPM_CONTROLS = 1 << 4;
+        const CMOS_RTC_NOT_PRESENT = 1 << 5;
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum AddressSpace {
     SystemMemory = 0,
     SystemIO = 1,
@@ -77,6 +171,8 @@ enum AddressSpace {
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
 enum AccessSize {
+    /// Used by legacy firmware, the access size should be inferred from `bit_width`.
+    Undefined = 0,
     ByteAccess = 1,
     TwoByteAccess = 2,
     FourByteAccess = 3,
@@ -94,6 +190,130 @@ pub struct GenericAddressStructure {
 }
 
 impl GenericAddressStructure {
+    /// Creates a `GenericAddressStructure` describing a legacy I/O port block of `length` bytes.
+    fn from_io_port(port: u32, length: u8) -> Self {
+        Self {
+            address_space: AddressSpace::SystemIO,
+            bit_width: length * 8,
+            bit_offset: 0,
+            access_size: AccessSize::Undefined,
+            address: port as u64,
+        }
+    }
+
+    /// Returns whether this structure describes a register (a zero address means the register is not implemented).
+    pub fn is_present(&self) -> bool {
+        let address = self.address;
+        address != 0
+    }
+
+    /// Returns whether `read()` and `write()` support this register's address space.
+    pub fn is_accessible(&self) -> bool {
+        matches!(
+            self.address_space,
+            AddressSpace::SystemMemory | AddressSpace::SystemIO
+        )
+    }
+
+    /// Gets the width in bits of each access to this register.
+    fn access_width(&self) -> u8 {
+        match self.access_size {
+            AccessSize::Undefined => match self.bit_width.saturating_add(self.bit_offset) {
+                0..=8 => 8,
+                9..=16 => 16,
+                17..=32 => 32,
+                _ => 64,
+            },
+            AccessSize::ByteAccess => 8,
+            AccessSize::TwoByteAccess => 16,
+            AccessSize::FourByteAccess => 32,
+            AccessSize::EightByteAccess => 64,
+        }
+    }
+
+    /// Gets the bits of the register, before they are shifted by `bit_offset`. A width of 0 means the whole access.
+    fn value_mask(&self) -> u64 {
+        match self.bit_width {
+            0 | 64..=u8::MAX => u64::MAX,
+            width => (1 << width) - 1,
+        }
+    }
+
+    /// Returns whether the register is every bit of an access, so writing it doesn't have to keep the bits around it.
+    fn covers_access(&self) -> bool {
+        self.bit_offset == 0 && (self.bit_width == 0 || self.bit_width >= self.access_width())
+    }
+
+    /// Reads the register described by this structure, shifted down by its bit offset and masked to its bit width.
+    /// Panics if the register is not in system memory or system I/O space.
+    /// Caller must ensure that reading the register does not violate memory safety.
+    pub unsafe fn read(&self) -> u64 {
+        self.read_access().checked_shr(self.bit_offset as u32).unwrap_or(0) & self.value_mask()
+    }
+
+    /// Writes `value` to the register described by this structure, at its bit offset. If the register doesn't cover the whole
+    /// access, the bits around it are read first and written back unchanged.
+    /// Panics if the register is not in system memory or system I/O space.
+    /// Caller must ensure that writing the register does not violate memory safety.
+    pub unsafe fn write(&self, value: u64) {
+        let offset = self.bit_offset as u32;
+        let value = (value & self.value_mask()).checked_shl(offset).unwrap_or(0);
+        if self.covers_access() {
+            self.write_access(value);
+        } else {
+            let mask = self.value_mask().checked_shl(offset).unwrap_or(0);
+            self.write_access((self.read_access() & !mask) | value);
+        }
+    }
+
+    /// Does a single read of `access_width()` bits at the register's address.
+    unsafe fn read_access(&self) -> u64 {
+        let address = self.address;
+        match self.address_space {
+            AddressSpace::SystemIO => match self.access_width() {
+                8 => inb(address as u16) as u64,
+                16 => inw(address as u16) as u64,
+                32 => inl(address as u16) as u64,
+                width => panic!("Invalid I/O access width: {}", width),
+            },
+            AddressSpace::SystemMemory => {
+                // ACPI registers are usually device memory outside of RAM, Limine direct maps the first 4GiB regardless.
+                let virtual_address = address + DIRECT_MAP_START.get().unwrap();
+                match self.access_width() {
+                    8 => (virtual_address as *const u8).read_volatile() as u64,
+                    16 => (virtual_address as *const u16).read_volatile() as u64,
+                    32 => (virtual_address as *const u32).read_volatile() as u64,
+                    _ => (virtual_address as *const u64).read_volatile(),
+                }
+            }
+            address_space => panic!("Unsupported address space: {:?}", address_space),
+        }
+    }
+
+    /// Does a single write of `access_width()` bits at the register's address.
+    unsafe fn write_access(&self, value: u64) {
+        let address = self.address;
+        match self.address_space {
+            AddressSpace::SystemIO => match self.access_width() {
+                8 => outb(address as u16, value as u8),
+                16 => outw(address as u16, value as u16),
+                32 => outl(address as u16, value as u32),
+                width => panic!("Invalid I/O access width: {}", width),
+            },
+            AddressSpace::SystemMemory => {
+                // ACPI registers are usually device memory outside of RAM, Limine direct maps the first 4GiB regardless.
+                let virtual_address = address + DIRECT_MAP_START.get().unwrap();
+                match self.access_width() {
+                    8 => (virtual_address as *mut u8).write_volatile(value as u8),
+                    16 => (virtual_address as *mut u16).write_volatile(value as u16),
+                    32 => (virtual_address as *mut u32).write_volatile(value as u32),
+                    _ => (virtual_address as *mut u64).write_volatile(value),
+                }
+            }
+            address_space => panic!("Unsupported address space: {:?}", address_space),
+        }
+    }
+
     pub fn check_offsets() {
         assert_eq!(offset_of!(GenericAddressStructure, address_space), 0);
         assert_eq!(offset_of!(GenericAddressStructure, bit_width), 1);
@@ -102,9 +322,188 @@ impl GenericAddressStructure {
         assert_eq!(offset_of!(GenericAddressStructure, address), 4);
         assert_eq!(size_of::<GenericAddressStructure>(), 12);
     }
+
+    /// Checks that a register narrower than its access is shifted and masked, and the bits around it are kept.
+    pub fn self_check() {
+        use crate::pmm::FrameAllocator;
+        use crate::FRAME_ALLOCATOR;
+
+        let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+        let register = GenericAddressStructure {
+            address_space: AddressSpace::SystemMemory,
+            bit_width: 4,
+            bit_offset: 4,
+            access_size: AccessSize::ByteAccess,
+            address: frame.get_starting_address().get_address(),
+        };
+        let byte = DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<u8>();
+        // This is safe because the frame was just allocated, and the register is in its first byte
+        unsafe {
+            byte.write_volatile(0xA5);
+            assert_eq!(register.read(), 0xA);
+            register.write(0x13);
+            assert_eq!(byte.read_volatile(), 0x35);
+        }
+        FRAME_ALLOCATOR.get().unwrap().lock().free(frame);
+    }
 }
 
-impl FADT{
+impl FADT {
+    /// Gets the preferred power management profile, or None if the firmware reported a reserved value.
+    pub fn preferred_power_management_profile(&self) -> Option<PowerProfile> {
+        PowerProfile::from_u8(self.preferred_power_management_profile)
+    }
+
+    /// Gets the fixed feature flags.
+    pub fn flags(&self) -> FadtFlags {
+        self.flags
+    }
+
+    /// Returns whether this is a hardware-reduced ACPI platform.
+    pub fn hardware_reduced(&self) -> bool {
+        self.flags().contains(FadtFlags::HW_REDUCED_ACPI)
+    }
+
+    /// Gets the IA-PC boot architecture flags.
+    pub fn boot_architecture_flags(&self) -> BootArchitectureFlags {
+        self.boot_architecture_flags
+    }
+
+    /// Gets the reset register and the value to write to it to reset the system, or None if the reset register is not supported.
+    pub fn reset_register(&self) -> Option<(GenericAddressStructure, u8)> {
+        if self.flags().contains(FadtFlags::RESET_REG_SUP) && self.has_field(offset_of!(FADT, x_firmware_control)) {
+            Some((self.reset_register, self.reset_value))
+        } else {
+            None
+        }
+    }
+
+    /// Gets the PM1a control block, or None on hardware-reduced platforms.
+    pub fn pm1a_control_block(&self) -> Option<GenericAddressStructure> {
+        // the extended field is only read if the table is long enough to have it
+        let extended = if self.has_field(offset_of!(FADT, x_pm1b_control_block)) {
+            Some(self.x_pm1a_control_block)
+        } else {
+            None
+        };
+        self.fixed_hardware_block(extended, self.pm1a_control_block, self.pm1_control_length)
+    }
+
+    /// Gets the PM1b control block, or None if it is not implemented.
+    pub fn pm1b_control_block(&self) -> Option<GenericAddressStructure> {
+        let extended = if self.has_field(offset_of!(FADT, x_pm2_control_block)) {
+            Some(self.x_pm1b_control_block)
+        } else {
+            None
+        };
+        self.fixed_hardware_block(extended, self.pm1b_control_block, self.pm1_control_length)
+    }
+
+    /// Gets the PM timer block, or None if it is not implemented.
+    pub fn pm_timer_block(&self) -> Option<GenericAddressStructure> {
+        let extended = if self.has_field(offset_of!(FADT, x_gpe0_block)) {
+            Some(self.x_pm_timer_block)
+        } else {
+            None
+        };
+        self.fixed_hardware_block(extended, self.pm_timer_block, self.pm_timer_length)
+    }
+
+    /// Gets the interrupt the SCI is wired to in 8259 mode, or None on hardware-reduced platforms (which have no SCI).
+    pub fn sci_interrupt(&self) -> Option<u16> {
+        if self.hardware_reduced() {
+            None
+        } else {
+            Some(self.sci_interrupt)
+        }
+    }
+
+    /// Gets the sleep control register, or None if it is not present (it is only used on hardware-reduced platforms).
+    pub fn sleep_control_register(&self) -> Option<GenericAddressStructure> {
+        if !self.has_field(offset_of!(FADT, sleep_status_register)) {
+            return None;
+        }
+        Some(self.sleep_control_register).filter(GenericAddressStructure::is_present)
+    }
+
+    /// Gets the sleep status register, or None if it is not present (it is only used on hardware-reduced platforms).
+    pub fn sleep_status_register(&self) -> Option<GenericAddressStructure> {
+        if !self.has_field(offset_of!(FADT, hypervisor_vendor_identity)) {
+            return None;
+        }
+        Some(self.sleep_status_register).filter(GenericAddressStructure::is_present)
+    }
+
+    /// Picks between the extended and legacy version of a fixed hardware register block.
+    /// The extended version is preferred when present, hardware-reduced platforms have no fixed hardware blocks.
+    fn fixed_hardware_block(
+        &self,
+        extended: Option<GenericAddressStructure>,
+        legacy: u32,
+        length: u8,
+    ) -> Option<GenericAddressStructure> {
+        if self.hardware_reduced() {
+            None
+        } else if let Some(extended) = extended.filter(GenericAddressStructure::is_present) {
+            Some(extended)
+        } else if legacy != 0 {
+            Some(GenericAddressStructure::from_io_port(legacy, length))
+        } else {
+            None
+        }
+    }
+
+    /// Gets the Firmware ACPI Control Structure, or None if there isn't one (it is optional on hardware-reduced platforms).
+    pub fn facs(&self) -> Option<&'static FACS> {
+        let x_firmware_control = if self.has_field(offset_of!(FADT, x_dsdt)) { self.x_firmware_control } else { 0 };
+        let address = if x_firmware_control != 0 {
+            x_firmware_control
+        } else {
+            self.firmware_control as u64
+        };
+        if address == 0 {
+            return None;
+        }
+        let facs = DirectMappedAddress::from_physical(PhysicalAddress::new(address))
+            .as_pointer::<FACS>();
+        // This is safe because the firmware guarantees the FACS is in ACPI NVS memory (which is direct mapped)
+        let facs = unsafe { &*facs };
+        Some(facs).filter(|facs| facs.is_valid())
+    }
+
+    /// Returns whether the checksum and signature of this table are valid, and it has every field of ACPI 1.0.
+    /// Fields added later must be checked with `has_field()` before they are read.
+    pub fn checksum(&self) -> bool {
+        bytes::table(self.header.bytes(), acpi_signature!('F', 'A', 'C', 'P'), offset_of!(FADT, reset_register)).is_ok()
+    }
+
+    /// Returns whether the table is long enough to have the fields that end at `end`.
+    fn has_field(&self, end: usize) -> bool {
+        self.header.length as usize >= end
+    }
+
+    /// Returns whether the system may have an 8042 (PS/2) controller that is safe to probe.
+    pub fn has_ps2_controller(&self) -> bool {
+        // The boot architecture flags were added in revision 3, older firmware is assumed to have legacy devices.
+        // Hardware-reduced platforms describe all of their devices in the namespace instead.
+        (self.header.revision < 3 && !self.hardware_reduced())
+            || self
+                .boot_architecture_flags()
+                .contains(BootArchitectureFlags::PS2_CONTROLLER)
+    }
+
+    /// Returns whether the system has a CMOS real time clock.
+    pub fn has_cmos_rtc(&self) -> bool {
+        !self
+            .boot_architecture_flags()
+            .contains(BootArchitectureFlags::CMOS_RTC_NOT_PRESENT)
+    }
+
+    /// Gets the index of the CMOS register that holds the century, or None if the RTC has no century register.
+    pub fn century_register(&self) -> Option<u8> {
+        Some(self.century).filter(|&register| register != 0)
+    }
+
     /// Checks the offsets of FADT fields. Panics if any are incorrect
     pub fn check_offsets() {
 
@@ -136,7 +535,15 @@ impl FADT{
         assert_eq!(offset_of!(FADT, cstate_control), 95);
         assert_eq!(offset_of!(FADT, worst_c2_latency), 96);
         assert_eq!(offset_of!(FADT, worst_c3_latency), 98);
+        assert_eq!(offset_of!(FADT, century), 108);
+        assert_eq!(offset_of!(FADT, boot_architecture_flags), 109);
+        assert_eq!(offset_of!(FADT, flags), 112);
+        assert_eq!(offset_of!(FADT, reset_register), 116);
+        assert_eq!(offset_of!(FADT, reset_value), 128);
 
         assert_eq!(offset_of!(FADT, x_gpe0_block), 232);
+        assert_eq!(offset_of!(FADT, sleep_control_register), 244);
+        assert_eq!(offset_of!(FADT, sleep_status_register), 256);
+        assert_eq!(offset_of!(FADT, hypervisor_vendor_identity), 268);
     }
 }
\ No newline at end of file
@@ -3,9 +3,13 @@ use core::fmt::Write;
 
 use bitflags::bitflags;
 
-use super::root::{SDTHeader, validate_checksum};
+use super::bytes::{self, TableBytes};
+use super::root::SDTHeader;
 use crate::{acpi_signature};
 
+/// The offset of the first entry, after the header and the fixed fields.
+const ENTRIES_OFFSET: usize = 0x2C;
+
 #[repr(packed)]
 #[derive(Debug)]
 pub struct MADT {
@@ -38,6 +42,7 @@ enum MadtEntryType {
     LocalApicNonmaskableInterrupts = 4,
     LocalApicAddressOverride = 5,
     ProcessorLocalX2Apic = 9,
+    LocalX2ApicNonmaskableInterrupts = 0xA,
 }
 
 #[repr(packed)]
@@ -48,6 +53,16 @@ pub struct ProcessorLocalApic {
     flags: ProcessorLocalApicFlags,
 }
 
+impl ProcessorLocalApic {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            acpi_processor_id: entry.u8_at(0)?,
+            apic_id: entry.u8_at(1)?,
+            flags: ProcessorLocalApicFlags::from_bits_retain(entry.u32_at(2)?),
+        })
+    }
+}
+
 #[repr(packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct IOApic {
@@ -57,6 +72,31 @@ pub struct IOApic {
     global_system_interrupt_base: u32,
 }
 
+impl IOApic {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            apic_id: entry.u8_at(0)?,
+            reserved: entry.u8_at(1)?,
+            address: entry.u32_at(2)?,
+            global_system_interrupt_base: entry.u32_at(6)?,
+        })
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+
+    /// Gets the physical address of this I/O APIC's registers.
+    pub fn address(&self) -> u64 {
+        self.address as u64
+    }
+
+    /// Gets the first global system interrupt handled by this I/O APIC, which is connected to its input 0.
+    pub fn global_system_interrupt_base(&self) -> u32 {
+        self.global_system_interrupt_base
+    }
+}
+
 #[repr(packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct IOApicInterruptSourceOverride {
@@ -66,6 +106,31 @@ pub struct IOApicInterruptSourceOverride {
     flags: IOApicInterruptSourceFlags,
 }
 
+impl IOApicInterruptSourceOverride {
+    /// Gets the ISA IRQ that is overridden.
+    pub fn irq_source(&self) -> u8 {
+        self.irq_source
+    }
+
+    /// Gets the global system interrupt the ISA IRQ is connected to.
+    pub fn global_system_interrupt(&self) -> u32 {
+        self.global_system_interrupt
+    }
+
+    pub fn flags(&self) -> IOApicInterruptSourceFlags {
+        self.flags
+    }
+
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            bus_source: entry.u8_at(0)?,
+            irq_source: entry.u8_at(1)?,
+            global_system_interrupt: entry.u32_at(2)?,
+            flags: IOApicInterruptSourceFlags::from_bits_retain(entry.u16_at(6)?),
+        })
+    }
+}
+
 #[repr(packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct IOApicNonmaskableInterruptSource {
@@ -75,6 +140,17 @@ pub struct IOApicNonmaskableInterruptSource {
     global_system_interrupt: u32,
 }
 
+impl IOApicNonmaskableInterruptSource {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            non_maskable_interrupt_source: entry.u8_at(0)?,
+            reserved: entry.u8_at(1)?,
+            flags: IOApicInterruptSourceFlags::from_bits_retain(entry.u16_at(2)?),
+            global_system_interrupt: entry.u32_at(4)?,
+        })
+    }
+}
+
 #[repr(packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct LocalApicNonmaskableInterrupts {
@@ -83,6 +159,36 @@ pub struct LocalApicNonmaskableInterrupts {
     lint_number: u8,
 }
 
+impl LocalApicNonmaskableInterrupts {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            acpi_processor_id: entry.u8_at(0)?,
+            flags: IOApicInterruptSourceFlags::from_bits_retain(entry.u16_at(1)?),
+            lint_number: entry.u8_at(3)?,
+        })
+    }
+}
+
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct LocalX2ApicNonmaskableInterrupts {
+    flags: IOApicInterruptSourceFlags,
+    acpi_processor_uid: u32,
+    lint_number: u8,
+    reserved: [u8; 3],
+}
+
+impl LocalX2ApicNonmaskableInterrupts {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            flags: IOApicInterruptSourceFlags::from_bits_retain(entry.u16_at(0)?),
+            acpi_processor_uid: entry.u32_at(2)?,
+            lint_number: entry.u8_at(6)?,
+            reserved: entry.array_at(7)?,
+        })
+    }
+}
+
 #[repr(packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct LocalApicAddressOverride {
@@ -90,6 +196,15 @@ pub struct LocalApicAddressOverride {
     physical_address: u64,
 }
 
+impl LocalApicAddressOverride {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            reserved: entry.u16_at(0)?,
+            physical_address: entry.u64_at(2)?,
+        })
+    }
+}
+
 #[repr(packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct ProcessorLocalX2Apic {
@@ -99,6 +214,17 @@ pub struct ProcessorLocalX2Apic {
     acpi_id: u32,
 }
 
+impl ProcessorLocalX2Apic {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            reserved: entry.u16_at(0)?,
+            processor_local_x2apic_id: entry.u32_at(2)?,
+            flags: LocalApicFlags::from_bits_retain(entry.u32_at(6)?),
+            acpi_id: entry.u32_at(10)?,
+        })
+    }
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy)]
     pub struct ProcessorLocalApicFlags: u32 {
@@ -108,11 +234,70 @@ bitflags! {
 }
 
 bitflags! {
+    /// The MPS INTI flags, use `polarity()` and `trigger_mode()` to decode them.
     #[derive(Debug, Clone, Copy)]
     pub struct IOApicInterruptSourceFlags: u16 {
-        const ACTIVE_LOW = 0x10;
-        const LEVEL_TRIGGERED = 0x1000;
+        const POLARITY = 0b11;
+        const TRIGGER_MODE = 0b1100;
+    }
+}
+
+/// The polarity of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// The polarity is the default for the bus (active high for ISA).
+    ConformsToBus,
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// The trigger mode of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// The trigger mode is the default for the bus (edge for ISA).
+    ConformsToBus,
+    Edge,
+    Level,
+}
+
+impl IOApicInterruptSourceFlags {
+    pub fn polarity(self) -> Polarity {
+        match self.bits() & Self::POLARITY.bits() {
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            // 0b10 is reserved
+            _ => Polarity::ConformsToBus,
+        }
     }
+
+    pub fn trigger_mode(self) -> TriggerMode {
+        match (self.bits() & Self::TRIGGER_MODE.bits()) >> 2 {
+            0b01 => TriggerMode::Edge,
+            0b11 => TriggerMode::Level,
+            // 0b10 is reserved
+            _ => TriggerMode::ConformsToBus,
+        }
+    }
+}
+
+/// Where the firmware says an NMI is connected.
+#[derive(Debug, Clone, Copy)]
+pub enum NmiSource {
+    /// An NMI connected to a LINT pin of one or all local APICs.
+    LocalApic {
+        /// The ACPI processor UID of the processor this applies to, or None if it applies to all processors.
+        acpi_processor_id: Option<u32>,
+        /// The LINT pin (0 or 1) the NMI is connected to.
+        lint: u8,
+        polarity: Polarity,
+        trigger_mode: TriggerMode,
+    },
+    /// An NMI connected to an I/O APIC input.
+    IOApic {
+        global_system_interrupt: u32,
+        polarity: Polarity,
+        trigger_mode: TriggerMode,
+    },
 }
 
 #[derive(Debug)]
@@ -124,61 +309,88 @@ pub enum MadtEntry {
     LocalApicNonmaskableInterrupts(LocalApicNonmaskableInterrupts),
     LocalApicAddressOverride(LocalApicAddressOverride),
     ProcessorLocalX2Apic(ProcessorLocalX2Apic),
+    LocalX2ApicNonmaskableInterrupts(LocalX2ApicNonmaskableInterrupts),
 }
 
+/// Iterates over the entries of a MADT, skipping entries of unknown types and entries too short for their type.
+/// It stops at an entry that claims to go past the end of the table.
 #[derive(Debug)]
-pub struct MadtEntryIterator {
-    current: *const u8,
-    max: *const u8,
+pub struct MadtEntryIterator<'a> {
+    bytes: &'a [u8],
 }
 
-impl Iterator for MadtEntryIterator {
+impl<'a> MadtEntryIterator<'a> {
+    /// Iterates over the entries in `bytes`, which are the bytes of a MADT after its fixed fields.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        MadtEntryIterator { bytes }
+    }
+}
+
+impl Iterator for MadtEntryIterator<'_> {
     type Item = MadtEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current >= self.max {
-            return None;
-        }
-        
-        let entry_type: MadtEntryType = unsafe { *(self.current as *mut MadtEntryType) };
-        let record_length = unsafe { *(self.current.add(1)) };
-        let entry_ptr = unsafe { self.current.add(2) };
-
-        let entry = Some(match entry_type {
-            MadtEntryType::ProcessorLocalApic => {
-                MadtEntry::ProcessorLocalApic(unsafe { *(entry_ptr as *mut ProcessorLocalApic) })
-            }
-            MadtEntryType::IOApic => MadtEntry::IOApic(unsafe { *(entry_ptr as *mut IOApic) }),
-            MadtEntryType::IOApicInterruptSourceOverride => {
-                MadtEntry::IOApicInterruptSourceOverride(unsafe {
-                    *(entry_ptr as *mut IOApicInterruptSourceOverride)
-                })
-            }
-            MadtEntryType::IOApicNonmaskableInterruptSource => {
-                MadtEntry::IOApicNonmaskableInterruptSource(unsafe {
-                    *(entry_ptr as *mut IOApicNonmaskableInterruptSource)
-                })
+        loop {
+            // The type is read as a u8 since firmware may contain entry types we don't know about
+            let entry_type = self.bytes.u8_at(0)?;
+            let record_length = self.bytes.u8_at(1)? as usize;
+            if record_length < 2 || record_length > self.bytes.len() {
+                // a malformed entry would make us loop forever or read past the table
+                self.bytes = &[];
+                return None;
             }
-            MadtEntryType::LocalApicNonmaskableInterrupts => {
-                MadtEntry::LocalApicNonmaskableInterrupts(unsafe {
-                    *(entry_ptr as *mut LocalApicNonmaskableInterrupts)
-                })
-            }
-            MadtEntryType::LocalApicAddressOverride => {
-                MadtEntry::LocalApicAddressOverride(unsafe {
-                    *(entry_ptr as *mut LocalApicAddressOverride)
-                })
-            }
-            MadtEntryType::ProcessorLocalX2Apic => {
-                MadtEntry::ProcessorLocalX2Apic(unsafe { *(entry_ptr as *mut ProcessorLocalX2Apic) })
+            let entry = &self.bytes[2..record_length];
+            self.bytes = &self.bytes[record_length..];
+
+            let Some(entry_type) = MadtEntryType::from_u8(entry_type) else {
+                continue;
+            };
+
+            let entry = match entry_type {
+                MadtEntryType::ProcessorLocalApic => ProcessorLocalApic::parse(entry).map(MadtEntry::ProcessorLocalApic),
+                MadtEntryType::IOApic => IOApic::parse(entry).map(MadtEntry::IOApic),
+                MadtEntryType::IOApicInterruptSourceOverride => {
+                    IOApicInterruptSourceOverride::parse(entry).map(MadtEntry::IOApicInterruptSourceOverride)
+                }
+                MadtEntryType::IOApicNonmaskableInterruptSource => {
+                    IOApicNonmaskableInterruptSource::parse(entry).map(MadtEntry::IOApicNonmaskableInterruptSource)
+                }
+                MadtEntryType::LocalApicNonmaskableInterrupts => {
+                    LocalApicNonmaskableInterrupts::parse(entry).map(MadtEntry::LocalApicNonmaskableInterrupts)
+                }
+                MadtEntryType::LocalApicAddressOverride => {
+                    LocalApicAddressOverride::parse(entry).map(MadtEntry::LocalApicAddressOverride)
+                }
+                MadtEntryType::ProcessorLocalX2Apic => {
+                    ProcessorLocalX2Apic::parse(entry).map(MadtEntry::ProcessorLocalX2Apic)
+                }
+                MadtEntryType::LocalX2ApicNonmaskableInterrupts => {
+                    LocalX2ApicNonmaskableInterrupts::parse(entry).map(MadtEntry::LocalX2ApicNonmaskableInterrupts)
+                }
+            };
+            if let Some(entry) = entry {
+                return Some(entry);
             }
-        });
-        self.current = unsafe{self.current.add(record_length as usize)};
-        entry
+        }
     }
 }
 
 impl MadtEntryType{
+    /// Converts a raw entry type to a `MadtEntryType`, returning None for types that aren't supported.
+    fn from_u8(entry_type: u8) -> Option<Self> {
+        Some(match entry_type {
+            0 => MadtEntryType::ProcessorLocalApic,
+            1 => MadtEntryType::IOApic,
+            2 => MadtEntryType::IOApicInterruptSourceOverride,
+            3 => MadtEntryType::IOApicNonmaskableInterruptSource,
+            4 => MadtEntryType::LocalApicNonmaskableInterrupts,
+            5 => MadtEntryType::LocalApicAddressOverride,
+            9 => MadtEntryType::ProcessorLocalX2Apic,
+            0xA => MadtEntryType::LocalX2ApicNonmaskableInterrupts,
+            _ => return None,
+        })
+    }
+
     /// Gets the size of an madt entry of type `self`
     pub fn entry_size(&self) -> usize{
         match self {
@@ -189,25 +401,123 @@ impl MadtEntryType{
             MadtEntryType::LocalApicNonmaskableInterrupts => size_of::<LocalApicNonmaskableInterrupts>(),
             MadtEntryType::LocalApicAddressOverride => size_of::<LocalApicAddressOverride>(),
             MadtEntryType::ProcessorLocalX2Apic => size_of::<ProcessorLocalX2Apic>(),
+            MadtEntryType::LocalX2ApicNonmaskableInterrupts => size_of::<LocalX2ApicNonmaskableInterrupts>(),
         }
     }
 }
 
 impl MADT {
-    pub fn entries(&self) -> MadtEntryIterator {
-        let base_ptr = &self.entries as *const u8;
-        MadtEntryIterator {
-            current: base_ptr,
-            max: unsafe { base_ptr.add(self.header.length as usize - 0x2C) },
-        }
+    pub fn entries(&self) -> MadtEntryIterator<'_> {
+        MadtEntryIterator::new(self.header.bytes().get(ENTRIES_OFFSET..).unwrap_or(&[]))
+    }
+
+    /// Gets the physical address of the local APIC, using the 64-bit address from a `LocalApicAddressOverride` entry if there is one.
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride(address_override) => {
+                    Some(address_override.physical_address)
+                }
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address as u64)
+    }
+
+    /// Returns whether the system also has the legacy 8259 PICs, which must be masked when using the APICs.
+    pub fn has_legacy_pics(&self) -> bool {
+        { self.local_apic_flags }.contains(LocalApicFlags::LEGACY_PICS)
+    }
+
+    /// Gets the I/O APICs described by this table.
+    pub fn io_apics(&self) -> impl Iterator<Item = IOApic> + '_ {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::IOApic(io_apic) => Some(io_apic),
+            _ => None,
+        })
+    }
+
+    /// Gets the ISA IRQs that aren't connected to the global system interrupt of the same number, or aren't edge triggered and
+    /// active high.
+    pub fn interrupt_source_overrides(&self) -> impl Iterator<Item = IOApicInterruptSourceOverride> + '_ {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::IOApicInterruptSourceOverride(source_override) => Some(source_override),
+            _ => None,
+        })
     }
 
-    /// Returns whether the checksum and signature of this table are valid
+    /// Gets the ACPI processor UID of the processor with the local APIC id `apic_id`, which NMI sources refer to processors by.
+    pub fn acpi_processor_id(&self, apic_id: u32) -> Option<u32> {
+        self.entries().find_map(|entry| match entry {
+            MadtEntry::ProcessorLocalApic(processor) if processor.apic_id as u32 == apic_id => {
+                Some(processor.acpi_processor_id as u32)
+            }
+            MadtEntry::ProcessorLocalX2Apic(processor) if { processor.processor_local_x2apic_id } == apic_id => {
+                Some(processor.acpi_id)
+            }
+            _ => None,
+        })
+    }
+
+    /// Gets the NMI sources described by this table, which must be programmed into the local APIC LINT pins and I/O APIC redirection entries.
+    pub fn nmi_sources(&self) -> impl Iterator<Item = NmiSource> + '_ {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApicNonmaskableInterrupts(nmi) => Some(NmiSource::LocalApic {
+                // 0xFF means all processors
+                acpi_processor_id: Some(nmi.acpi_processor_id as u32).filter(|id| *id != 0xFF),
+                lint: nmi.lint_number,
+                polarity: { nmi.flags }.polarity(),
+                trigger_mode: { nmi.flags }.trigger_mode(),
+            }),
+            MadtEntry::LocalX2ApicNonmaskableInterrupts(nmi) => Some(NmiSource::LocalApic {
+                // 0xFFFFFFFF means all processors
+                acpi_processor_id: Some(nmi.acpi_processor_uid).filter(|id| *id != 0xFFFFFFFF),
+                lint: nmi.lint_number,
+                polarity: { nmi.flags }.polarity(),
+                trigger_mode: { nmi.flags }.trigger_mode(),
+            }),
+            MadtEntry::IOApicNonmaskableInterruptSource(nmi) => Some(NmiSource::IOApic {
+                global_system_interrupt: nmi.global_system_interrupt,
+                polarity: { nmi.flags }.polarity(),
+                trigger_mode: { nmi.flags }.trigger_mode(),
+            }),
+            _ => None,
+        })
+    }
+
+    /// Returns whether the checksum and signature of this table are valid, and it is long enough for its fixed fields
     pub fn checksum(&self) -> bool {
-        if self.header.signature != acpi_signature!('A', 'P', 'I', 'C') {
-            return false;
-        }
-        // This is safe because an XSDT can only be constructed from `RSDP64Bit::get_xsdt()` which checks that the entire table is in memory
-        unsafe { validate_checksum(self as *const _ as *const u8, self.header.length as usize) }
+        bytes::table(self.header.bytes(), acpi_signature!('A', 'P', 'I', 'C'), ENTRIES_OFFSET).is_ok()
     }
 }
+
+/// Checks that malformed tables are rejected, and that MADT entries that are truncated or run past the table are not read.
+pub fn self_check() {
+    use bytes::TableError;
+
+    let mut table = [0u8; 0x2C];
+    table[..4].copy_from_slice(b"APIC");
+    table[4] = 0x2C;
+    table[9] = 0u8.wrapping_sub(table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+    assert_eq!(bytes::table(&table, acpi_signature!('A', 'P', 'I', 'C'), ENTRIES_OFFSET), Ok(&table[..]));
+    assert_eq!(bytes::table(&table, acpi_signature!('F', 'A', 'C', 'P'), 0), Err(TableError::BadSignature));
+    assert_eq!(bytes::table(&table[..0x20], acpi_signature!('A', 'P', 'I', 'C'), 0), Err(TableError::Truncated));
+    assert_eq!(bytes::table(&table, acpi_signature!('A', 'P', 'I', 'C'), 0x30), Err(TableError::TooShort));
+    table[10] ^= 1;
+    assert_eq!(bytes::table(&table, acpi_signature!('A', 'P', 'I', 'C'), 0), Err(TableError::BadChecksum));
+
+    let entries = [
+        // a local APIC
+        0, 8, 1, 2, 1, 0, 0, 0,
+        // an I/O APIC entry too short for its fields, skipped
+        1, 4, 0, 0,
+        // an unknown entry, skipped
+        0x7F, 3, 0,
+        // an address override that claims to run past the end
+        5, 12, 0, 0,
+    ];
+    let mut iterator = MadtEntryIterator::new(&entries);
+    assert!(matches!(iterator.next(), Some(MadtEntry::ProcessorLocalApic(apic)) if apic.apic_id == 2));
+    assert!(iterator.next().is_none());
+    assert!(MadtEntryIterator::new(&[0, 0, 0, 0]).next().is_none());
+    assert!(MadtEntryIterator::new(&[0]).next().is_none());
+}
@@ -0,0 +1,80 @@
+use core::mem::size_of;
+
+use super::bytes::{self, TableBytes};
+use super::root::SDTHeader;
+use crate::acpi_signature;
+
+/// The offset of the first entry, after the header and 8 reserved bytes.
+const ENTRIES_OFFSET: usize = size_of::<SDTHeader>() + size_of::<u64>();
+
+/// The PCI Express memory mapped configuration space base address description table.
+/// It describes where the enhanced configuration access mechanism (ECAM) region of each PCI segment group is.
+#[repr(packed)]
+#[derive(Debug)]
+pub struct MCFG {
+    header: SDTHeader,
+    reserved: u64,
+    entries: McfgEntry,
+}
+
+/// Describes the ECAM region of one PCI segment group.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    base_address: u64,
+    segment_group: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
+}
+
+impl McfgEntry {
+    fn parse(entry: &[u8]) -> Option<Self> {
+        Some(Self {
+            base_address: entry.u64_at(0)?,
+            segment_group: entry.u16_at(8)?,
+            start_bus: entry.u8_at(10)?,
+            end_bus: entry.u8_at(11)?,
+            reserved: entry.u32_at(12)?,
+        })
+    }
+
+    /// Gets the physical address of the configuration space of `start_bus`, each bus after it follows at 1MiB intervals.
+    pub fn base_address(&self) -> u64 {
+        self.base_address
+    }
+
+    pub fn segment_group(&self) -> u16 {
+        self.segment_group
+    }
+
+    pub fn start_bus(&self) -> u8 {
+        self.start_bus
+    }
+
+    pub fn end_bus(&self) -> u8 {
+        self.end_bus
+    }
+}
+
+impl MCFG {
+    /// Gets the number of ECAM regions in the table.
+    pub fn length(&self) -> usize {
+        self.entries().count()
+    }
+
+    /// Gets the ECAM regions, a partial entry at the end of the table is ignored.
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + '_ {
+        self.header
+            .bytes()
+            .get(ENTRIES_OFFSET..)
+            .unwrap_or(&[])
+            .chunks_exact(size_of::<McfgEntry>())
+            .filter_map(McfgEntry::parse)
+    }
+
+    /// Returns whether the checksum and signature of this table are valid
+    pub fn checksum(&self) -> bool {
+        bytes::table(self.header.bytes(), acpi_signature!('M', 'C', 'F', 'G'), ENTRIES_OFFSET).is_ok()
+    }
+}
@@ -1,3 +1,82 @@
+//! ACPI tables. They are found once at boot and kept in an `AcpiTables`, which the rest of the kernel reads them through.
+
+use crate::acpi_signature;
+
+use self::bgrt::BGRT;
+use self::facs::FACS;
+use self::fadt::FADT;
+use self::madt::MADT;
+use self::mcfg::MCFG;
+use self::root::{SDTHeader, XSDT};
+
 pub mod root;
 pub mod madt;
-pub mod fadt;
\ No newline at end of file
+pub mod fadt;
+pub mod dump;
+pub mod sleep;
+pub mod facs;
+pub mod mcfg;
+pub mod bgrt;
+pub mod bytes;
+
+/// The ACPI tables the kernel uses, found and checked once at boot.
+/// Tables are in the direct map, which is never unmapped, so references to them live forever.
+pub struct AcpiTables {
+    xsdt: &'static XSDT,
+    fadt: Option<&'static FADT>,
+    facs: Option<&'static FACS>,
+    madt: Option<&'static MADT>,
+    mcfg: Option<&'static MCFG>,
+    bgrt: Option<&'static BGRT>,
+}
+
+impl AcpiTables {
+    /// Finds the tables referenced by `xsdt`. An MCFG or BGRT with a bad checksum is left out.
+    pub fn parse(xsdt: &'static XSDT) -> Self {
+        let fadt = xsdt.get_fadt().map(|fadt| &*fadt);
+        let facs = fadt.and_then(|fadt| fadt.facs());
+        // This is safe because `XSDT::get_table()` returns pointers to entire tables in the direct map
+        let bgrt = xsdt
+            .get_table(acpi_signature!('B', 'G', 'R', 'T'))
+            .map(|header| unsafe { &*(header as *const BGRT) })
+            .filter(|bgrt| bgrt.checksum());
+        AcpiTables {
+            xsdt,
+            fadt,
+            facs,
+            madt: xsdt.get_madt().map(|madt| &*madt),
+            mcfg: xsdt.get_mcfg(),
+            bgrt,
+        }
+    }
+
+    pub fn xsdt(&self) -> &'static XSDT {
+        self.xsdt
+    }
+
+    pub fn fadt(&self) -> Option<&'static FADT> {
+        self.fadt
+    }
+
+    pub fn facs(&self) -> Option<&'static FACS> {
+        self.facs
+    }
+
+    pub fn madt(&self) -> Option<&'static MADT> {
+        self.madt
+    }
+
+    pub fn mcfg(&self) -> Option<&'static MCFG> {
+        self.mcfg
+    }
+
+    pub fn bgrt(&self) -> Option<&'static BGRT> {
+        self.bgrt
+    }
+
+    /// Gets any table by signature, for tables without a typed getter.
+    pub fn table(&self, signature: [u8; 4]) -> Option<&'static SDTHeader> {
+        // This is safe because `XSDT::get_table()` returns pointers into the direct map
+        self.xsdt.get_table(signature).map(|header| unsafe { &*header })
+    }
+}
@@ -6,8 +6,10 @@ use crate::{acpi_signature, DEBUG_SERIAL_PORT};
 use crate::memory::{DirectMappedAddress, PhysicalAddress};
 
 
+use super::bytes::{self, TableBytes};
 use super::fadt::FADT;
 use super::madt::MADT;
+use super::mcfg::MCFG;
 
 #[repr(packed)]
 #[derive(Debug)]
@@ -54,6 +56,14 @@ pub struct XSDT {
     pub sdts: PhysicalAddress,
 }
 
+impl SDTHeader {
+    /// Gets the bytes of the whole table this header starts, `length` of them. Parsers should read the table through these.
+    pub fn bytes(&self) -> &[u8] {
+        // This is safe because headers are only found through the XSDT, which points to entire tables in the direct map
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+}
+
 impl RSDP32Bit {
     /// Returns whether this RSDP satisfies the checksum
     pub fn checksum(&self) -> bool {
@@ -118,42 +128,34 @@ impl RSDP64Bit {
 impl XSDT {
     /// Validates the checksum and signature of this XSDT, returning true if they are both valid.
     pub fn checksum(&self) -> bool {
-        if self.header.signature != acpi_signature!('X', 'S', 'D', 'T') {
-            return false;
-        }
-        // This is safe because an XSDT can only be constructed from `RSDP64Bit::get_xsdt()` which checks that the entire table is in memory
-        unsafe { validate_checksum(self as *const _ as *const u8, self.header.length as usize) }
+        bytes::table(self.header.bytes(), acpi_signature!('X', 'S', 'D', 'T'), 0).is_ok()
+    }
+
+    /// Gets the physical addresses of the tables, a partial entry at the end of the table is ignored.
+    pub fn entries(&self) -> impl Iterator<Item = PhysicalAddress> + '_ {
+        let entries = self.header.bytes().get(size_of::<SDTHeader>()..).unwrap_or(&[]);
+        // all entries are 8 byte pointers
+        (0..entries.len() / 8).filter_map(|i| entries.u64_at(i * 8)).map(PhysicalAddress::new)
     }
 
     /// Gets the number of entries in the table.
     pub fn length(&self) -> u64 {
-        // divide by 8 because all entries are 8 byte pointers
-        (self.header.length as u64 - size_of::<SDTHeader>() as u64) / 8
+        self.entries().count() as u64
     }
 
     /// Gets the `index`-th pointer in the table.
     /// Panics if index is out of range
     pub fn get_pointer(&self, index: u64) -> *mut SDTHeader {
-        assert!(index < self.length(), "index out of bounds in XSDT");
-        // Assertion makes this safe
-        let header_address = unsafe {
-            let array_base = (self as *const _ as *const PhysicalAddress).byte_offset(36);
-            let header_pointer = array_base.add(index as usize);
-            header_pointer.read_unaligned()
-        };
+        let header_address = self.entries().nth(index as usize).expect("index out of bounds in XSDT");
         DirectMappedAddress::from_physical(header_address).as_pointer::<SDTHeader>()
     }
 
     /// Gets the table with the given signature
     pub fn get_table(&self, signature: [u8; 4]) -> Option<*mut SDTHeader> {
-        for i in 0..self.length() {
-            
-            let x = unsafe { &mut *self.get_pointer(i) };
-            if x.signature == signature {
-                return Some(self.get_pointer(i));
-            }
-        }
-        None
+        self.entries()
+            .map(|address| DirectMappedAddress::from_physical(address).as_pointer::<SDTHeader>())
+            // This is safe because the entries point to tables in the direct map
+            .find(|&header| unsafe { (*header).signature } == signature)
     }
 
     /// Gets the Multiple APIC Descriptor Table associated with this XSDT.
@@ -166,10 +168,21 @@ impl XSDT {
         madt
     }
 
-    /// Gets the Fixed ACPI Description Table associated with this XSDT.
+    /// Gets the Fixed ACPI Description Table associated with this XSDT, if it is valid.
     pub fn get_fadt(&self) -> Option<&mut FADT> {
         let ptr = self.get_table(acpi_signature!('F', 'A', 'C', 'P'))? as *mut FADT;
-        unsafe {ptr.as_mut()}
+        let fadt = unsafe {ptr.as_mut()}?;
+        fadt.checksum().then_some(fadt)
+    }
+
+    /// Gets the PCI Express memory mapped configuration table associated with this XSDT.
+    pub fn get_mcfg(&self) -> Option<&MCFG> {
+        let ptr = self.get_table(acpi_signature!('M', 'C', 'F', 'G'))? as *const MCFG;
+        let mcfg = unsafe { &*ptr };
+        if !mcfg.checksum() {
+            return None;
+        }
+        Some(mcfg)
     }
 }
 
@@ -177,13 +190,7 @@ impl XSDT {
 /// Used to validate ACPI tables.
 /// Safe if the range of addresses starting at start and of length `size` is valid.
 pub unsafe fn validate_checksum(start: *const u8, size: usize) -> bool {
-    let mut sum: u8 = 0;
-    for i in 0..size {
-        let byte = start.add(i).read();
-        //write!(DEBUG_SERIAL_PORT.lock(), "byte: {:x} ", byte);
-        sum = sum.wrapping_add(byte);
-    }
-    sum == 0
+    bytes::checksum(core::slice::from_raw_parts(start, size))
 }
 #[macro_export]
 macro_rules! acpi_signature {
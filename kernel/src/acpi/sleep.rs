@@ -0,0 +1,108 @@
+use super::fadt::FADT;
+use crate::device::{self, DeviceId, PowerError};
+use crate::log;
+
+/// The SLP_TYP bits in the PM1 control registers.
+const PM1_SLP_TYP_SHIFT: u64 = 10;
+const PM1_SLP_TYP_MASK: u64 = 0b111 << PM1_SLP_TYP_SHIFT;
+/// The SLP_EN bit in the PM1 control registers.
+const PM1_SLP_EN: u64 = 1 << 13;
+
+/// The SLP_TYP bits in the sleep control register.
+const SLEEP_CONTROL_SLP_TYP_SHIFT: u64 = 2;
+/// The SLP_EN bit in the sleep control register.
+const SLEEP_CONTROL_SLP_EN: u64 = 1 << 5;
+/// The WAK_STS bit in the sleep status register.
+const SLEEP_STATUS_WAK_STS: u64 = 1 << 7;
+
+/// The SLP_TYPa and SLP_TYPb values for a sleep state, found in the `\_Sx` objects of the DSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SleepError {
+    /// The firmware did not describe the registers needed to enter a sleep state.
+    NoSleepRegisters,
+    /// The sleep registers were written, but the system kept running.
+    StillRunning,
+    /// A device refused to suspend, so the system stayed awake.
+    DeviceNotSuspended(DeviceId, PowerError),
+}
+
+/// Enters the sleep state described by `sleep_type`.
+/// On hardware-reduced platforms the sleep control and status registers are used, otherwise the PM1 control blocks are.
+/// Returns once the system wakes up (this will not return when entering S5 on working hardware).
+pub fn enter_sleep_state(fadt: &FADT, sleep_type: SleepType) -> Result<(), SleepError> {
+    if fadt.hardware_reduced() {
+        let sleep_control = fadt
+            .sleep_control_register()
+            .ok_or(SleepError::NoSleepRegisters)?;
+        let sleep_status = fadt.sleep_status_register();
+        unsafe {
+            if let Some(sleep_status) = sleep_status {
+                // WAK_STS is cleared by writing a 1 to it
+                sleep_status.write(SLEEP_STATUS_WAK_STS);
+            }
+            sleep_control.write(
+                ((sleep_type.a as u64 & 0b111) << SLEEP_CONTROL_SLP_TYP_SHIFT)
+                    | SLEEP_CONTROL_SLP_EN,
+            );
+            if let Some(sleep_status) = sleep_status {
+                while sleep_status.read() & SLEEP_STATUS_WAK_STS == 0 {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    } else {
+        let pm1a_control = fadt
+            .pm1a_control_block()
+            .ok_or(SleepError::NoSleepRegisters)?;
+        let pm1b_control = fadt.pm1b_control_block();
+        unsafe {
+            // SLP_TYP has to be written to both blocks before SLP_EN is set in either
+            let pm1a_value = set_sleep_type(pm1a_control.read(), sleep_type.a);
+            pm1a_control.write(pm1a_value);
+            let pm1b_value = pm1b_control.map(|pm1b_control| {
+                let value = set_sleep_type(pm1b_control.read(), sleep_type.b);
+                pm1b_control.write(value);
+                value
+            });
+
+            pm1a_control.write(pm1a_value | PM1_SLP_EN);
+            if let (Some(pm1b_control), Some(pm1b_value)) = (pm1b_control, pm1b_value) {
+                pm1b_control.write(pm1b_value | PM1_SLP_EN);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the SLP_TYP bits of a PM1 control register value.
+fn set_sleep_type(pm1_control: u64, sleep_type: u8) -> u64 {
+    (pm1_control & !(PM1_SLP_TYP_MASK | PM1_SLP_EN))
+        | ((sleep_type as u64 & 0b111) << PM1_SLP_TYP_SHIFT)
+}
+
+/// Suspends every device, enters the sleep state described by `sleep_type` and resumes the devices once it returns.
+/// Restoring the CPUs through the FACS waking vector is the other half of S3 and isn't handled here.
+pub fn suspend(fadt: &FADT, sleep_type: SleepType) -> Result<(), SleepError> {
+    device::suspend_all().map_err(|(id, error)| SleepError::DeviceNotSuspended(id, error))?;
+    let result = enter_sleep_state(fadt, sleep_type);
+    if let Err(error) = result {
+        log!("sleep: entering sleep state failed: {:?}", error);
+    }
+    device::resume_all();
+    result
+}
+
+/// Powers off the system by entering S5, `s5` should be read from the `\_S5` object of the DSDT.
+/// Returns an error if the sleep registers are missing or the system did not power off.
+pub fn shutdown(fadt: &FADT, s5: SleepType) -> Result<(), SleepError> {
+    device::shutdown_all();
+    enter_sleep_state(fadt, s5)?;
+    // if we woke up again the firmware didn't actually power off
+    Err(SleepError::StillRunning)
+}
@@ -0,0 +1,137 @@
+//! Address spaces, a set of page tables for a process to run in.
+//! The upper (kernel) half of every address space is shared: new address spaces copy the kernel's PML4 entries, so they
+//! reference the same Pdpts. Kernel mappings made later are seen by every address space as long as they go in a PML4 entry
+//! that already existed, which is why kernel regions like the heap create their Pdpt at boot.
+//! The lower (user) half belongs to the address space, and its frames and paging structures are freed with it.
+
+use crate::memory::{DirectMappedAddress, PhysicalAddress, VirtualAddress};
+use crate::pmm::{Frame, FrameAllocator};
+use crate::x64::mapper::{MapError, Mapper};
+use crate::x64::page_table::{MappingStats, PageDirectoryEntry, PageFlags, PdptEntry, PML4};
+use crate::x64::registers::{get_cr3, set_cr3, Cr3};
+use crate::FRAME_ALLOCATOR;
+
+/// The first PML4 entry of the kernel half.
+const KERNEL_PML4_START: usize = 256;
+
+pub struct AddressSpace {
+    pml4: &'static mut PML4,
+    /// The physical address of the PML4, for CR3
+    physical_address: u64,
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in the user half, sharing the kernel half of the current one.
+    pub fn new() -> Self {
+        let pml4 = PML4::new();
+        let cr3 = get_cr3();
+        pml4.entries[KERNEL_PML4_START..].copy_from_slice(&cr3.pml4().entries[KERNEL_PML4_START..]);
+        let physical_address = DirectMappedAddress::try_from_virtual(VirtualAddress::create(pml4 as *const PML4 as u64))
+            .unwrap()
+            .get_physical_address()
+            .get_address();
+        AddressSpace { pml4, physical_address }
+    }
+
+    /// Gets the physical address of the PML4.
+    pub fn physical_address(&self) -> PhysicalAddress {
+        PhysicalAddress::new(self.physical_address)
+    }
+
+    /// Checks whether this is the address space the CPU is using.
+    pub fn is_current(&self) -> bool {
+        get_cr3().address() == self.physical_address
+    }
+
+    /// Makes the CPU use this address space. The kernel half is the same, so the kernel keeps running.
+    /// It must not be dropped while any CPU is still using it.
+    pub fn switch_to(&self) {
+        // This is safe because the kernel half, with the running code and the stack, is shared
+        unsafe { set_cr3(Cr3::new(self.physical_address)) };
+    }
+
+    /// Counts what is mapped in the user half, which is what this address space owns.
+    pub fn stats(&self) -> MappingStats {
+        self.pml4.stats(0..KERNEL_PML4_START)
+    }
+}
+
+impl Mapper for AddressSpace {
+    fn map(&mut self, frame: Frame, virtual_address: VirtualAddress, flags: PageFlags) -> Result<(), MapError> {
+        self.pml4.map(frame, virtual_address, flags)
+    }
+
+    fn unmap(&mut self, virtual_address: VirtualAddress) -> Result<Frame, MapError> {
+        self.pml4.unmap(virtual_address)
+    }
+
+    fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
+        self.pml4.translate(virtual_address)
+    }
+
+    fn update_flags(&mut self, virtual_address: VirtualAddress, flags: PageFlags) -> Result<(), MapError> {
+        self.pml4.update_flags(virtual_address, flags)
+    }
+}
+
+/// Frees the frame at `address`, which was allocated from the frame allocator.
+fn free_frame(allocator: &mut impl FrameAllocator, address: PhysicalAddress) {
+    allocator.free(Frame::from_starting_address(address));
+}
+
+impl Drop for AddressSpace {
+    /// Frees the frames mapped in the user half, the user half's paging structures, and the PML4.
+    /// Huge pages in the user half aren't from the frame allocator, so they are left alone.
+    fn drop(&mut self) {
+        assert!(!self.is_current(), "Attempted to free the current address space");
+        let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+        for pml4_entry in self.pml4.entries[..KERNEL_PML4_START].iter().filter(|entry| entry.present()) {
+            // This is safe because present entries reference paging structures in the direct map, and nothing else uses the user half
+            let pdpt = unsafe { &*pml4_entry.pdpt() };
+            for pdpt_entry in pdpt.entries.iter().filter(|entry| entry.present()) {
+                let PdptEntry::PageDirectory(pdpt_entry) = pdpt_entry.get_entry() else {
+                    continue;
+                };
+                let page_directory = unsafe { &*pdpt_entry.page_directory() };
+                for page_directory_entry in page_directory.entries.iter().filter(|entry| entry.present()) {
+                    let PageDirectoryEntry::PageTable(page_directory_entry) = page_directory_entry.get_entry() else {
+                        continue;
+                    };
+                    let page_table = unsafe { &*page_directory_entry.page_table() };
+                    for page_table_entry in page_table.entries.iter().filter(|entry| entry.present()) {
+                        free_frame(&mut *allocator, page_table_entry.address());
+                    }
+                    free_frame(&mut *allocator, page_directory_entry.address());
+                }
+                free_frame(&mut *allocator, pdpt_entry.address());
+            }
+            free_frame(&mut *allocator, pml4_entry.address());
+        }
+        free_frame(&mut *allocator, PhysicalAddress::new(self.physical_address));
+    }
+}
+
+/// Checks that a new address space runs the kernel, keeps its user half to itself, and gives back all its frames when dropped.
+pub fn self_check() {
+    const USER_ADDRESS: u64 = 0x40_0000;
+
+    let free_before = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    let previous = get_cr3();
+    let mut address_space = AddressSpace::new();
+    let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+    address_space
+        .map(frame, VirtualAddress::create(USER_ADDRESS), PageFlags::KERNEL_DATA | PageFlags::USER)
+        .unwrap();
+    let stats = address_space.stats();
+    assert_eq!((stats.pages_4kb, stats.table_frames()), (1, 3));
+    address_space.switch_to();
+    assert!(address_space.is_current());
+    // This is safe because the page was just mapped writable in this address space
+    unsafe { (USER_ADDRESS as *mut u64).write_volatile(0x1234_5678) };
+    assert_eq!(unsafe { (USER_ADDRESS as *const u64).read_volatile() }, 0x1234_5678);
+    // This is safe because the previous page tables were in use until just now
+    unsafe { set_cr3(previous) };
+    assert!(previous.pml4().translate(VirtualAddress::create(USER_ADDRESS)).is_none());
+    drop(address_space);
+    assert_eq!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames(), free_before);
+}
@@ -0,0 +1,91 @@
+//! Loop devices, which present an image in memory as a block device so filesystems can be used without a storage driver.
+//! There is no filesystem to take files from yet, so the images are modules loaded by the bootloader.
+//! A module is made into a loop device if its command line in the bootloader config is `loop`.
+
+use core::ffi::CStr;
+
+use spin::Mutex;
+
+use crate::block::{self, check_request, BlockDevice, BlockError};
+use crate::device::DeviceName;
+use crate::init::InitError;
+use crate::pmm::leak_in_frame;
+use crate::{log, MODULE_REQUEST};
+
+/// The module command line that marks a module as a loop device image.
+const LOOP_MODULE_CMDLINE: &str = "loop";
+
+const BLOCK_SIZE: usize = 512;
+
+/// A block device backed by memory. Writes change the image in memory and are lost on reboot.
+pub struct LoopDevice {
+    image: &'static mut [u8],
+    block_size: usize,
+}
+
+impl LoopDevice {
+    /// Creates a loop device over `image`, the trailing part of the image that isn't a whole block is ignored.
+    pub fn new(image: &'static mut [u8], block_size: usize) -> Self {
+        LoopDevice { image, block_size }
+    }
+
+    fn byte_range(&self, start: u64, length: usize) -> core::ops::Range<usize> {
+        let start = start as usize * self.block_size;
+        start..start + length
+    }
+}
+
+impl BlockDevice for LoopDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.image.len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        buffer.copy_from_slice(&self.image[self.byte_range(start, buffer.len())]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        let range = self.byte_range(start, buffer.len());
+        self.image[range].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+/// Registers a loop device for each bootloader module marked as a loop device image.
+pub fn init_loop_devices() -> Result<(), InitError> {
+    let Some(response) = MODULE_REQUEST.get_response().get() else {
+        return Ok(());
+    };
+    for module in response.modules() {
+        let (Some(base), Some(cmdline)) = (module.base.as_ptr(), module.cmdline.as_ptr()) else {
+            continue;
+        };
+        // This is safe because Limine gives us null terminated strings that are never freed
+        if unsafe { CStr::from_ptr(cmdline) }.to_str() != Ok(LOOP_MODULE_CMDLINE) {
+            continue;
+        }
+        let path = module
+            .path
+            .as_ptr()
+            .and_then(|path| unsafe { CStr::from_ptr(path) }.to_str().ok())
+            .unwrap_or("?");
+        // This is safe because modules are in memory Limine gives to the kernel, and each module is only used once
+        let image = unsafe { core::slice::from_raw_parts_mut(base, module.length as usize) };
+        let Some(device) = leak_in_frame(Mutex::new(LoopDevice::new(image, BLOCK_SIZE))) else {
+            return Err(InitError::new("Out of memory for loop devices"));
+        };
+        let name = DeviceName::new("loop", block::count_with_prefix("loop"));
+        if !block::register(name, device) {
+            return Err(InitError::new("Too many block devices"));
+        }
+        log!("loop: {} is {} ({} bytes)", name, path, module.length);
+    }
+    Ok(())
+}
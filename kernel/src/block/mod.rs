@@ -0,0 +1,149 @@
+//! Block devices, storage that is read and written in fixed size blocks.
+
+use alloc::boxed::Box;
+
+use spin::Mutex;
+
+use crate::device::{self, Bus, DeviceName};
+use crate::event::{self, DeviceKind, Event};
+use crate::sync::rcu::{self, Rcu};
+use queue::RequestQueue;
+
+pub mod loopback;
+pub mod queue;
+pub mod ramdisk;
+
+/// The most block devices that can be registered.
+const MAX_BLOCK_DEVICES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request goes past the end of the device.
+    OutOfRange,
+    /// The buffer is not a whole number of blocks.
+    BadBufferSize,
+    /// The device does not support writes.
+    ReadOnly,
+    /// The device did not respond in time.
+    Timeout,
+    /// The device reported an error.
+    DeviceError,
+}
+
+/// A device that is read and written in blocks.
+pub trait BlockDevice {
+    /// Gets the size of a block in bytes.
+    fn block_size(&self) -> usize;
+    /// Gets the number of blocks on the device.
+    fn block_count(&self) -> u64;
+    /// Reads `buffer.len() / block_size()` blocks starting at block `start`, `buffer` must be a whole number of blocks long.
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+    /// Writes `buffer.len() / block_size()` blocks starting at block `start`, `buffer` must be a whole number of blocks long.
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Checks that a request for `buffer_length` bytes at block `start` fits on `device`, returning the number of blocks in the request.
+pub fn check_request<D: BlockDevice + ?Sized>(
+    device: &D,
+    start: u64,
+    buffer_length: usize,
+) -> Result<u64, BlockError> {
+    if buffer_length % device.block_size() != 0 {
+        return Err(BlockError::BadBufferSize);
+    }
+    let count = (buffer_length / device.block_size()) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+pub type SharedBlockDevice = Mutex<dyn BlockDevice + Send>;
+
+#[derive(Clone, Copy)]
+pub struct RegisteredBlockDevice {
+    pub name: DeviceName,
+    pub device: &'static SharedBlockDevice,
+    pub queue: &'static RequestQueue,
+}
+
+type BlockDeviceTable = [Option<RegisteredBlockDevice>; MAX_BLOCK_DEVICES];
+
+/// The registered block devices. They are looked up for every request and only change when a device is added, so lookups
+/// read the table with RCU and `register` publishes a changed copy.
+static BLOCK_DEVICES: Rcu<BlockDeviceTable> = Rcu::empty();
+/// Serializes `register`, so devices added at the same time don't lose each other's copy.
+static REGISTER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Copies the current table.
+fn devices() -> BlockDeviceTable {
+    let guard = rcu::read_lock();
+    BLOCK_DEVICES.read(&guard).copied().unwrap_or([None; MAX_BLOCK_DEVICES])
+}
+
+/// Makes a block device available by name and gives it a request queue, returns false if there are too many devices.
+pub fn register(name: DeviceName, device: &'static SharedBlockDevice) -> bool {
+    let writer = REGISTER_LOCK.lock();
+    let mut devices = devices();
+    match devices.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            // devices are never unregistered, so neither are their queues
+            let queue = Box::leak(Box::new(RequestQueue::new(device)));
+            *slot = Some(RegisteredBlockDevice { name, device, queue });
+            // the old copy is freed once the lookups that may be using it are done
+            BLOCK_DEVICES.replace(Box::new(devices));
+            drop(writer);
+            let parent = device::find_or_register("block", Bus::Block, None);
+            device::register(name, Bus::Block, parent);
+            event::publish(Event::DeviceAdded {
+                kind: DeviceKind::Block,
+                name,
+            });
+            true
+        }
+        None => false,
+    }
+}
+
+/// Finds a block device by name.
+pub fn find(name: &str) -> Option<&'static SharedBlockDevice> {
+    let guard = rcu::read_lock();
+    BLOCK_DEVICES
+        .read(&guard)?
+        .iter()
+        .flatten()
+        .find(|registered| registered.name.as_str() == name)
+        .map(|registered| registered.device)
+}
+
+/// Finds the request queue of a block device by name.
+pub fn find_queue(name: &str) -> Option<&'static RequestQueue> {
+    let guard = rcu::read_lock();
+    BLOCK_DEVICES
+        .read(&guard)?
+        .iter()
+        .flatten()
+        .find(|registered| registered.name.as_str() == name)
+        .map(|registered| registered.queue)
+}
+
+/// Calls `f` with each registered block device. It is called on a copy of the table, outside the read-side critical section,
+/// so it may block.
+pub fn for_each(mut f: impl FnMut(&RegisteredBlockDevice)) {
+    for registered in devices().iter().flatten() {
+        f(registered);
+    }
+}
+
+/// Counts the registered devices whose names start with `prefix`, for numbering new devices.
+pub fn count_with_prefix(prefix: &str) -> usize {
+    let guard = rcu::read_lock();
+    let Some(devices) = BLOCK_DEVICES.read(&guard) else {
+        return 0;
+    };
+    devices
+        .iter()
+        .flatten()
+        .filter(|registered| registered.name.as_str().starts_with(prefix))
+        .count()
+}
@@ -0,0 +1,359 @@
+//! Request queues, which sit between the users of a block device and its driver.
+//! Requests carry their own buffer and a completion callback that gets the buffer back. A queue merges requests for adjacent
+//! blocks into one driver call, which matters for devices like SD cards where each command has a large fixed cost.
+//!
+//! Requests are scheduled by priority class, so synchronous reads (someone is waiting for them) go ahead of background
+//! write-back. Every request also gets a deadline from its class when it is submitted; once a deadline passes that request goes
+//! first regardless of class, so the lower classes can't be starved.
+//!
+//! There are no kernel threads yet, so the queue's worker runs on whichever CPU submits a request while the worker is idle,
+//! and completions are called from there. Submitting under a `Plug` holds requests back until the plug is dropped, so a
+//! batch of requests can be merged before any of them are sent to the driver.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::{check_request, BlockError, SharedBlockDevice};
+use crate::delay::Deadline;
+
+/// The largest request merging will build, so one request can't hold the device for too long.
+const MAX_MERGED_BYTES: usize = 128 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// How urgently a request is needed, higher classes are sent to the driver first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Someone is waiting for the request, like a synchronous read or a metadata read.
+    Sync,
+    Normal,
+    /// Nobody is waiting, like write-back.
+    Background,
+}
+
+impl Priority {
+    /// How long a request of this class can wait before it goes ahead of everything else.
+    fn expiry_ms(&self, operation: Operation) -> u64 {
+        match (self, operation) {
+            (Priority::Sync, _) => 10,
+            (Priority::Normal, Operation::Read) => 100,
+            (Priority::Normal, Operation::Write) => 1000,
+            (Priority::Background, _) => 5000,
+        }
+    }
+}
+
+/// Called when a request finishes, with the request (so the buffer can be reused) and the result.
+pub type Completion = Box<dyn FnOnce(BlockRequest, Result<(), BlockError>) + Send>;
+
+pub struct BlockRequest {
+    pub operation: Operation,
+    /// The first block
+    pub start: u64,
+    /// The data to write, or where to put the data read. Must be a whole number of blocks.
+    pub buffer: Vec<u8>,
+    pub priority: Priority,
+    /// Set when the request is submitted
+    deadline: Option<Deadline>,
+    completion: Option<Completion>,
+}
+
+impl BlockRequest {
+    pub fn new(
+        operation: Operation,
+        start: u64,
+        buffer: Vec<u8>,
+        completion: impl FnOnce(BlockRequest, Result<(), BlockError>) + Send + 'static,
+    ) -> Self {
+        BlockRequest {
+            operation,
+            start,
+            buffer,
+            priority: Priority::Normal,
+            deadline: None,
+            completion: Some(Box::new(completion)),
+        }
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    fn complete(mut self, result: Result<(), BlockError>) {
+        if let Some(completion) = self.completion.take() {
+            completion(self, result);
+        }
+    }
+}
+
+pub struct RequestQueue {
+    device: &'static SharedBlockDevice,
+    pending: Mutex<VecDeque<BlockRequest>>,
+    /// Held while the worker runs
+    worker: Mutex<()>,
+    plugs: AtomicUsize,
+    /// The number of requests merged into another instead of going to the driver on their own
+    merged: AtomicU64,
+}
+
+impl RequestQueue {
+    pub fn new(device: &'static SharedBlockDevice) -> Self {
+        RequestQueue {
+            device,
+            pending: Mutex::new(VecDeque::new()),
+            worker: Mutex::new(()),
+            plugs: AtomicUsize::new(0),
+            merged: AtomicU64::new(0),
+        }
+    }
+
+    pub fn device(&self) -> &'static SharedBlockDevice {
+        self.device
+    }
+
+    /// Queues a request. Unless the queue is plugged it runs before this returns, if no other CPU is running the queue.
+    pub fn submit(&self, mut request: BlockRequest) {
+        request.deadline = Some(Deadline::after_ms(request.priority.expiry_ms(request.operation)));
+        self.pending.lock().push_back(request);
+        if self.plugs.load(Ordering::Acquire) == 0 {
+            self.run();
+        }
+    }
+
+    /// Holds back requests until the returned plug is dropped.
+    pub fn plug(&self) -> Plug<'_> {
+        self.plugs.fetch_add(1, Ordering::AcqRel);
+        Plug { queue: self }
+    }
+
+    /// Gets the number of requests that were merged into another.
+    pub fn merged_count(&self) -> u64 {
+        self.merged.load(Ordering::Relaxed)
+    }
+
+    /// Sends pending requests to the driver until there are none left, unless another CPU is already doing so.
+    pub fn run(&self) {
+        loop {
+            let Some(_worker) = self.worker.try_lock() else {
+                return;
+            };
+            while let Some(batch) = self.next_batch() {
+                self.dispatch(batch);
+            }
+            drop(_worker);
+            // a request submitted while the worker was finishing would otherwise be left behind
+            if self.pending.lock().is_empty() {
+                return;
+            }
+        }
+    }
+
+    /// Chooses the next request: the one whose deadline passed longest ago, or if none have passed the oldest request of the
+    /// highest class.
+    fn choose(pending: &VecDeque<BlockRequest>) -> Option<usize> {
+        let expired = pending
+            .iter()
+            .enumerate()
+            .filter(|(_, request)| request.deadline.is_some_and(|deadline| deadline.has_passed()))
+            .min_by_key(|(_, request)| request.deadline);
+        if let Some((index, _)) = expired {
+            return Some(index);
+        }
+        // `min_by_key` keeps the first of equal keys, so this is first in first out within a class
+        pending
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, request)| request.priority)
+            .map(|(index, _)| index)
+    }
+
+    /// Takes the next request, along with pending requests of any class that continue it.
+    fn next_batch(&self) -> Option<Vec<BlockRequest>> {
+        let mut pending = self.pending.lock();
+        let index = Self::choose(&pending)?;
+        let first = pending.remove(index).unwrap();
+        let block_size = self.device.lock().block_size();
+        let mut end = first.start + (first.buffer.len() / block_size) as u64;
+        let mut bytes = first.buffer.len();
+        let operation = first.operation;
+        // a bad request is sent alone, so its error doesn't fail the others
+        let mergeable = first.buffer.len() % block_size == 0;
+        let mut batch = vec![first];
+        while let Some(index) = pending.iter().position(|request| {
+            mergeable
+                && request.operation == operation
+                && request.start == end
+                && request.buffer.len() % block_size == 0
+                && bytes + request.buffer.len() <= MAX_MERGED_BYTES
+        }) {
+            let request = pending.remove(index).unwrap();
+            end += (request.buffer.len() / block_size) as u64;
+            bytes += request.buffer.len();
+            batch.push(request);
+        }
+        self.merged.fetch_add(batch.len() as u64 - 1, Ordering::Relaxed);
+        Some(batch)
+    }
+
+    /// Sends a batch of adjacent requests to the driver as one request, then completes them.
+    fn dispatch(&self, mut batch: Vec<BlockRequest>) {
+        let start = batch[0].start;
+        let operation = batch[0].operation;
+        let result = {
+            let mut device = self.device.lock();
+            if batch.len() == 1 {
+                let request = &mut batch[0];
+                match operation {
+                    Operation::Read => device.read_blocks(start, &mut request.buffer),
+                    Operation::Write => device.write_blocks(start, &request.buffer),
+                }
+            } else {
+                let length = batch.iter().map(|request| request.buffer.len()).sum();
+                match operation {
+                    Operation::Read => {
+                        let mut buffer = vec![0; length];
+                        let result = device.read_blocks(start, &mut buffer);
+                        if result.is_ok() {
+                            let mut offset = 0;
+                            for request in batch.iter_mut() {
+                                let end = offset + request.buffer.len();
+                                request.buffer.copy_from_slice(&buffer[offset..end]);
+                                offset = end;
+                            }
+                        }
+                        result
+                    }
+                    Operation::Write => {
+                        let mut buffer = Vec::with_capacity(length);
+                        for request in batch.iter() {
+                            buffer.extend_from_slice(&request.buffer);
+                        }
+                        device.write_blocks(start, &buffer)
+                    }
+                }
+            }
+        };
+        // completions may submit more requests, so they are called without the device locked
+        for request in batch {
+            request.complete(result);
+        }
+    }
+}
+
+/// Holds back a queue's requests while it exists, so they can be merged.
+pub struct Plug<'a> {
+    queue: &'a RequestQueue,
+}
+
+impl Drop for Plug<'_> {
+    fn drop(&mut self) {
+        if self.queue.plugs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.queue.run();
+        }
+    }
+}
+
+/// Checks that plugged adjacent requests reach the driver as one request and complete with the right data.
+pub fn self_check() {
+    use super::BlockDevice;
+
+    struct CountingDisk {
+        data: [u8; 8 * 512],
+        calls: usize,
+    }
+
+    impl BlockDevice for CountingDisk {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn block_count(&self) -> u64 {
+            8
+        }
+
+        fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+            check_request(self, start, buffer.len())?;
+            self.calls += 1;
+            let start = start as usize * 512;
+            buffer.copy_from_slice(&self.data[start..start + buffer.len()]);
+            Ok(())
+        }
+
+        fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+            check_request(self, start, buffer.len())?;
+            self.calls += 1;
+            let start = start as usize * 512;
+            self.data[start..start + buffer.len()].copy_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    let disk: &'static Mutex<CountingDisk> = Box::leak(Box::new(Mutex::new(CountingDisk {
+        data: [0; 8 * 512],
+        calls: 0,
+    })));
+    let queue = RequestQueue::new(disk);
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+    COMPLETED.store(0, Ordering::Relaxed);
+
+    {
+        let _plug = queue.plug();
+        // submitted out of order, merging has to find the continuation
+        for block in [2u8, 0, 1] {
+            queue.submit(BlockRequest::new(Operation::Write, block as u64, vec![block + 1; 512], |_, result| {
+                assert_eq!(result, Ok(()));
+                COMPLETED.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        assert_eq!(disk.lock().calls, 0);
+    }
+    assert_eq!(COMPLETED.load(Ordering::Relaxed), 3);
+    // block 2 was first so it went alone, then 0 and 1 were merged
+    assert_eq!(disk.lock().calls, 2);
+    assert_eq!(queue.merged_count(), 1);
+
+    {
+        let _plug = queue.plug();
+        for block in 0..3u64 {
+            queue.submit(BlockRequest::new(Operation::Read, block, vec![0; 512], move |request, result| {
+                assert_eq!(result, Ok(()));
+                assert!(request.buffer.iter().all(|&byte| byte == block as u8 + 1));
+                COMPLETED.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+    }
+    assert_eq!(COMPLETED.load(Ordering::Relaxed), 6);
+    assert_eq!(disk.lock().calls, 3);
+
+    queue.submit(BlockRequest::new(Operation::Read, 8, vec![0; 512], |_, result| {
+        assert_eq!(result, Err(BlockError::OutOfRange));
+    }));
+
+    // a synchronous read submitted after background writes goes first
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+    ORDER.store(0, Ordering::Relaxed);
+    {
+        let _plug = queue.plug();
+        for block in [4, 6] {
+            let request = BlockRequest::new(Operation::Write, block, vec![0; 512], |_, _| {
+                assert_ne!(ORDER.fetch_add(1, Ordering::Relaxed), 0);
+            });
+            queue.submit(request.with_priority(Priority::Background));
+        }
+        let request = BlockRequest::new(Operation::Read, 0, vec![0; 512], |_, _| {
+            assert_eq!(ORDER.fetch_add(1, Ordering::Relaxed), 0);
+        });
+        queue.submit(request.with_priority(Priority::Sync));
+    }
+    assert_eq!(ORDER.load(Ordering::Relaxed), 3);
+}
@@ -0,0 +1,159 @@
+//! RAM disks, block devices stored in frames from the frame allocator.
+//! The frames don't need to be contiguous, the disk keeps a two level table of them: a directory frame pointing to table frames,
+//! each pointing to up to 512 data frames.
+
+use spin::Mutex;
+
+use crate::block::{self, check_request, BlockDevice, BlockError};
+use crate::device::DeviceName;
+use crate::init::InitError;
+use crate::memory::{DirectMappedAddress, PhysicalAddress};
+use crate::pmm::{leak_in_frame, memzero_frame, FrameAllocator};
+use crate::{cmdline, log, FRAME_ALLOCATOR};
+
+const FRAME_SIZE: usize = 0x1000;
+const BLOCK_SIZE: usize = 512;
+/// The number of frame addresses that fit in a table frame.
+const ENTRIES_PER_TABLE: usize = FRAME_SIZE / 8;
+/// The largest RAM disk, limited by the size of the directory.
+pub const MAX_RAMDISK_SIZE: u64 = (ENTRIES_PER_TABLE * ENTRIES_PER_TABLE * FRAME_SIZE) as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamDiskError {
+    /// The size is zero or larger than `MAX_RAMDISK_SIZE`.
+    BadSize,
+    /// There weren't enough free frames.
+    OutOfMemory,
+    /// The block device registry is full.
+    TooManyDevices,
+}
+
+type FrameTable = [u64; ENTRIES_PER_TABLE];
+
+/// A block device backed by frames. Its frames are never freed.
+pub struct RamDisk {
+    /// The physical addresses of the table frames
+    directory: &'static mut FrameTable,
+    frame_count: usize,
+}
+
+/// Allocates a zeroed frame, returning its physical address.
+fn allocate_zeroed_frame() -> Option<u64> {
+    let frame = FRAME_ALLOCATOR.get()?.lock().allocate()?;
+    // This is safe because the frame was just allocated, so nothing else references it
+    unsafe { memzero_frame(&frame) };
+    Some(frame.get_starting_address().get_address())
+}
+
+/// Gets a frame by its physical address.
+fn frame<T>(address: u64) -> *mut T {
+    DirectMappedAddress::from_physical(PhysicalAddress::new(address)).as_pointer::<T>()
+}
+
+impl RamDisk {
+    /// Creates a zeroed RAM disk of `size` bytes, rounded up to a whole number of frames.
+    /// If the frame allocator runs out part way, the frames allocated so far are not returned.
+    pub fn new(size: u64) -> Result<Self, RamDiskError> {
+        if size == 0 || size > MAX_RAMDISK_SIZE {
+            return Err(RamDiskError::BadSize);
+        }
+        let frame_count = size.div_ceil(FRAME_SIZE as u64) as usize;
+        let directory = allocate_zeroed_frame().ok_or(RamDiskError::OutOfMemory)?;
+        // This is safe because the directory frame is only referenced by this disk
+        let directory = unsafe { &mut *frame::<FrameTable>(directory) };
+        for (i, table) in directory.iter_mut().take(frame_count.div_ceil(ENTRIES_PER_TABLE)).enumerate() {
+            *table = allocate_zeroed_frame().ok_or(RamDiskError::OutOfMemory)?;
+            // This is safe because the table frame is only referenced by this disk
+            let table = unsafe { &mut *frame::<FrameTable>(*table) };
+            let entries = (frame_count - i * ENTRIES_PER_TABLE).min(ENTRIES_PER_TABLE);
+            for entry in table.iter_mut().take(entries) {
+                *entry = allocate_zeroed_frame().ok_or(RamDiskError::OutOfMemory)?;
+            }
+        }
+        Ok(RamDisk {
+            directory,
+            frame_count,
+        })
+    }
+
+    /// Gets the data frame at `index`.
+    fn data_frame(&self, index: usize) -> *mut [u8; FRAME_SIZE] {
+        let table = frame::<FrameTable>(self.directory[index / ENTRIES_PER_TABLE]);
+        // This is safe because table frames are only referenced by this disk
+        frame(unsafe { (*table)[index % ENTRIES_PER_TABLE] })
+    }
+
+    /// Calls `f` with each part of the disk covered by `length` bytes from block `start`, as (frame, range in frame, offset in the request).
+    fn for_each_chunk(&self, start: u64, length: usize, mut f: impl FnMut(*mut [u8; FRAME_SIZE], core::ops::Range<usize>, usize)) {
+        let mut position = start as usize * BLOCK_SIZE;
+        let mut done = 0;
+        while done < length {
+            let offset = position % FRAME_SIZE;
+            let chunk = (FRAME_SIZE - offset).min(length - done);
+            f(self.data_frame(position / FRAME_SIZE), offset..offset + chunk, done);
+            position += chunk;
+            done += chunk;
+        }
+    }
+}
+
+// This is safe because the disk's frames are only accessed through the `Mutex` it is registered in
+unsafe impl Send for RamDisk {}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.frame_count * FRAME_SIZE / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.for_each_chunk(start, buffer.len(), |frame, range, done| {
+            // This is safe because data frames are only referenced by this disk
+            let frame = unsafe { &*frame };
+            buffer[done..done + range.len()].copy_from_slice(&frame[range]);
+        });
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.for_each_chunk(start, buffer.len(), |frame, range, done| {
+            // This is safe because data frames are only referenced by this disk, and the disk is borrowed mutably
+            let frame = unsafe { &mut *frame };
+            frame[range.clone()].copy_from_slice(&buffer[done..done + range.len()]);
+        });
+        Ok(())
+    }
+}
+
+/// Creates a RAM disk of `size` bytes and registers it as `ramN`, returning its name.
+pub fn create(size: u64) -> Result<DeviceName, RamDiskError> {
+    let disk = RamDisk::new(size)?;
+    let disk = leak_in_frame(Mutex::new(disk)).ok_or(RamDiskError::OutOfMemory)?;
+    let name = DeviceName::new("ram", block::count_with_prefix("ram"));
+    if !block::register(name, disk) {
+        return Err(RamDiskError::TooManyDevices);
+    }
+    Ok(name)
+}
+
+/// Creates the RAM disk requested with the `ramdisk=<SIZE>` command line option, if any.
+pub fn init_ramdisk() -> Result<(), InitError> {
+    let Some(size) = cmdline::option("ramdisk") else {
+        return Ok(());
+    };
+    let size = cmdline::parse_size(size).ok_or(InitError::new("Invalid ramdisk size"))?;
+    match create(size) {
+        Ok(name) => {
+            log!("ramdisk: created {} with {} bytes", name, size);
+            Ok(())
+        }
+        Err(RamDiskError::BadSize) => Err(InitError::new("Invalid ramdisk size")),
+        Err(RamDiskError::OutOfMemory) => Err(InitError::new("Out of memory for the ramdisk")),
+        Err(RamDiskError::TooManyDevices) => Err(InitError::new("Too many block devices")),
+    }
+}
@@ -0,0 +1,98 @@
+//! A bump allocator for memory needed before the frame allocator exists.
+//! It hands out memory from the start of the largest usable region in the memory map. Once the frame allocator is set up,
+//! `finish()` stops the allocator and reports how much it used so the frame allocator can take over the rest of the region.
+
+use core::ops::Range;
+
+use limine::MemoryMapEntryType;
+use spin::Mutex;
+
+use crate::{HHDM_REQUEST, MEMORY_MAP_REQUEST};
+
+/// The most memory the boot allocator will use.
+const BOOTMEM_SIZE: u64 = 1 << 20;
+
+#[derive(Debug)]
+struct BootMemAllocator {
+    /// The physical address of the start of the region
+    start: u64,
+    /// The physical address of the next free byte
+    next: u64,
+    /// The physical address of the end of the region
+    end: u64,
+    /// Offset of the direct map, the bootloader's HHDM
+    direct_map_start: u64,
+    /// Set once the frame allocator has taken over
+    finished: bool,
+}
+
+static BOOTMEM: Mutex<Option<BootMemAllocator>> = Mutex::new(None);
+
+/// Picks the region the boot allocator uses. Does nothing if the memory map or HHDM aren't available, in which case allocations fail.
+pub fn init() {
+    let Some(memory_map) = MEMORY_MAP_REQUEST.get_response().get() else {
+        return;
+    };
+    let Some(hhdm) = HHDM_REQUEST.get_response().get() else {
+        return;
+    };
+    let Some(region) = memory_map
+        .memmap()
+        .iter()
+        .filter(|entry| entry.typ == MemoryMapEntryType::Usable)
+        .max_by_key(|entry| entry.len)
+    else {
+        return;
+    };
+    *BOOTMEM.lock() = Some(BootMemAllocator {
+        start: region.base,
+        next: region.base,
+        end: region.base + region.len.min(BOOTMEM_SIZE),
+        direct_map_start: hhdm.offset,
+        finished: false,
+    });
+}
+
+/// Allocates `size` bytes aligned to `align` (which must be a power of two), returns a pointer to zeroed memory in the direct map.
+/// Memory from the boot allocator is never freed.
+pub fn alloc(size: usize, align: usize) -> Option<*mut u8> {
+    assert!(align.is_power_of_two(), "bootmem alignment must be a power of two");
+    let mut bootmem = BOOTMEM.lock();
+    let bootmem = bootmem.as_mut()?;
+    assert!(!bootmem.finished, "bootmem used after the frame allocator took over");
+    let start = bootmem.next.checked_add(align as u64 - 1)? & !(align as u64 - 1);
+    let end = start.checked_add(size as u64)?;
+    if end > bootmem.end {
+        return None;
+    }
+    bootmem.next = end;
+    let pointer = (start + bootmem.direct_map_start) as *mut u8;
+    // This is safe because the memory is usable RAM that nothing else has been given
+    unsafe { pointer.write_bytes(0, size) };
+    Some(pointer)
+}
+
+/// Allocates zeroed space for a `T`.
+pub fn alloc_zeroed<T>() -> Option<*mut T> {
+    alloc(core::mem::size_of::<T>(), core::mem::align_of::<T>()).map(|pointer| pointer as *mut T)
+}
+
+/// Allocates `count` zeroed, contiguous 4KiB frames, returning the physical address of the first one.
+pub fn alloc_frames(count: usize) -> Option<u64> {
+    let pointer = alloc(count * 0x1000, 0x1000)?;
+    let direct_map_start = BOOTMEM.lock().as_ref()?.direct_map_start;
+    Some(pointer as u64 - direct_map_start)
+}
+
+/// Stops the boot allocator, returning the physical memory it used (rounded up to whole frames).
+/// The frame allocator must not hand out this range, the rest of the boot allocator's region is its to use.
+pub fn finish() -> Range<u64> {
+    let mut bootmem = BOOTMEM.lock();
+    match bootmem.as_mut() {
+        Some(bootmem) => {
+            bootmem.finished = true;
+            bootmem.start..(bootmem.next + 0xFFF) & !0xFFF
+        }
+        None => 0..0,
+    }
+}
@@ -0,0 +1,284 @@
+//! A buddy allocator for runs of physically contiguous frames, for DMA buffers and huge pages.
+//! It manages one zone of memory set aside at boot; the rest of memory stays with the single frame `MemoryMapAllocator`.
+//! Blocks are 2^order frames, aligned to their size. Freeing a block merges it with its buddy (the other half of the block
+//! of the next order up) whenever the buddy is also free.
+
+use core::ops::Range;
+use core::ptr::null_mut;
+
+use limine::{MemmapEntry, MemoryMapEntryType, NonNullPtr};
+
+use crate::memory::PhysicalAddress;
+use crate::page_info::{self, PageInfoFlags};
+use crate::pmm::{Frame, FrameAllocator};
+use crate::{bootmem, cmdline};
+
+/// The largest order, blocks of 2^10 frames (4MiB).
+pub const MAX_ORDER: usize = 10;
+const FRAME_SIZE: u64 = 0x1000;
+/// The size of the largest block, the zone is aligned to this.
+pub const MAX_BLOCK_SIZE: u64 = FRAME_SIZE << MAX_ORDER;
+
+/// Marks a frame's metadata as the first frame of a free block, the low bits hold the block's order.
+const FREE_BLOCK: u8 = 0x80;
+/// Marks a frame's metadata as the first frame of an allocated block, the low bits hold the block's order.
+const ALLOCATED_BLOCK: u8 = 0x40;
+/// Metadata of frames that aren't the first frame of a block.
+const NOT_FREE_HEAD: u8 = 0;
+
+/// A free block, stored in its first frame. The free lists are doubly linked so a buddy can be taken out of the middle.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    previous: *mut FreeBlock,
+}
+
+pub struct BuddyAllocator {
+    /// The physical address of the start of the zone
+    base: u64,
+    frame_count: usize,
+    /// A byte for each frame in the zone, see `FREE_BLOCK`
+    metadata: &'static mut [u8],
+    free_lists: [*mut FreeBlock; MAX_ORDER + 1],
+    free_frames: usize,
+    /// Offset of the direct map
+    direct_map_start: u64,
+}
+
+// This is probably fine because the free lists are only accessed through the allocator
+unsafe impl Send for BuddyAllocator {}
+
+impl core::fmt::Debug for BuddyAllocator {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BuddyAllocator")
+            .field("zone", &self.zone())
+            .field("free_frames", &self.free_frames)
+            .finish()
+    }
+}
+
+/// Gets the smallest order whose blocks hold `count` frames.
+pub fn order_for(count: usize) -> usize {
+    count.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl BuddyAllocator {
+    /// Creates an allocator for the frames in `zone`, whose start must be aligned to `MAX_BLOCK_SIZE`.
+    /// `metadata` must have a byte for each frame in the zone.
+    /// Safety: the zone must be usable memory that nothing else uses, and must be in the direct map at `direct_map_start`.
+    pub unsafe fn new(zone: Range<u64>, metadata: &'static mut [u8], direct_map_start: u64) -> Self {
+        assert_eq!(zone.start % MAX_BLOCK_SIZE, 0, "Buddy allocator zone is not aligned");
+        let frame_count = ((zone.end - zone.start) / FRAME_SIZE) as usize;
+        assert!(metadata.len() >= frame_count, "Buddy allocator metadata is too small");
+        metadata.fill(NOT_FREE_HEAD);
+        let mut allocator = BuddyAllocator {
+            base: zone.start,
+            frame_count,
+            metadata,
+            free_lists: [null_mut(); MAX_ORDER + 1],
+            free_frames: 0,
+            direct_map_start,
+        };
+        // cover the zone with the largest blocks that fit
+        let mut index = 0;
+        while index < frame_count {
+            let mut order = MAX_ORDER;
+            while index % (1 << order) != 0 || index + (1 << order) > frame_count {
+                order -= 1;
+            }
+            allocator.push(index, order);
+            index += 1 << order;
+        }
+        allocator
+    }
+
+    fn block(&self, index: usize) -> *mut FreeBlock {
+        (self.base + index as u64 * FRAME_SIZE + self.direct_map_start) as *mut FreeBlock
+    }
+
+    fn index_of(&self, block: *mut FreeBlock) -> usize {
+        ((block as u64 - self.direct_map_start - self.base) / FRAME_SIZE) as usize
+    }
+
+    /// Adds the block at frame `index` to the free list for `order`.
+    fn push(&mut self, index: usize, order: usize) {
+        let block = self.block(index);
+        let head = self.free_lists[order];
+        // This is safe because the block is free and in the zone, and every block on the list is too
+        unsafe {
+            block.write(FreeBlock {
+                next: head,
+                previous: null_mut(),
+            });
+            if !head.is_null() {
+                (*head).previous = block;
+            }
+        }
+        self.free_lists[order] = block;
+        self.metadata[index] = FREE_BLOCK | order as u8;
+        self.free_frames += 1 << order;
+    }
+
+    /// Takes the block at frame `index` off the free list for `order`.
+    fn remove(&mut self, index: usize, order: usize) {
+        let block = self.block(index);
+        // This is safe because the block is on the free list, so it and its neighbours are free blocks in the zone
+        unsafe {
+            let FreeBlock { next, previous } = block.read();
+            if previous.is_null() {
+                self.free_lists[order] = next;
+            } else {
+                (*previous).next = next;
+            }
+            if !next.is_null() {
+                (*next).previous = previous;
+            }
+        }
+        self.metadata[index] = NOT_FREE_HEAD;
+        self.free_frames -= 1 << order;
+    }
+
+    /// Allocates a block of 2^`order` frames, aligned to its size. Returns the physical address of the first frame.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysicalAddress> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        // find the smallest free block that is large enough
+        let found = (order..=MAX_ORDER).find(|&order| !self.free_lists[order].is_null())?;
+        let index = self.index_of(self.free_lists[found]);
+        self.remove(index, found);
+        // split it, giving back the upper halves
+        for split in (order..found).rev() {
+            self.push(index + (1 << split), split);
+        }
+        self.metadata[index] = ALLOCATED_BLOCK | order as u8;
+        let address = self.base + index as u64 * FRAME_SIZE;
+        page_info::allocated(address, 1 << order, PageInfoFlags::BUDDY);
+        Some(PhysicalAddress::new(address))
+    }
+
+    /// Allocates at least `count` contiguous frames, rounded up to a power of two.
+    pub fn allocate_frames(&mut self, count: usize) -> Option<PhysicalAddress> {
+        self.allocate(order_for(count))
+    }
+
+    /// Frees a block allocated with `allocate(order)`, merging it with its buddies.
+    /// Panics unless the block is an allocated block of that order in this zone, so a block that is already free (on its own
+    /// or merged into a larger free block) can't be freed again.
+    pub fn free(&mut self, address: PhysicalAddress, order: usize) {
+        let address = address.get_address();
+        assert!(
+            address >= self.base && address < self.base + self.frame_count as u64 * FRAME_SIZE,
+            "Attempted to free a block outside the buddy zone"
+        );
+        let mut index = ((address - self.base) / FRAME_SIZE) as usize;
+        assert_eq!(index % (1 << order), 0, "Attempted to free a misaligned block");
+        match self.metadata[index] {
+            metadata if metadata == ALLOCATED_BLOCK | order as u8 => {}
+            metadata if metadata & ALLOCATED_BLOCK != 0 => panic!(
+                "Attempted to free a block of order {} as order {}",
+                metadata & !ALLOCATED_BLOCK,
+                order
+            ),
+            _ => panic!("Attempted to free a block that isn't allocated"),
+        }
+        self.metadata[index] = NOT_FREE_HEAD;
+        page_info::freed(address, 1 << order);
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy + (1 << order) > self.frame_count || self.metadata[buddy] != FREE_BLOCK | order as u8 {
+                break;
+            }
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    /// Frees `count` frames allocated with `allocate_frames(count)`.
+    pub fn free_frames(&mut self, address: PhysicalAddress, count: usize) {
+        self.free(address, order_for(count));
+    }
+
+    /// Returns the physical memory the zone covers.
+    pub fn zone(&self) -> Range<u64> {
+        self.base..self.base + self.frame_count as u64 * FRAME_SIZE
+    }
+
+    /// Returns the number of free frames.
+    pub fn free_frame_count(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of free blocks of each order.
+    pub fn free_blocks(&self) -> [usize; MAX_ORDER + 1] {
+        let mut counts = [0; MAX_ORDER + 1];
+        for (count, &head) in counts.iter_mut().zip(self.free_lists.iter()) {
+            let mut block = head;
+            while !block.is_null() {
+                *count += 1;
+                // This is safe because every block on a free list is a free block in the zone
+                block = unsafe { (*block).next };
+            }
+        }
+        counts
+    }
+}
+
+impl FrameAllocator for BuddyAllocator {
+    fn allocate(&mut self) -> Option<Frame> {
+        BuddyAllocator::allocate(self, 0).map(Frame::from_starting_address)
+    }
+
+    fn free(&mut self, frame: Frame) {
+        BuddyAllocator::free(self, frame.get_starting_address(), 0);
+    }
+}
+
+/// Checks that splitting and merging blocks puts the allocator back the way it was.
+pub fn self_check(allocator: &mut BuddyAllocator) {
+    let free_before = allocator.free_frame_count();
+    let blocks_before = allocator.free_blocks();
+    let single = allocator.allocate(0).unwrap();
+    let huge = allocator.allocate(9).unwrap();
+    assert_eq!(huge.get_address() % (FRAME_SIZE << 9), 0);
+    let odd = allocator.allocate_frames(3).unwrap();
+    assert_eq!(allocator.free_frame_count(), free_before - 1 - 512 - 4);
+    let odd_index = ((odd.get_address() - allocator.base) / FRAME_SIZE) as usize;
+    assert_eq!(allocator.metadata[odd_index], ALLOCATED_BLOCK | 2);
+    assert_eq!(allocator.metadata[odd_index + 1], NOT_FREE_HEAD);
+    allocator.free(single, 0);
+    allocator.free_frames(odd, 3);
+    allocator.free(huge, 9);
+    assert_eq!(allocator.free_frame_count(), free_before);
+    assert_eq!(allocator.free_blocks(), blocks_before);
+}
+
+/// The default size of the buddy zone, `buddy=<SIZE>` on the command line overrides it.
+const DEFAULT_ZONE_SIZE: u64 = 16 << 20;
+
+/// Sets aside the buddy zone at the end of the largest usable region, below the last `reserved_end` bytes of it, and allocates
+/// its metadata from the boot allocator.
+/// Must be called before `bootmem::finish()`. Returns None if the region is too small or the zone is disabled with `buddy=0`.
+pub fn reserve_zone(memory_map: &[NonNullPtr<MemmapEntry>], reserved_end: u64) -> Option<(Range<u64>, &'static mut [u8])> {
+    let size = cmdline::option("buddy")
+        .and_then(cmdline::parse_size)
+        .unwrap_or(DEFAULT_ZONE_SIZE);
+    let region = memory_map
+        .iter()
+        .filter(|entry| entry.typ == MemoryMapEntryType::Usable)
+        .max_by_key(|entry| entry.len)?;
+    // leave most of the region to the frame allocator, and the start of it to the boot allocator
+    let size = size.min(region.len / 4) & !(MAX_BLOCK_SIZE - 1);
+    if size == 0 {
+        return None;
+    }
+    let end = region.base + region.len - reserved_end;
+    let start = (end - size) & !(MAX_BLOCK_SIZE - 1);
+    let frame_count = ((end - start) / FRAME_SIZE) as usize;
+    let metadata = bootmem::alloc(frame_count, 1)?;
+    // This is safe because the boot allocator just gave us this memory
+    let metadata = unsafe { core::slice::from_raw_parts_mut(metadata, frame_count) };
+    Some((start..end, metadata))
+}
@@ -0,0 +1,74 @@
+//! Assertions that produce a useful crash report rather than a bare panic message.
+//! `kassert!` and `kbug!` name the subsystem that found the problem and take a closure that describes the relevant state.
+//! The report is formatted by the panic handler once the other CPUs are stopped, so the closure sees the state as it was left,
+//! and it ends with the last lines of the log.
+
+use core::fmt::{self, Display, Write};
+
+use crate::log;
+
+/// The number of log lines included in a report.
+const RECENT_LOG_LINES: usize = 16;
+
+/// Describes the state relevant to a bug, for the crash report.
+pub type StateSnapshot<'a> = &'a dyn Fn(&mut dyn Write) -> fmt::Result;
+
+/// Panics with a report if `condition` is false.
+/// `kassert!(subsystem, condition)`, `kassert!(subsystem, condition, state)` or `kassert!(subsystem, condition, state, format, args...)`,
+/// where `state` is a closure that writes the relevant state to the `&mut dyn fmt::Write` it is given.
+#[macro_export]
+macro_rules! kassert {
+    ($subsystem:expr, $condition:expr $(,)?) => {
+        $crate::kassert!($subsystem, $condition, |_: &mut dyn core::fmt::Write| Ok(()))
+    };
+    ($subsystem:expr, $condition:expr, $state:expr $(,)?) => {
+        if !$condition {
+            $crate::bug::report($subsystem, format_args!("assertion failed: {}", stringify!($condition)), &$state)
+        }
+    };
+    ($subsystem:expr, $condition:expr, $state:expr, $($arg:tt)+) => {
+        if !$condition {
+            $crate::bug::report($subsystem, format_args!($($arg)+), &$state)
+        }
+    };
+}
+
+/// Panics with a report, for code that found something that can't happen.
+/// `kbug!(subsystem, state, format, args...)`, see `kassert!`.
+#[macro_export]
+macro_rules! kbug {
+    ($subsystem:expr, $state:expr, $($arg:tt)+) => {
+        $crate::bug::report($subsystem, format_args!($($arg)+), &$state)
+    };
+}
+
+/// The panic message of a report, formatted when the panic handler prints it.
+struct BugReport<'a> {
+    subsystem: &'a str,
+    message: fmt::Arguments<'a>,
+    state: StateSnapshot<'a>,
+}
+
+impl Display for BugReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "bug in {}: {}", self.subsystem, self.message)?;
+        writeln!(f, "state:")?;
+        (self.state)(f)?;
+        writeln!(f, "\nrecent log:")?;
+        // This is safe because the panic handler formats the report after stopping the other CPUs
+        unsafe { log::write_recent_lines(f, RECENT_LOG_LINES) }
+    }
+}
+
+/// Panics with a report of a bug in `subsystem`. Use `kassert!` or `kbug!` rather than calling this.
+#[track_caller]
+pub fn report(subsystem: &str, message: fmt::Arguments, state: StateSnapshot) -> ! {
+    panic!(
+        "{}",
+        BugReport {
+            subsystem,
+            message,
+            state,
+        }
+    )
+}
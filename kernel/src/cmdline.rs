@@ -0,0 +1,48 @@
+//! Access to the kernel command line passed by the bootloader.
+//! The command line is a list of whitespace separated options, each either `key=value` or a bare `key`.
+
+use core::ffi::CStr;
+
+use crate::KERNEL_FILE_REQUEST;
+
+/// Gets the kernel command line, or an empty string if the bootloader didn't give us one.
+pub fn get() -> &'static str {
+    KERNEL_FILE_REQUEST
+        .get_response()
+        .get()
+        .and_then(|response| response.kernel_file.get())
+        .and_then(|file| file.cmdline.as_ptr())
+        // This is safe because Limine gives us a null terminated string that is never freed
+        .and_then(|cmdline| unsafe { CStr::from_ptr(cmdline) }.to_str().ok())
+        .unwrap_or("")
+}
+
+/// Gets the value of the option `key`. A bare `key` has the value "".
+pub fn option(key: &str) -> Option<&'static str> {
+    get().split_whitespace().find_map(|option| match option.split_once('=') {
+        Some((k, value)) if k == key => Some(value),
+        None if option == key => Some(""),
+        _ => None,
+    })
+}
+
+/// Gets the value of a boolean option, which is true for a bare `key` or `key=1`/`key=on`/`key=true`.
+/// Returns `default` if the option is missing or its value is not recognized.
+pub fn flag(key: &str, default: bool) -> bool {
+    match option(key) {
+        Some("" | "1" | "on" | "true") => true,
+        Some("0" | "off" | "false") => false,
+        _ => default,
+    }
+}
+
+/// Parses a size like `4096`, `64K`, `16M` or `1G`.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (number, shift) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
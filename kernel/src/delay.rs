@@ -0,0 +1,237 @@
+//! Busy-wait delays and deadlines, measured with the TSC.
+//! The TSC frequency is read once at boot from the crystal clock ratio in CPUID leaf 0x15, which is exact, or calibrated
+//! against the ACPI PM timer when the processor doesn't report it, with the base frequency from leaf 0x16 as a last resort.
+//! `udelay` and `mdelay` don't need interrupts, so they work with interrupts disabled. Before the TSC is calibrated they count
+//! PM timer ticks instead, so delays in early device setup are still the right length.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::acpi::fadt::{FadtFlags, GenericAddressStructure};
+use crate::init::InitError;
+use crate::{log, ACPI_TABLES};
+
+/// The frequency of the ACPI PM timer in Hz.
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+/// How long to measure the TSC against the PM timer for, in PM timer ticks (about 10ms).
+const CALIBRATION_TICKS: u64 = PM_TIMER_FREQUENCY / 100;
+/// The frequency assumed before calibration, or if calibration fails.
+/// This is higher than any real TSC so uncalibrated delays are too long rather than too short.
+const FALLBACK_TSC_KHZ: u64 = 10_000_000;
+
+/// The TSC frequency in kHz, 0 until calibrated.
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+/// Gets the TSC frequency in kHz.
+pub fn tsc_khz() -> u64 {
+    match TSC_KHZ.load(Ordering::Relaxed) {
+        0 => FALLBACK_TSC_KHZ,
+        khz => khz,
+    }
+}
+
+/// Finds the TSC frequency, this must run after the ACPI tables are found to use the PM timer.
+pub fn init_delay() -> Result<(), InitError> {
+    let (khz, source) = if let Some(khz) = tsc_khz_from_crystal() {
+        (khz, "CPUID crystal clock")
+    } else if let Some(pm_timer) = PmTimer::find() {
+        (calibrate_with_pm_timer(&pm_timer), "PM timer calibration")
+    } else {
+        let khz = tsc_khz_from_base_frequency().ok_or(InitError::new("unable to determine the TSC frequency"))?;
+        (khz, "CPUID base frequency")
+    };
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+    log::set_tsc_frequency(khz);
+    log!("delay: TSC runs at {} kHz ({})", khz, source);
+    Ok(())
+}
+
+/// The ACPI PM timer, a counter running at `PM_TIMER_FREQUENCY` that is used to calibrate the other timers.
+pub struct PmTimer {
+    block: GenericAddressStructure,
+    /// The timer is 24 or 32 bits wide.
+    mask: u64,
+}
+
+impl PmTimer {
+    /// Finds the PM timer in the FADT, returns None if there is no PM timer or it can't be read.
+    pub fn find() -> Option<Self> {
+        let fadt = ACPI_TABLES.get().and_then(|tables| tables.fadt())?;
+        let block = fadt.pm_timer_block().filter(|block| block.is_accessible())?;
+        let mask = if fadt.flags().contains(FadtFlags::TMR_VAL_EXT) {
+            0xFFFF_FFFF
+        } else {
+            0xFF_FFFF
+        };
+        Some(PmTimer { block, mask })
+    }
+
+    /// Gets the bits the timer has, 24 or 32.
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    pub fn read(&self) -> u64 {
+        // This is safe because reading the PM timer has no side effects
+        unsafe { self.block.read() & self.mask }
+    }
+
+    /// Busy-waits until the timer has advanced `ticks` since it read `start`, returns the ticks that actually passed.
+    /// `ticks` must be less than `mask()`, or the timer wraps around before the wait ends.
+    pub fn wait_since(&self, start: u64, ticks: u64) -> u64 {
+        loop {
+            // The timer wraps around, the mask makes the subtraction handle that
+            let elapsed = self.read().wrapping_sub(start) & self.mask;
+            if elapsed >= ticks {
+                return elapsed;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Busy-waits for at least `us` microseconds, waiting half a wrap of the timer at a time.
+    pub fn delay(&self, us: u64) {
+        let mut remaining = (us as u128 * PM_TIMER_FREQUENCY as u128).div_ceil(1_000_000) as u64;
+        let mut start = self.read();
+        while remaining > 0 {
+            let elapsed = self.wait_since(start, remaining.min(self.mask / 2));
+            start = start.wrapping_add(elapsed) & self.mask;
+            remaining = remaining.saturating_sub(elapsed);
+        }
+    }
+}
+
+/// Measures the TSC frequency against the PM timer.
+fn calibrate_with_pm_timer(pm_timer: &PmTimer) -> u64 {
+    let start_ticks = pm_timer.read();
+    let start_tsc = unsafe { _rdtsc() };
+    let elapsed_ticks = pm_timer.wait_since(start_ticks, CALIBRATION_TICKS);
+    let elapsed_tsc = unsafe { _rdtsc() } - start_tsc;
+    elapsed_tsc * PM_TIMER_FREQUENCY / elapsed_ticks / 1000
+}
+
+/// Gets the TSC frequency from the crystal clock in CPUID leaf 0x15, if the processor reports it.
+fn tsc_khz_from_crystal() -> Option<u64> {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 0x15 {
+        return None;
+    }
+    let leaf = unsafe { __cpuid(0x15) };
+    // ebx/eax is the ratio of the TSC to the crystal clock, ecx is the crystal frequency in Hz
+    if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+        return Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64 / 1000);
+    }
+    None
+}
+
+/// Gets the processor's base frequency from CPUID leaf 0x16, which is close to the TSC frequency.
+fn tsc_khz_from_base_frequency() -> Option<u64> {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 0x16 {
+        let base_mhz = unsafe { __cpuid(0x16) }.eax & 0xFFFF;
+        if base_mhz != 0 {
+            return Some(base_mhz as u64 * 1000);
+        }
+    }
+    None
+}
+
+/// A point in time, measured in TSC cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline {
+    tsc: u64,
+}
+
+impl Deadline {
+    /// Gets a deadline `us` microseconds from now.
+    pub fn after_us(us: u64) -> Self {
+        let cycles = us.saturating_mul(tsc_khz()) / 1000;
+        Deadline {
+            tsc: unsafe { _rdtsc() }.saturating_add(cycles),
+        }
+    }
+
+    /// Gets a deadline `ms` milliseconds from now.
+    pub fn after_ms(ms: u64) -> Self {
+        Deadline::after_us(ms.saturating_mul(1000))
+    }
+
+    pub fn has_passed(&self) -> bool {
+        let now = unsafe { _rdtsc() };
+        now >= self.tsc
+    }
+}
+
+/// Busy-waits for at least `us` microseconds.
+pub fn udelay(us: u64) {
+    if TSC_KHZ.load(Ordering::Relaxed) == 0 {
+        if let Some(pm_timer) = PmTimer::find() {
+            pm_timer.delay(us);
+            return;
+        }
+    }
+    let deadline = Deadline::after_us(us);
+    while !deadline.has_passed() {
+        spin_loop();
+    }
+}
+
+/// Busy-waits for at least `ms` milliseconds.
+pub fn mdelay(ms: u64) {
+    udelay(ms.saturating_mul(1000));
+}
+
+/// The most spin loop hints `Backoff::spin()` issues in one call.
+const MAX_BACKOFF_SPINS: u32 = 1 << 10;
+
+/// Exponential backoff for polling hardware registers, so tight polling loops don't hammer the bus.
+#[derive(Debug)]
+pub struct Backoff {
+    spins: u32,
+}
+
+impl Backoff {
+    pub const fn new() -> Self {
+        Backoff { spins: 1 }
+    }
+
+    /// Spins for a while, twice as long as the previous call (up to a limit).
+    pub fn spin(&mut self) {
+        for _ in 0..self.spins {
+            spin_loop();
+        }
+        self.spins = (self.spins * 2).min(MAX_BACKOFF_SPINS);
+    }
+}
+
+/// Polls `condition` until it returns true or `timeout_us` microseconds have passed, returns whether the condition became true.
+pub fn poll_until(timeout_us: u64, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Deadline::after_us(timeout_us);
+    let mut backoff = Backoff::new();
+    loop {
+        if condition() {
+            return true;
+        }
+        if deadline.has_passed() {
+            // check one last time in case we were delayed between the check and the deadline
+            return condition();
+        }
+        backoff.spin();
+    }
+}
+
+/// Checks that delays last at least as long as asked, by the TSC and by the PM timer.
+pub fn self_check() {
+    let start = unsafe { _rdtsc() };
+    udelay(1000);
+    let elapsed = unsafe { _rdtsc() } - start;
+    assert!(elapsed >= tsc_khz(), "udelay(1000) took {} cycles", elapsed);
+
+    if let Some(pm_timer) = PmTimer::find() {
+        let start = pm_timer.read();
+        pm_timer.delay(1000);
+        let elapsed = pm_timer.read().wrapping_sub(start) & pm_timer.mask();
+        assert!(elapsed >= PM_TIMER_FREQUENCY / 1000, "the PM timer waited {} ticks for 1ms", elapsed);
+    }
+}
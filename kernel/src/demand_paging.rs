@@ -0,0 +1,178 @@
+//! Virtual regions that are only backed by memory once they are used.
+//! A region is reserved with `register`, and when a page in it is first touched the page fault handler maps a zeroed frame
+//! there and resumes the faulting code. This lets large regions like the heap be reserved without using memory up front.
+
+use crate::memory::VirtualAddress;
+use crate::pmm::{memzero_frame, FrameAllocator};
+use crate::sync::owned_mutex::OwnedMutex;
+use crate::x64::idt::PageFaultErrorCode;
+use crate::x64::page_table::PageFlags;
+use crate::x64::registers::get_cr3;
+use crate::x64::tlb;
+use crate::FRAME_ALLOCATOR;
+
+const MAX_REGIONS: usize = 16;
+const PAGE_SIZE: u64 = 0x1000;
+/// How many pages `unregister` unmaps before shooting them down and freeing their frames.
+const UNMAP_BATCH: u64 = 32;
+
+/// A reserved virtual region that is mapped on demand.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    pub name: &'static str,
+    pub start: u64,
+    pub size: u64,
+    /// The flags pages are mapped with.
+    pub flags: PageFlags,
+}
+
+impl LazyRegion {
+    fn contains(&self, address: u64) -> bool {
+        address >= self.start && address - self.start < self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The start or size isn't page aligned.
+    Unaligned,
+    /// The region overlaps one that is already registered.
+    Overlaps,
+    /// There is no room for another region.
+    Full,
+    /// The region isn't canonical, or is user accessible but in the kernel half.
+    InvalidAddress,
+}
+
+static REGIONS: OwnedMutex<[Option<LazyRegion>; MAX_REGIONS]> = OwnedMutex::new([None; MAX_REGIONS]);
+
+/// Reserves `size` bytes at `start`, to be mapped with `flags` as they are used.
+/// Kernel regions should be in a PML4 entry that exists at boot, see `address_space`.
+pub fn register(name: &'static str, start: u64, size: u64, flags: PageFlags) -> Result<(), RegionError> {
+    if size == 0 || start % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+        return Err(RegionError::Unaligned);
+    }
+    let first = VirtualAddress::try_create(start).map_err(|_| RegionError::InvalidAddress)?;
+    let last = VirtualAddress::try_create(start + size - 1).map_err(|_| RegionError::InvalidAddress)?;
+    if first.is_user() != last.is_user() || (flags.contains(PageFlags::USER) && first.is_kernel()) {
+        return Err(RegionError::InvalidAddress);
+    }
+    let region = LazyRegion {
+        name,
+        start,
+        size,
+        flags,
+    };
+    let mut regions = REGIONS.lock();
+    if regions
+        .iter()
+        .flatten()
+        .any(|other| start < other.start + other.size && other.start < start + size)
+    {
+        return Err(RegionError::Overlaps);
+    }
+    let slot = regions.iter_mut().find(|slot| slot.is_none()).ok_or(RegionError::Full)?;
+    *slot = Some(region);
+    Ok(())
+}
+
+/// Removes the region starting at `start`, unmapping and freeing the pages that were used.
+/// The pages are shot down on every CPU before their frames are freed, see `tlb::shootdown` for when that can be done.
+pub fn unregister(start: u64) -> Option<LazyRegion> {
+    let region = REGIONS
+        .lock()
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|region| region.start == start))?
+        .take()?;
+    let cr3 = get_cr3();
+    let pml4 = cr3.pml4();
+    let end = region.start + region.size;
+    for batch_start in (region.start..end).step_by((UNMAP_BATCH * PAGE_SIZE) as usize) {
+        let pages = ((end - batch_start) / PAGE_SIZE).min(UNMAP_BATCH);
+        let mut frames = [const { None }; UNMAP_BATCH as usize];
+        for (page, frame) in frames.iter_mut().take(pages as usize).enumerate() {
+            *frame = pml4.unmap(VirtualAddress::create(batch_start + page as u64 * PAGE_SIZE)).ok();
+        }
+        if frames.iter().all(Option::is_none) {
+            continue;
+        }
+        // another CPU may still have a translation cached, so the frames can't be reused until every TLB has dropped it
+        tlb::shootdown(VirtualAddress::create(batch_start), pages);
+        let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+        for frame in frames.into_iter().flatten() {
+            frame_allocator.free(frame);
+        }
+    }
+    Some(region)
+}
+
+/// Gets the region that `address` is in, if any.
+pub fn find(address: u64) -> Option<LazyRegion> {
+    REGIONS.lock().iter().flatten().find(|region| region.contains(address)).copied()
+}
+
+/// Handles a page fault at `address`, returns whether it was the first use of a page in a lazy region, which is now mapped.
+/// Faults on present pages are protection violations and are never handled here.
+pub fn handle_page_fault(address: u64, error_code: &PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PRESENT) {
+        return false;
+    }
+    // The fault may have happened while this CPU held a lock the handler needs, in which case it can't be handled. A lock
+    // held by another CPU is released, so the handler waits for it
+    if REGIONS.held_by_this_cpu() {
+        return false;
+    }
+    let Some(region) = REGIONS.lock().iter().flatten().find(|region| region.contains(address)).copied() else {
+        return false;
+    };
+    if error_code.contains(PageFaultErrorCode::USER) && !region.flags.contains(PageFlags::USER) {
+        return false;
+    }
+    let Some(frame_allocator) = FRAME_ALLOCATOR.get() else {
+        return false;
+    };
+    if frame_allocator.held_by_this_cpu() {
+        return false;
+    }
+    let Some(frame) = frame_allocator.lock().allocate() else {
+        return false;
+    };
+    // This is safe because the frame was just allocated
+    unsafe { memzero_frame(&frame) };
+    // This can't fail because the page wasn't present and `register` checked the flags suit the region's half
+    get_cr3()
+        .pml4()
+        .map(frame, VirtualAddress::create(address & !(PAGE_SIZE - 1)), region.flags)
+        .unwrap();
+    true
+}
+
+/// Checks that touching a lazy region maps it, that overlapping regions are refused and that unregistering unmaps it.
+pub fn self_check() {
+    const TEST_START: u64 = 0xFFFF_E000_0020_0000;
+
+    let free_before = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    register("self-test", TEST_START, 2 * PAGE_SIZE, PageFlags::KERNEL_DATA).unwrap();
+    assert_eq!(
+        register("overlap", TEST_START + PAGE_SIZE, PAGE_SIZE, PageFlags::KERNEL_DATA),
+        Err(RegionError::Overlaps)
+    );
+    assert_eq!(register("unaligned", TEST_START + 1, PAGE_SIZE, PageFlags::KERNEL_DATA), Err(RegionError::Unaligned));
+    let cr3 = get_cr3();
+    let pml4 = cr3.pml4();
+    assert!(pml4.translate(VirtualAddress::create(TEST_START + PAGE_SIZE)).is_none());
+    // This is safe because the page is in a lazy region, so it is mapped by the fault
+    unsafe {
+        let pointer = (TEST_START + PAGE_SIZE + 8) as *mut u64;
+        assert_eq!(pointer.read_volatile(), 0);
+        pointer.write_volatile(0x1234_5678);
+        assert_eq!(pointer.read_volatile(), 0x1234_5678);
+    }
+    assert!(pml4.translate(VirtualAddress::create(TEST_START + PAGE_SIZE)).is_some());
+    assert!(pml4.translate(VirtualAddress::create(TEST_START)).is_none());
+    unregister(TEST_START).unwrap();
+    assert!(find(TEST_START).is_none());
+    assert!(pml4.translate(VirtualAddress::create(TEST_START + PAGE_SIZE)).is_none());
+    // the paging structures created for the page are kept, so only the page itself is given back
+    assert!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames() <= free_before);
+}
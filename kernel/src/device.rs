@@ -0,0 +1,282 @@
+//! The device tree: every device the kernel knows about, whichever bus it was found on, with its parent.
+//! Drivers attach power management hooks to their devices. Suspend runs children before their parents (a device may still
+//! need its bus), resume runs parents first, and shutdown runs in the same order as suspend.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::dma::iommu::DmaDomain;
+use crate::drivers::serial::DEBUG_SERIAL_DRIVER;
+use crate::init::InitError;
+use crate::log;
+
+/// The most devices that can be registered.
+const MAX_DEVICES: usize = 128;
+/// The longest device name.
+const MAX_NAME_LENGTH: usize = 16;
+
+/// Identifies a registered device. Ids are reused after a device is removed.
+pub type DeviceId = usize;
+
+/// The name of a device, such as `sd0` or a PCI address.
+#[derive(Clone, Copy)]
+pub struct DeviceName {
+    bytes: [u8; MAX_NAME_LENGTH],
+    length: usize,
+}
+
+impl DeviceName {
+    /// Creates a name from a prefix and number, like `sd` and 0.
+    pub fn new(prefix: &str, number: usize) -> Self {
+        Self::from_fmt(format_args!("{}{}", prefix, number))
+    }
+
+    /// Creates a name from formatted text, names that don't fit are truncated.
+    pub fn from_fmt(args: fmt::Arguments) -> Self {
+        let mut name = DeviceName {
+            bytes: [0; MAX_NAME_LENGTH],
+            length: 0,
+        };
+        let _ = fmt::Write::write_fmt(&mut name, args);
+        name
+    }
+
+    pub fn as_str(&self) -> &str {
+        // This can't fail because only whole `str`s are written
+        core::str::from_utf8(&self.bytes[..self.length]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for DeviceName {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.length + s.len();
+        if end > MAX_NAME_LENGTH {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.length..end].copy_from_slice(s.as_bytes());
+        self.length = end;
+        Ok(())
+    }
+}
+
+impl fmt::Display for DeviceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Where a device was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    /// Devices at fixed addresses, like the legacy serial ports.
+    Platform,
+    /// Devices described by ACPI tables.
+    Acpi,
+    Pci,
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// The device can't change state now, for example because it has requests in flight.
+    Busy,
+    DeviceError,
+}
+
+/// Power management hooks of a driver, which do nothing unless overridden.
+pub trait DeviceDriver: Sync {
+    /// Stops the device and saves whatever `resume` needs to restart it.
+    fn suspend(&self) -> Result<(), PowerError> {
+        Ok(())
+    }
+
+    /// Restarts the device after `suspend`, the device may have lost power in between.
+    fn resume(&self) -> Result<(), PowerError> {
+        Ok(())
+    }
+
+    /// Quiesces the device before the system resets or powers off.
+    fn shutdown(&self) {}
+}
+
+#[derive(Clone, Copy)]
+pub struct Device {
+    pub name: DeviceName,
+    pub bus: Bus,
+    pub parent: Option<DeviceId>,
+    pub driver: Option<&'static dyn DeviceDriver>,
+    /// The IOMMU domain the device does DMA in, None for the passthrough domain.
+    pub dma_domain: Option<&'static dyn DmaDomain>,
+}
+
+static DEVICES: Mutex<[Option<Device>; MAX_DEVICES]> = Mutex::new([None; MAX_DEVICES]);
+
+/// Adds a device, returns its id or None if there are too many devices.
+pub fn register(name: DeviceName, bus: Bus, parent: Option<DeviceId>) -> Option<DeviceId> {
+    let mut devices = DEVICES.lock();
+    let id = devices.iter().position(|slot| slot.is_none())?;
+    devices[id] = Some(Device {
+        name,
+        bus,
+        parent,
+        driver: None,
+        dma_domain: None,
+    });
+    Some(id)
+}
+
+/// Finds a device by name, registering it if there is none. For bus nodes that are created by whichever device needs them first.
+pub fn find_or_register(name: &str, bus: Bus, parent: Option<DeviceId>) -> Option<DeviceId> {
+    find(name).or_else(|| register(DeviceName::from_fmt(format_args!("{}", name)), bus, parent))
+}
+
+/// Attaches a driver's power management hooks to a device.
+pub fn set_driver(id: DeviceId, driver: &'static dyn DeviceDriver) {
+    if let Some(device) = DEVICES.lock()[id].as_mut() {
+        device.driver = Some(driver);
+    }
+}
+
+/// Puts a device in an IOMMU domain, which the DMA API gets its bus addresses from.
+pub fn set_dma_domain(id: DeviceId, domain: &'static dyn DmaDomain) {
+    if let Some(device) = DEVICES.lock()[id].as_mut() {
+        device.dma_domain = Some(domain);
+    }
+}
+
+/// Removes a device and its children.
+pub fn unregister(id: DeviceId) {
+    let mut devices = DEVICES.lock();
+    devices[id] = None;
+    // removing a device orphans its children, so repeat until nothing is orphaned
+    loop {
+        let orphan = (0..MAX_DEVICES).find(|&child| {
+            devices[child].is_some_and(|device| device.parent.is_some_and(|parent| devices[parent].is_none()))
+        });
+        match orphan {
+            Some(child) => devices[child] = None,
+            None => break,
+        }
+    }
+}
+
+pub fn find(name: &str) -> Option<DeviceId> {
+    DEVICES
+        .lock()
+        .iter()
+        .position(|slot| slot.is_some_and(|device| device.name.as_str() == name))
+}
+
+pub fn get(id: DeviceId) -> Option<Device> {
+    DEVICES.lock().get(id).copied().flatten()
+}
+
+/// Calls `f` with each device and its depth in the tree, parents before their children.
+pub fn walk(mut f: impl FnMut(DeviceId, &Device, usize)) {
+    // copied so `f` can register devices
+    let devices = *DEVICES.lock();
+    fn visit(devices: &[Option<Device>], parent: Option<DeviceId>, depth: usize, f: &mut impl FnMut(DeviceId, &Device, usize)) {
+        for (id, device) in devices.iter().enumerate() {
+            if let Some(device) = device.filter(|device| device.parent == parent) {
+                f(id, &device, depth);
+                visit(devices, Some(id), depth + 1, f);
+            }
+        }
+    }
+    visit(&devices, None, 0, &mut f);
+}
+
+/// Gets the devices with drivers, parents before their children.
+fn drivers_in_order() -> ([Option<(DeviceId, &'static dyn DeviceDriver)>; MAX_DEVICES], usize) {
+    let mut order = [None; MAX_DEVICES];
+    let mut count = 0;
+    walk(|id, device, _| {
+        if let Some(driver) = device.driver {
+            order[count] = Some((id, driver));
+            count += 1;
+        }
+    });
+    (order, count)
+}
+
+/// Suspends every device, children first. If a device fails the devices already suspended are resumed, and the failing device
+/// is returned with the error.
+pub fn suspend_all() -> Result<(), (DeviceId, PowerError)> {
+    let (order, count) = drivers_in_order();
+    for (index, entry) in order[..count].iter().enumerate().rev() {
+        let Some((id, driver)) = *entry else {
+            continue;
+        };
+        if let Err(error) = driver.suspend() {
+            for &(resumed, driver) in order[index + 1..count].iter().flatten() {
+                if let Err(error) = driver.resume() {
+                    log!("device: resuming {} failed: {:?}", resumed, error);
+                }
+            }
+            return Err((id, error));
+        }
+    }
+    Ok(())
+}
+
+/// Resumes every device, parents first. Devices that fail are logged and skipped.
+pub fn resume_all() {
+    let (order, count) = drivers_in_order();
+    for &(id, driver) in order[..count].iter().flatten() {
+        if let Err(error) = driver.resume() {
+            log!("device: resuming {} failed: {:?}", id, error);
+        }
+    }
+}
+
+/// Shuts down every device, children first.
+pub fn shutdown_all() {
+    let (order, count) = drivers_in_order();
+    for &(_, driver) in order[..count].iter().flatten().rev() {
+        driver.shutdown();
+    }
+}
+
+/// Registers the platform devices that are always there.
+pub fn init_devices() -> Result<(), InitError> {
+    let platform = find_or_register("platform", Bus::Platform, None);
+    let serial =
+        register(DeviceName::new("serial", 0), Bus::Platform, platform).ok_or(InitError::new("Too many devices"))?;
+    set_driver(serial, &DEBUG_SERIAL_DRIVER);
+    Ok(())
+}
+
+/// Checks that suspend runs children before parents and resume runs parents first, and that removing a device removes its children.
+pub fn self_check() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Each hook shifts its device's number into this, so the order they ran in can be read back.
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+    struct TestDriver(usize);
+    impl DeviceDriver for TestDriver {
+        fn suspend(&self) -> Result<(), PowerError> {
+            ORDER.store(ORDER.load(Ordering::Relaxed) * 10 + self.0, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn resume(&self) -> Result<(), PowerError> {
+            self.suspend()
+        }
+    }
+    static PARENT: TestDriver = TestDriver(1);
+    static CHILD: TestDriver = TestDriver(2);
+
+    let parent = register(DeviceName::new("test", 0), Bus::Platform, None).unwrap();
+    let child = register(DeviceName::new("test", 1), Bus::Platform, Some(parent)).unwrap();
+    set_driver(child, &CHILD);
+    set_driver(parent, &PARENT);
+    ORDER.store(0, Ordering::Relaxed);
+    assert!(suspend_all().is_ok());
+    assert_eq!(ORDER.load(Ordering::Relaxed), 21);
+    ORDER.store(0, Ordering::Relaxed);
+    resume_all();
+    assert_eq!(ORDER.load(Ordering::Relaxed), 12);
+    unregister(parent);
+    assert!(get(child).is_none());
+}
@@ -0,0 +1,67 @@
+//! IOMMU domains, the address spaces devices do DMA in. The DMA API gets bus addresses from the domain of the device it is
+//! given, so with an IOMMU they are IOVAs and a device can only reach what was mapped for it.
+//! There is no IOMMU driver yet, so every device is in the passthrough domain, where the bus address is the physical address.
+//! An IOMMU driver attaches its domains to devices with `device::set_dma_domain`.
+
+use crate::acpi_signature;
+use crate::device::{self, DeviceId};
+use crate::init::InitError;
+use crate::log;
+use crate::memory::PhysicalAddress;
+use crate::ACPI_TABLES;
+
+use super::{AddressLimit, DmaError};
+
+/// The address space a device's DMA goes through.
+pub trait DmaDomain: Sync {
+    /// Makes the `length` bytes at `physical_address` reachable by the domain's devices at an address within `limit`, and
+    /// returns that address.
+    fn map(&self, physical_address: PhysicalAddress, length: usize, limit: AddressLimit) -> Result<u64, DmaError>;
+
+    /// Makes `length` bytes at `bus_address`, from `map`, unreachable again.
+    fn unmap(&self, bus_address: u64, length: usize);
+
+    fn name(&self) -> &'static str;
+}
+
+/// The domain of devices without an IOMMU, which can reach all of memory at its physical addresses.
+pub struct Passthrough;
+
+impl DmaDomain for Passthrough {
+    fn map(&self, physical_address: PhysicalAddress, length: usize, limit: AddressLimit) -> Result<u64, DmaError> {
+        let bus_address = physical_address.get_address();
+        if !limit.reaches(bus_address, length) {
+            return Err(DmaError::NotAddressable);
+        }
+        Ok(bus_address)
+    }
+
+    fn unmap(&self, _bus_address: u64, _length: usize) {}
+
+    fn name(&self) -> &'static str {
+        "passthrough"
+    }
+}
+
+pub static PASSTHROUGH: Passthrough = Passthrough;
+
+/// Gets the domain `device` does DMA in, the passthrough domain unless an IOMMU driver gave it another.
+pub fn domain_of(device: DeviceId) -> &'static dyn DmaDomain {
+    device::get(device)
+        .and_then(|device| device.dma_domain)
+        .unwrap_or(&PASSTHROUGH)
+}
+
+/// Looks for an IOMMU in the ACPI tables, VT-d's DMAR or AMD-Vi's IVRS. Without a driver for it devices stay in the
+/// passthrough domain, which is also where they are when there is no IOMMU at all.
+pub fn init_iommu() -> Result<(), InitError> {
+    let xsdt = ACPI_TABLES.get().ok_or(InitError::new("no ACPI tables"))?.xsdt();
+    if xsdt.get_table(acpi_signature!('D', 'M', 'A', 'R')).is_some() {
+        log!("iommu: found a DMAR table, there is no VT-d driver so devices use passthrough DMA");
+    } else if xsdt.get_table(acpi_signature!('I', 'V', 'R', 'S')).is_some() {
+        log!("iommu: found an IVRS table, there is no AMD-Vi driver so devices use passthrough DMA");
+    } else {
+        log!("iommu: none found, devices use passthrough DMA");
+    }
+    Ok(())
+}
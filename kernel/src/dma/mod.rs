@@ -0,0 +1,435 @@
+//! Memory for devices to read and write with DMA.
+//! Devices see bus addresses, which are physical addresses unless an IOMMU puts the device in a domain of its own (see
+//! `iommu`); drivers should only get them from here, for their device, so that can change in one place.
+//! x86 DMA is cache coherent, so keeping the CPU and the device in sync is only a matter of ordering, see `x64::barrier`.
+//!
+//! Coherent buffers are long lived and shared with the device, like descriptor rings. They come from the buddy zone so they are
+//! physically contiguous. The buddy zone is usually above 4GB, so when it is, a second zone below 4GB is set aside at boot for
+//! devices with 32 bit DMA addresses and handed out by a `DmaAllocator`. Streaming mappings give the device one buffer for one transfer, which the CPU must not touch until
+//! the mapping is synced or unmapped.
+
+pub mod iommu;
+pub mod sg;
+
+use core::ops::Range;
+
+use limine::{MemmapEntry, MemoryMapEntryType, NonNullPtr};
+
+use crate::buddy::{order_for, BuddyAllocator, MAX_BLOCK_SIZE};
+use crate::device::DeviceId;
+use crate::lowmem::LOW_MEMORY_END;
+use crate::memory::{DirectMappedAddress, PhysicalAddress, VirtualAddress};
+use crate::x64::barrier::{dma_read_barrier, dma_write_barrier};
+use crate::{bootmem, cmdline, BUDDY_ALLOCATOR, DMA32_ALLOCATOR};
+
+use self::iommu::{domain_of, DmaDomain};
+
+const FRAME_SIZE: usize = 0x1000;
+/// The end of the memory devices with 32 bit DMA addresses can reach.
+const DMA32_LIMIT: u64 = 1 << 32;
+/// The default size of the zone below 4GB, `dma32=<SIZE>` on the command line overrides it.
+const DEFAULT_DMA32_ZONE_SIZE: u64 = 4 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// There is no contiguous memory zone, or it has no block large enough.
+    OutOfMemory,
+    /// The memory is above what the device can address.
+    NotAddressable,
+    /// A streaming buffer isn't in the direct map, so it may not be physically contiguous.
+    NotDirectMapped,
+    /// Part of a buffer isn't mapped.
+    NotMapped,
+}
+
+/// The physical addresses a device can reach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressLimit {
+    /// Devices with 32 bit DMA addresses, which can only reach the first 4GB.
+    Dma32,
+    Dma64,
+}
+
+impl AddressLimit {
+    /// Checks whether the device can reach all of `length` bytes at bus address `address`.
+    pub fn reaches(&self, address: u64, length: usize) -> bool {
+        match self {
+            AddressLimit::Dma32 => address + length as u64 <= 1 << 32,
+            AddressLimit::Dma64 => true,
+        }
+    }
+}
+
+/// Which way the data of a streaming mapping moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    ToDevice,
+    FromDevice,
+    Bidirectional,
+}
+
+/// Gets the address a device in the passthrough domain uses to reach `physical_address`.
+pub fn bus_address(physical_address: PhysicalAddress) -> u64 {
+    physical_address.get_address()
+}
+
+/// Gets the order of the buddy block that holds `length` bytes aligned to `align`. Blocks are aligned to their size.
+fn block_order(length: usize, align: usize) -> usize {
+    order_for(length.div_ceil(FRAME_SIZE)).max(order_for(align / FRAME_SIZE))
+}
+
+/// Hands out physically contiguous memory below 4GB, for devices with 32 bit DMA addresses.
+#[derive(Debug)]
+pub struct DmaAllocator {
+    zone: BuddyAllocator,
+}
+
+impl DmaAllocator {
+    /// Creates an allocator for the frames in `zone`, which must end below 4GB. See `BuddyAllocator::new()`.
+    /// Safety: the zone must be usable memory that nothing else uses, and must be in the direct map at `direct_map_start`.
+    pub unsafe fn new(zone: Range<u64>, metadata: &'static mut [u8], direct_map_start: u64) -> Self {
+        assert!(zone.end <= DMA32_LIMIT, "DMA32 zone is above 4GB");
+        DmaAllocator {
+            zone: BuddyAllocator::new(zone, metadata, direct_map_start),
+        }
+    }
+
+    /// Allocates at least `length` contiguous bytes aligned to `align`, which must be a power of two.
+    /// Returns the physical address and the direct mapped pointer of the memory, which is not zeroed.
+    pub fn allocate(&mut self, length: usize, align: usize) -> Option<(PhysicalAddress, *mut u8)> {
+        assert!(align.is_power_of_two(), "DMA alignment must be a power of two");
+        let physical_address = self.zone.allocate(block_order(length, align))?;
+        Some((physical_address, DirectMappedAddress::from_physical(physical_address).as_pointer::<u8>()))
+    }
+
+    /// Frees memory from `allocate()`, `length` and `align` must be what it was allocated with.
+    pub fn free(&mut self, physical_address: PhysicalAddress, length: usize, align: usize) {
+        self.zone.free(physical_address, block_order(length, align));
+    }
+
+    pub fn zone(&self) -> Range<u64> {
+        self.zone.zone()
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        self.zone.free_frame_count()
+    }
+}
+
+/// Sets aside the zone below 4GB for `DmaAllocator`, at the end of the largest usable region below 4GB that doesn't overlap
+/// `reserved`, and allocates its metadata from the boot allocator.
+/// Must be called before `bootmem::finish()`. Returns None if there is no room or the zone is disabled with `dma32=0`.
+pub fn reserve_dma32_zone(
+    memory_map: &[NonNullPtr<MemmapEntry>],
+    reserved: &[Range<u64>],
+) -> Option<(Range<u64>, &'static mut [u8])> {
+    let size = cmdline::option("dma32")
+        .and_then(cmdline::parse_size)
+        .unwrap_or(DEFAULT_DMA32_ZONE_SIZE);
+    let region = memory_map
+        .iter()
+        .filter(|entry| entry.typ == MemoryMapEntryType::Usable)
+        .filter_map(|entry| {
+            let start = entry.base.max(LOW_MEMORY_END);
+            // the zone goes below whatever is already reserved at the end of the region
+            let end = reserved
+                .iter()
+                .filter(|range| range.start < range.end && range.start < entry.base + entry.len && range.end > start)
+                .fold(entry.base + entry.len, |end, range| end.min(range.start));
+            // the frame allocator can only leave out the end of a region, so regions that go past 4GB can't be used
+            (end <= DMA32_LIMIT && start < end).then_some(start..end)
+        })
+        .max_by_key(|range| range.end - range.start)?;
+    // leave most of the region to the frame allocator
+    let size = size.min((region.end - region.start) / 4) & !(MAX_BLOCK_SIZE - 1);
+    if size == 0 {
+        return None;
+    }
+    let start = (region.end - size) & !(MAX_BLOCK_SIZE - 1);
+    if start < region.start {
+        return None;
+    }
+    let frame_count = ((region.end - start) / FRAME_SIZE as u64) as usize;
+    let metadata = bootmem::alloc(frame_count, 1)?;
+    // This is safe because the boot allocator just gave us this memory
+    let metadata = unsafe { core::slice::from_raw_parts_mut(metadata, frame_count) };
+    Some((start..region.end, metadata))
+}
+
+/// Which allocator a `DmaBuffer` came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Zone {
+    Buddy,
+    Dma32,
+}
+
+/// A zeroed, physically contiguous buffer shared with a device. It is unmapped from the device's domain and returned to its
+/// allocator when dropped.
+pub struct DmaBuffer {
+    pointer: *mut u8,
+    physical_address: PhysicalAddress,
+    bus_address: u64,
+    domain: &'static dyn DmaDomain,
+    length: usize,
+    align: usize,
+    zone: Zone,
+}
+
+// This is safe because the buffer is only referenced by this `DmaBuffer` (and the device)
+unsafe impl Send for DmaBuffer {}
+
+impl DmaBuffer {
+    /// Gets the kernel's pointer to the buffer.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.pointer
+    }
+
+    /// Gets the address the device uses for the buffer.
+    pub fn bus_address(&self) -> u64 {
+        self.bus_address
+    }
+
+    /// Gets the physical address of the buffer, devices should be given `bus_address()` instead.
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.physical_address
+    }
+
+    /// Gets the length that was asked for, the buffer may be larger.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Gets the buffer's contents. The device may change them at any time, so this should only be used when it is known not to.
+    pub fn as_slice(&self) -> &[u8] {
+        // This is safe because the buffer is at least `length` bytes, and is only referenced through this
+        unsafe { core::slice::from_raw_parts(self.pointer, self.length) }
+    }
+
+    /// Gets the buffer's contents. Call `dma_write_barrier` after changing them and before telling the device to look.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // This is safe because the buffer is at least `length` bytes, and is only referenced through this
+        unsafe { core::slice::from_raw_parts_mut(self.pointer, self.length) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        self.domain
+            .unmap(self.bus_address, FRAME_SIZE << block_order(self.length, self.align));
+        match self.zone {
+            Zone::Buddy => BUDDY_ALLOCATOR
+                .get()
+                .unwrap()
+                .lock()
+                .free(self.physical_address, block_order(self.length, self.align)),
+            Zone::Dma32 => DMA32_ALLOCATOR
+                .get()
+                .unwrap()
+                .lock()
+                .free(self.physical_address, self.length, self.align),
+        }
+    }
+}
+
+/// Allocates a zeroed buffer of at least `length` bytes for `device`, which can reach all of its bus addresses.
+pub fn alloc_coherent(device: DeviceId, length: usize) -> Result<DmaBuffer, DmaError> {
+    alloc_coherent_limited(device, length, AddressLimit::Dma64)
+}
+
+/// Allocates a zeroed buffer of at least `length` bytes that `device`, with the address limit `limit`, can reach.
+pub fn alloc_coherent_limited(device: DeviceId, length: usize, limit: AddressLimit) -> Result<DmaBuffer, DmaError> {
+    alloc_coherent_aligned(device, length, FRAME_SIZE, limit)
+}
+
+/// Allocates a zeroed buffer of at least `length` bytes aligned to `align` (a power of two), that `device`, with the address
+/// limit `limit`, can reach. Buffers are always at least page aligned.
+pub fn alloc_coherent_aligned(
+    device: DeviceId,
+    length: usize,
+    align: usize,
+    limit: AddressLimit,
+) -> Result<DmaBuffer, DmaError> {
+    assert!(align.is_power_of_two(), "DMA alignment must be a power of two");
+    let domain = domain_of(device);
+    let order = block_order(length, align);
+    let size = FRAME_SIZE << order;
+    let (physical_address, zone) = match (limit, DMA32_ALLOCATOR.get()) {
+        (AddressLimit::Dma32, Some(allocator)) => {
+            let (physical_address, _) = allocator.lock().allocate(length, align).ok_or(DmaError::OutOfMemory)?;
+            (physical_address, Zone::Dma32)
+        }
+        _ => {
+            let physical_address = BUDDY_ALLOCATOR
+                .get()
+                .ok_or(DmaError::OutOfMemory)?
+                .lock()
+                .allocate(order)
+                .ok_or(DmaError::OutOfMemory)?;
+            (physical_address, Zone::Buddy)
+        }
+    };
+    let bus_address = match domain.map(physical_address, size, limit) {
+        Ok(bus_address) => bus_address,
+        Err(error) => {
+            match zone {
+                Zone::Buddy => BUDDY_ALLOCATOR.get().unwrap().lock().free(physical_address, order),
+                Zone::Dma32 => DMA32_ALLOCATOR.get().unwrap().lock().free(physical_address, length, align),
+            }
+            return Err(error);
+        }
+    };
+    let pointer = DirectMappedAddress::from_physical(physical_address).as_pointer_with_size::<u8>(size as u64);
+    // This is safe because the block was just allocated, so nothing else references it
+    unsafe { pointer.write_bytes(0, size) };
+    Ok(DmaBuffer {
+        pointer,
+        physical_address,
+        bus_address,
+        domain,
+        length,
+        align,
+        zone,
+    })
+}
+
+/// A buffer lent to a device for a transfer.
+pub struct DmaMapping {
+    bus_address: u64,
+    length: usize,
+    direction: DmaDirection,
+    domain: &'static dyn DmaDomain,
+}
+
+impl DmaMapping {
+    pub fn bus_address(&self) -> u64 {
+        self.bus_address
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn direction(&self) -> DmaDirection {
+        self.direction
+    }
+
+    /// Gives the buffer back to the device after the CPU has written to it.
+    pub fn sync_for_device(&self) {
+        if self.direction != DmaDirection::FromDevice {
+            dma_write_barrier();
+        }
+    }
+
+    /// Lets the CPU read what the device wrote, once the device has said it is done.
+    pub fn sync_for_cpu(&self) {
+        if self.direction != DmaDirection::ToDevice {
+            dma_read_barrier();
+        }
+    }
+}
+
+/// Maps `length` bytes at `pointer` for a transfer in `direction`, by `device` with the address limit `limit`.
+/// The buffer must be in the direct map, so it is physically contiguous; it stays owned by the caller, who must not use it
+/// until `unmap_single`.
+pub fn map_single(
+    device: DeviceId,
+    pointer: *const u8,
+    length: usize,
+    direction: DmaDirection,
+    limit: AddressLimit,
+) -> Result<DmaMapping, DmaError> {
+    let start = DirectMappedAddress::try_from_virtual(VirtualAddress::create(pointer as u64)).ok_or(DmaError::NotDirectMapped)?;
+    if length > 0 {
+        DirectMappedAddress::try_from_virtual(VirtualAddress::create(pointer as u64 + length as u64 - 1))
+            .ok_or(DmaError::NotDirectMapped)?;
+    }
+    let domain = domain_of(device);
+    let bus_address = domain.map(start.get_physical_address(), length, limit)?;
+    let mapping = DmaMapping {
+        bus_address,
+        length,
+        direction,
+        domain,
+    };
+    mapping.sync_for_device();
+    Ok(mapping)
+}
+
+/// Ends a transfer, after which the CPU can use the buffer again.
+pub fn unmap_single(mapping: DmaMapping) {
+    mapping.sync_for_cpu();
+    mapping.domain.unmap(mapping.bus_address, mapping.length);
+}
+
+/// Checks that coherent buffers are zeroed, reachable at their bus address, and go back to the buddy allocator when dropped,
+/// and that a device's domain gives its bus addresses.
+pub fn self_check() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::device::{self, Bus, DeviceName};
+
+    /// A domain that puts memory at a fixed offset from its physical address, and counts what is mapped.
+    struct OffsetDomain {
+        mapped: AtomicUsize,
+    }
+
+    const OFFSET: u64 = 1 << 48;
+
+    impl DmaDomain for OffsetDomain {
+        fn map(&self, physical_address: PhysicalAddress, length: usize, _: AddressLimit) -> Result<u64, DmaError> {
+            self.mapped.fetch_add(length, Ordering::SeqCst);
+            Ok(physical_address.get_address() + OFFSET)
+        }
+
+        fn unmap(&self, _: u64, length: usize) {
+            self.mapped.fetch_sub(length, Ordering::SeqCst);
+        }
+
+        fn name(&self) -> &'static str {
+            "offset"
+        }
+    }
+
+    static OFFSET_DOMAIN: OffsetDomain = OffsetDomain {
+        mapped: AtomicUsize::new(0),
+    };
+
+    let device = device::register(DeviceName::new("dmatest", 0), Bus::Platform, None).unwrap();
+    let free_before = BUDDY_ALLOCATOR.get().unwrap().lock().free_frame_count();
+    let mut buffer = alloc_coherent(device, 3 * FRAME_SIZE).unwrap();
+    assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+    assert_eq!(buffer.bus_address() % (4 * FRAME_SIZE) as u64, 0);
+    buffer.as_mut_slice()[0] = 0xA5;
+    let through_bus_address = DirectMappedAddress::from_physical(PhysicalAddress::new(buffer.bus_address())).as_pointer::<u8>();
+    // This is safe because the buffer is allocated, and in the passthrough domain the bus address is the physical address
+    assert_eq!(unsafe { through_bus_address.read() }, 0xA5);
+    let mapping = map_single(device, buffer.as_ptr(), buffer.len(), DmaDirection::ToDevice, AddressLimit::Dma64).unwrap();
+    assert_eq!(mapping.bus_address(), buffer.bus_address());
+    unmap_single(mapping);
+    assert_eq!(BUDDY_ALLOCATOR.get().unwrap().lock().free_frame_count(), free_before - 4);
+    drop(buffer);
+    assert_eq!(BUDDY_ALLOCATOR.get().unwrap().lock().free_frame_count(), free_before);
+
+    // without a DMA32 zone the buddy zone is below 4GB, which the checks above cover
+    if let Some(allocator) = DMA32_ALLOCATOR.get() {
+        let free_before = allocator.lock().free_frame_count();
+        let buffer = alloc_coherent_aligned(device, FRAME_SIZE, 16 * FRAME_SIZE, AddressLimit::Dma32).unwrap();
+        assert!(AddressLimit::Dma32.reaches(buffer.bus_address(), 16 * FRAME_SIZE));
+        assert_eq!(buffer.bus_address() % (16 * FRAME_SIZE) as u64, 0);
+        assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+        assert_eq!(allocator.lock().free_frame_count(), free_before - 16);
+        drop(buffer);
+        assert_eq!(allocator.lock().free_frame_count(), free_before);
+    }
+
+    device::set_dma_domain(device, &OFFSET_DOMAIN);
+    let buffer = alloc_coherent(device, FRAME_SIZE).unwrap();
+    assert_eq!(buffer.bus_address(), buffer.physical_address().get_address() + OFFSET);
+    let mapping = map_single(device, buffer.as_ptr(), 16, DmaDirection::FromDevice, AddressLimit::Dma64).unwrap();
+    assert_eq!(mapping.bus_address(), buffer.bus_address());
+    assert_eq!(OFFSET_DOMAIN.mapped.load(Ordering::SeqCst), FRAME_SIZE + 16);
+    unmap_single(mapping);
+    drop(buffer);
+    assert_eq!(OFFSET_DOMAIN.mapped.load(Ordering::SeqCst), 0);
+    device::unregister(device);
+}
@@ -0,0 +1,132 @@
+//! Scatter-gather lists, buffers made of several physically contiguous segments, so I/O to a buffer that is only contiguous
+//! in virtual memory (like one on the heap, or in a user address space) doesn't have to be copied through a bounce buffer.
+//! Controllers describe these differently (NVMe PRPs are page sized, AHCI PRDT entries are up to 4MB, virtio descriptors are
+//! any length), `chunks` splits the segments to fit.
+
+use alloc::vec::Vec;
+
+use super::{bus_address, AddressLimit, DmaError};
+use crate::memory::VirtualAddress;
+use crate::x64::page_table::PML4;
+use crate::x64::registers::get_cr3;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// A physically contiguous part of a buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgSegment {
+    pub bus_address: u64,
+    pub length: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SgList {
+    segments: Vec<SgSegment>,
+}
+
+impl SgList {
+    pub fn new() -> Self {
+        SgList { segments: Vec::new() }
+    }
+
+    /// Adds a segment to the end of the list, merging it into the last segment if it continues it.
+    pub fn push(&mut self, bus_address: u64, length: usize) {
+        if length == 0 {
+            return;
+        }
+        if let Some(last) = self.segments.last_mut() {
+            if last.bus_address + last.length as u64 == bus_address {
+                last.length += length;
+                return;
+            }
+        }
+        self.segments.push(SgSegment { bus_address, length });
+    }
+
+    /// Describes the `length` bytes at `address` in the address space of `page_table`.
+    /// The buffer must stay mapped and not be moved while the list is in use.
+    pub fn from_range(page_table: &PML4, address: u64, length: usize) -> Result<Self, DmaError> {
+        let mut list = SgList::new();
+        let end = address + length as u64;
+        let mut current = address;
+        while current < end {
+            let physical_address = page_table
+                .translate(VirtualAddress::create(current))
+                .ok_or(DmaError::NotMapped)?;
+            // walk a 4KB page at a time, which works inside huge pages too, `push` merges what is contiguous
+            let chunk = ((current & !(PAGE_SIZE - 1)) + PAGE_SIZE).min(end) - current;
+            list.push(bus_address(physical_address), chunk as usize);
+            current += chunk;
+        }
+        Ok(list)
+    }
+
+    /// Describes the `length` bytes at `pointer` in the kernel's address space.
+    pub fn from_kernel_range(pointer: *const u8, length: usize) -> Result<Self, DmaError> {
+        let cr3 = get_cr3();
+        Self::from_range(cr3.pml4(), pointer as u64, length)
+    }
+
+    pub fn segments(&self) -> &[SgSegment] {
+        &self.segments
+    }
+
+    /// Gets the total length of the segments.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.length).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Checks whether a device with the address limit `limit` can reach every segment.
+    pub fn reachable(&self, limit: AddressLimit) -> bool {
+        self.segments.iter().all(|segment| limit.reaches(segment.bus_address, segment.length))
+    }
+
+    /// Splits the segments so none crosses a multiple of `size`, which must be a power of two.
+    /// With the page size this gives the pages of NVMe PRP entries.
+    pub fn chunks(&self, size: usize) -> impl Iterator<Item = SgSegment> + '_ {
+        assert!(size.is_power_of_two(), "Scatter-gather chunk size must be a power of two");
+        let size = size as u64;
+        self.segments.iter().flat_map(move |segment| {
+            let end = segment.bus_address + segment.length as u64;
+            let mut current = segment.bus_address;
+            core::iter::from_fn(move || {
+                if current >= end {
+                    return None;
+                }
+                let chunk_end = ((current & !(size - 1)) + size).min(end);
+                let chunk = SgSegment {
+                    bus_address: current,
+                    length: (chunk_end - current) as usize,
+                };
+                current = chunk_end;
+                Some(chunk)
+            })
+        })
+    }
+}
+
+/// Checks that a list built from a heap buffer covers the buffer's bytes in order.
+pub fn self_check() {
+    use crate::memory::{DirectMappedAddress, PhysicalAddress};
+
+    // start part way into a page so the first and last segments are partial
+    let buffer: Vec<u8> = (0..3 * PAGE_SIZE as usize + 100).map(|i| i as u8).collect();
+    let start = 50;
+    let list = SgList::from_kernel_range(buffer[start..].as_ptr(), buffer.len() - start).unwrap();
+    assert_eq!(list.len(), buffer.len() - start);
+    let mut offset = start;
+    for chunk in list.chunks(PAGE_SIZE as usize) {
+        assert!(chunk.length as u64 <= PAGE_SIZE);
+        // There is no IOMMU, so the bus address is the physical address
+        let pointer = DirectMappedAddress::from_physical(PhysicalAddress::new(chunk.bus_address)).as_pointer::<u8>();
+        // This is safe because the chunk is part of `buffer`, which is alive
+        let bytes = unsafe { core::slice::from_raw_parts(pointer, chunk.length) };
+        assert_eq!(bytes, &buffer[offset..offset + chunk.length]);
+        offset += chunk.length;
+    }
+    assert_eq!(offset, buffer.len());
+}
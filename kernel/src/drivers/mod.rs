@@ -0,0 +1,5 @@
+//! Device drivers.
+
+pub mod rtc;
+pub mod sdhci;
+pub mod serial;
@@ -0,0 +1,239 @@
+//! The CMOS real time clock, which keeps the date and time while the machine is off.
+//! Its registers are read through an index and a data port. They hold BCD or binary values, with the hour in 12 or 24 hour
+//! format, as status register B says. The year is only two digits; the FADT names the CMOS register with the century, if
+//! there is one, and otherwise the year is taken to be in the 2000s.
+//! The clock is read once at boot to give the time subsystem the wall clock time, after which it follows the clock source.
+//! The RTC is assumed to be set to UTC.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::init::InitError;
+use crate::resource::{self, ResourceKind};
+use crate::x64::port::{inb, outb};
+use crate::{log, time, ACPI_TABLES};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Register indexes.
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+/// The clock is updating its registers, which takes under 2ms and gives torn values if they are read meanwhile.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in the hours register for PM in 12 hour format.
+const HOUR_PM: u8 = 1 << 7;
+/// How many times to read the registers looking for two reads that agree.
+const MAX_READS: usize = 10;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The index and data ports are a pair, so accesses to them are serialized.
+static CMOS: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// The FADT says the machine has no CMOS RTC.
+    NotPresent,
+    /// The registers kept changing, or held a date that doesn't exist.
+    InvalidTime,
+}
+
+/// A date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    fn is_valid(&self) -> bool {
+        let leap = self.year % 4 == 0 && (self.year % 100 != 0 || self.year % 400 == 0);
+        let days_in_month = match self.month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        self.year >= 1970
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Gets the seconds since the Unix epoch.
+    pub fn unix_seconds(&self) -> u64 {
+        // days since 1970-01-01 of the proleptic Gregorian calendar, counting years from March so the leap day comes last
+        let (year, month) = if self.month <= 2 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * SECONDS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    // This is safe because selecting and reading a CMOS register has no side effects, and the caller holds the CMOS lock
+    unsafe {
+        outb(CMOS_INDEX, register);
+        inb(CMOS_DATA)
+    }
+}
+
+/// The raw registers, in the order of `DateTime`'s fields, then the century (0 if there is no century register).
+type RawTime = [u8; 7];
+
+/// Reads the registers between updates.
+fn read_raw(century_register: Option<u8>) -> RawTime {
+    while read_register(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(YEAR),
+        read_register(MONTH),
+        read_register(DAY),
+        read_register(HOURS),
+        read_register(MINUTES),
+        read_register(SECONDS),
+        century_register.map_or(0, read_register),
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Converts raw registers to a date, given status register B.
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let [year, month, day, hours, minutes, seconds, century] = raw;
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+    // the PM bit isn't part of the BCD or binary value
+    let mut hour = decode(hours & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hour %= 12;
+        if hours & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = match century {
+        0 => 20,
+        century => decode(century) as u16,
+    };
+    DateTime {
+        year: century * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minutes),
+        second: decode(seconds),
+    }
+}
+
+/// Reads the date and time from the RTC.
+pub fn read() -> Result<DateTime, RtcError> {
+    let fadt = ACPI_TABLES.get().and_then(|tables| tables.fadt());
+    if fadt.is_some_and(|fadt| !fadt.has_cmos_rtc()) {
+        return Err(RtcError::NotPresent);
+    }
+    let century_register = fadt.and_then(|fadt| fadt.century_register());
+    let _cmos = CMOS.lock();
+    // an update can still start between checking for one and reading, so read until two reads agree
+    let mut previous = read_raw(century_register);
+    for _ in 0..MAX_READS {
+        let raw = read_raw(century_register);
+        if raw == previous {
+            let time = decode(raw, read_register(STATUS_B));
+            return if time.is_valid() { Ok(time) } else { Err(RtcError::InvalidTime) };
+        }
+        previous = raw;
+    }
+    Err(RtcError::InvalidTime)
+}
+
+/// Claims the CMOS ports, reads the RTC and sets the wall clock from it.
+pub fn init_rtc() -> Result<(), InitError> {
+    if let Err(error) = resource::claim_io(CMOS_INDEX, 2, "rtc") {
+        log!("rtc: can't claim the CMOS ports: {}", error);
+        return Err(InitError::new("the CMOS ports are claimed"));
+    }
+    let now = read().map_err(|error| {
+        resource::release(ResourceKind::Io, CMOS_INDEX as u64);
+        match error {
+            RtcError::NotPresent => InitError::new("there is no CMOS RTC"),
+            RtcError::InvalidTime => InitError::new("the CMOS RTC doesn't hold a valid time"),
+        }
+    })?;
+    time::set_wall_clock(now.unix_seconds());
+    log!("rtc: the time is {}", now);
+    Ok(())
+}
+
+/// Checks the decoding of the RTC's formats and the conversion to Unix time.
+pub fn self_check() {
+    let epoch = DateTime {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    assert_eq!(epoch.unix_seconds(), 0);
+    let leap_day = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 45,
+        second: 30,
+    };
+    assert!(leap_day.is_valid());
+    assert_eq!(leap_day.unix_seconds(), 1_709_214_330);
+    assert!(!DateTime { year: 2023, ..leap_day }.is_valid());
+
+    // 2024-02-29 1:45:30 PM, in BCD and 12 hour format with a century register
+    assert_eq!(decode([0x24, 0x02, 0x29, HOUR_PM | 0x01, 0x45, 0x30, 0x20], 0), leap_day);
+    // the same in binary and 24 hour format without one
+    assert_eq!(decode([24, 2, 29, 13, 45, 30, 0], STATUS_B_BINARY | STATUS_B_24_HOUR), leap_day);
+    // 12 AM is midnight
+    assert_eq!(decode([0x24, 0x02, 0x29, 0x12, 0x45, 0x30, 0], 0).hour, 0);
+
+    match read() {
+        Ok(now) => assert!(now.year >= 2024, "the RTC says it is {}", now),
+        Err(error) => log!("rtc: not checking the clock: {:?}", error),
+    }
+}
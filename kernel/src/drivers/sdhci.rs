@@ -0,0 +1,419 @@
+//! A driver for SD cards behind PCI SD host controllers (SDHCI).
+//! Transfers are done one block at a time through the buffer data port, without DMA or interrupts.
+
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::block::{self, check_request, BlockDevice, BlockError};
+use crate::delay::{mdelay, poll_until};
+use crate::device::DeviceName;
+use crate::init::InitError;
+use crate::pci::{Bar, PciCommand, PciDevice, PCI};
+use crate::pmm::leak_in_frame;
+use crate::memory::PhysicalAddress;
+use crate::resource;
+use crate::x64::mmio::{map_mmio, Mmio};
+use crate::log;
+
+const PCI_CLASS_BASE_SYSTEM_PERIPHERAL: u8 = 0x08;
+const PCI_SUBCLASS_SD_HOST_CONTROLLER: u8 = 0x05;
+
+const BLOCK_SIZE: usize = 512;
+
+/// The size of a slot's registers.
+const REGISTERS_SIZE: usize = 0x100;
+
+// Register offsets
+const BLOCK_SIZE_REGISTER: usize = 0x04;
+const BLOCK_COUNT: usize = 0x06;
+const ARGUMENT: usize = 0x08;
+const TRANSFER_MODE: usize = 0x0C;
+const COMMAND: usize = 0x0E;
+const RESPONSE: usize = 0x10;
+const BUFFER_DATA_PORT: usize = 0x20;
+const PRESENT_STATE: usize = 0x24;
+const POWER_CONTROL: usize = 0x29;
+const CLOCK_CONTROL: usize = 0x2C;
+const TIMEOUT_CONTROL: usize = 0x2E;
+const SOFTWARE_RESET: usize = 0x2F;
+const NORMAL_INTERRUPT_STATUS: usize = 0x30;
+const ERROR_INTERRUPT_STATUS: usize = 0x32;
+const NORMAL_INTERRUPT_STATUS_ENABLE: usize = 0x34;
+const ERROR_INTERRUPT_STATUS_ENABLE: usize = 0x36;
+const CAPABILITIES: usize = 0x40;
+const HOST_CONTROLLER_VERSION: usize = 0xFE;
+
+const PRESENT_STATE_COMMAND_INHIBIT: u32 = 1;
+const PRESENT_STATE_DATA_INHIBIT: u32 = 1 << 1;
+const PRESENT_STATE_CARD_INSERTED: u32 = 1 << 16;
+
+const POWER_ON: u8 = 1;
+const POWER_3_3V: u8 = 0b111 << 1;
+
+const CLOCK_INTERNAL_ENABLE: u16 = 1;
+const CLOCK_INTERNAL_STABLE: u16 = 1 << 1;
+const CLOCK_SD_ENABLE: u16 = 1 << 2;
+
+const SOFTWARE_RESET_ALL: u8 = 1;
+const SOFTWARE_RESET_COMMAND: u8 = 1 << 1;
+const SOFTWARE_RESET_DATA: u8 = 1 << 2;
+
+const TRANSFER_MODE_READ: u16 = 1 << 4;
+
+// Flags in the command register
+const RESPONSE_NONE: u16 = 0b00;
+const RESPONSE_136: u16 = 0b01;
+const RESPONSE_48: u16 = 0b10;
+const RESPONSE_48_BUSY: u16 = 0b11;
+const COMMAND_CRC_CHECK: u16 = 1 << 3;
+const COMMAND_INDEX_CHECK: u16 = 1 << 4;
+const COMMAND_DATA_PRESENT: u16 = 1 << 5;
+
+/// The voltage window sent with ACMD41 (2.7-3.6V).
+const OCR_VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+/// Set in ACMD41 to say we support high capacity cards, and in its response if the card is one.
+const OCR_HIGH_CAPACITY: u32 = 1 << 30;
+/// Clear in the ACMD41 response while the card is still powering up.
+const OCR_READY: u32 = 1 << 31;
+/// The argument to CMD8, 2.7-3.6V and a check pattern the card echoes.
+const SEND_IF_COND_ARGUMENT: u32 = 0x1AA;
+
+const IDENTIFICATION_CLOCK_HZ: u32 = 400_000;
+const TRANSFER_CLOCK_HZ: u32 = 25_000_000;
+
+const COMMAND_TIMEOUT_US: u64 = 100_000;
+const TRANSFER_TIMEOUT_US: u64 = 500_000;
+const RESET_TIMEOUT_US: u64 = 100_000;
+/// How long the card gets to finish powering up.
+const POWER_UP_TIMEOUT_MS: u64 = 1000;
+
+/// The most SD host controllers the driver handles.
+const MAX_CONTROLLERS: usize = 4;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    struct NormalInterrupts: u16 {
+        const COMMAND_COMPLETE = 1;
+        const TRANSFER_COMPLETE = 1 << 1;
+        const BUFFER_WRITE_READY = 1 << 4;
+        const BUFFER_READ_READY = 1 << 5;
+        const ERROR = 1 << 15;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Response {
+    None,
+    /// A 48 bit response, the card status or OCR
+    Short,
+    /// A 48 bit response after which the card signals busy on DAT0
+    ShortBusy,
+    /// A 136 bit response, the CID or CSD
+    Long,
+}
+
+/// An SD card in a slot of an SD host controller.
+pub struct SdCard {
+    /// The controller's registers
+    registers: Mmio,
+    /// The card's relative card address
+    rca: u16,
+    /// High capacity cards are addressed by block, standard capacity cards by byte
+    high_capacity: bool,
+    block_count: u64,
+}
+
+impl SdCard {
+    fn normal_interrupts(&self) -> NormalInterrupts {
+        NormalInterrupts::from_bits_retain(self.registers.read16(NORMAL_INTERRUPT_STATUS))
+    }
+
+    /// Clears interrupt status bits, which are write 1 to clear.
+    fn acknowledge(&self, interrupts: NormalInterrupts) {
+        self.registers.write16(NORMAL_INTERRUPT_STATUS, interrupts.bits());
+    }
+
+    /// Waits for any of `interrupts` to be signalled, failing if an error is signalled instead.
+    fn wait_for(&self, interrupts: NormalInterrupts, timeout_us: u64) -> Result<(), BlockError> {
+        if !poll_until(timeout_us, || self.normal_interrupts().intersects(interrupts | NormalInterrupts::ERROR)) {
+            return Err(BlockError::Timeout);
+        }
+        if self.normal_interrupts().contains(NormalInterrupts::ERROR) {
+            let errors = self.registers.read16(ERROR_INTERRUPT_STATUS);
+            self.registers.write16(ERROR_INTERRUPT_STATUS, errors);
+            self.acknowledge(NormalInterrupts::ERROR);
+            self.reset(SOFTWARE_RESET_COMMAND | SOFTWARE_RESET_DATA)?;
+            log!("sdhci: command failed with error status {:#x}", errors);
+            return Err(BlockError::DeviceError);
+        }
+        self.acknowledge(interrupts & self.normal_interrupts());
+        Ok(())
+    }
+
+    fn reset(&self, what: u8) -> Result<(), BlockError> {
+        self.registers.write8(SOFTWARE_RESET, what);
+        if poll_until(RESET_TIMEOUT_US, || self.registers.read8(SOFTWARE_RESET) & what == 0) {
+            Ok(())
+        } else {
+            Err(BlockError::Timeout)
+        }
+    }
+
+    /// Sends a command to the card and waits for it to complete, returning the first 32 bits of the response.
+    fn command(&self, index: u8, argument: u32, response: Response, data: bool) -> Result<u32, BlockError> {
+        let inhibit = if data {
+            PRESENT_STATE_COMMAND_INHIBIT | PRESENT_STATE_DATA_INHIBIT
+        } else {
+            PRESENT_STATE_COMMAND_INHIBIT
+        };
+        if !poll_until(COMMAND_TIMEOUT_US, || self.registers.read32(PRESENT_STATE) & inhibit == 0) {
+            return Err(BlockError::Timeout);
+        }
+        let flags = match response {
+            Response::None => RESPONSE_NONE,
+            Response::Short => RESPONSE_48 | COMMAND_CRC_CHECK | COMMAND_INDEX_CHECK,
+            Response::ShortBusy => RESPONSE_48_BUSY | COMMAND_CRC_CHECK | COMMAND_INDEX_CHECK,
+            // long responses have no index, and the controller strips the CRC
+            Response::Long => RESPONSE_136 | COMMAND_CRC_CHECK,
+        };
+        let flags = if data { flags | COMMAND_DATA_PRESENT } else { flags };
+        self.registers.write32(ARGUMENT, argument);
+        self.registers.write16(COMMAND, (index as u16) << 8 | flags);
+        self.wait_for(NormalInterrupts::COMMAND_COMPLETE, COMMAND_TIMEOUT_US)?;
+        Ok(self.registers.read32(RESPONSE))
+    }
+
+    /// Sends an application specific command, which is CMD55 followed by the command.
+    fn app_command(&self, index: u8, argument: u32, response: Response) -> Result<u32, BlockError> {
+        self.command(55, (self.rca as u32) << 16, Response::Short, false)?;
+        self.command(index, argument, response, false)
+    }
+
+    /// Reads the 120 bits of a long response the controller keeps (the CRC is stripped, so bit 0 of this is bit 8 of the response).
+    fn long_response(&self) -> u128 {
+        (0..4).fold(0, |response, i| response | (self.registers.read32(RESPONSE + 4 * i) as u128) << (32 * i))
+    }
+
+    /// Sets the SD clock to at most `hz`.
+    fn set_clock(&self, hz: u32) -> Result<(), BlockError> {
+        self.registers.write16(CLOCK_CONTROL, 0);
+        let capabilities = self.registers.read32(CAPABILITIES);
+        let version = self.registers.read16(HOST_CONTROLLER_VERSION) & 0xFF;
+        // the base clock field is 8 bits wide from version 3, 6 bits before
+        let base_clock_mhz = if version >= 2 {
+            (capabilities >> 8) & 0xFF
+        } else {
+            (capabilities >> 8) & 0x3F
+        };
+        // assume a 50MHz base clock if the controller doesn't report one
+        let base_clock_hz = if base_clock_mhz == 0 { 50_000_000 } else { base_clock_mhz * 1_000_000 };
+        // the clock is base / (2 * divider), with 0 meaning no division
+        let mut divider = 0;
+        while base_clock_hz / if divider == 0 { 1 } else { 2 * divider } > hz {
+            divider += 1;
+        }
+        let divider = if version >= 2 {
+            divider.min(0x3FF)
+        } else {
+            // before version 3 the divider is 8 bits and only powers of two are valid, rounding up keeps the clock below `hz`
+            match divider {
+                0 => 0,
+                divider => divider.next_power_of_two().min(0x80),
+            }
+        } as u16;
+        let clock = (divider & 0xFF) << 8 | (divider >> 8) << 6 | CLOCK_INTERNAL_ENABLE;
+        self.registers.write16(CLOCK_CONTROL, clock);
+        if !poll_until(COMMAND_TIMEOUT_US, || self.registers.read16(CLOCK_CONTROL) & CLOCK_INTERNAL_STABLE != 0) {
+            return Err(BlockError::Timeout);
+        }
+        self.registers.write16(CLOCK_CONTROL, clock | CLOCK_SD_ENABLE);
+        Ok(())
+    }
+
+    /// Resets the controller at `registers` and initializes the card in it, returns None if there is no card or it doesn't respond.
+    fn init(registers: Mmio) -> Result<Option<Self>, BlockError> {
+        let mut card = SdCard {
+            registers,
+            rca: 0,
+            high_capacity: false,
+            block_count: 0,
+        };
+        card.reset(SOFTWARE_RESET_ALL)?;
+        if card.registers.read32(PRESENT_STATE) & PRESENT_STATE_CARD_INSERTED == 0 {
+            return Ok(None);
+        }
+        card.registers.write8(POWER_CONTROL, POWER_3_3V | POWER_ON);
+        card.set_clock(IDENTIFICATION_CLOCK_HZ)?;
+        // the longest data timeout
+        card.registers.write8(TIMEOUT_CONTROL, 0xE);
+        // the status bits are polled, so enable all of them without enabling the interrupt signals
+        card.registers.write16(NORMAL_INTERRUPT_STATUS_ENABLE, 0xFFFF);
+        card.registers.write16(ERROR_INTERRUPT_STATUS_ENABLE, 0xFFFF);
+        // make sure the power is on before starting to wait for it to settle
+        card.registers.flush(PRESENT_STATE);
+        mdelay(1);
+
+        // GO_IDLE_STATE
+        card.command(0, 0, Response::None, false)?;
+        // SEND_IF_COND, version 1 cards don't respond
+        let version_2 = card.command(8, SEND_IF_COND_ARGUMENT, Response::Short, false)
+            .map(|response| response & 0xFFF == SEND_IF_COND_ARGUMENT)
+            .unwrap_or(false);
+        let capacity_support = if version_2 { OCR_HIGH_CAPACITY } else { 0 };
+        // SD_SEND_OP_COND until the card finishes powering up
+        let mut ocr = 0;
+        let powered_up = poll_until(POWER_UP_TIMEOUT_MS * 1000, || {
+            match card.app_command(41, OCR_VOLTAGE_WINDOW | capacity_support, Response::Short) {
+                Ok(response) => {
+                    ocr = response;
+                    response & OCR_READY != 0
+                }
+                Err(_) => false,
+            }
+        });
+        if !powered_up {
+            return Err(BlockError::Timeout);
+        }
+        card.high_capacity = ocr & OCR_HIGH_CAPACITY != 0;
+        // ALL_SEND_CID
+        card.command(2, 0, Response::Long, false)?;
+        // SEND_RELATIVE_ADDR
+        card.rca = (card.command(3, 0, Response::Short, false)? >> 16) as u16;
+        // SEND_CSD
+        card.command(9, (card.rca as u32) << 16, Response::Long, false)?;
+        card.block_count = block_count_from_csd(card.long_response());
+        // SELECT_CARD
+        card.command(7, (card.rca as u32) << 16, Response::ShortBusy, false)?;
+        if !card.high_capacity {
+            // SET_BLOCKLEN, high capacity cards always use 512 byte blocks
+            card.command(16, BLOCK_SIZE as u32, Response::Short, false)?;
+        }
+        card.set_clock(TRANSFER_CLOCK_HZ)?;
+        Ok(Some(card))
+    }
+
+    /// Gets the address of a block in the form the card expects.
+    fn card_address(&self, block: u64) -> u32 {
+        if self.high_capacity {
+            block as u32
+        } else {
+            (block * BLOCK_SIZE as u64) as u32
+        }
+    }
+
+    fn read_block(&self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.registers.write16(BLOCK_SIZE_REGISTER, BLOCK_SIZE as u16);
+        self.registers.write16(BLOCK_COUNT, 1);
+        self.registers.write16(TRANSFER_MODE, TRANSFER_MODE_READ);
+        // READ_SINGLE_BLOCK
+        self.command(17, self.card_address(block), Response::Short, true)?;
+        self.wait_for(NormalInterrupts::BUFFER_READ_READY, TRANSFER_TIMEOUT_US)?;
+        for chunk in buffer.chunks_exact_mut(4) {
+            chunk.copy_from_slice(&self.registers.read32(BUFFER_DATA_PORT).to_le_bytes());
+        }
+        self.wait_for(NormalInterrupts::TRANSFER_COMPLETE, TRANSFER_TIMEOUT_US)
+    }
+
+    fn write_block(&self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.registers.write16(BLOCK_SIZE_REGISTER, BLOCK_SIZE as u16);
+        self.registers.write16(BLOCK_COUNT, 1);
+        self.registers.write16(TRANSFER_MODE, 0);
+        // WRITE_BLOCK
+        self.command(24, self.card_address(block), Response::Short, true)?;
+        self.wait_for(NormalInterrupts::BUFFER_WRITE_READY, TRANSFER_TIMEOUT_US)?;
+        for chunk in buffer.chunks_exact(4) {
+            self.registers.write32(BUFFER_DATA_PORT, u32::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        self.wait_for(NormalInterrupts::TRANSFER_COMPLETE, TRANSFER_TIMEOUT_US)
+    }
+}
+
+/// Works out the number of 512 byte blocks on a card from its CSD, given as the 120 bits the controller keeps.
+fn block_count_from_csd(csd: u128) -> u64 {
+    // The controller drops the low 8 bits of the CSD, so CSD bit n is bit n - 8 here
+    let bits = |high: u32, low: u32| ((csd >> (low - 8)) & ((1 << (high - low + 1)) - 1)) as u64;
+    match bits(127, 126) {
+        // CSD version 2, capacity is (C_SIZE + 1) * 512KiB
+        1 => (bits(69, 48) + 1) * 1024,
+        // CSD version 1, capacity is (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN bytes
+        _ => {
+            let c_size = bits(73, 62);
+            let c_size_mult = bits(49, 47);
+            let read_bl_len = bits(83, 80);
+            ((c_size + 1) << (c_size_mult + 2 + read_bl_len)) / BLOCK_SIZE as u64
+        }
+    }
+}
+
+impl BlockDevice for SdCard {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        for (block, chunk) in (start..).zip(buffer.chunks_exact_mut(BLOCK_SIZE)) {
+            self.read_block(block, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        for (block, chunk) in (start..).zip(buffer.chunks_exact(BLOCK_SIZE)) {
+            self.write_block(block, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds SD host controllers on the PCI bus and registers the cards in them as block devices.
+pub fn init_sdhci() -> Result<(), InitError> {
+    let mut controllers = [None; MAX_CONTROLLERS];
+    for (slot, device) in controllers.iter_mut().zip(PCI.lock().devices().filter(|device| {
+        device.class() == PCI_CLASS_BASE_SYSTEM_PERIPHERAL && device.subclass() == PCI_SUBCLASS_SD_HOST_CONTROLLER
+    })) {
+        *slot = Some(*device);
+    }
+    for device in controllers.iter().flatten() {
+        if let Err(error) = probe_controller(device) {
+            log!("sdhci: {} failed: {:?}", device.address(), error);
+        }
+    }
+    Ok(())
+}
+
+fn probe_controller(device: &PciDevice) -> Result<(), BlockError> {
+    // Controllers with several slots have a BAR per slot, only the first slot is used
+    let Some(Bar::Memory { address, .. }) = device.bar(0) else {
+        log!("sdhci: {} has no register BAR", device.address());
+        return Ok(());
+    };
+    if let Err(error) = resource::claim_mmio(address, REGISTERS_SIZE as u64, "sdhci") {
+        log!("sdhci: {} can't claim its registers: {}", device.address(), error);
+        return Ok(());
+    }
+    device.enable(PciCommand::MEMORY_SPACE);
+    // This is safe because the BAR is the controller's registers, which are only used by this card
+    let Some(registers) = (unsafe { map_mmio(PhysicalAddress::device(address), REGISTERS_SIZE as u64) }) else {
+        log!("sdhci: out of MMIO space");
+        return Ok(());
+    };
+    let Some(card) = SdCard::init(registers)? else {
+        log!("sdhci: {} has no card", device.address());
+        return Ok(());
+    };
+    let block_count = card.block_count();
+    let Some(card) = leak_in_frame(Mutex::new(card)) else {
+        log!("sdhci: out of memory");
+        return Ok(());
+    };
+    let name = DeviceName::new("sd", block::count_with_prefix("sd"));
+    log!("sdhci: {} has a card with {} blocks, registered as {}", device.address(), block_count, name);
+    block::register(name, card);
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Power management for the debug serial port, which is set up before the device tree exists, and reading from it.
+//! Received bytes are read straight from the UART's ports rather than through `DEBUG_SERIAL_PORT`, so a reader doesn't hold the
+//! lock everything that logs needs. Only the data and line status registers are touched, which sending doesn't use.
+//! Once `init_serial_input` has routed the UART's interrupt, the handler moves received bytes into a buffer and readers take
+//! them from there, halting until the next interrupt while it is empty. Before that, readers poll the UART.
+
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::device::{DeviceDriver, PowerError};
+use crate::init::InitError;
+use crate::irq::{self, IrqReturn};
+use crate::sync::preempt::might_sleep;
+use crate::sync::without_interrupts;
+use crate::work;
+use crate::x64::ioapic::isa_irq_to_gsi;
+use crate::x64::port::inb;
+use crate::{log, DEBUG_SERIAL_PORT, DEBUG_SERIAL_PORT_BASE};
+
+const DATA: u16 = DEBUG_SERIAL_PORT_BASE;
+const LINE_STATUS: u16 = DEBUG_SERIAL_PORT_BASE + 5;
+/// Set in the line status register while a received byte is waiting in the data register.
+const LINE_STATUS_DATA_READY: u8 = 1;
+/// The ISA IRQ of COM1. `SerialPort::init` enables its received data interrupt.
+const COM1_IRQ: u8 = 4;
+/// The size of the buffer of received bytes. It only has to hold what arrives while nobody is reading, bytes that don't fit
+/// are dropped.
+const INPUT_BUFFER_SIZE: usize = 256;
+
+pub struct DebugSerialDriver;
+
+pub static DEBUG_SERIAL_DRIVER: DebugSerialDriver = DebugSerialDriver;
+
+impl DeviceDriver for DebugSerialDriver {
+    fn resume(&self) -> Result<(), PowerError> {
+        // The UART loses its baud rate and line settings when it is powered off
+        DEBUG_SERIAL_PORT.lock().init();
+        Ok(())
+    }
+}
+
+/// A ring buffer of received bytes.
+struct InputBuffer {
+    bytes: [u8; INPUT_BUFFER_SIZE],
+    /// The index of the oldest byte.
+    start: usize,
+    length: usize,
+}
+
+impl InputBuffer {
+    fn push(&mut self, byte: u8) {
+        if self.length < INPUT_BUFFER_SIZE {
+            self.bytes[(self.start + self.length) % INPUT_BUFFER_SIZE] = byte;
+            self.length += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.length == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % INPUT_BUFFER_SIZE;
+        self.length -= 1;
+        Some(byte)
+    }
+}
+
+/// Filled by the interrupt handler, so it is only locked with interrupts disabled.
+static INPUT: Mutex<InputBuffer> = Mutex::new(InputBuffer {
+    bytes: [0; INPUT_BUFFER_SIZE],
+    start: 0,
+    length: 0,
+});
+/// Whether the interrupt handler is taking the received bytes.
+static INPUT_INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+/// Takes a received byte from the UART itself.
+fn read_data() -> Option<u8> {
+    // This is safe because reading the line status has no side effects, and reading the data register only takes the byte
+    unsafe { (inb(LINE_STATUS) & LINE_STATUS_DATA_READY != 0).then(|| inb(DATA)) }
+}
+
+/// Moves the received bytes into the buffer, which also clears the interrupt.
+fn receive_interrupt(_vector: u8) -> IrqReturn {
+    let mut input = INPUT.lock();
+    let mut received = false;
+    while let Some(byte) = read_data() {
+        input.push(byte);
+        received = true;
+    }
+    if received {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotMine
+    }
+}
+
+/// Routes the debug serial port's interrupt, so received bytes are buffered and readers can halt while they wait.
+pub fn init_serial_input() -> Result<(), InitError> {
+    let vector = irq::register_irq(isa_irq_to_gsi(COM1_IRQ), receive_interrupt, "serial")
+        .map_err(|_| InitError::new("failed to route the serial interrupt"))?;
+    INPUT_INTERRUPT.store(true, Ordering::SeqCst);
+    // bytes that arrived before the line was routed raised an interrupt that was missed
+    without_interrupts(|| receive_interrupt(vector));
+    log!("serial: receiving on vector {:#x}", vector);
+    Ok(())
+}
+
+/// Takes a received byte, if there is one.
+pub fn try_receive() -> Option<u8> {
+    if INPUT_INTERRUPT.load(Ordering::SeqCst) {
+        without_interrupts(|| INPUT.lock().pop())
+    } else {
+        read_data()
+    }
+}
+
+/// Waits for a received byte. This halts until the next interrupt while there is none, so it must be called with interrupts
+/// enabled and outside RCU read-side critical sections, and it spins instead until the serial interrupt is routed.
+/// Deferred work runs while it waits.
+pub fn receive() -> u8 {
+    might_sleep();
+    loop {
+        if !INPUT_INTERRUPT.load(Ordering::SeqCst) {
+            if let Some(byte) = read_data() {
+                return byte;
+            }
+            spin_loop();
+            continue;
+        }
+        work::run_pending();
+        // The buffer is checked with interrupts disabled, so a byte that arrives after the check still wakes the halt
+        // This is safe because the caller has interrupts enabled
+        unsafe { asm!("cli") };
+        if let Some(byte) = INPUT.lock().pop() {
+            unsafe { asm!("sti") };
+            return byte;
+        }
+        work::idle_halt();
+    }
+}
@@ -0,0 +1,161 @@
+//! A publish/subscribe bus for system events, so subsystems can react to each other without calling each other directly.
+//! Subscribers are called synchronously on the publishing CPU, with no locks held, so they should be quick and may publish
+//! events themselves. Recent events are also kept in a log with sequence numbers, so a reader that polls (the shell now,
+//! a device file once there is a filesystem) can catch up on what it missed.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::device::DeviceName;
+
+/// The most subscribers there can be.
+const MAX_SUBSCRIBERS: usize = 16;
+/// The number of recent events kept in the log.
+const LOG_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Block,
+}
+
+#[derive(Clone, Copy)]
+pub enum Event {
+    DeviceAdded { kind: DeviceKind, name: DeviceName },
+    DeviceRemoved { kind: DeviceKind, name: DeviceName },
+    PowerButton,
+    /// A thermal zone's temperature crossed a trip point.
+    Thermal { zone: u8, millidegrees_celsius: i32 },
+    /// The number of free frames fell below the low memory threshold.
+    LowMemory { free_frames: usize },
+}
+
+impl Event {
+    /// Gets the bit for this kind of event in an `EventMask`.
+    fn mask(&self) -> EventMask {
+        match self {
+            Event::DeviceAdded { .. } | Event::DeviceRemoved { .. } => EventMask::DEVICE,
+            Event::PowerButton => EventMask::POWER,
+            Event::Thermal { .. } => EventMask::THERMAL,
+            Event::LowMemory { .. } => EventMask::MEMORY,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::DeviceAdded { kind, name } => write!(f, "{:?} device {} added", kind, name),
+            Event::DeviceRemoved { kind, name } => write!(f, "{:?} device {} removed", kind, name),
+            Event::PowerButton => write!(f, "power button pressed"),
+            Event::Thermal {
+                zone,
+                millidegrees_celsius,
+            } => write!(f, "thermal zone {} at {}mC", zone, millidegrees_celsius),
+            Event::LowMemory { free_frames } => write!(f, "low memory, {} frames free", free_frames),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// The kinds of events a subscriber wants.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EventMask: u32 {
+        const DEVICE = 1 << 0;
+        const POWER = 1 << 1;
+        const THERMAL = 1 << 2;
+        const MEMORY = 1 << 3;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Subscriber {
+    mask: EventMask,
+    handler: fn(&Event),
+}
+
+static SUBSCRIBERS: Mutex<[Option<Subscriber>; MAX_SUBSCRIBERS]> = Mutex::new([None; MAX_SUBSCRIBERS]);
+
+/// The most recent events, `next_sequence` is the sequence number the next event will get.
+struct EventLog {
+    events: [Option<Event>; LOG_LENGTH],
+    next_sequence: u64,
+}
+
+static LOG: Mutex<EventLog> = Mutex::new(EventLog {
+    events: [None; LOG_LENGTH],
+    next_sequence: 0,
+});
+
+/// Calls `handler` with every later event matching `mask`, returns false if there are too many subscribers.
+pub fn subscribe(mask: EventMask, handler: fn(&Event)) -> bool {
+    let mut subscribers = SUBSCRIBERS.lock();
+    match subscribers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(Subscriber { mask, handler });
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stops calling `handler`.
+pub fn unsubscribe(handler: fn(&Event)) {
+    for slot in SUBSCRIBERS.lock().iter_mut() {
+        if slot.is_some_and(|subscriber| subscriber.handler == handler) {
+            *slot = None;
+        }
+    }
+}
+
+/// Adds an event to the log and calls the subscribers that want it.
+pub fn publish(event: Event) {
+    {
+        let mut log = LOG.lock();
+        let index = (log.next_sequence % LOG_LENGTH as u64) as usize;
+        log.events[index] = Some(event);
+        log.next_sequence += 1;
+    }
+    // copied so handlers can subscribe, unsubscribe and publish
+    let subscribers = *SUBSCRIBERS.lock();
+    for subscriber in subscribers.iter().flatten() {
+        if subscriber.mask.contains(event.mask()) {
+            (subscriber.handler)(&event);
+        }
+    }
+}
+
+/// Calls `f` with each logged event with a sequence number of at least `since`, oldest first, along with its sequence number.
+/// Returns the sequence number to pass next time. Events that have already left the log are skipped.
+pub fn read_since(since: u64, mut f: impl FnMut(u64, &Event)) -> u64 {
+    let log = LOG.lock();
+    let oldest = log.next_sequence.saturating_sub(LOG_LENGTH as u64);
+    for sequence in since.max(oldest)..log.next_sequence {
+        if let Some(event) = &log.events[(sequence % LOG_LENGTH as u64) as usize] {
+            f(sequence, event);
+        }
+    }
+    log.next_sequence
+}
+
+/// Checks that subscribers only get the events they asked for, and that published events are logged.
+pub fn self_check() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+    fn handler(event: &Event) {
+        assert!(matches!(event, Event::PowerButton));
+        RECEIVED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let since = read_since(u64::MAX, |_, _| {});
+    assert!(subscribe(EventMask::POWER, handler));
+    publish(Event::LowMemory { free_frames: 0 });
+    publish(Event::PowerButton);
+    unsubscribe(handler);
+    publish(Event::PowerButton);
+    assert_eq!(RECEIVED.load(Ordering::Relaxed), 1);
+    let mut logged = 0;
+    assert_eq!(read_since(since, |_, _| logged += 1), since + 3);
+    assert_eq!(logged, 3);
+}
@@ -0,0 +1,243 @@
+//! The handlers of the 32 architecture exceptions.
+//! Page faults and general protection faults first try the fixup table (for code that expects to fault, like `try_rdmsr`), and page
+//! faults are then offered to demand paging. Breakpoints are logged and execution continues, as do debug exceptions from
+//! watches. Everything else is a bug in the kernel, so it panics with the exception and where it happened.
+
+use core::arch::global_asm;
+use core::fmt;
+
+use crate::memory::{DirectMappedAddress, VirtualAddress};
+use crate::x64::fixup;
+use crate::x64::gdt::SegmentSelector;
+use crate::x64::idt::{
+    GateOptions, Idt, InterruptStackFrame, PageFaultErrorCode, BREAKPOINT_VECTOR, DOUBLE_FAULT_VECTOR, MACHINE_CHECK_VECTOR,
+    NMI_VECTOR,
+};
+use crate::x64::registers::get_cr2;
+use crate::{demand_paging, emergency_log, panicking, stack, watch};
+
+/// Defines a handler that panics with `$name` and where the exception happened, with or without an error code.
+macro_rules! fatal_exception {
+    ($handler:ident, $name:literal) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            panic!(concat!($name, "! RIP: {:#x}, RSP: {:#x}"), stack_frame.instruction_pointer, stack_frame.stack_pointer);
+        }
+    };
+    ($handler:ident, $name:literal, error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            panic!(
+                concat!($name, "! Error code: {:#x}, RIP: {:#x}, RSP: {:#x}"),
+                error_code, stack_frame.instruction_pointer, stack_frame.stack_pointer
+            );
+        }
+    };
+}
+
+fatal_exception!(divide_error, "Divide error");
+fatal_exception!(overflow, "Overflow");
+fatal_exception!(bound_range_exceeded, "Bound range exceeded");
+fatal_exception!(invalid_opcode, "Invalid opcode");
+fatal_exception!(device_not_available, "Device not available");
+fatal_exception!(coprocessor_segment_overrun, "Coprocessor segment overrun");
+fatal_exception!(invalid_tss, "Invalid TSS", error_code);
+fatal_exception!(segment_not_present, "Segment not present", error_code);
+fatal_exception!(stack_segment_fault, "Stack segment fault", error_code);
+fatal_exception!(x87_floating_point, "x87 floating point exception");
+fatal_exception!(alignment_check, "Alignment check", error_code);
+fatal_exception!(simd_floating_point, "SIMD floating point exception");
+fatal_exception!(virtualization, "Virtualization exception");
+fatal_exception!(control_protection, "Control protection exception", error_code);
+fatal_exception!(hypervisor_injection, "Hypervisor injection exception");
+fatal_exception!(vmm_communication, "VMM communication exception", error_code);
+fatal_exception!(security, "Security exception", error_code);
+fatal_exception!(reserved, "Reserved exception");
+
+extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
+    emergency_log!("Breakpoint at {:#x}", stack_frame.instruction_pointer);
+}
+
+/// The general purpose registers of interrupted code, saved by an entry stub in this order, followed by what the CPU pushed.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SavedRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub frame: InterruptStackFrame,
+}
+
+impl fmt::Display for SavedRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x} RDX: {:#018x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "RSI: {:#018x} RDI: {:#018x} RBP: {:#018x} RSP: {:#018x}", self.rsi, self.rdi, self.rbp, self.frame.stack_pointer)?;
+        writeln!(f, "R8:  {:#018x} R9:  {:#018x} R10: {:#018x} R11: {:#018x}", self.r8, self.r9, self.r10, self.r11)?;
+        writeln!(f, "R12: {:#018x} R13: {:#018x} R14: {:#018x} R15: {:#018x}", self.r12, self.r13, self.r14, self.r15)?;
+        write!(f, "RIP: {:#018x} RFLAGS: {:#010x}", self.frame.instruction_pointer, self.frame.cpu_flags)
+    }
+}
+
+// The debug exception entry. Watches dump every register of the code that hit them, which `x86-interrupt` handlers can't
+// see, so it saves all of them in the layout of `SavedRegisters`.
+// The CPU pushed 5 words on a 16 byte aligned stack, so after 15 more the stack is aligned for the call.
+global_asm!(
+    ".global debug_entry",
+    "debug_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {debug}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    debug = sym debug,
+);
+
+extern "C" {
+    fn debug_entry();
+}
+
+extern "C" fn debug(registers: &SavedRegisters) {
+    if watch::handle(registers) {
+        return;
+    }
+    panic!(
+        "Debug exception! RIP: {:#x}, RSP: {:#x}",
+        registers.frame.instruction_pointer, registers.frame.stack_pointer
+    );
+}
+
+extern "x86-interrupt" fn machine_check(stack_frame: InterruptStackFrame) -> ! {
+    panic!("Machine check! RIP: {:#x}", stack_frame.instruction_pointer);
+}
+
+extern "x86-interrupt" fn page_fault(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    if fixup::apply(&mut stack_frame) {
+        return;
+    }
+    // The x86-interrupt calling convention helpfully pops the error code for us, but we still need to read cr2 to find the virtual address of the page fault
+    let address = get_cr2();
+    if demand_paging::handle_page_fault(address, &error_code) {
+        return;
+    }
+    // Reaching this needs the handler to run on another stack, otherwise an overflow becomes a double fault
+    if !error_code.contains(PageFaultErrorCode::PRESENT) && stack::is_stack_overflow(address) {
+        panic!("Kernel stack overflow! Address: {:x}, RIP: {:x}", address, stack_frame.instruction_pointer);
+    }
+    let direct_address = DirectMappedAddress::try_from_virtual(VirtualAddress::create(address));
+    let physical_address = match direct_address {
+        Some(direct_mapped_address) => direct_mapped_address.get_physical_address().get_address(),
+        None => 1,
+    };
+    panic!(
+        "Page fault! Error code: {:?}, Address: {:x}, Phyiscal Address: {:x}, RIP: {:#x}",
+        error_code, address, physical_address, stack_frame.instruction_pointer
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault(mut stack_frame: InterruptStackFrame, error_code: u64) {
+    if fixup::apply(&mut stack_frame) {
+        return;
+    }
+    panic!("General protection fault! Error code: {:#x}, RIP: {:#x}", error_code, stack_frame.instruction_pointer);
+}
+
+extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    // An overflow faults when something is pushed below the stack, and faults again when the CPU pushes the page fault's frame
+    // there. The saved stack pointer may still be just above the guard page, in which case CR2 has the address that faulted.
+    let overflow = stack::find_overflow(stack_frame.stack_pointer).or_else(|| stack::find_overflow(get_cr2()));
+    if let Some(overflow) = overflow {
+        panic!(
+            "Kernel stack overflow in stack {}: {:#x} bytes below its bottom at {:#x}, RSP: {:#x}, RIP: {:#x}",
+            overflow.id, overflow.depth, overflow.bottom, stack_frame.stack_pointer, stack_frame.instruction_pointer
+        );
+    }
+    panic!("Double fault! Error code: {}, RIP: {:#x}", error_code, stack_frame.instruction_pointer);
+}
+
+impl Idt {
+    /// Points all 32 exception vectors at the kernel's handlers, including the reserved ones.
+    pub fn install_exception_handlers(&mut self, cs: SegmentSelector) {
+        let handlers: [(u8, *const ()); 32] = [
+            (0x0, divide_error as *const ()),
+            (0x1, debug_entry as *const ()),
+            (NMI_VECTOR, panicking::nmi as *const ()),
+            (BREAKPOINT_VECTOR, breakpoint as *const ()),
+            (0x4, overflow as *const ()),
+            (0x5, bound_range_exceeded as *const ()),
+            (0x6, invalid_opcode as *const ()),
+            (0x7, device_not_available as *const ()),
+            (DOUBLE_FAULT_VECTOR, double_fault as *const ()),
+            (0x9, coprocessor_segment_overrun as *const ()),
+            (0xA, invalid_tss as *const ()),
+            (0xB, segment_not_present as *const ()),
+            (0xC, stack_segment_fault as *const ()),
+            (0xD, general_protection_fault as *const ()),
+            (0xE, page_fault as *const ()),
+            (0xF, reserved as *const ()),
+            (0x10, x87_floating_point as *const ()),
+            (0x11, alignment_check as *const ()),
+            (MACHINE_CHECK_VECTOR, machine_check as *const ()),
+            (0x13, simd_floating_point as *const ()),
+            (0x14, virtualization as *const ()),
+            (0x15, control_protection as *const ()),
+            (0x16, reserved as *const ()),
+            (0x17, reserved as *const ()),
+            (0x18, reserved as *const ()),
+            (0x19, reserved as *const ()),
+            (0x1A, reserved as *const ()),
+            (0x1B, reserved as *const ()),
+            (0x1C, hypervisor_injection as *const ()),
+            (0x1D, vmm_communication as *const ()),
+            (0x1E, security as *const ()),
+            (0x1F, reserved as *const ()),
+        ];
+        for (vector, handler) in handlers {
+            self.set_handler(vector, handler as u64, cs, GateOptions::for_vector(vector));
+        }
+    }
+}
+
+/// Checks that breakpoints come back.
+pub fn self_check() {
+    // This is safe because the breakpoint handler only logs
+    unsafe { core::arch::asm!("int3") };
+}
@@ -0,0 +1,100 @@
+//! Tracks every framebuffer the bootloader gave us as a display, and which display the console draws on.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::Surface;
+
+/// The most displays we keep track of, any further framebuffers are ignored.
+pub const MAX_DISPLAYS: usize = 8;
+
+pub struct Display {
+    surface: Mutex<Surface>,
+    /// The index of the framebuffer in the bootloader's response
+    framebuffer_index: usize,
+}
+
+impl Display {
+    pub fn surface(&self) -> &Mutex<Surface> {
+        &self.surface
+    }
+
+    pub fn framebuffer_index(&self) -> usize {
+        self.framebuffer_index
+    }
+}
+
+pub struct Displays {
+    displays: [Option<Display>; MAX_DISPLAYS],
+    count: usize,
+    /// The index of the display the console draws on
+    console: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayError {
+    NoSuchDisplay,
+}
+
+impl Displays {
+    /// Creates a display for each supported framebuffer provided by the bootloader.
+    /// Safety: nothing else may draw on the framebuffers.
+    pub unsafe fn from_limine(response: &limine::FramebufferResponse) -> Self {
+        const NONE: Option<Display> = None;
+        let mut displays = [NONE; MAX_DISPLAYS];
+        let mut count = 0;
+        for (framebuffer_index, framebuffer) in response.framebuffers().iter().enumerate() {
+            if count == MAX_DISPLAYS {
+                break;
+            }
+            if let Some(surface) = Surface::from_limine(framebuffer) {
+                displays[count] = Some(Display {
+                    surface: Mutex::new(surface),
+                    framebuffer_index,
+                });
+                count += 1;
+            }
+        }
+        Displays {
+            displays,
+            count,
+            console: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Display> {
+        self.displays.get(index)?.as_ref()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Display> {
+        self.displays[..self.count].iter().flatten()
+    }
+
+    /// Gets the display the console draws on.
+    pub fn console(&self) -> Option<&Display> {
+        self.get(self.console.load(Ordering::Relaxed))
+    }
+
+    /// Gets the index of the display the console draws on.
+    pub fn console_index(&self) -> usize {
+        self.console.load(Ordering::Relaxed)
+    }
+
+    /// Makes the console draw on the display at `index`.
+    pub fn select_console(&self, index: usize) -> Result<(), DisplayError> {
+        if index >= self.count {
+            return Err(DisplayError::NoSuchDisplay);
+        }
+        self.console.store(index, Ordering::Relaxed);
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+//! PC Screen Fonts (PSF1 and PSF2), the bitmap fonts the Linux console uses, for drawing text on a `Surface`.
+//! Fonts are loaded from bootloader modules whose command line is `font`, since there is no initramfs to take files from.
+//! `console.font=<NAME>` on the kernel command line picks the module whose path ends in `NAME`, which is how a larger font
+//! is chosen for a HiDPI panel; otherwise the first font module is used.
+//! Each glyph is rows of 1 bit per pixel padded to whole bytes, most significant bit leftmost, which is what
+//! `Surface::draw_bitmap` takes.
+
+use alloc::vec::Vec;
+use core::ffi::CStr;
+
+use crate::init::InitError;
+use crate::sync::boot_once::BootOnce;
+use crate::{cmdline, log, MODULE_REQUEST};
+
+use super::{Color, Surface};
+
+/// The module command line that marks a module as a font.
+const FONT_MODULE_CMDLINE: &str = "font";
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+/// The font has 512 glyphs instead of 256.
+const PSF1_MODE_512: u8 = 0x01;
+/// The font is followed by a table of the characters each glyph draws.
+const PSF1_MODE_HAS_TABLE: u8 = 0x02;
+const PSF1_MODE_HAS_SEQUENCES: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// The file is neither a PSF1 nor a PSF2 font.
+    BadMagic,
+    /// The header describes more glyphs than the file holds.
+    Truncated,
+    /// The glyph size doesn't match the width and height.
+    BadGlyphSize,
+}
+
+/// A bitmap font, borrowing its glyphs from the file it was parsed from.
+#[derive(Debug)]
+pub struct Font {
+    width: usize,
+    height: usize,
+    glyphs: &'static [u8],
+    glyph_size: usize,
+    glyph_count: usize,
+    /// The glyph of each character with one in the font's unicode table, sorted by character. Without a table, the glyph of a
+    /// character is its code point.
+    characters: Vec<(char, u32)>,
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Font {
+    /// Parses a PSF1 or PSF2 font.
+    pub fn parse(bytes: &'static [u8]) -> Result<Self, FontError> {
+        if bytes.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(bytes)
+        } else if bytes.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(bytes)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(bytes: &'static [u8]) -> Result<Self, FontError> {
+        if bytes.len() < PSF1_HEADER_SIZE {
+            return Err(FontError::Truncated);
+        }
+        let mode = bytes[2];
+        let height = bytes[3] as usize;
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyphs_end = PSF1_HEADER_SIZE + glyph_count * height;
+        let glyphs = bytes.get(PSF1_HEADER_SIZE..glyphs_end).ok_or(FontError::Truncated)?;
+
+        let mut characters = Vec::new();
+        if mode & (PSF1_MODE_HAS_TABLE | PSF1_MODE_HAS_SEQUENCES) != 0 {
+            let mut entries = bytes[glyphs_end..]
+                .chunks_exact(2)
+                .map(|entry| u16::from_le_bytes([entry[0], entry[1]]));
+            for glyph in 0..glyph_count as u32 {
+                let mut in_sequence = false;
+                for entry in entries.by_ref() {
+                    match entry {
+                        PSF1_SEPARATOR => break,
+                        PSF1_START_SEQUENCE => in_sequence = true,
+                        // only single characters are looked up, sequences need combining characters
+                        _ if !in_sequence => characters.extend(char::from_u32(entry as u32).map(|c| (c, glyph))),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(Font::new(8, height, glyphs, height, glyph_count, characters))
+    }
+
+    fn parse_psf2(bytes: &'static [u8]) -> Result<Self, FontError> {
+        if bytes.len() < PSF2_HEADER_SIZE {
+            return Err(FontError::Truncated);
+        }
+        let header_size = u32_at(bytes, 8) as usize;
+        let flags = u32_at(bytes, 12);
+        let glyph_count = u32_at(bytes, 16) as usize;
+        let glyph_size = u32_at(bytes, 20) as usize;
+        let height = u32_at(bytes, 24) as usize;
+        let width = u32_at(bytes, 28) as usize;
+        if glyph_size != width.div_ceil(8) * height {
+            return Err(FontError::BadGlyphSize);
+        }
+        let glyphs_end = glyph_count
+            .checked_mul(glyph_size)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(FontError::Truncated)?;
+        let glyphs = bytes.get(header_size..glyphs_end).ok_or(FontError::Truncated)?;
+
+        let mut characters = Vec::new();
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            let mut table = bytes[glyphs_end..].split(|&byte| byte == PSF2_SEPARATOR);
+            for glyph in 0..glyph_count as u32 {
+                let Some(entry) = table.next() else {
+                    break;
+                };
+                // the single characters come before the first sequence
+                let singles = entry.split(|&byte| byte == PSF2_START_SEQUENCE).next().unwrap_or(&[]);
+                if let Ok(singles) = core::str::from_utf8(singles) {
+                    characters.extend(singles.chars().map(|c| (c, glyph)));
+                }
+            }
+        }
+        Ok(Font::new(width, height, glyphs, glyph_size, glyph_count, characters))
+    }
+
+    fn new(
+        width: usize,
+        height: usize,
+        glyphs: &'static [u8],
+        glyph_size: usize,
+        glyph_count: usize,
+        mut characters: Vec<(char, u32)>,
+    ) -> Self {
+        characters.sort_unstable_by_key(|&(c, _)| c);
+        // a character listed for two glyphs keeps the first
+        characters.dedup_by_key(|&mut (c, _)| c);
+        Font {
+            width,
+            height,
+            glyphs,
+            glyph_size,
+            glyph_count,
+            characters,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Gets the bitmap of the glyph that draws `c`, or None if the font can't draw it.
+    pub fn glyph(&self, c: char) -> Option<&'static [u8]> {
+        let index = if self.characters.is_empty() {
+            c as usize
+        } else {
+            let position = self.characters.binary_search_by_key(&c, |&(c, _)| c).ok()?;
+            self.characters[position].1 as usize
+        };
+        if index >= self.glyph_count {
+            return None;
+        }
+        Some(&self.glyphs[index * self.glyph_size..(index + 1) * self.glyph_size])
+    }
+
+    /// Draws `text` on one line starting at (`x`, `y`), characters the font can't draw are drawn as `?`.
+    /// Returns the x coordinate after the last character.
+    pub fn draw(
+        &self,
+        surface: &mut Surface,
+        mut x: usize,
+        y: usize,
+        text: &str,
+        foreground: Color,
+        background: Option<Color>,
+    ) -> usize {
+        for c in text.chars() {
+            if let Some(glyph) = self.glyph(c).or_else(|| self.glyph('?')) {
+                surface.draw_bitmap(x, y, glyph, self.width, foreground, background);
+            }
+            x += self.width;
+        }
+        x
+    }
+}
+
+/// The font the console draws with, set by `init_fonts`.
+static CONSOLE_FONT: BootOnce<Font> = BootOnce::new();
+
+/// Gets the font the console draws with, None if no font module was loaded.
+pub fn console_font() -> Option<&'static Font> {
+    CONSOLE_FONT.get()
+}
+
+/// Parses the font modules and picks the console font, the one named by `console.font=<NAME>` or else the first.
+pub fn init_fonts() -> Result<(), InitError> {
+    let Some(response) = MODULE_REQUEST.get_response().get() else {
+        return Ok(());
+    };
+    let wanted = cmdline::option("console.font");
+    let mut chosen = None;
+    for module in response.modules() {
+        let (Some(base), Some(module_cmdline)) = (module.base.as_ptr(), module.cmdline.as_ptr()) else {
+            continue;
+        };
+        // This is safe because Limine gives us null terminated strings that are never freed
+        if unsafe { CStr::from_ptr(module_cmdline) }.to_str() != Ok(FONT_MODULE_CMDLINE) {
+            continue;
+        }
+        let path = module
+            .path
+            .as_ptr()
+            .and_then(|path| unsafe { CStr::from_ptr(path) }.to_str().ok())
+            .unwrap_or("?");
+        // This is safe because modules are in memory Limine gives to the kernel, which is never freed or written
+        let bytes = unsafe { core::slice::from_raw_parts(base, module.length as usize) };
+        let font = match Font::parse(bytes) {
+            Ok(font) => font,
+            Err(error) => {
+                log!("font: {} is not a PSF font: {:?}", path, error);
+                continue;
+            }
+        };
+        log!("font: {} is {}x{}", path, font.width(), font.height());
+        let matches = wanted.map_or(chosen.is_none(), |wanted| path.ends_with(wanted));
+        if matches {
+            chosen = Some((path, font));
+        }
+    }
+    let Some((path, font)) = chosen else {
+        return match wanted {
+            Some(_) => Err(InitError::new("the font named by console.font wasn't loaded")),
+            None => Ok(()),
+        };
+    };
+    log!("font: the console uses {}", path);
+    CONSOLE_FONT
+        .set(font)
+        .map_err(|_| InitError::new("the console font was already set"))?;
+    Ok(())
+}
+
+/// Checks that PSF1 and PSF2 fonts are parsed, with and without a unicode table.
+pub fn self_check() {
+    // a PSF2 font of two 10x2 glyphs, with a table mapping 'A' and 'Ä' to the second glyph
+    static PSF2: [u8; 32 + 8 + 4 + 1] = {
+        let mut font = [0; 32 + 8 + 4 + 1];
+        let header: [u32; 8] = [u32::from_le_bytes(PSF2_MAGIC), 0, 32, PSF2_HAS_UNICODE_TABLE, 2, 4, 2, 10];
+        let mut i = 0;
+        while i < 8 {
+            let bytes = header[i].to_le_bytes();
+            let mut j = 0;
+            while j < 4 {
+                font[i * 4 + j] = bytes[j];
+                j += 1;
+            }
+            i += 1;
+        }
+        // the second glyph has its top left pixel set
+        font[36] = 0x80;
+        // glyph 0 has no characters, glyph 1 is 'A' and U+00C4
+        let table = [PSF2_SEPARATOR, b'A', 0xC3, 0x84, PSF2_SEPARATOR];
+        let mut k = 0;
+        while k < table.len() {
+            font[40 + k] = table[k];
+            k += 1;
+        }
+        font
+    };
+    let font = Font::parse(&PSF2).unwrap();
+    assert_eq!((font.width(), font.height()), (10, 2));
+    assert_eq!(font.glyph('A'), Some(&PSF2[36..40]));
+    assert_eq!(font.glyph('Ä'), Some(&PSF2[36..40]));
+    assert_eq!(font.glyph('B'), None);
+
+    static PSF1: [u8; 4 + 256 * 8] = {
+        let mut font = [0; 4 + 256 * 8];
+        font[0] = PSF1_MAGIC[0];
+        font[1] = PSF1_MAGIC[1];
+        font[3] = 8;
+        font[4 + b'A' as usize * 8] = 0xFF;
+        font
+    };
+    let font = Font::parse(&PSF1).unwrap();
+    assert_eq!((font.width(), font.height()), (8, 8));
+    assert_eq!(font.glyph('A').unwrap()[0], 0xFF);
+    assert_eq!(font.glyph('\u{100}'), None);
+
+    assert_eq!(Font::parse(&PSF1[..100]).unwrap_err(), FontError::Truncated);
+    assert_eq!(Font::parse(&[0; 64]).unwrap_err(), FontError::BadMagic);
+}
@@ -0,0 +1,393 @@
+//! A small 2D drawing layer over a linear framebuffer.
+//! Consumers draw in `Color`s and the `Surface` converts them to whatever pixel format the framebuffer uses.
+
+use core::ptr::{read_volatile, write_volatile};
+
+pub mod display;
+pub mod font;
+
+/// The Limine memory model for RGB framebuffers, the only one defined.
+const MEMORY_MODEL_RGB: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(0xFF, 0xFF, 0xFF);
+
+    pub const fn rgb(red: u8, green: u8, blue: u8) -> Self {
+        Color { red, green, blue }
+    }
+
+    /// Blends `self` over `background`, where an `alpha` of 255 is fully `self`.
+    pub fn blend(self, background: Color, alpha: u8) -> Color {
+        let mix = |foreground: u8, background: u8| -> u8 {
+            ((foreground as u16 * alpha as u16 + background as u16 * (255 - alpha as u16)) / 255) as u8
+        };
+        Color {
+            red: mix(self.red, background.red),
+            green: mix(self.green, background.green),
+            blue: mix(self.blue, background.blue),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Channel {
+    size: u8,
+    shift: u8,
+}
+
+impl Channel {
+    fn encode(&self, value: u8) -> u32 {
+        if self.size == 0 {
+            return 0;
+        }
+        // take the top `size` bits of the value
+        let value = if self.size >= 8 {
+            (value as u32) << (self.size - 8)
+        } else {
+            value as u32 >> (8 - self.size)
+        };
+        value << self.shift
+    }
+
+    fn decode(&self, raw: u32) -> u8 {
+        if self.size == 0 {
+            return 0;
+        }
+        let value = (raw >> self.shift) & ((1u32 << self.size) - 1);
+        if self.size >= 8 {
+            (value >> (self.size - 8)) as u8
+        } else {
+            // replicate the high bits so full intensity maps to 0xFF
+            let mut expanded = value << (8 - self.size);
+            expanded |= expanded >> self.size;
+            expanded as u8
+        }
+    }
+}
+
+/// Describes how a color is laid out in a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    bytes_per_pixel: u8,
+    red: Channel,
+    green: Channel,
+    blue: Channel,
+}
+
+impl PixelFormat {
+    /// 32 bits per pixel with 8 bits of blue, green and red from the lowest byte up, what most framebuffers use.
+    pub const XRGB8888: PixelFormat = PixelFormat {
+        bytes_per_pixel: 4,
+        red: Channel { size: 8, shift: 16 },
+        green: Channel { size: 8, shift: 8 },
+        blue: Channel { size: 8, shift: 0 },
+    };
+
+    /// Gets the pixel format of a framebuffer given to us by Limine, returns None if it is not a supported format.
+    pub fn from_limine(framebuffer: &limine::Framebuffer) -> Option<Self> {
+        if framebuffer.memory_model != MEMORY_MODEL_RGB {
+            return None;
+        }
+        if !matches!(framebuffer.bpp, 16 | 24 | 32) {
+            return None;
+        }
+        Some(PixelFormat {
+            bytes_per_pixel: (framebuffer.bpp / 8) as u8,
+            red: Channel {
+                size: framebuffer.red_mask_size,
+                shift: framebuffer.red_mask_shift,
+            },
+            green: Channel {
+                size: framebuffer.green_mask_size,
+                shift: framebuffer.green_mask_shift,
+            },
+            blue: Channel {
+                size: framebuffer.blue_mask_size,
+                shift: framebuffer.blue_mask_shift,
+            },
+        })
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bytes_per_pixel as usize
+    }
+
+    /// Converts a color to a raw pixel value.
+    pub fn encode(&self, color: Color) -> u32 {
+        self.red.encode(color.red) | self.green.encode(color.green) | self.blue.encode(color.blue)
+    }
+
+    /// Converts a raw pixel value to a color.
+    pub fn decode(&self, raw: u32) -> Color {
+        Color::rgb(self.red.decode(raw), self.green.decode(raw), self.blue.decode(raw))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect { x, y, width, height }
+    }
+}
+
+/// A linear framebuffer that can be drawn on. All drawing is clipped to the bounds of the surface.
+#[derive(Debug)]
+pub struct Surface {
+    base: *mut u8,
+    width: usize,
+    height: usize,
+    /// The number of bytes between the start of each row
+    pitch: usize,
+    format: PixelFormat,
+}
+
+// Safety: the surface is the only thing that writes to its framebuffer memory
+unsafe impl Send for Surface {}
+
+impl Surface {
+    /// Creates a surface for the framebuffer at `base`.
+    /// Safety: `base` must point to `pitch * height` bytes of writable framebuffer memory that isn't used by anything else.
+    pub unsafe fn new(base: *mut u8, width: usize, height: usize, pitch: usize, format: PixelFormat) -> Self {
+        Surface {
+            base,
+            width,
+            height,
+            pitch,
+            format,
+        }
+    }
+
+    /// Creates a surface for a framebuffer given to us by Limine, returns None if its pixel format is not supported.
+    /// Safety: nothing else may draw on the framebuffer while the surface exists.
+    pub unsafe fn from_limine(framebuffer: &limine::Framebuffer) -> Option<Self> {
+        let format = PixelFormat::from_limine(framebuffer)?;
+        let base = framebuffer.address.as_ptr()?;
+        Some(Surface::new(
+            base,
+            framebuffer.width as usize,
+            framebuffer.height as usize,
+            framebuffer.pitch as usize,
+            format,
+        ))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    fn pixel_ptr(&self, x: usize, y: usize) -> *mut u8 {
+        debug_assert!(x < self.width && y < self.height);
+        // This is safe because the pixel is in the bounds of the framebuffer
+        unsafe { self.base.add(y * self.pitch + x * self.format.bytes_per_pixel()) }
+    }
+
+    fn write_raw(&mut self, x: usize, y: usize, raw: u32) {
+        let ptr = self.pixel_ptr(x, y);
+        // Volatile writes are used since this is device memory
+        unsafe {
+            match self.format.bytes_per_pixel {
+                4 => write_volatile(ptr as *mut u32, raw),
+                2 => write_volatile(ptr as *mut u16, raw as u16),
+                _ => {
+                    for i in 0..self.format.bytes_per_pixel() {
+                        write_volatile(ptr.add(i), (raw >> (i * 8)) as u8);
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_raw(&self, x: usize, y: usize) -> u32 {
+        let ptr = self.pixel_ptr(x, y);
+        unsafe {
+            match self.format.bytes_per_pixel {
+                4 => read_volatile(ptr as *const u32),
+                2 => read_volatile(ptr as *const u16) as u32,
+                _ => {
+                    let mut raw = 0;
+                    for i in 0..self.format.bytes_per_pixel() {
+                        raw |= (read_volatile(ptr.add(i)) as u32) << (i * 8);
+                    }
+                    raw
+                }
+            }
+        }
+    }
+
+    /// Clips `rect` to the bounds of the surface.
+    fn clip(&self, rect: Rect) -> Rect {
+        let x = rect.x.min(self.width);
+        let y = rect.y.min(self.height);
+        Rect {
+            x,
+            y,
+            width: rect.width.min(self.width - x),
+            height: rect.height.min(self.height - y),
+        }
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Color) {
+        if x < self.width && y < self.height {
+            self.write_raw(x, y, self.format.encode(color));
+        }
+    }
+
+    /// Gets the color of a pixel, returns None if it is out of bounds.
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<Color> {
+        if x < self.width && y < self.height {
+            Some(self.format.decode(self.read_raw(x, y)))
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let rect = self.clip(rect);
+        let raw = self.format.encode(color);
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                self.write_raw(x, y, raw);
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        self.fill_rect(Rect::new(0, 0, self.width, self.height), color);
+    }
+
+    /// Copies an image stored as rows of `source_width` colors to (`x`, `y`).
+    pub fn blit(&mut self, x: usize, y: usize, source: &[Color], source_width: usize) {
+        if source_width == 0 {
+            return;
+        }
+        let source_height = source.len() / source_width;
+        let rect = self.clip(Rect::new(x, y, source_width, source_height));
+        for row in 0..rect.height {
+            for column in 0..rect.width {
+                let color = source[row * source_width + column];
+                self.write_raw(rect.x + column, rect.y + row, self.format.encode(color));
+            }
+        }
+    }
+
+    /// Draws `color` through a coverage mask stored as rows of `mask_width` alpha values, blending it with what is already on the surface.
+    /// This is what anti-aliased text is drawn with.
+    pub fn draw_alpha_mask(&mut self, x: usize, y: usize, mask: &[u8], mask_width: usize, color: Color) {
+        if mask_width == 0 {
+            return;
+        }
+        let mask_height = mask.len() / mask_width;
+        let rect = self.clip(Rect::new(x, y, mask_width, mask_height));
+        for row in 0..rect.height {
+            for column in 0..rect.width {
+                let alpha = mask[row * mask_width + column];
+                let (x, y) = (rect.x + column, rect.y + row);
+                let pixel = match alpha {
+                    0 => continue,
+                    255 => color,
+                    _ => color.blend(self.format.decode(self.read_raw(x, y)), alpha),
+                };
+                self.write_raw(x, y, self.format.encode(pixel));
+            }
+        }
+    }
+
+    /// Draws a 1 bit per pixel bitmap, such as a glyph from a bitmap font, with the most significant bit of each byte leftmost.
+    /// Each row is padded to a whole number of bytes. Set bits are drawn in `foreground`, clear bits in `background` if it is given.
+    pub fn draw_bitmap(
+        &mut self,
+        x: usize,
+        y: usize,
+        bitmap: &[u8],
+        width: usize,
+        foreground: Color,
+        background: Option<Color>,
+    ) {
+        let bytes_per_row = width.div_ceil(8);
+        if bytes_per_row == 0 {
+            return;
+        }
+        let height = bitmap.len() / bytes_per_row;
+        let rect = self.clip(Rect::new(x, y, width, height));
+        let foreground = self.format.encode(foreground);
+        let background = background.map(|color| self.format.encode(color));
+        for row in 0..rect.height {
+            for column in 0..rect.width {
+                let byte = bitmap[row * bytes_per_row + column / 8];
+                if byte & (0x80 >> (column % 8)) != 0 {
+                    self.write_raw(rect.x + column, rect.y + row, foreground);
+                } else if let Some(background) = background {
+                    self.write_raw(rect.x + column, rect.y + row, background);
+                }
+            }
+        }
+    }
+
+    /// Draws a line from (`x0`, `y0`) to (`x1`, `y1`) using Bresenham's algorithm, points may be off the surface.
+    pub fn draw_line(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, color: Color) {
+        let raw = self.format.encode(color);
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+                self.write_raw(x as usize, y as usize, raw);
+            }
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled_error = 2 * error;
+            if doubled_error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled_error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Moves the contents of the surface up by `rows` pixels and fills the uncovered area with `fill`, used for scrolling text.
+    pub fn scroll_up(&mut self, rows: usize, fill: Color) {
+        let rows = rows.min(self.height);
+        let row_bytes = self.width * self.format.bytes_per_pixel();
+        for y in 0..self.height - rows {
+            // This is safe because both rows are in the framebuffer, and `copy` handles overlap
+            unsafe {
+                core::ptr::copy(
+                    self.base.add((y + rows) * self.pitch),
+                    self.base.add(y * self.pitch),
+                    row_bytes,
+                );
+            }
+        }
+        self.fill_rect(Rect::new(0, self.height - rows, self.width, rows), fill);
+    }
+}
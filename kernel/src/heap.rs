@@ -0,0 +1,236 @@
+//! The kernel heap, which backs `alloc` types like `Vec`, `Box` and `BTreeMap`.
+//! The heap lives in its own lazily mapped virtual region (see `demand_paging`), so it only uses frames for the pages it has touched.
+//! Free memory is kept in a linked list sorted by address, so neighbouring free blocks can be merged when memory is freed.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+
+use spin::Mutex;
+
+use crate::demand_paging;
+use crate::init::InitError;
+use crate::log;
+use crate::x64::page_table::PageFlags;
+
+/// The start of the heap's virtual region.
+pub const HEAP_START: u64 = 0xFFFF_D000_0000_0000;
+/// The most the heap can grow to.
+pub const HEAP_MAX_SIZE: u64 = 1 << 30;
+/// How much of the heap is put on the free list by `init_heap`.
+const HEAP_INITIAL_SIZE: u64 = 256 * 1024;
+/// The least the heap grows by at a time, so small allocations don't map one page each.
+const HEAP_GROW_SIZE: u64 = 64 * 1024;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// A free block of memory, stored in the block itself.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// The smallest block, every block has to be able to hold a `FreeBlock` once it is freed.
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+
+struct Heap {
+    /// The free block with the lowest address
+    first_free: *mut FreeBlock,
+    /// The end of the used part of the heap region, 0 until the heap is set up
+    end: u64,
+}
+
+// This is safe because the free list is only accessed through the heap's `Mutex`
+unsafe impl Send for Heap {}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Gets the size and alignment of the block used for `layout`.
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(align_of::<FreeBlock>());
+    let size = align_up(layout.size().max(MIN_BLOCK_SIZE), align_of::<FreeBlock>());
+    (size, align)
+}
+
+impl Heap {
+    const fn new() -> Self {
+        Heap {
+            first_free: null_mut(),
+            end: 0,
+        }
+    }
+
+    /// Takes a block of `size` bytes aligned to `align` from the free list, returns None if no free block is large enough.
+    fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut link: *mut *mut FreeBlock = &mut self.first_free;
+        // This is safe because the free list only contains free blocks in the used part of the heap
+        unsafe {
+            while !(*link).is_null() {
+                let block = *link;
+                let block_start = block as usize;
+                let block_end = block_start + (*block).size;
+                let mut start = align_up(block_start, align);
+                // padding before the allocation has to be large enough to stay on the free list
+                if start != block_start && start - block_start < MIN_BLOCK_SIZE {
+                    start = align_up(block_start + MIN_BLOCK_SIZE, align);
+                }
+                let end = start + size;
+                // so does the remainder after it
+                if end <= block_end && (block_end - end == 0 || block_end - end >= MIN_BLOCK_SIZE) {
+                    let next = (*block).next;
+                    let after = if end < block_end {
+                        let remainder = end as *mut FreeBlock;
+                        remainder.write(FreeBlock {
+                            size: block_end - end,
+                            next,
+                        });
+                        remainder
+                    } else {
+                        next
+                    };
+                    if start > block_start {
+                        (*block).size = start - block_start;
+                        (*block).next = after;
+                    } else {
+                        *link = after;
+                    }
+                    return Some(start as *mut u8);
+                }
+                link = &mut (*block).next;
+            }
+        }
+        None
+    }
+
+    /// Adds a block to the free list, merging it with the free blocks on either side.
+    /// Safety: the block must be unused memory in the used part of the heap, and not already on the free list.
+    unsafe fn free(&mut self, start: usize, size: usize) {
+        let mut previous: *mut FreeBlock = null_mut();
+        let mut next = self.first_free;
+        while !next.is_null() && (next as usize) < start {
+            previous = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if previous.is_null() {
+            self.first_free = block;
+        } else if previous as usize + (*previous).size == start {
+            (*previous).size += (*block).size;
+            (*previous).next = (*block).next;
+        } else {
+            (*previous).next = block;
+        }
+    }
+
+    /// Adds at least `size` more bytes at the end of the heap to the free list, returns false if the heap is full.
+    /// The heap is a lazy region, so the new pages are only backed by frames once they are used.
+    fn grow(&mut self, size: u64) -> bool {
+        let size = (size.max(HEAP_GROW_SIZE) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if self.end == 0 || self.end + size > HEAP_START + HEAP_MAX_SIZE {
+            return false;
+        }
+        let start = self.end;
+        self.end += size;
+        // This is safe because the memory is in the heap's region and nothing uses it
+        unsafe { self.free(start as usize, size as usize) };
+        true
+    }
+}
+
+/// The kernel's global allocator. Allocations fail until `init_heap` has run.
+pub struct KernelHeap {
+    heap: Mutex<Heap>,
+}
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap {
+    heap: Mutex::new(Heap::new()),
+};
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        let mut heap = self.heap.lock();
+        if let Some(pointer) = heap.allocate(size, align) {
+            return pointer;
+        }
+        // enough for the allocation even if it has to be aligned within the new memory
+        if heap.grow((size + align) as u64) {
+            heap.allocate(size, align).unwrap_or(null_mut())
+        } else {
+            null_mut()
+        }
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.heap.lock().free(pointer as usize, size);
+    }
+}
+
+/// Returns how many bytes of the heap are in use or on the free list and how many of those are free.
+pub fn usage() -> (u64, u64) {
+    let heap = KERNEL_HEAP.heap.lock();
+    let mut free = 0;
+    let mut block = heap.first_free;
+    while !block.is_null() {
+        // This is safe because the free list only contains free blocks in the used part of the heap
+        unsafe {
+            free += (*block).size as u64;
+            block = (*block).next;
+        }
+    }
+    (heap.end.saturating_sub(HEAP_START), free)
+}
+
+/// Reserves the heap's region so allocations can be made. It is mapped on demand as it is used.
+pub fn init_heap() -> Result<(), InitError> {
+    demand_paging::register("heap", HEAP_START, HEAP_MAX_SIZE, PageFlags::KERNEL_DATA)
+        .map_err(|_| InitError::new("Could not reserve the kernel heap"))?;
+    let mut heap = KERNEL_HEAP.heap.lock();
+    heap.end = HEAP_START;
+    // This touches the first page, which creates the heap's Pdpt before any address space copies the kernel half
+    heap.grow(HEAP_INITIAL_SIZE);
+    log!("heap: {:#x} bytes reserved at {:#x}", HEAP_MAX_SIZE, HEAP_START);
+    Ok(())
+}
+
+/// Checks that `alloc` types work and that freeing everything returns the heap to where it started.
+pub fn self_check() {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+
+    let (mapped_before, free_before) = usage();
+    {
+        let boxed = Box::new(0x1234_5678u64);
+        assert_eq!(*boxed, 0x1234_5678);
+        let mut vec = Vec::new();
+        for i in 0..10_000u32 {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().map(|&i| i as u64).sum::<u64>(), 49_995_000);
+        let mut map = BTreeMap::new();
+        for i in 0..1000u32 {
+            map.insert(i * 7 % 1000, i);
+        }
+        assert_eq!(map.len(), 1000);
+        let aligned = Box::new(Aligned([0; 64]));
+        assert_eq!(&*aligned as *const Aligned as usize % 4096, 0);
+    }
+    let (mapped_after, free_after) = usage();
+    // growing the heap adds free memory, everything else must have been given back
+    assert_eq!(free_after - free_before, mapped_after - mapped_before);
+}
+
+#[repr(align(4096))]
+struct Aligned([u8; 64]);
@@ -0,0 +1,79 @@
+use core::arch::x86_64::_rdtsc;
+
+use crate::log;
+
+/// The maximum number of stages that can be registered.
+const MAX_STAGES: usize = 32;
+
+/// An error returned by a failed init stage.
+#[derive(Debug, Clone, Copy)]
+pub struct InitError {
+    pub message: &'static str,
+}
+
+impl InitError {
+    pub const fn new(message: &'static str) -> Self {
+        Self { message }
+    }
+}
+
+/// A step of kernel initialization.
+pub struct InitStage {
+    pub name: &'static str,
+    /// The names of stages that must have completed successfully before this stage runs.
+    pub dependencies: &'static [&'static str],
+    /// If set, the kernel panics when this stage fails (or can't run), otherwise the failure is logged and boot continues.
+    pub critical: bool,
+    pub run: fn() -> Result<(), InitError>,
+}
+
+/// The outcome of running an init stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StageStatus {
+    Succeeded,
+    Failed,
+    /// The stage was skipped because a dependency failed or did not run first.
+    Skipped,
+}
+
+/// Runs `stages` in order, reporting the outcome and duration (in TSC cycles) of each.
+/// A stage only runs if all of its dependencies appear earlier in `stages` and succeeded.
+pub fn run_stages(stages: &[InitStage]) {
+    assert!(stages.len() <= MAX_STAGES, "Too many init stages");
+    let mut statuses = [StageStatus::Skipped; MAX_STAGES];
+
+    for (index, stage) in stages.iter().enumerate() {
+        let missing_dependency = stage.dependencies.iter().find(|dependency| {
+            !stages[..index]
+                .iter()
+                .zip(statuses.iter())
+                .any(|(s, status)| s.name == **dependency && *status == StageStatus::Succeeded)
+        });
+        if let Some(dependency) = missing_dependency {
+            log!("init: skipping {}, dependency {} did not complete", stage.name, dependency);
+            assert!(!stage.critical, "critical init stage {} skipped", stage.name);
+            continue;
+        }
+
+        let start = unsafe { _rdtsc() };
+        let result = (stage.run)();
+        let cycles = unsafe { _rdtsc() } - start;
+
+        match result {
+            Ok(()) => {
+                statuses[index] = StageStatus::Succeeded;
+                log!("init: {} done in {} cycles", stage.name, cycles);
+            }
+            Err(error) => {
+                statuses[index] = StageStatus::Failed;
+                log!("init: {} failed after {} cycles: {}", stage.name, cycles, error.message);
+                assert!(
+                    !stage.critical,
+                    "critical init stage {} failed: {}",
+                    stage.name,
+                    error.message
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! Dispatch of external interrupts to their handlers, and accounting of the interrupts nobody handles.
+//! Every vector from `FIRST_EXTERNAL_VECTOR` up gets an entry stub that pushes its vector and calls `dispatch()`, so an interrupt
+//! on a vector without a handler is counted instead of faulting on a missing gate.
+//! A line that keeps interrupting without a handler claiming it (there is no handler, or the device never deasserts the line)
+//! is masked after `irq.unhandled_limit` unhandled interrupts in a row, and so is a line that interrupts more than
+//! `irq.storm_limit` times in `STORM_WINDOW_MS`, both of which are tunables. Masking needs the `LineControl` of whatever routes
+//! the line to the vector, which also ends the interrupt once it is handled.
+//! Interrupts are also counted per CPU, once the CPU has called `register_cpu()`, and `dump_irq_stats()` prints both counts,
+//! which shows a storm or a line that stops interrupting after its first interrupt (a missing EOI) at a glance.
+//! Drivers get interrupts with `register_irq`, which routes a device's line through the I/O APIC to a vector of its own.
+//! Handlers run with interrupts disabled and must not take locks that are held with interrupts enabled.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::delay::tsc_khz;
+use crate::emergency_log;
+use crate::sync::{current_cpu, MAX_CPUS};
+use crate::sysctl::{self, Tunable};
+use crate::trace::{self, TraceEvent};
+use crate::x64::gdt::SegmentSelector;
+use crate::x64::idt::{GateOptions, Idt, FIRST_EXTERNAL_VECTOR};
+use crate::x64::ioapic::{self, RouteError};
+use crate::x64::vectors::{self, VectorClass, SPURIOUS_VECTOR};
+
+/// Unhandled interrupts in a row after which a line is masked.
+static UNHANDLED_LIMIT: Tunable = Tunable::integer(
+    "irq.unhandled_limit",
+    "unhandled interrupts in a row after which a line is masked",
+    1000,
+    1,
+    u32::MAX as u64,
+);
+/// Interrupts in one window after which a line is considered stuck and masked.
+static STORM_LIMIT: Tunable = Tunable::integer(
+    "irq.storm_limit",
+    "interrupts in 100ms after which a line is masked as stuck",
+    10_000,
+    1,
+    u32::MAX as u64,
+);
+const STORM_WINDOW_MS: u64 = 100;
+/// The size of each entry stub, they are aligned so the stub of a vector can be found without a table.
+const STUB_SIZE: u64 = 16;
+
+/// What a handler did with an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    /// The interrupt wasn't from the handler's device, for lines that are shared or spurious.
+    NotMine,
+}
+
+/// Handles an interrupt on the vector it is given.
+pub type IrqHandler = fn(u8) -> IrqReturn;
+
+/// How to mask and unmask the line behind a vector and end its interrupts, provided by the interrupt controller that routes it.
+pub struct LineControl {
+    pub mask: fn(u8),
+    pub unmask: fn(u8),
+    /// Called after the handler, so the controller delivers the next interrupt.
+    pub eoi: fn(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The vector is reserved for exceptions.
+    NotExternal,
+    /// The vector already has a handler.
+    InUse,
+    /// There are no free vectors.
+    NoFreeVector,
+    /// Nothing can route the GSI to a vector.
+    NoRoute,
+}
+
+/// The counters of a vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStats {
+    pub count: u64,
+    pub unhandled: u64,
+    /// Whether the line was masked for misbehaving.
+    pub masked: bool,
+}
+
+/// The state of a vector. Everything is atomic because it is used from interrupt handlers.
+struct Line {
+    /// An `IrqHandler`, or 0 if there is none.
+    handler: AtomicUsize,
+    control: AtomicPtr<LineControl>,
+    count: AtomicU64,
+    unhandled: AtomicU64,
+    unhandled_in_a_row: AtomicU32,
+    /// The TSC value at the start of the current storm window.
+    window_start: AtomicU64,
+    window_count: AtomicU32,
+    masked: AtomicBool,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Line {
+            handler: AtomicUsize::new(0),
+            control: AtomicPtr::new(null_mut()),
+            count: AtomicU64::new(0),
+            unhandled: AtomicU64::new(0),
+            unhandled_in_a_row: AtomicU32::new(0),
+            window_start: AtomicU64::new(0),
+            window_count: AtomicU32::new(0),
+            masked: AtomicBool::new(false),
+        }
+    }
+}
+
+static LINES: [Line; 256] = [const { Line::new() }; 256];
+/// The names of the lines registered with `register_irq`.
+static NAMES: Mutex<[Option<&'static str>; 256]> = Mutex::new([None; 256]);
+/// Interrupts on the local APIC's spurious vector, which need no handling (not even an EOI).
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// One CPU's interrupt counts, by vector.
+struct CpuCounts([AtomicU64; 256]);
+
+/// Each CPU's counts, allocated by `register_cpu()` and never freed.
+static CPU_COUNTS: [AtomicPtr<CpuCounts>; MAX_CPUS] = [const { AtomicPtr::new(null_mut()) }; MAX_CPUS];
+
+// An entry stub for each external vector, each `STUB_SIZE` bytes long. They push their vector and jump to the common entry,
+// which saves the registers that aren't preserved across calls and calls `dispatch()`.
+// The CPU pushed 5 words on a 16 byte aligned stack, so after the vector and 9 registers the stack needs 8 more bytes for the call.
+global_asm!(
+    ".balign 16",
+    ".global irq_entry_stubs",
+    "irq_entry_stubs:",
+    ".set irq_vector, {first}",
+    ".rept 256 - {first}",
+    ".balign 16",
+    "push irq_vector",
+    "jmp irq_common_entry",
+    ".set irq_vector, irq_vector + 1",
+    ".endr",
+    "irq_common_entry:",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rax",
+    "mov rdi, [rsp + 72]",
+    "sub rsp, 8",
+    "cld",
+    "call {dispatch}",
+    "add rsp, 8",
+    "pop rax",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    // drop the vector
+    "add rsp, 8",
+    "iretq",
+    first = const FIRST_EXTERNAL_VECTOR,
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn irq_entry_stubs();
+}
+
+/// Points every external vector at its entry stub, and registers the tunables. Gates installed afterwards (like the syscall
+/// gate) replace the stubs.
+pub fn install(idt: &mut Idt, cs: SegmentSelector) {
+    let _ = sysctl::register(&UNHANDLED_LIMIT);
+    let _ = sysctl::register(&STORM_LIMIT);
+    for vector in FIRST_EXTERNAL_VECTOR..=u8::MAX {
+        let stub = irq_entry_stubs as *const () as u64 + (vector - FIRST_EXTERNAL_VECTOR) as u64 * STUB_SIZE;
+        idt.set_handler(vector, stub, cs, GateOptions::for_vector(vector));
+    }
+}
+
+/// Sets the handler of `vector`.
+pub fn set_handler(vector: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if vector < FIRST_EXTERNAL_VECTOR {
+        return Err(IrqError::NotExternal);
+    }
+    LINES[vector as usize]
+        .handler
+        .compare_exchange(0, handler as usize, Ordering::SeqCst, Ordering::SeqCst)
+        .map(|_| ())
+        .map_err(|_| IrqError::InUse)
+}
+
+/// Routes the global system interrupt `gsi` to a newly allocated vector with `handler` as its handler, returns the vector.
+/// `name` identifies the line in statistics. The line is unmasked once the handler is in place.
+pub fn register_irq(gsi: u32, handler: IrqHandler, name: &'static str) -> Result<u8, IrqError> {
+    let vector = vectors::allocate(VectorClass::Normal).ok_or(IrqError::NoFreeVector)?;
+    if let Err(error) = set_handler(vector, handler) {
+        vectors::free(vector);
+        return Err(error);
+    }
+    NAMES.lock()[vector as usize] = Some(name);
+    set_line_control(vector, &ioapic::LINE_CONTROL);
+    if let Err(RouteError::NoIoApic) = ioapic::route(gsi, vector) {
+        unregister_irq(vector);
+        return Err(IrqError::NoRoute);
+    }
+    Ok(vector)
+}
+
+/// Masks and forgets a line registered with `register_irq`, and frees its vector.
+pub fn unregister_irq(vector: u8) {
+    ioapic::unroute(vector);
+    remove_handler(vector);
+    LINES[vector as usize].control.store(null_mut(), Ordering::SeqCst);
+    NAMES.lock()[vector as usize] = None;
+    vectors::free(vector);
+}
+
+/// Gets the name a line was registered with.
+pub fn name(vector: u8) -> Option<&'static str> {
+    NAMES.lock()[vector as usize]
+}
+
+/// Removes the handler of `vector`, interrupts on it are counted as unhandled again.
+pub fn remove_handler(vector: u8) {
+    LINES[vector as usize].handler.store(0, Ordering::SeqCst);
+}
+
+/// Sets how to mask the line behind `vector`, so it can be masked if it misbehaves.
+pub fn set_line_control(vector: u8, control: &'static LineControl) {
+    LINES[vector as usize]
+        .control
+        .store(control as *const LineControl as *mut LineControl, Ordering::SeqCst);
+}
+
+/// Unmasks a line that was masked for misbehaving, for when its driver has dealt with the device.
+pub fn unmask(vector: u8) {
+    let line = &LINES[vector as usize];
+    line.unhandled_in_a_row.store(0, Ordering::SeqCst);
+    line.window_count.store(0, Ordering::SeqCst);
+    if line.masked.swap(false, Ordering::SeqCst) {
+        // This is safe because line controls are `'static`
+        if let Some(control) = unsafe { line.control.load(Ordering::SeqCst).as_ref() } {
+            (control.unmask)(vector);
+        }
+    }
+}
+
+/// Allocates this CPU's interrupt counts, if it doesn't have them yet. Interrupts before this are only counted in total.
+pub fn register_cpu() {
+    let slot = &CPU_COUNTS[current_cpu()];
+    if slot.load(Ordering::SeqCst).is_null() {
+        slot.store(Box::leak(Box::new(CpuCounts([const { AtomicU64::new(0) }; 256]))), Ordering::SeqCst);
+    }
+}
+
+/// Gets the number of interrupts on `vector` that `cpu` took, None if the CPU isn't counting.
+pub fn cpu_count(cpu: usize, vector: u8) -> Option<u64> {
+    // This is safe because counts are never freed
+    let counts = unsafe { CPU_COUNTS[cpu].load(Ordering::Acquire).as_ref() }?;
+    Some(counts.0[vector as usize].load(Ordering::Relaxed))
+}
+
+fn count_on_this_cpu(vector: u8) {
+    // This is safe because counts are never freed
+    if let Some(counts) = unsafe { CPU_COUNTS[current_cpu()].load(Ordering::Acquire).as_ref() } {
+        counts.0[vector as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn stats(vector: u8) -> IrqStats {
+    let line = &LINES[vector as usize];
+    IrqStats {
+        count: line.count.load(Ordering::Relaxed),
+        unhandled: line.unhandled.load(Ordering::Relaxed),
+        masked: line.masked.load(Ordering::Relaxed),
+    }
+}
+
+/// Gets the number of interrupts on the spurious vector.
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Calls the handler of `vector` and does the accounting. Called by the entry stubs.
+extern "C" fn dispatch(vector: u64) {
+    let vector = vector as u8;
+    count_on_this_cpu(vector);
+    if vector == SPURIOUS_VECTOR {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    trace::record(TraceEvent::IrqEntry, vector as u64);
+    let line = &LINES[vector as usize];
+    line.count.fetch_add(1, Ordering::Relaxed);
+    let handler = line.handler.load(Ordering::Acquire);
+    let result = if handler == 0 {
+        IrqReturn::NotMine
+    } else {
+        // This is safe because only `IrqHandler`s are stored in `handler`
+        let handler: IrqHandler = unsafe { core::mem::transmute(handler) };
+        handler(vector)
+    };
+    if result == IrqReturn::NotMine {
+        line.unhandled.fetch_add(1, Ordering::Relaxed);
+        if line.unhandled_in_a_row.fetch_add(1, Ordering::Relaxed) + 1 >= UNHANDLED_LIMIT.get() as u32 {
+            mask_line(vector, "unhandled interrupts");
+        }
+    } else {
+        line.unhandled_in_a_row.store(0, Ordering::Relaxed);
+    }
+    if is_storm(line) {
+        mask_line(vector, "an interrupt storm");
+    }
+    // This is safe because line controls are `'static`
+    if let Some(control) = unsafe { line.control.load(Ordering::SeqCst).as_ref() } {
+        (control.eoi)(vector);
+    }
+    trace::record(TraceEvent::IrqExit, vector as u64);
+}
+
+/// Writes the counts of each vector that has interrupted: the total, the count on each CPU that is counting, how many were
+/// unhandled, and the vector's name and whether it was masked.
+pub fn dump_irq_stats<W: Write>(writer: &mut W) -> fmt::Result {
+    let cpus: Vec<usize> = (0..MAX_CPUS)
+        .filter(|&cpu| !CPU_COUNTS[cpu].load(Ordering::Acquire).is_null())
+        .collect();
+    write!(writer, "vector {:>10}", "total")?;
+    for cpu in &cpus {
+        write!(writer, " {:>10}", alloc::format!("cpu{}", cpu))?;
+    }
+    writeln!(writer, " {:>10} name", "unhandled")?;
+    for vector in FIRST_EXTERNAL_VECTOR..=u8::MAX {
+        let stats = stats(vector);
+        let (total, name) = match vector {
+            SPURIOUS_VECTOR => (spurious_count(), Some("spurious")),
+            _ => (stats.count, name(vector)),
+        };
+        if total == 0 {
+            continue;
+        }
+        write!(writer, "{:#6x} {:>10}", vector, total)?;
+        for &cpu in &cpus {
+            write!(writer, " {:>10}", cpu_count(cpu, vector).unwrap_or(0))?;
+        }
+        writeln!(
+            writer,
+            " {:>10} {}{}",
+            stats.unhandled,
+            name.unwrap_or("-"),
+            if stats.masked { " (masked)" } else { "" }
+        )?;
+    }
+    Ok(())
+}
+
+/// Counts an interrupt in the line's storm window, returns whether the line interrupted too often.
+fn is_storm(line: &Line) -> bool {
+    let window = STORM_WINDOW_MS * tsc_khz();
+    if window == 0 {
+        // without a calibrated TSC there is no telling how long a window is
+        return false;
+    }
+    // This is safe because rdtsc has no side effects
+    let now = unsafe { core::arch::x86_64::_rdtsc() };
+    if now.wrapping_sub(line.window_start.load(Ordering::Relaxed)) > window {
+        line.window_start.store(now, Ordering::Relaxed);
+        line.window_count.store(0, Ordering::Relaxed);
+    }
+    line.window_count.fetch_add(1, Ordering::Relaxed) + 1 > STORM_LIMIT.get() as u32
+}
+
+/// Masks the line behind `vector` because of `reason`, once. Runs in the interrupt, so it logs with `emergency_log!`: the
+/// interrupted code may hold the serial port's lock.
+fn mask_line(vector: u8, reason: &str) {
+    let line = &LINES[vector as usize];
+    if line.masked.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // This is safe because line controls are `'static`
+    match unsafe { line.control.load(Ordering::SeqCst).as_ref() } {
+        Some(control) => {
+            (control.mask)(vector);
+            emergency_log!("irq: masked vector {:#x} after {}", vector, reason);
+        }
+        None => emergency_log!("irq: vector {:#x} has {} but its line can't be masked", vector, reason),
+    }
+}
+
+/// Checks that interrupts reach their handler through the entry stubs, and that a line nobody handles is masked.
+pub fn self_check() {
+
+    static HANDLED: AtomicU32 = AtomicU32::new(0);
+    static MASKED: AtomicU32 = AtomicU32::new(0);
+    static EOIS: AtomicU32 = AtomicU32::new(0);
+    static CONTROL: LineControl = LineControl {
+        mask: |_| {
+            MASKED.fetch_add(1, Ordering::SeqCst);
+        },
+        unmask: |_| {
+            MASKED.fetch_sub(1, Ordering::SeqCst);
+        },
+        eoi: |_| {
+            EOIS.fetch_add(1, Ordering::SeqCst);
+        },
+    };
+    const TEST_VECTOR: u8 = 0xFE;
+
+    assert!(vectors::reserve(TEST_VECTOR));
+    assert_eq!(VectorClass::of(TEST_VECTOR), VectorClass::Ipi);
+    set_handler(TEST_VECTOR, |_| {
+        HANDLED.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::Handled
+    })
+    .unwrap();
+    assert_eq!(set_handler(TEST_VECTOR, |_| IrqReturn::Handled), Err(IrqError::InUse));
+    let before = cpu_count(current_cpu(), TEST_VECTOR);
+    // This is safe because the vector's handler only counts
+    unsafe { asm!("int 0xFE") };
+    assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+    assert_eq!(cpu_count(current_cpu(), TEST_VECTOR), before.map(|count| count + 1));
+
+    remove_handler(TEST_VECTOR);
+    set_line_control(TEST_VECTOR, &CONTROL);
+    let limit = UNHANDLED_LIMIT.get();
+    for _ in 0..limit {
+        dispatch(TEST_VECTOR as u64);
+    }
+    let stats = stats(TEST_VECTOR);
+    assert_eq!((stats.count, stats.unhandled, stats.masked), (limit + 1, limit, true));
+    assert_eq!(MASKED.load(Ordering::SeqCst), 1);
+    assert_eq!(EOIS.load(Ordering::SeqCst), limit as u32);
+    let mut dump = alloc::string::String::new();
+    dump_irq_stats(&mut dump).unwrap();
+    assert!(dump.lines().any(|line| line.starts_with("  0xfe") && line.ends_with("(masked)")), "{}", dump);
+    unmask(TEST_VECTOR);
+    assert_eq!(MASKED.load(Ordering::SeqCst), 0);
+    LINES[TEST_VECTOR as usize].control.store(null_mut(), Ordering::SeqCst);
+    vectors::free(TEST_VECTOR);
+
+    // a GSI nothing handles is refused, and leaves nothing behind
+    let free_vector = vectors::allocate(VectorClass::Normal).unwrap();
+    vectors::free(free_vector);
+    assert_eq!(register_irq(u32::MAX - 1, |_| IrqReturn::Handled, "test"), Err(IrqError::NoRoute));
+    assert!(!vectors::is_used(free_vector));
+    assert_eq!(name(free_vector), None);
+}
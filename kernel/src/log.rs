@@ -0,0 +1,204 @@
+//! Kernel logging to the debug serial port.
+//! Each message is prefixed with a timestamp and the id of the CPU that logged it, so interleaved logs from several CPUs can be pulled apart.
+//! The prefixes can be turned off with the `log.timestamps=off` and `log.cpu=off` command line options, or with the tunables
+//! of the same names at runtime.
+//! The last few KiB of the log are also kept in memory, so crash reports can include what happened just before.
+//! `emergency_log!` is for code that can't trust the rest of the kernel, like a CPU that panicked while another one is
+//! reporting a panic: it takes no locks and doesn't allocate, at the cost of maybe interleaving with other output.
+//! `log!` holds the debug serial port's lock with interrupts enabled, so interrupt and exception handlers must use
+//! `emergency_log!` instead: the code they interrupted may be holding the lock.
+
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+use core::fmt::{self, Write};
+
+use spin::Mutex;
+use uart_16550::SerialPort;
+
+use crate::pstore;
+use crate::sync::seqlock::SeqLock;
+use crate::sysctl::{self, Tunable};
+use crate::trace;
+use crate::x64::cpuid::get_initial_apic_id;
+use crate::{DEBUG_SERIAL_PORT, DEBUG_SERIAL_PORT_BASE};
+
+static TIMESTAMPS: Tunable = Tunable::bool("log.timestamps", "prefix each message with the time since boot", true);
+static CPU_PREFIX: Tunable = Tunable::bool("log.cpu", "prefix each message with the CPU that logged it", true);
+
+/// What's needed to turn a TSC value into a timestamp.
+#[derive(Clone, Copy)]
+struct LogClock {
+    /// The TSC value when logging was initialized, timestamps are relative to this.
+    boot_tsc: u64,
+    /// The TSC frequency in kHz, or 0 if it hasn't been calibrated yet in which case timestamps are printed in cycles.
+    tsc_khz: u64,
+}
+
+/// Read for every message, so it is published with a seqlock rather than locked.
+static CLOCK: SeqLock<LogClock> = SeqLock::new(LogClock {
+    boot_tsc: 0,
+    tsc_khz: 0,
+});
+
+/// The size of the in-memory copy of the end of the log.
+const RECENT_LOG_SIZE: usize = 4096;
+
+/// A ring of the most recent log output.
+struct RecentLog {
+    bytes: [u8; RECENT_LOG_SIZE],
+    /// The number of bytes ever written, the ring position is this modulo the size.
+    written: usize,
+}
+
+static RECENT_LOG: Mutex<RecentLog> = Mutex::new(RecentLog {
+    bytes: [0; RECENT_LOG_SIZE],
+    written: 0,
+});
+
+impl RecentLog {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.bytes[self.written % RECENT_LOG_SIZE] = byte;
+            self.written += 1;
+        }
+    }
+
+    /// Writes the last `lines` complete lines to `writer`.
+    fn write_lines(&self, writer: &mut dyn Write, lines: usize) -> fmt::Result {
+        let oldest = self.written.saturating_sub(RECENT_LOG_SIZE);
+        // walk back over `lines` newlines, plus the one ending the last line
+        let mut start = self.written;
+        let mut newlines = 0;
+        while start > oldest {
+            if self.bytes[(start - 1) % RECENT_LOG_SIZE] == b'\n' {
+                newlines += 1;
+                if newlines > lines {
+                    break;
+                }
+            }
+            start -= 1;
+        }
+        for position in start..self.written {
+            writer.write_char(self.bytes[position % RECENT_LOG_SIZE] as char)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the last `lines` lines of the log to `writer`, without waiting for the lock.
+/// Safety: nothing else may be logging, which is the case once a panicking CPU has stopped the others (a message this CPU was
+/// interrupted in the middle of is abandoned).
+pub unsafe fn write_recent_lines(writer: &mut dyn Write, lines: usize) -> fmt::Result {
+    if RECENT_LOG.is_locked() {
+        RECENT_LOG.force_unlock();
+    }
+    RECENT_LOG.lock().write_lines(writer, lines)
+}
+
+/// Copies the recent log output, oldest first.
+pub fn recent() -> Vec<u8> {
+    let recent_log = RECENT_LOG.lock();
+    let oldest = recent_log.written.saturating_sub(RECENT_LOG_SIZE);
+    (oldest..recent_log.written)
+        .map(|position| recent_log.bytes[position % RECENT_LOG_SIZE])
+        .collect()
+}
+
+/// Logs a line to the debug serial port.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::_log(format_args!($($arg)*))
+    };
+}
+
+/// Logs a line without taking any lock or allocating.
+#[macro_export]
+macro_rules! emergency_log {
+    ($($arg:tt)*) => {
+        $crate::log::_emergency_log(format_args!($($arg)*))
+    };
+}
+
+/// Starts the log clock and reads the logging options from the command line.
+pub fn init() {
+    CLOCK.update(|clock| clock.boot_tsc = unsafe { _rdtsc() });
+    let _ = sysctl::register(&TIMESTAMPS);
+    let _ = sysctl::register(&CPU_PREFIX);
+}
+
+/// Sets the TSC frequency so timestamps can be printed in seconds.
+pub fn set_tsc_frequency(khz: u64) {
+    CLOCK.update(|clock| clock.tsc_khz = khz);
+}
+
+/// Writes to the serial port, the persistent copy of the log and the recent log.
+struct LogWriter<'a> {
+    serial_port: &'a mut SerialPort,
+}
+
+impl Write for LogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.serial_port.write_str(s)?;
+        pstore::write(s.as_bytes());
+        RECENT_LOG.lock().write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Writes straight to the serial port's registers, and to the recent and persistent logs only if their locks are free.
+struct EmergencyWriter {
+    serial_port: SerialPort,
+}
+
+impl Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.serial_port.write_str(s)?;
+        pstore::try_write(s.as_bytes());
+        if let Some(mut recent_log) = RECENT_LOG.try_lock() {
+            recent_log.write(s.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _emergency_log(args: fmt::Arguments) {
+    let mut writer = EmergencyWriter {
+        // This is safe because the port was initialized at boot, and a line that interleaves with another one is acceptable here
+        serial_port: unsafe { SerialPort::new(DEBUG_SERIAL_PORT_BASE) },
+    };
+    let _ = write_prefix(&mut writer);
+    let _ = writer.write_fmt(args);
+    let _ = writer.write_str("\n");
+}
+
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    // The lock is held for the whole message so lines from different CPUs don't interleave
+    let mut serial_port = trace::lock(&DEBUG_SERIAL_PORT);
+    let mut writer = LogWriter {
+        serial_port: &mut serial_port,
+    };
+    let _ = write_prefix(&mut writer);
+    let _ = writer.write_fmt(args);
+    let _ = writer.write_str("\n");
+}
+
+fn write_prefix(writer: &mut impl Write) -> fmt::Result {
+    if TIMESTAMPS.get_bool() {
+        let clock = CLOCK.read();
+        let cycles = unsafe { _rdtsc() }.wrapping_sub(clock.boot_tsc);
+        match clock.tsc_khz {
+            0 => write!(writer, "[{:>14}c] ", cycles)?,
+            khz => {
+                let micros = cycles / (khz / 1000).max(1);
+                write!(writer, "[{:>6}.{:06}] ", micros / 1_000_000, micros % 1_000_000)?
+            }
+        }
+    }
+    if CPU_PREFIX.get_bool() {
+        write!(writer, "cpu{}: ", get_initial_apic_id())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! The memory below 1MiB, which is kept out of the frame allocator and handed out here instead.
+//! Real-mode code like the AP trampoline has to run from a 4KiB-aligned page below 1MiB, and legacy ISA DMA buffers have to be
+//! low too, so this memory is too valuable to give to ordinary allocations. Only frames the memory map says are usable are
+//! handed out, except page 0 (the real-mode IVT), the EBDA and the VGA/BIOS area from 640KiB up, which are never usable.
+
+use core::ops::Range;
+
+use limine::{MemmapEntry, MemoryMapEntryType, NonNullPtr};
+use spin::Mutex;
+
+use crate::log;
+use crate::memory::PhysicalAddress;
+use crate::page_info::{self, PageInfoFlags};
+
+/// The end of the low memory region.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+/// The start of the VGA memory and BIOS ROMs.
+const BIOS_AREA_START: u64 = 0xA_0000;
+/// Where the BIOS data area stores the EBDA's segment.
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+/// ISA DMA transfers can't cross a 64KiB boundary.
+const ISA_DMA_BOUNDARY: u64 = 0x1_0000;
+
+const FRAME_SIZE: u64 = 0x1000;
+const FRAME_COUNT: usize = (LOW_MEMORY_END / FRAME_SIZE) as usize;
+
+struct LowMemory {
+    /// A set bit means the frame is free.
+    free: [u64; FRAME_COUNT / 64],
+    ebda: Option<Range<u64>>,
+}
+
+static LOW_MEMORY: Mutex<LowMemory> = Mutex::new(LowMemory {
+    free: [0; FRAME_COUNT / 64],
+    ebda: None,
+});
+
+impl LowMemory {
+    fn is_free(&self, frame: usize) -> bool {
+        self.free[frame / 64] & 1 << (frame % 64) != 0
+    }
+
+    fn set_free(&mut self, frame: usize, free: bool) {
+        if free {
+            self.free[frame / 64] |= 1 << (frame % 64);
+        } else {
+            self.free[frame / 64] &= !(1 << (frame % 64));
+        }
+    }
+
+    /// Finds `count` free frames in a row that don't cross a multiple of `boundary`, and marks them used.
+    fn allocate(&mut self, count: usize, boundary: u64) -> Option<u64> {
+        let frames_per_boundary = (boundary / FRAME_SIZE) as usize;
+        if count == 0 || count > frames_per_boundary {
+            return None;
+        }
+        let start = (0..=FRAME_COUNT - count).find(|&start| {
+            start / frames_per_boundary == (start + count - 1) / frames_per_boundary
+                && (start..start + count).all(|frame| self.is_free(frame))
+        })?;
+        for frame in start..start + count {
+            self.set_free(frame, false);
+        }
+        page_info::allocated(start as u64 * FRAME_SIZE, count as u64, PageInfoFlags::LOW_MEMORY);
+        Some(start as u64 * FRAME_SIZE)
+    }
+}
+
+/// Takes over the usable memory below 1MiB. The frame allocator must leave `0..LOW_MEMORY_END` out.
+pub fn init(memory_map: &[NonNullPtr<MemmapEntry>], physical_memory_offset: u64) {
+    let mut low_memory = LOW_MEMORY.lock();
+    for entry in memory_map.iter().filter(|entry| entry.typ == MemoryMapEntryType::Usable) {
+        // only whole frames are usable
+        let start = (entry.base + FRAME_SIZE - 1) / FRAME_SIZE;
+        let end = (entry.base + entry.len).min(LOW_MEMORY_END) / FRAME_SIZE;
+        for frame in start.max(1)..end {
+            low_memory.set_free(frame as usize, true);
+        }
+    }
+
+    // This is safe because the BIOS data area is below 1MiB, which is in the direct map
+    let ebda_segment = unsafe { ((EBDA_SEGMENT_POINTER + physical_memory_offset) as *const u16).read_unaligned() };
+    let ebda_start = (ebda_segment as u64) << 4;
+    // firmware without an EBDA leaves the pointer 0, anything outside the usual range can't be trusted
+    if (0x8_0000..BIOS_AREA_START).contains(&ebda_start) {
+        low_memory.ebda = Some(ebda_start..BIOS_AREA_START);
+    }
+    let reserved_start = low_memory.ebda.as_ref().map_or(BIOS_AREA_START, |ebda| ebda.start) / FRAME_SIZE;
+    for frame in reserved_start..FRAME_COUNT as u64 {
+        low_memory.set_free(frame as usize, false);
+    }
+
+    let free = low_memory.free.iter().map(|bits| bits.count_ones() as u64).sum::<u64>();
+    log!("lowmem: {} KiB free below 1MiB, EBDA at {:x?}", free * FRAME_SIZE / 1024, low_memory.ebda);
+}
+
+/// Allocates `count` contiguous frames below 1MiB, returns the address of the first one.
+pub fn allocate(count: usize) -> Option<PhysicalAddress> {
+    LOW_MEMORY
+        .lock()
+        .allocate(count, LOW_MEMORY_END)
+        .map(PhysicalAddress::new)
+}
+
+/// Allocates `count` contiguous frames below 1MiB that a legacy ISA DMA channel can use in one transfer.
+pub fn allocate_isa_dma(count: usize) -> Option<PhysicalAddress> {
+    LOW_MEMORY
+        .lock()
+        .allocate(count, ISA_DMA_BOUNDARY)
+        .map(PhysicalAddress::new)
+}
+
+/// Frees `count` frames starting at `address`, which must have come from this allocator.
+pub fn free(address: PhysicalAddress, count: usize) {
+    let start = (address.get_address() / FRAME_SIZE) as usize;
+    assert!(
+        address.is_frame_aligned() && start + count <= FRAME_COUNT,
+        "Attempted to free memory that isn't low memory"
+    );
+    let mut low_memory = LOW_MEMORY.lock();
+    for frame in start..start + count {
+        assert!(!low_memory.is_free(frame), "Attempted to free low memory that is already free");
+        low_memory.set_free(frame, true);
+    }
+    page_info::freed(address.get_address(), count as u64);
+}
+
+/// Gets the range the EBDA was found at, if there is one.
+pub fn ebda() -> Option<Range<u64>> {
+    LOW_MEMORY.lock().ebda.clone()
+}
+
+/// Returns the number of free frames below 1MiB.
+pub fn free_frames() -> usize {
+    LOW_MEMORY.lock().free.iter().map(|bits| bits.count_ones() as usize).sum()
+}
+
+/// Checks that allocations are aligned, low, don't cross ISA DMA boundaries and are given back.
+pub fn self_check() {
+    let free_before = free_frames();
+    if free_before < 2 {
+        log!("lowmem: not enough low memory to self-test");
+        return;
+    }
+    let trampoline = allocate(1).unwrap();
+    assert!(trampoline.is_frame_aligned() && trampoline.get_address() < LOW_MEMORY_END);
+    let buffer = allocate_isa_dma(1).unwrap();
+    assert_ne!(buffer.get_address(), trampoline.get_address());
+    assert_eq!(free_frames(), free_before - 2);
+    free(trampoline, 1);
+    free(buffer, 1);
+    assert_eq!(free_frames(), free_before);
+}
@@ -6,6 +6,8 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
+extern crate alloc;
+
 use core::arch::asm;
 
 use core::fmt::Write;
@@ -13,28 +15,50 @@ use core::mem::{offset_of, size_of};
 
 use acpi::root::RSDP32Bit;
 use generic_once_cell::OnceCell;
-use memory::DirectMappedAddress;
 use spin::Mutex;
 use uart_16550::SerialPort;
-use x64::idt::PageFaultErrorCode;
 
 static FRAMEBUFFER_REQUEST: limine::FramebufferRequest = limine::FramebufferRequest::new(0);
 static MEMORY_MAP_REQUEST: limine::MemmapRequest = limine::MemmapRequest::new(0);
 static HHDM_REQUEST: limine::HhdmRequest = limine::HhdmRequest::new(0);
 static RSDP_REQUEST: limine::RsdpRequest = limine::RsdpRequest::new(0);
+static KERNEL_FILE_REQUEST: limine::KernelFileRequest = limine::KernelFileRequest::new(0);
+static SMP_REQUEST: limine::SmpRequest = limine::SmpRequest::new(0);
+static MODULE_REQUEST: limine::ModuleRequest = limine::ModuleRequest::new(0);
+
+/// Read on every conversion between physical and virtual addresses, so these are `BootOnce` rather than `OnceCell`.
+static DIRECT_MAP_START: BootOnce<u64> = BootOnce::new();
+static PHYSICAL_MEMORY_SIZE: BootOnce<u64> = BootOnce::new();
+
+/// Also taken by the page fault handler to back lazy regions, so it tracks its owner.
+static FRAME_ALLOCATOR: OnceCell<Mutex<()>, OwnedMutex<MemoryMapAllocator>> = OnceCell::new();
+/// Allocates physically contiguous runs of frames, from a zone of memory set aside at boot.
+static BUDDY_ALLOCATOR: OnceCell<Mutex<()>, Mutex<BuddyAllocator>> = OnceCell::new();
+/// Memory below 4GB for devices with 32 bit DMA addresses, only set up if the buddy zone is above 4GB.
+static DMA32_ALLOCATOR: OnceCell<Mutex<()>, Mutex<DmaAllocator>> = OnceCell::new();
+
+static ACPI_TABLES: OnceCell<Mutex<()>, AcpiTables> = OnceCell::new();
 
-static DIRECT_MAP_START: OnceCell<Mutex<()>, u64> = OnceCell::new();
-static PHYSICAL_MEMORY_SIZE: OnceCell<Mutex<()>, u64> = OnceCell::new();
+/// The IDT every CPU loads, gates can be changed after boot with `Idt::register`.
+static IDT: Mutex<Idt> = Mutex::new(Idt::new());
 
-static FRAME_ALLOCATOR: OnceCell<Mutex<()>, Mutex<MemoryMapAllocator>> = OnceCell::new();
+#[cfg(feature = "framebuffer")]
+static DISPLAYS: OnceCell<Mutex<()>, graphics::display::Displays> = OnceCell::new();
 
 mod x64;
+use crate::acpi::facs::FACS;
 use crate::acpi::fadt::{GenericAddressStructure, FADT};
 use crate::acpi::root::RSDP64Bit;
+use crate::acpi::AcpiTables;
+use crate::init::{InitError, InitStage};
 use crate::memory::VirtualAddress;
-use crate::pmm::{FrameAllocator, MemoryMapAllocator};
+use crate::buddy::BuddyAllocator;
+use crate::dma::DmaAllocator;
+use crate::pmm::{FrameAllocator, MemoryMapAllocator, MemoryStats, MEMORY_TYPES};
+use crate::sync::boot_once::BootOnce;
+use crate::sync::owned_mutex::OwnedMutex;
 use crate::x64::idt::Idt;
-use crate::x64::page_table::PML4;
+use crate::x64::page_table::{PageFlags, PML4};
 use crate::x64::registers::{get_cr3, get_cs};
 
 mod pmm;
@@ -43,87 +67,367 @@ mod memory;
 
 mod acpi;
 
-static DEBUG_SERIAL_PORT: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(0x3F8) });
+mod init;
+
+mod reboot;
+
+mod cmdline;
+
+mod log;
+
+mod delay;
+
+mod sync;
+
+mod smp;
+
+mod bootmem;
+
+mod lowmem;
+
+mod pstore;
+
+mod panicking;
+
+mod page_info;
+
+mod buddy;
+
+mod address_space;
+
+mod stack;
+
+mod demand_paging;
+
+mod dma;
+
+mod event;
+
+mod heap;
+
+mod syscall;
+
+mod irq;
+
+mod work;
+
+mod exceptions;
+
+mod watch;
+
+mod trace;
+
+mod version;
+
+mod time;
+
+mod timer;
+
+mod sysctl;
+
+mod resource;
+
+mod bug;
+
+mod pci;
+
+mod block;
+
+mod device;
+
+mod drivers;
+
+mod xmodem;
+
+#[cfg(feature = "debug-shell")]
+mod shell;
+
+#[cfg(feature = "framebuffer")]
+mod graphics;
+
+#[cfg(feature = "framebuffer")]
+mod tty;
+
+/// The I/O port of COM1, which the kernel logs to.
+const DEBUG_SERIAL_PORT_BASE: u16 = 0x3F8;
+static DEBUG_SERIAL_PORT: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(DEBUG_SERIAL_PORT_BASE) });
+
+/// The stages of kernel initialization, in the order they run.
+static INIT_STAGES: &[InitStage] = &[
+    #[cfg(feature = "framebuffer")]
+    InitStage {
+        name: "framebuffer",
+        dependencies: &[],
+        critical: false,
+        run: init_framebuffer,
+    },
+    InitStage {
+        name: "memory",
+        dependencies: &[],
+        critical: true,
+        run: init_memory,
+    },
+    InitStage {
+        name: "heap",
+        dependencies: &["memory"],
+        critical: true,
+        run: heap::init_heap,
+    },
+    InitStage {
+        name: "interrupts",
+        dependencies: &["memory"],
+        critical: true,
+        run: init_interrupts,
+    },
+    InitStage {
+        name: "devices",
+        dependencies: &[],
+        critical: false,
+        run: device::init_devices,
+    },
+    InitStage {
+        name: "acpi",
+        dependencies: &["memory"],
+        critical: false,
+        run: init_acpi,
+    },
+    InitStage {
+        name: "apic",
+        dependencies: &["acpi", "interrupts"],
+        critical: false,
+        run: x64::apic::init_local_apic,
+    },
+    InitStage {
+        name: "ioapic",
+        dependencies: &["apic"],
+        critical: false,
+        run: x64::ioapic::init_io_apics,
+    },
+    InitStage {
+        name: "serial",
+        dependencies: &["ioapic"],
+        critical: false,
+        run: drivers::serial::init_serial_input,
+    },
+    // Runs after ACPI so the PM timer can be used, but can fall back to CPUID without it
+    InitStage {
+        name: "delay",
+        dependencies: &[],
+        critical: false,
+        run: delay::init_delay,
+    },
+    InitStage {
+        name: "clock",
+        dependencies: &["delay"],
+        critical: false,
+        run: time::init_clock,
+    },
+    InitStage {
+        name: "rtc",
+        dependencies: &["acpi", "clock"],
+        critical: false,
+        run: drivers::rtc::init_rtc,
+    },
+    // Runs after the delay stage so the PM timer is known to work, but calibrates against the PIT without it
+    InitStage {
+        name: "timer",
+        dependencies: &["apic"],
+        critical: false,
+        run: x64::apic_timer::init_apic_timer,
+    },
+    InitStage {
+        name: "iommu",
+        dependencies: &["acpi"],
+        critical: false,
+        run: dma::iommu::init_iommu,
+    },
+    InitStage {
+        name: "pci",
+        dependencies: &["acpi"],
+        critical: false,
+        run: pci::init_pci,
+    },
+    InitStage {
+        name: "sdhci",
+        dependencies: &["pci", "memory", "delay"],
+        critical: false,
+        run: drivers::sdhci::init_sdhci,
+    },
+    #[cfg(feature = "framebuffer")]
+    InitStage {
+        name: "font",
+        dependencies: &["framebuffer", "heap"],
+        critical: false,
+        run: graphics::font::init_fonts,
+    },
+    #[cfg(feature = "framebuffer")]
+    InitStage {
+        name: "tty",
+        dependencies: &["font", "devices"],
+        critical: false,
+        run: tty::init_tty,
+    },
+    InitStage {
+        name: "loop",
+        dependencies: &["memory"],
+        critical: false,
+        run: block::loopback::init_loop_devices,
+    },
+    InitStage {
+        name: "ramdisk",
+        dependencies: &["memory"],
+        critical: false,
+        run: block::ramdisk::init_ramdisk,
+    },
+    #[cfg(feature = "smp")]
+    InitStage {
+        name: "smp",
+        dependencies: &["interrupts"],
+        critical: false,
+        run: smp::init_smp,
+    },
+    #[cfg(feature = "tests")]
+    InitStage {
+        name: "self-tests",
+        dependencies: &[],
+        critical: true,
+        run: run_self_tests,
+    },
+];
 
 #[no_mangle]
 unsafe extern "C" fn _start() -> ! {
+    time::tsc::mark_boot();
     DEBUG_SERIAL_PORT.lock().init();
+    let _ = resource::claim_io(DEBUG_SERIAL_PORT_BASE, 8, "debug serial");
+    log::init();
+    bootmem::init();
+    // Replace the bootloader's GDT first, the IDTs refer to the kernel code selector
+    x64::gdt::load();
+    // Install minimal exception handlers before doing anything that could fault, so early faults are reported instead of triple faulting.
+    x64::early_idt::install();
+    sync::rcu::register_cpu();
+    log!("{}", version::BUILD_INFO);
+
+    init::run_stages(INIT_STAGES);
+    // Interrupts stay disabled during initialization, the tick and device interrupts are only delivered from here on
+    // This is safe because every external vector has a dispatch stub and every exception has a handler
+    asm!("sti");
+    #[cfg(feature = "tests")]
+    timer::check_after_init();
+
+    #[cfg(feature = "debug-shell")]
+    {
+        log!("finished, starting debug shell");
+        shell::run();
+    }
 
-    // Ensure we got a framebuffer.
-    let framebuffer = if let Some(framebuffer_response) = FRAMEBUFFER_REQUEST.get_response().get() {
-        if framebuffer_response.framebuffer_count < 1 {
-            panic!("No framebuffers found!");
-        }
-        // Get the first framebuffer's information.
-        &framebuffer_response.framebuffers()[0]
-    } else {
-        panic!("Framebuffer response not received!");
-    };
+    #[cfg(not(feature = "debug-shell"))]
+    {
+        log!("finished, idling");
+        idle_loop();
+    }
+}
 
-    let memory_map = if let Some(memory_map_response) = MEMORY_MAP_REQUEST.get_response().get() {
-        let mut highest_address: u64 = 0;
-        for entry in memory_map_response.memmap() {
-            highest_address = u64::max(highest_address, entry.base + entry.len);
-        }
-        if highest_address == 0 {
-            panic!("Error in memory map!");
-        } else {
-            PHYSICAL_MEMORY_SIZE.set(highest_address).unwrap();
-        }
-        memory_map_response
-    } else {
-        panic!("Memory map not received!");
-    };
+/// Sets up a display for each framebuffer provided by the bootloader.
+#[cfg(feature = "framebuffer")]
+fn init_framebuffer() -> Result<(), InitError> {
+    let framebuffer_response = FRAMEBUFFER_REQUEST
+        .get_response()
+        .get()
+        .ok_or(InitError::new("Framebuffer response not received!"))?;
+    if framebuffer_response.framebuffer_count < 1 {
+        return Err(InitError::new("No framebuffers found!"));
+    }
+    // This is safe because nothing else draws on the framebuffers
+    let displays = unsafe { graphics::display::Displays::from_limine(framebuffer_response) };
+    if displays.is_empty() {
+        return Err(InitError::new("No framebuffers with a supported format!"));
+    }
+    for (i, display) in displays.iter().enumerate() {
+        let mut surface = display.surface().lock();
+        surface.clear(graphics::Color::BLACK);
+        log!(
+            "display {}: framebuffer {}, {}x{}, {} bytes per pixel",
+            i,
+            display.framebuffer_index(),
+            surface.width(),
+            surface.height(),
+            surface.format().bytes_per_pixel()
+        );
+    }
+    DISPLAYS.set(displays).map_err(|_| InitError::new("displays already initialized"))?;
+    Ok(())
+}
 
+/// Reads the memory map and direct map offset from the bootloader and sets up the frame allocator.
+fn init_memory() -> Result<(), InitError> {
+    let memory_map = MEMORY_MAP_REQUEST
+        .get_response()
+        .get()
+        .ok_or(InitError::new("Memory map not received!"))?;
+    let mut highest_address: u64 = 0;
     for entry in memory_map.memmap() {
-        writeln!(
-            DEBUG_SERIAL_PORT.lock(),
-            "memory map entry: {:x?}, last_frame: {:x}",
-            entry,
-            entry.base + entry.len - 0x1000
-        );
+        highest_address = u64::max(highest_address, entry.base + entry.len);
     }
+    if highest_address == 0 {
+        return Err(InitError::new("Error in memory map!"));
+    }
+    PHYSICAL_MEMORY_SIZE.set(highest_address).unwrap();
 
-    let physical_memory_offset = if let Some(hhdm_response) = HHDM_REQUEST.get_response().get() {
-        DIRECT_MAP_START.set(hhdm_response.offset).unwrap();
-        hhdm_response.offset
-    } else {
-        panic!("HHDM response not received!");
-    };
+    for entry in memory_map.memmap() {
+        log!("memory map entry: {:x?}, last_frame: {:x}", entry, entry.base + entry.len - 0x1000);
+    }
 
-    let rsdp_ptr = if let Some(rsdp_response) = RSDP_REQUEST.get_response().get() {
-        rsdp_response.address.as_ptr().unwrap() as *mut RSDP32Bit
+    let physical_memory_offset = HHDM_REQUEST
+        .get_response()
+        .get()
+        .ok_or(InitError::new("HHDM response not received!"))?
+        .offset;
+    DIRECT_MAP_START.set(physical_memory_offset).unwrap();
+
+    lowmem::init(memory_map.memmap(), physical_memory_offset);
+    let pstore_range = pstore::reserve(memory_map.memmap(), physical_memory_offset).unwrap_or(0..0);
+
+    // The frame allocator takes over from the boot allocator here
+    // The buddy zone's metadata comes from the boot allocator, so it is set aside first
+    let buddy_zone = buddy::reserve_zone(memory_map.memmap(), pstore_range.end - pstore_range.start);
+    let buddy_range = buddy_zone.as_ref().map_or(0..0, |(zone, _)| zone.clone());
+    let dma32_zone = if buddy_range.end > 1 << 32 {
+        dma::reserve_dma32_zone(memory_map.memmap(), &[pstore_range.clone(), buddy_range.clone()])
     } else {
-        panic!("RSDP response not received or invalid!");
+        None
     };
-
-    let cs = get_cs();
-
-    // TODO: make idt a mut static
-    let mut idt = Idt::new();
-    idt.set_page_fault_handler(page_fault, cs);
-    idt.set_general_protection_fault_handler(general_protection_fault, cs);
-    idt.set_double_fault_handler(double_fault, cs);
-
-    let idtr = idt.get_idtr();
-    idtr.load();
-
+    let dma32_range = dma32_zone.as_ref().map_or(0..0, |(zone, _)| zone.clone());
+    let bootmem_used = bootmem::finish();
+    log!("bootmem used {:#x}-{:#x}", bootmem_used.start, bootmem_used.end);
     FRAME_ALLOCATOR
-        .set(Mutex::new(MemoryMapAllocator::new(
+        .set(OwnedMutex::new(MemoryMapAllocator::new(
             memory_map.memmap(),
             physical_memory_offset,
+            // the pstore region is at the very end of its usable region, so it has to come before the buddy zone below it
+            &[bootmem_used.clone(), pstore_range, buddy_range, dma32_range, 0..lowmem::LOW_MEMORY_END],
         )))
         .unwrap();
+    page_info::init(memory_map.memmap(), bootmem_used);
+    log_memory_stats(&FRAME_ALLOCATOR.get().unwrap().lock().stats());
+    if let Some((zone, metadata)) = buddy_zone {
+        log!("buddy zone {:#x}-{:#x}", zone.start, zone.end);
+        // This is safe because the zone was left out of the frame allocator
+        let buddy_allocator = unsafe { BuddyAllocator::new(zone, metadata, physical_memory_offset) };
+        BUDDY_ALLOCATOR.set(Mutex::new(buddy_allocator)).unwrap();
+    }
+    if let Some((zone, metadata)) = dma32_zone {
+        log!("dma32 zone {:#x}-{:#x}", zone.start, zone.end);
+        // This is safe because the zone was left out of the frame allocator
+        let dma32_allocator = unsafe { DmaAllocator::new(zone, metadata, physical_memory_offset) };
+        DMA32_ALLOCATOR.set(Mutex::new(dma32_allocator)).unwrap();
+    }
 
     let cr3 = get_cr3();
-    writeln!(DEBUG_SERIAL_PORT.lock(), "cr3: {:x}", cr3.address()).unwrap();
+    log!("cr3: {:x}", cr3.address());
 
-    writeln!(
-        DEBUG_SERIAL_PORT.lock(),
-        "physical memory offset: {:x}",
-        physical_memory_offset
-    )
-    .unwrap();
+    log!("physical memory offset: {:x}", physical_memory_offset);
 
     let current_pml4 = cr3.pml4();
 
@@ -131,48 +435,166 @@ unsafe extern "C" fn _start() -> ! {
     new_pml4.map(
         FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap(),
         VirtualAddress::create(0xFFFFCC << 40),
-        true,
-        true,
+        PageFlags::KERNEL_DATA,
+    )
+    .unwrap();
+    Ok(())
+}
+
+/// Prints how much memory the frame allocator has and how the memory map divides up the rest.
+fn log_memory_stats(stats: &MemoryStats) {
+    log!(
+        "memory: {} MiB managed, {} MiB free, {} MiB used",
+        (stats.total_frames * 4096) >> 20,
+        (stats.free_frames * 4096) >> 20,
+        (stats.used_frames * 4096) >> 20
     );
+    for (typ, bytes) in MEMORY_TYPES.iter().zip(stats.bytes_by_type) {
+        if bytes != 0 {
+            log!("memory: {:?} {} KiB", typ, bytes >> 10);
+        }
+    }
+}
+
+/// Fills in the kernel's exception handlers and loads the IDT, replacing the early IDT.
+fn init_interrupts() -> Result<(), InitError> {
+    let cs = get_cs();
+
+    // the IDT's gates for double faults, NMIs and machine checks use the IST stacks in the TSS
+    x64::tss::load().map_err(|_| InitError::new("failed to allocate the IST stacks"))?;
+    irq::register_cpu();
+    let mut idt = IDT.lock();
+    idt.install_exception_handlers(cs);
+    // the syscall gate replaces the interrupt stub of its vector, so it goes after them
+    irq::install(&mut idt, cs);
+    syscall::install(&mut idt, cs);
+
+    // This is safe because the IDT is in a static and will never be moved
+    unsafe { idt.get_idtr().load() };
+    // the firmware may leave the PICs on the exception vectors, they have to move before anything enables interrupts
+    x64::pic::remap_and_mask();
+    Ok(())
+}
+
+/// Finds and validates the ACPI tables.
+fn init_acpi() -> Result<(), InitError> {
+    let rsdp_ptr = RSDP_REQUEST
+        .get_response()
+        .get()
+        .and_then(|rsdp_response| rsdp_response.address.as_ptr())
+        .ok_or(InitError::new("RSDP response not received or invalid!"))?
+        as *mut RSDP32Bit;
 
     let rsdp = unsafe { &mut *rsdp_ptr };
-    assert!(rsdp.checksum());
+    if !rsdp.checksum() {
+        return Err(InitError::new("RSDP checksum is invalid"));
+    }
     let rsdp = if rsdp.revision() == 2 {
         unsafe { &mut *(rsdp_ptr as *mut RSDP64Bit) }
     } else {
-        panic!("expected ACPI revision 2");
+        return Err(InitError::new("expected ACPI revision 2"));
     };
-    assert!(rsdp.checksum());
+    if !rsdp.checksum() {
+        return Err(InitError::new("extended RSDP checksum is invalid"));
+    }
     let xsdt = rsdp.get_xsdt();
     let xsdt = unsafe { &mut *xsdt };
-    assert!(xsdt.checksum());
-
-    let madt = xsdt.get_madt().unwrap();
-
-    writeln!(DEBUG_SERIAL_PORT.lock(), "finished, halting").unwrap();
-    halt_loop();
+    if !xsdt.checksum() {
+        return Err(InitError::new("XSDT checksum is invalid"));
+    }
+    let tables = ACPI_TABLES.get_or_init(|| AcpiTables::parse(xsdt));
+    device::find_or_register("acpi", device::Bus::Acpi, None);
+
+    let madt = tables.madt().ok_or(InitError::new("MADT not found"))?;
+    log!("local APIC at {:#x}", madt.local_apic_address());
+    for io_apic in madt.io_apics() {
+        log!(
+            "I/O APIC {} at {:#x}, GSI base {}",
+            io_apic.apic_id(),
+            io_apic.address(),
+            io_apic.global_system_interrupt_base()
+        );
+    }
+    for nmi in madt.nmi_sources() {
+        log!("NMI source: {:?}", nmi);
+    }
+    if let Some(bgrt) = tables.bgrt() {
+        let (x, y) = bgrt.image_offset();
+        log!("boot logo at {:#x}, drawn at ({}, {})", bgrt.image_address(), x, y);
+    }
+    Ok(())
 }
 
-/// Pauses execution (counts really high)
-fn pause() {
-    writeln!(DEBUG_SERIAL_PORT.lock(), "pausing").unwrap();
-    let mut x: i32 = 0;
-    while x < 500000000 {
-        x += 1;
+/// Runs the boot-time self checks of structure layouts.
+#[cfg(feature = "tests")]
+fn run_self_tests() -> Result<(), InitError> {
+    FADT::check_offsets();
+    GenericAddressStructure::check_offsets();
+    GenericAddressStructure::self_check();
+    FACS::check_offsets();
+    acpi::madt::self_check();
+    x64::gdt::self_check();
+    x64::idt::self_check();
+    x64::tss::self_check();
+    x64::apic::self_check();
+    x64::apic_timer::self_check();
+    timer::self_check();
+    sysctl::self_check();
+    resource::self_check();
+    drivers::rtc::self_check();
+    delay::self_check();
+    time::self_check();
+    x64::vectors::self_check();
+    irq::self_check();
+    work::self_check();
+    exceptions::self_check();
+    watch::self_check();
+    trace::self_check();
+    pmm::self_check();
+    page_info::self_check();
+    lowmem::self_check();
+    if let Some(buddy_allocator) = BUDDY_ALLOCATOR.get() {
+        buddy::self_check(&mut buddy_allocator.lock());
+        dma::self_check();
+    }
+    heap::self_check();
+    x64::mapper::self_check();
+    x64::page_table::self_check();
+    address_space::self_check();
+    sync::owned_mutex::self_check();
+    sync::rcu::self_check();
+    demand_paging::self_check();
+    stack::self_check();
+    dma::sg::self_check();
+    #[cfg(feature = "framebuffer")]
+    graphics::font::self_check();
+    #[cfg(feature = "framebuffer")]
+    tty::self_check();
+    block::queue::self_check();
+    xmodem::self_check();
+    event::self_check();
+    device::self_check();
+    syscall::self_check();
+    if let Err((vector, error)) = IDT.lock().validate() {
+        panic!("IDT entry {:#x} is invalid: {:?}", vector, error);
     }
-    writeln!(DEBUG_SERIAL_PORT.lock(), "unpausing").unwrap();
+    Ok(())
 }
 
 #[panic_handler]
 fn rust_panic(info: &core::panic::PanicInfo) -> ! {
-    // the serial port is used elsewhere, but that doesn't matter for a panic handler
-    // (there is no issue interrupting it because we aren't coming back to it)
-    let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-    serial_port.init();
-
-    let _ = writeln!(serial_port, "\nPANIC!: {}", info);
+    panicking::panic(info)
+}
 
-    halt_loop();
+/// Waits for interrupts forever, with interrupts enabled so the tick and devices are still handled.
+fn idle_loop() -> ! {
+    loop {
+        sync::rcu::quiescent_state();
+        work::run_pending();
+        // This is safe because `idle_halt` enables interrupts again, and every vector that can be delivered has a handler
+        unsafe { asm!("cli") };
+        work::idle_halt();
+    }
 }
 
 fn halt_loop() -> ! {
@@ -184,34 +606,6 @@ fn halt_loop() -> ! {
     }
 }
 
-extern "x86-interrupt" fn page_fault(_: u64, error_code: PageFaultErrorCode) {
-    let address: u64;
-    // The x86-interrupt calling convention helpfully pops the error code for us, but we still need to read cr2 to find the virtual address of the page fault
-    unsafe {
-        asm!(
-        "mov {addr}, cr2",
-        addr = out(reg) address,
-        )
-    };
-    let direct_address = DirectMappedAddress::try_from_virtual(VirtualAddress::create(address));
-    let physical_address = match direct_address {
-        Some(direct_mapped_address) => direct_mapped_address.get_physical_address().get_address(),
-        None => 1,
-    };
-    panic!(
-        "Page fault! Error code: {:?}, Address: {:x}, Phyiscal Address: {:x}",
-        error_code, address, physical_address
-    );
-}
-
-extern "x86-interrupt" fn general_protection_fault(_: u64, error_code: u64) {
-    panic!("Page fault! Error code: {},", error_code);
-}
-
-extern "x86-interrupt" fn double_fault(_: u64, error_code: u64) -> ! {
-    panic!("Double fault! Error code: {}", error_code);
-}
-
 /// Generates a breakpoint interrupt
 pub fn breakpoint() {
     unsafe {
@@ -2,7 +2,30 @@ use core::mem::{align_of, size_of};
 
 use bitfield_struct::bitfield;
 
-use crate::{DIRECT_MAP_START, PHYSICAL_MEMORY_SIZE};
+use crate::{mapping_assert, mapping_assert_eq, DIRECT_MAP_START, PHYSICAL_MEMORY_SIZE};
+
+const FRAME_SIZE: u64 = 0x1000;
+
+/// Asserts something about an address or a mapping. They are checked on every address conversion, so release builds leave
+/// them out unless the `debug_mappings` feature is on.
+#[macro_export]
+macro_rules! mapping_assert {
+    ($($arg:tt)*) => {
+        if cfg!(any(debug_assertions, feature = "debug_mappings")) {
+            assert!($($arg)*);
+        }
+    };
+}
+
+/// Like `mapping_assert!`, for `assert_eq!`.
+#[macro_export]
+macro_rules! mapping_assert_eq {
+    ($($arg:tt)*) => {
+        if cfg!(any(debug_assertions, feature = "debug_mappings")) {
+            assert_eq!($($arg)*);
+        }
+    };
+}
 
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy)]
@@ -12,11 +35,11 @@ pub struct PhysicalAddress {
 impl PhysicalAddress {
     /// Creates a new `PhysicalAddress` with the given address
     pub fn new(address: u64) -> Self {
-        assert!(
+        mapping_assert!(
             address < *PHYSICAL_MEMORY_SIZE.get().unwrap(),
             "Attempted to construct PhysicalAddress with address greater than PHYSICAL_MEMORY_SIZE"
         );
-        assert!(
+        mapping_assert!(
             address >= 0x1000,
             "Attempted to construct PhysicalAddress in page 0, address: {}",
             address
@@ -24,6 +47,33 @@ impl PhysicalAddress {
         PhysicalAddress { address }
     }
 
+    /// Creates a `PhysicalAddress` of device memory, which can be above the end of RAM.
+    pub fn device(address: u64) -> Self {
+        mapping_assert!(
+            address >= 0x1000,
+            "Attempted to construct PhysicalAddress in page 0, address: {}",
+            address
+        );
+        PhysicalAddress { address }
+    }
+
+    /// Creates the address of each frame in `length` bytes of RAM at `start`, which must be frame aligned.
+    /// The whole range is checked once, rather than once per frame as `new` would in a loop.
+    pub fn frames(start: u64, length: u64) -> impl Iterator<Item = PhysicalAddress> {
+        mapping_assert!(
+            start + length <= *PHYSICAL_MEMORY_SIZE.get().unwrap(),
+            "Attempted to construct PhysicalAddresses past PHYSICAL_MEMORY_SIZE"
+        );
+        Self::device_frames(start, length)
+    }
+
+    /// Like `frames`, for device memory which can be above the end of RAM.
+    pub fn device_frames(start: u64, length: u64) -> impl Iterator<Item = PhysicalAddress> {
+        mapping_assert!(start >= 0x1000, "Attempted to construct PhysicalAddress in page 0, address: {}", start);
+        mapping_assert_eq!(start % FRAME_SIZE, 0, "Attempted to get the frames of an unaligned range");
+        (start..start + length).step_by(FRAME_SIZE as usize).map(|address| PhysicalAddress { address })
+    }
+
     /// Gets the `PhysicalAddress` as a `u64`
     pub fn get_address(&self) -> u64 {
         self.address
@@ -46,14 +96,13 @@ pub struct DirectMappedAddress {
 impl DirectMappedAddress {
     /// Creates a new `DirectMappedAddress` from a virtual address.
     pub fn from_virtual(virtual_address: VirtualAddress) -> Self {
-        assert!(
+        mapping_assert!(
             virtual_address.address() > *DIRECT_MAP_START.get().unwrap(),
             "Attempted to construct DirectMappedAddress with address lower than DIRECT_MAP_START"
         );
         let physical_address = virtual_address.address() - DIRECT_MAP_START.get().unwrap();
-        assert!(physical_address < *PHYSICAL_MEMORY_SIZE.get().unwrap());
         Self {
-            physical_address: PhysicalAddress::new(virtual_address.address()),
+            physical_address: PhysicalAddress::new(physical_address),
         }
     }
 
@@ -92,12 +141,12 @@ impl DirectMappedAddress {
 
     /// Gets a pointer to this direct mapped address.
     pub fn as_pointer<T>(&self) -> *mut T {
-        assert!(
+        mapping_assert!(
             self.physical_address.address + (size_of::<T>() as u64)
                 <= *PHYSICAL_MEMORY_SIZE.get().unwrap(),
             "Attempted to construct pointer to value that exceeds the bounds of physical memory"
         );
-        assert_eq!(
+        mapping_assert_eq!(
             self.get_virtual_address().address() % (align_of::<T>() as u64),
             0,
             "Attempted to get unaligned address as pointer!"
@@ -107,11 +156,11 @@ impl DirectMappedAddress {
 
     /// Gets a pointer to this direct mapped address. This function should be used for structs with sizes not known at compile time (for example, an XSDT).
     pub fn as_pointer_with_size<T>(&self, size: u64) -> *mut T {
-        assert!(
+        mapping_assert!(
             self.physical_address.address + size <= *PHYSICAL_MEMORY_SIZE.get().unwrap(),
             "Attempted to construct pointer to value that exceeds the bounds of physical memory"
         );
-        assert_eq!(
+        mapping_assert_eq!(
             self.get_virtual_address().address() % (align_of::<T>() as u64),
             0,
             "Attempted to get unaligned address as pointer!"
@@ -153,6 +202,13 @@ pub struct VirtualAddress {
     pub sign_extension: u16,
 }
 
+/// Why an address couldn't be made into a `VirtualAddress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressError {
+    /// Bits 48 to 63 aren't copies of bit 47.
+    NonCanonical,
+}
+
 impl VirtualAddress {
     /// Creates a new virtual address
     /// Panics if `virtual_address` is non canonical
@@ -160,8 +216,7 @@ impl VirtualAddress {
         let new = Self::from(virtual_address);
 
         assert!(
-            (new.sign_extension() == 0 && new.pml4_index() & 1 << 8 == 0)
-                || (new.sign_extension() == 0xFFFF && new.pml4_index() & 1 << 8 == 1 << 8),
+            new.is_canonical(),
             "Attempted to create non canonical virtual address {:x}, {:x?}",
             virtual_address,
             new
@@ -170,7 +225,39 @@ impl VirtualAddress {
         new
     }
 
+    /// Creates a new virtual address, returning an error instead of panicking if it is non canonical.
+    /// For addresses that come from outside the kernel, like syscall arguments.
+    pub fn try_create(virtual_address: u64) -> Result<Self, AddressError> {
+        let new = Self::from(virtual_address);
+        if new.is_canonical() {
+            Ok(new)
+        } else {
+            Err(AddressError::NonCanonical)
+        }
+    }
+
     pub fn address(&self) -> u64 {
         (*self).into()
     }
+
+    /// Returns whether bits 48 to 63 are copies of bit 47, which the CPU requires of every address it uses.
+    pub fn is_canonical(&self) -> bool {
+        (self.sign_extension() == 0 && self.pml4_index() & 1 << 8 == 0)
+            || (self.sign_extension() == 0xFFFF && self.pml4_index() & 1 << 8 == 1 << 8)
+    }
+
+    /// Returns whether this is the start of a 4KB page.
+    pub fn page_aligned(&self) -> bool {
+        self.page_offset() == 0
+    }
+
+    /// Returns whether this is in the lower half, which belongs to user programs.
+    pub fn is_user(&self) -> bool {
+        self.is_canonical() && self.sign_extension() == 0
+    }
+
+    /// Returns whether this is in the upper half, which belongs to the kernel.
+    pub fn is_kernel(&self) -> bool {
+        self.is_canonical() && self.sign_extension() == 0xFFFF
+    }
 }
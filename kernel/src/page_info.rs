@@ -0,0 +1,217 @@
+//! Metadata for every physical frame: a reference count and flags saying who owns it.
+//! The array is allocated once the frame allocator exists and covers every frame up to the end of usable memory. Every frame
+//! allocator reports its allocations and frees here, so a frame allocated twice or freed while it is still shared is caught,
+//! and frames can be shared by taking more references.
+
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bitflags::bitflags;
+use generic_once_cell::OnceCell;
+use limine::{MemmapEntry, MemoryMapEntryType, NonNullPtr};
+use spin::Mutex;
+
+use crate::memory::{PhysicalAddress, VirtualAddress};
+use crate::pmm::FrameAllocator;
+use crate::stack::{STACK_REGION_SIZE, STACK_REGION_START};
+use crate::x64::page_table::PageFlags;
+use crate::x64::registers::get_cr3;
+use crate::{kassert, log, FRAME_ALLOCATOR};
+
+/// The start of the virtual region the array is mapped at, after the kernel stacks.
+const PAGE_INFO_START: u64 = STACK_REGION_START + STACK_REGION_SIZE;
+/// The largest the array can be, enough for 8TiB of memory.
+const PAGE_INFO_MAX_SIZE: u64 = 16 << 30;
+
+const FRAME_SIZE: u64 = 0x1000;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageInfoFlags: u32 {
+        /// Not usable memory, like firmware data or the kernel image. Never allocated.
+        const RESERVED = 1 << 0;
+        /// Used by the boot allocator.
+        const BOOTMEM = 1 << 1;
+        /// Allocated from the buddy zone.
+        const BUDDY = 1 << 2;
+        /// Allocated from the memory below 1MiB.
+        const LOW_MEMORY = 1 << 3;
+        /// Part of the page info array itself.
+        const METADATA = 1 << 4;
+    }
+}
+
+/// The metadata of one frame.
+#[repr(C)]
+pub struct PageInfo {
+    refcount: AtomicU32,
+    flags: AtomicU32,
+}
+
+impl PageInfo {
+    /// Gets the number of references to the frame, 0 if it is free.
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    pub fn flags(&self) -> PageInfoFlags {
+        PageInfoFlags::from_bits_retain(self.flags.load(Ordering::Relaxed))
+    }
+
+    /// Takes another reference to an allocated frame, returns the new count.
+    pub fn get(&self) -> u32 {
+        let previous = self.refcount.fetch_add(1, Ordering::AcqRel);
+        assert_ne!(previous, 0, "Attempted to take a reference to a free frame");
+        previous + 1
+    }
+
+    /// Drops a reference taken with `get`, returns whether only the allocation's own reference is left, in which case the
+    /// frame can be freed.
+    pub fn put(&self) -> bool {
+        let previous = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(previous > 1, "Attempted to drop the last reference to a frame without freeing it");
+        previous == 2
+    }
+}
+
+struct PageInfoArray {
+    entries: &'static [PageInfo],
+}
+
+static PAGE_INFO: OnceCell<Mutex<()>, PageInfoArray> = OnceCell::new();
+
+/// Gets the metadata of the frame `address` is in, or None for memory above the end of usable RAM (or before `init`).
+pub fn get(address: PhysicalAddress) -> Option<&'static PageInfo> {
+    PAGE_INFO
+        .get()?
+        .entries
+        .get((address.get_address() / FRAME_SIZE) as usize)
+}
+
+/// Records that `count` frames at `address` were allocated by the allocator identified by `flags`.
+/// Panics if any of them is already allocated.
+pub fn allocated(address: u64, count: u64, flags: PageInfoFlags) {
+    let Some(array) = PAGE_INFO.get() else {
+        return;
+    };
+    for frame in address / FRAME_SIZE..address / FRAME_SIZE + count {
+        let Some(info) = array.entries.get(frame as usize) else {
+            continue;
+        };
+        let previous = info.refcount.swap(1, Ordering::AcqRel);
+        kassert!(
+            "page_info",
+            previous == 0,
+            |f: &mut dyn fmt::Write| write!(f, "refcount: {}, flags: {:?}, now allocated as: {:?}", previous, info.flags(), flags),
+            "Frame {:#x} was allocated twice",
+            frame * FRAME_SIZE
+        );
+        info.flags.store(flags.bits(), Ordering::Relaxed);
+    }
+}
+
+/// Records that `count` frames at `address` were freed. Panics if any of them is free or still shared.
+pub fn freed(address: u64, count: u64) {
+    let Some(array) = PAGE_INFO.get() else {
+        return;
+    };
+    for frame in address / FRAME_SIZE..address / FRAME_SIZE + count {
+        let Some(info) = array.entries.get(frame as usize) else {
+            continue;
+        };
+        let previous = info.refcount.swap(0, Ordering::AcqRel);
+        kassert!(
+            "page_info",
+            previous == 1,
+            |f: &mut dyn fmt::Write| write!(f, "refcount: {}, flags: {:?}", previous, info.flags()),
+            "Frame {:#x} was freed with {} references",
+            frame * FRAME_SIZE,
+            previous
+        );
+        info.flags.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of allocated frames with all of `flags`, to look for leaks.
+pub fn allocated_frames(flags: PageInfoFlags) -> u64 {
+    let Some(array) = PAGE_INFO.get() else {
+        return 0;
+    };
+    array
+        .entries
+        .iter()
+        .filter(|info| info.refcount() != 0 && info.flags().contains(flags))
+        .count() as u64
+}
+
+/// Allocates and maps the array, marks the memory that isn't usable as reserved and the boot allocator's memory as used.
+/// Must run once the frame allocator exists, before anything else allocates from it.
+pub fn init(memory_map: &[NonNullPtr<MemmapEntry>], bootmem_used: Range<u64>) {
+    let end = memory_map
+        .iter()
+        .filter(|entry| entry.typ == MemoryMapEntryType::Usable)
+        .map(|entry| entry.base + entry.len)
+        .max()
+        .unwrap_or(0);
+    let frame_count = end / FRAME_SIZE;
+    let size = (frame_count * core::mem::size_of::<PageInfo>() as u64).next_multiple_of(FRAME_SIZE);
+    if size > PAGE_INFO_MAX_SIZE {
+        log!("page info: too much memory to track, frames won't have metadata");
+        return;
+    }
+
+    let cr3 = get_cr3();
+    let pml4 = cr3.pml4();
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    for offset in (0..size).step_by(FRAME_SIZE as usize) {
+        let Some(frame) = allocator.allocate() else {
+            log!("page info: out of memory, frames won't have metadata");
+            return;
+        };
+        pml4.map(frame, VirtualAddress::create(PAGE_INFO_START + offset), PageFlags::KERNEL_DATA).unwrap();
+        // This is safe because the page was just mapped, and a zeroed entry is a free frame
+        unsafe { (PAGE_INFO_START as *mut u8).add(offset as usize).write_bytes(0, FRAME_SIZE as usize) };
+    }
+    drop(allocator);
+    // This is safe because the array was just mapped and zeroed, and is never unmapped
+    let entries = unsafe { core::slice::from_raw_parts(PAGE_INFO_START as *const PageInfo, frame_count as usize) };
+
+    let mark = |range: Range<u64>, refcount: u32, flags: PageInfoFlags| {
+        for frame in range.start / FRAME_SIZE..range.end.div_ceil(FRAME_SIZE).min(frame_count) {
+            entries[frame as usize].refcount.store(refcount, Ordering::Relaxed);
+            entries[frame as usize].flags.store(flags.bits(), Ordering::Relaxed);
+        }
+    };
+    // everything is reserved except the usable memory, and only whole frames of it
+    mark(0..end, 0, PageInfoFlags::RESERVED);
+    for entry in memory_map.iter().filter(|entry| entry.typ == MemoryMapEntryType::Usable) {
+        mark(entry.base.next_multiple_of(FRAME_SIZE)..(entry.base + entry.len) & !(FRAME_SIZE - 1), 0, PageInfoFlags::empty());
+    }
+    mark(bootmem_used, 1, PageInfoFlags::BOOTMEM);
+    for offset in (0..size).step_by(FRAME_SIZE as usize) {
+        let frame = pml4.translate(VirtualAddress::create(PAGE_INFO_START + offset)).unwrap().get_address();
+        mark(frame..frame + FRAME_SIZE, 1, PageInfoFlags::METADATA);
+    }
+
+    if PAGE_INFO.set(PageInfoArray { entries }).is_err() {
+        panic!("page info initialised twice");
+    }
+    log!("page info: {} frames tracked in {} KiB", frame_count, size / 1024);
+}
+
+/// Checks that allocating and freeing a frame updates its metadata, and that references are counted.
+pub fn self_check() {
+    if PAGE_INFO.get().is_none() {
+        log!("page info: not set up, skipping self-test");
+        return;
+    }
+    let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+    let address = frame.get_starting_address();
+    let info = get(address).unwrap();
+    assert_eq!(info.refcount(), 1);
+    assert_eq!(info.get(), 2);
+    assert!(info.put());
+    FRAME_ALLOCATOR.get().unwrap().lock().free(frame);
+    assert_eq!(get(address).unwrap().refcount(), 0);
+}
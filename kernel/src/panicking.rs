@@ -0,0 +1,170 @@
+//! The panic handler. A panic can happen anywhere, including with the serial port, the log or the allocator locked, so the report
+//! is written without taking any lock: straight to the serial port's registers and to the persistent copy of the log.
+//! The first CPU to panic switches to its emergency stack (its own may be what overflowed), stops the other CPUs with an NMI so
+//! they don't keep changing what is being reported on, and then prints the report. A CPU that panics before it has reserved an
+//! emergency stack uses a static one instead. Another CPU that panics meanwhile switches to its own emergency stack, logs one
+//! line with `emergency_log!` and halts.
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use uart_16550::SerialPort;
+
+use crate::delay::poll_until;
+use crate::stack::emergency_stack_top;
+use crate::{emergency_log, pstore};
+use crate::smp::{cpu_state, CpuState};
+use crate::sync::{current_cpu, MAX_CPUS};
+use crate::version::BUILD_INFO;
+use crate::x64::apic::{APIC_BASE_ADDRESS, APIC_GLOBAL_ENABLE, APIC_X2APIC_ENABLE, IA32_APIC_BASE};
+use crate::x64::idt::InterruptStackFrame;
+use crate::x64::msr::{rdmsr, wrmsr};
+use crate::{halt_loop, DEBUG_SERIAL_PORT_BASE, DIRECT_MAP_START};
+
+const PANIC_STACK_SIZE: usize = 64 * 1024;
+/// How long to wait for the other CPUs to stop, in microseconds.
+const STOP_TIMEOUT_US: u64 = 10_000;
+
+const X2APIC_ICR: u32 = 0x830;
+/// The offset of the low half of the interrupt command register in the xAPIC's registers.
+const XAPIC_ICR_LOW: u64 = 0x300;
+/// An NMI sent to every CPU but this one.
+const ICR_NMI_ALL_EXCLUDING_SELF: u32 = (0b11 << 18) | (1 << 14) | (0b100 << 8);
+
+#[repr(C, align(16))]
+struct PanicStack([u8; PANIC_STACK_SIZE]);
+
+/// Only used by the first CPU to panic, if it has no emergency stack.
+static mut PANIC_STACK: PanicStack = PanicStack([0; PANIC_STACK_SIZE]);
+
+/// The id of the CPU that is panicking plus one, or 0 if none is.
+static PANICKING_CPU: AtomicUsize = AtomicUsize::new(0);
+/// Which CPUs have panicked while another was reporting, so a CPU that does it again just halts.
+static ALSO_PANICKED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+/// The number of CPUs that have stopped for the panic.
+static STOPPED_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes to the serial port's registers and the persistent log, neither of which takes a lock.
+struct RawWriter {
+    serial_port: SerialPort,
+}
+
+impl RawWriter {
+    fn new() -> Self {
+        // The serial port may be in use by code that was interrupted, which doesn't matter because we aren't going back to it
+        let mut serial_port = unsafe { SerialPort::new(DEBUG_SERIAL_PORT_BASE) };
+        serial_port.init();
+        RawWriter { serial_port }
+    }
+}
+
+impl Write for RawWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.serial_port.write_str(s)?;
+        // This is safe because the other CPUs have been stopped, or we are about to halt
+        unsafe { pstore::write_unlocked(s.as_bytes()) };
+        Ok(())
+    }
+}
+
+/// Returns whether a CPU is panicking.
+pub fn in_progress() -> bool {
+    PANICKING_CPU.load(Ordering::SeqCst) != 0
+}
+
+/// Handles a panic, called from the `#[panic_handler]`.
+pub fn panic(info: &PanicInfo) -> ! {
+    let cpu = current_cpu();
+    if let Err(panicking) = PANICKING_CPU.compare_exchange(0, cpu + 1, Ordering::SeqCst, Ordering::SeqCst) {
+        if panicking == cpu + 1 {
+            // the report itself panicked, it is not worth trying again
+            let _ = writeln!(RawWriter::new(), "\nPANIC while panicking: {}", info);
+            halt_loop();
+        }
+        match emergency_stack_top(cpu) {
+            // This is safe because the flag makes this the only time this CPU switches to its emergency stack
+            Some(top) if !ALSO_PANICKED[cpu].swap(true, Ordering::SeqCst) => unsafe { switch_stack(top, info, report_other) },
+            _ => halt_loop(),
+        }
+    }
+    let top = emergency_stack_top(cpu).unwrap_or(unsafe { addr_of!(PANIC_STACK) as u64 } + PANIC_STACK_SIZE as u64);
+    // This is safe because the CPU that won the exchange uses its emergency or the panic stack only once, and never returns
+    unsafe { switch_stack(top, info, report) }
+}
+
+/// Calls `function` with `info` on the stack whose top is `top`, leaving the current stack alone.
+/// Safety: nothing else may be using the stack, and the current stack is never returned to.
+unsafe fn switch_stack(top: u64, info: &PanicInfo, function: extern "C" fn(*const PanicInfo) -> !) -> ! {
+    asm!(
+        "mov rsp, {top}",
+        "call {function}",
+        top = in(reg) top,
+        function = in(reg) function,
+        in("rdi") info as *const PanicInfo,
+        options(noreturn)
+    );
+}
+
+/// Logs a panic on a CPU other than the one reporting, on its emergency stack. The reporting CPU will stop this one with an
+/// NMI, if it hasn't already.
+extern "C" fn report_other(info: *const PanicInfo) -> ! {
+    // This is safe because `panic()` passes a reference to the `PanicInfo` on the old stack, which is left alone
+    let info = unsafe { &*info };
+    emergency_log!("PANIC while another CPU is panicking: {}", info);
+    halt_loop();
+}
+
+/// Stops the other CPUs and prints the report, on the panic stack.
+extern "C" fn report(info: *const PanicInfo) -> ! {
+    // This is safe because `panic()` passes a reference to the `PanicInfo` on the old stack, which is left alone
+    let info = unsafe { &*info };
+    let stopped = stop_other_cpus();
+    let mut writer = RawWriter::new();
+    let _ = writeln!(writer, "\nPANIC on cpu{}: {}", current_cpu(), info);
+    let _ = writeln!(writer, "kernel: {}", BUILD_INFO);
+    if !stopped {
+        let _ = writeln!(writer, "(not every CPU stopped, they may still be running)");
+    }
+    halt_loop();
+}
+
+/// Sends an NMI to every other CPU and waits for the ones that are running to stop, returns whether they all did.
+fn stop_other_cpus() -> bool {
+    let cpu = current_cpu();
+    let others = (0..MAX_CPUS)
+        .filter(|&other| other != cpu && cpu_state(other) != CpuState::Offline)
+        .count();
+    if others == 0 {
+        return true;
+    }
+    // This is safe because the APIC base MSR exists on every x86-64 CPU
+    let apic_base = unsafe { rdmsr(IA32_APIC_BASE) };
+    if apic_base & APIC_GLOBAL_ENABLE == 0 {
+        return false;
+    }
+    if apic_base & APIC_X2APIC_ENABLE != 0 {
+        // This is safe because sending an NMI only affects the CPUs we want to stop
+        unsafe { wrmsr(X2APIC_ICR, ICR_NMI_ALL_EXCLUDING_SELF as u64) };
+    } else {
+        // The local APIC's registers are below 4GiB, which the bootloader puts in the direct map
+        let Some(direct_map_start) = DIRECT_MAP_START.get() else {
+            return false;
+        };
+        let icr = ((apic_base & APIC_BASE_ADDRESS) + XAPIC_ICR_LOW + direct_map_start) as *mut u32;
+        // This is safe because the ICR is a register of this CPU's local APIC
+        unsafe { icr.write_volatile(ICR_NMI_ALL_EXCLUDING_SELF) };
+    }
+    poll_until(STOP_TIMEOUT_US, || STOPPED_CPUS.load(Ordering::SeqCst) >= others)
+}
+
+/// The NMI handler. NMIs are only sent to stop CPUs for a panic, so any other NMI is ignored.
+/// NMIs stay blocked until an `iret`, so a CPU halted here stays halted.
+pub extern "x86-interrupt" fn nmi(_: InterruptStackFrame) {
+    if in_progress() {
+        STOPPED_CPUS.fetch_add(1, Ordering::SeqCst);
+        halt_loop();
+    }
+}
@@ -0,0 +1,119 @@
+//! The error interrupts of PCI Express root ports. Functions report the errors they detect to their root port, which with AER
+//! can interrupt for them; this routes that interrupt of each root port to a vector of its own with MSI.
+//! The handler only acknowledges the interrupt and schedules deferred work, which logs and clears the errors every function
+//! recorded: logging and the PCI lock aren't for interrupt context.
+
+use spin::Mutex;
+
+use crate::irq::{self, IrqReturn, LineControl};
+use crate::log;
+use crate::sync::without_interrupts;
+use crate::work::Work;
+use crate::x64::apic;
+use crate::x64::vectors::{self, VectorClass};
+
+use super::capabilities::{CAPABILITY_MSI, PORT_TYPE_ROOT_PORT};
+use super::{Pci, PciDevice};
+
+/// The most root ports whose interrupts are routed.
+const MAX_ROOT_PORTS: usize = 16;
+
+// Register offsets in the AER capability of a root port
+const AER_ROOT_ERROR_COMMAND: u16 = 0x2C;
+const AER_ROOT_ERROR_STATUS: u16 = 0x30;
+/// Enables the interrupt for correctable, non-fatal and fatal errors.
+const ROOT_ERROR_COMMAND_INTERRUPTS: u32 = 0b111;
+
+#[derive(Clone, Copy)]
+struct RootPort {
+    device: PciDevice,
+    /// The offset of the AER capability.
+    aer: u16,
+    vector: u8,
+}
+
+/// The root ports with a routed interrupt. The handler uses it, so it is only locked with interrupts disabled.
+static ROOT_PORTS: Mutex<[Option<RootPort>; MAX_ROOT_PORTS]> = Mutex::new([None; MAX_ROOT_PORTS]);
+
+static LOG_ERRORS: Work = Work::new(super::log_all_aer_errors);
+
+static LINE_CONTROL: LineControl = LineControl {
+    mask: |vector| set_enabled(vector, false),
+    unmask: |vector| set_enabled(vector, true),
+    eoi: |_| apic::eoi(),
+};
+
+fn root_port(vector: u8) -> Option<RootPort> {
+    without_interrupts(|| ROOT_PORTS.lock().iter().flatten().find(|port| port.vector == vector).copied())
+}
+
+fn set_enabled(vector: u8, enabled: bool) {
+    if let Some(port) = root_port(vector) {
+        port.device.set_msi_enabled(enabled);
+    }
+}
+
+fn error_interrupt(vector: u8) -> IrqReturn {
+    let Some(port) = root_port(vector) else {
+        return IrqReturn::NotMine;
+    };
+    let status = port.device.read_u32(port.aer + AER_ROOT_ERROR_STATUS);
+    if status == 0 {
+        return IrqReturn::NotMine;
+    }
+    // the status is write 1 to clear, an error that arrives after this interrupts again
+    port.device.write_u32(port.aer + AER_ROOT_ERROR_STATUS, status);
+    LOG_ERRORS.schedule();
+    IrqReturn::Handled
+}
+
+/// Turns on error reporting in every function, and routes the error interrupt of each root port that has AER and MSI to this
+/// CPU. Called by `init_pci` with the devices enumerated.
+pub(super) fn init_error_interrupts(pci: &Pci) {
+    for device in pci.devices() {
+        device.enable_error_reporting();
+    }
+    let Ok(apic_id) = u8::try_from(apic::id()) else {
+        log!("pci: MSI can't reach local APIC {}, not routing error interrupts", apic::id());
+        return;
+    };
+    for device in pci.devices() {
+        if device.port_type() != Some(PORT_TYPE_ROOT_PORT) {
+            continue;
+        }
+        let Some(aer) = device.extended_capabilities().aer else {
+            continue;
+        };
+        if device.capability(CAPABILITY_MSI).is_none() {
+            log!("pci: root port {} has no MSI, its errors are only reported by `pci aer`", device.address());
+            continue;
+        }
+        let Some(vector) = vectors::allocate(VectorClass::Normal) else {
+            log!("pci: no free vector for the errors of root port {}", device.address());
+            return;
+        };
+        let port = RootPort {
+            device: *device,
+            aer,
+            vector,
+        };
+        let added = without_interrupts(|| match ROOT_PORTS.lock().iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(port);
+                true
+            }
+            None => false,
+        });
+        if !added {
+            log!("pci: more than {} root ports, not routing the errors of {}", MAX_ROOT_PORTS, device.address());
+            vectors::free(vector);
+            return;
+        }
+        // the vector was just allocated, so nothing else handles it
+        let _ = irq::set_handler(vector, error_interrupt);
+        irq::set_line_control(vector, &LINE_CONTROL);
+        device.enable_msi(vector, apic_id);
+        device.write_u32(aer + AER_ROOT_ERROR_COMMAND, ROOT_ERROR_COMMAND_INTERRUPTS);
+        log!("pci: root port {} reports errors on vector {:#x}", device.address(), vector);
+    }
+}
@@ -0,0 +1,286 @@
+//! PCI Express extended capabilities, the linked list of capability structures at offset 0x100 of the configuration space,
+//! and the legacy capabilities, the list the capabilities pointer in the header starts.
+
+use bitflags::bitflags;
+
+use crate::log;
+
+use super::PciDevice;
+
+const STATUS: u16 = 0x06;
+/// Set in the status register if the function has a list of legacy capabilities.
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+const CAPABILITIES_POINTER: u16 = 0x34;
+/// Legacy capabilities are after the 64 byte header.
+const FIRST_CAPABILITY: u16 = 0x40;
+/// The most capabilities that fit in the legacy configuration space, bounds the walk in case the list loops.
+const MAX_CAPABILITIES: usize = (0x100 - 0x40) / 4;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+
+// Register offsets in the PCI Express capability
+const PCI_EXPRESS_CAPABILITIES: u16 = 0x02;
+const PCI_EXPRESS_DEVICE_CONTROL: u16 = 0x08;
+/// Enables sending error messages for correctable, non-fatal and fatal errors.
+const DEVICE_CONTROL_REPORT_ERRORS: u16 = 0b111;
+
+/// The device/port type of a root port of the root complex.
+pub const PORT_TYPE_ROOT_PORT: u8 = 0x4;
+
+/// The offset of the first extended capability.
+const FIRST_EXTENDED_CAPABILITY: u16 = 0x100;
+/// The most capabilities that fit in the extended configuration space, bounds the walk in case the list loops.
+const MAX_EXTENDED_CAPABILITIES: usize = (0x1000 - 0x100) / 4;
+
+const ADVANCED_ERROR_REPORTING: u16 = 0x0001;
+const DEVICE_SERIAL_NUMBER: u16 = 0x0003;
+const SINGLE_ROOT_IO_VIRTUALIZATION: u16 = 0x0010;
+
+// Register offsets in the AER capability
+const AER_UNCORRECTABLE_STATUS: u16 = 0x04;
+const AER_UNCORRECTABLE_SEVERITY: u16 = 0x0C;
+const AER_CORRECTABLE_STATUS: u16 = 0x10;
+const AER_HEADER_LOG: u16 = 0x1C;
+
+// Register offsets in the SR-IOV capability
+const SRIOV_INITIAL_VFS: u16 = 0x0C;
+const SRIOV_TOTAL_VFS: u16 = 0x0E;
+const SRIOV_NUM_VFS: u16 = 0x10;
+const SRIOV_FIRST_VF_OFFSET: u16 = 0x14;
+const SRIOV_VF_STRIDE: u16 = 0x16;
+const SRIOV_VF_DEVICE_ID: u16 = 0x1A;
+
+/// The header of an extended capability.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    /// The offset of the capability in configuration space.
+    pub offset: u16,
+}
+
+/// Iterates over the extended capabilities of a device.
+pub struct ExtendedCapabilityIterator<'a> {
+    device: &'a PciDevice,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for ExtendedCapabilityIterator<'_> {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // offsets below 0x100 are in the legacy configuration space and end the list
+        if self.next < FIRST_EXTENDED_CAPABILITY || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = self.next;
+        let header = self.device.read_u32(offset);
+        // a device without extended capabilities reads 0 here, and one that isn't PCI Express reads all ones
+        if header == 0 || header == 0xFFFF_FFFF {
+            return None;
+        }
+        self.next = ((header >> 20) as u16) & !0b11;
+        Some(ExtendedCapability {
+            id: header as u16,
+            version: ((header >> 16) & 0xF) as u8,
+            offset,
+        })
+    }
+}
+
+impl PciDevice {
+    /// Finds a capability in the legacy capability list, returns its offset.
+    pub fn capability(&self, id: u8) -> Option<u16> {
+        if self.read_u16(STATUS) & STATUS_CAPABILITIES_LIST == 0 {
+            return None;
+        }
+        let mut offset = (self.read_u8(CAPABILITIES_POINTER) & !0b11) as u16;
+        for _ in 0..MAX_CAPABILITIES {
+            // offsets in the header end the list
+            if offset < FIRST_CAPABILITY {
+                return None;
+            }
+            if self.read_u8(offset) == id {
+                return Some(offset);
+            }
+            offset = (self.read_u8(offset + 1) & !0b11) as u16;
+        }
+        None
+    }
+
+    /// Gets what kind of PCI Express function this is (an endpoint, a root port, a switch port...), None for conventional PCI.
+    pub fn port_type(&self) -> Option<u8> {
+        let pci_express = self.capability(CAPABILITY_PCI_EXPRESS)?;
+        Some((self.read_u16(pci_express + PCI_EXPRESS_CAPABILITIES) >> 4) as u8 & 0xF)
+    }
+
+    /// Makes a PCI Express function send a message to its root port when it detects an error, so the root port can
+    /// interrupt. Does nothing for conventional PCI.
+    pub fn enable_error_reporting(&self) {
+        if let Some(pci_express) = self.capability(CAPABILITY_PCI_EXPRESS) {
+            let control = self.read_u16(pci_express + PCI_EXPRESS_DEVICE_CONTROL);
+            self.write_u16(pci_express + PCI_EXPRESS_DEVICE_CONTROL, control | DEVICE_CONTROL_REPORT_ERRORS);
+        }
+    }
+
+    pub fn extended_capability_list(&self) -> ExtendedCapabilityIterator<'_> {
+        ExtendedCapabilityIterator {
+            device: self,
+            next: FIRST_EXTENDED_CAPABILITY,
+            remaining: MAX_EXTENDED_CAPABILITIES,
+        }
+    }
+}
+
+/// The SR-IOV capability of a physical function, which describes the virtual functions it can create.
+#[derive(Debug, Clone, Copy)]
+pub struct SriovHeader {
+    pub initial_vfs: u16,
+    pub total_vfs: u16,
+    pub num_vfs: u16,
+    /// The routing ID offset of the first virtual function from the physical function.
+    pub first_vf_offset: u16,
+    pub vf_stride: u16,
+    pub vf_device_id: u16,
+}
+
+/// The extended capabilities the kernel knows how to use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtendedCapabilities {
+    /// The offset of the advanced error reporting capability.
+    pub aer: Option<u16>,
+    pub serial_number: Option<u64>,
+    pub sriov: Option<SriovHeader>,
+}
+
+impl ExtendedCapabilities {
+    pub fn parse(device: &PciDevice) -> Self {
+        let mut capabilities = ExtendedCapabilities::default();
+        for capability in device.extended_capability_list() {
+            let offset = capability.offset;
+            match capability.id {
+                ADVANCED_ERROR_REPORTING => capabilities.aer = Some(offset),
+                DEVICE_SERIAL_NUMBER => {
+                    let low = device.read_u32(offset + 4) as u64;
+                    let high = device.read_u32(offset + 8) as u64;
+                    capabilities.serial_number = Some(high << 32 | low);
+                }
+                SINGLE_ROOT_IO_VIRTUALIZATION => {
+                    capabilities.sriov = Some(SriovHeader {
+                        initial_vfs: device.read_u16(offset + SRIOV_INITIAL_VFS),
+                        total_vfs: device.read_u16(offset + SRIOV_TOTAL_VFS),
+                        num_vfs: device.read_u16(offset + SRIOV_NUM_VFS),
+                        first_vf_offset: device.read_u16(offset + SRIOV_FIRST_VF_OFFSET),
+                        vf_stride: device.read_u16(offset + SRIOV_VF_STRIDE),
+                        vf_device_id: device.read_u16(offset + SRIOV_VF_DEVICE_ID),
+                    })
+                }
+                _ => {}
+            }
+        }
+        capabilities
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct UncorrectableErrors: u32 {
+        const DATA_LINK_PROTOCOL = 1 << 4;
+        const SURPRISE_DOWN = 1 << 5;
+        const POISONED_TLP = 1 << 12;
+        const FLOW_CONTROL_PROTOCOL = 1 << 13;
+        const COMPLETION_TIMEOUT = 1 << 14;
+        const COMPLETER_ABORT = 1 << 15;
+        const UNEXPECTED_COMPLETION = 1 << 16;
+        const RECEIVER_OVERFLOW = 1 << 17;
+        const MALFORMED_TLP = 1 << 18;
+        const ECRC = 1 << 19;
+        const UNSUPPORTED_REQUEST = 1 << 20;
+        const ACS_VIOLATION = 1 << 21;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct CorrectableErrors: u32 {
+        const RECEIVER = 1;
+        const BAD_TLP = 1 << 6;
+        const BAD_DLLP = 1 << 7;
+        const REPLAY_NUM_ROLLOVER = 1 << 8;
+        const REPLAY_TIMER_TIMEOUT = 1 << 12;
+        const ADVISORY_NON_FATAL = 1 << 13;
+        const CORRECTED_INTERNAL = 1 << 14;
+        const HEADER_LOG_OVERFLOW = 1 << 15;
+    }
+}
+
+/// The errors a device has recorded in its AER capability.
+#[derive(Debug, Clone, Copy)]
+pub struct AerStatus {
+    pub uncorrectable: UncorrectableErrors,
+    /// Set for uncorrectable errors that are fatal, clear for non-fatal ones.
+    pub uncorrectable_severity: UncorrectableErrors,
+    pub correctable: CorrectableErrors,
+    /// The header of the TLP that caused the first uncorrectable error.
+    pub header_log: [u32; 4],
+}
+
+impl AerStatus {
+    pub fn is_empty(&self) -> bool {
+        self.uncorrectable.is_empty() && self.correctable.is_empty()
+    }
+}
+
+impl PciDevice {
+    /// Reads the AER status registers, returns None if the device doesn't support AER.
+    pub fn aer_status(&self) -> Option<AerStatus> {
+        let aer = self.extended_capabilities().aer?;
+        let mut header_log = [0; 4];
+        for (i, dword) in header_log.iter_mut().enumerate() {
+            *dword = self.read_u32(aer + AER_HEADER_LOG + 4 * i as u16);
+        }
+        Some(AerStatus {
+            uncorrectable: UncorrectableErrors::from_bits_retain(
+                self.read_u32(aer + AER_UNCORRECTABLE_STATUS),
+            ),
+            uncorrectable_severity: UncorrectableErrors::from_bits_retain(
+                self.read_u32(aer + AER_UNCORRECTABLE_SEVERITY),
+            ),
+            correctable: CorrectableErrors::from_bits_retain(self.read_u32(aer + AER_CORRECTABLE_STATUS)),
+            header_log,
+        })
+    }
+
+    /// Clears the errors in `status` from the AER status registers, which are write 1 to clear.
+    pub fn clear_aer_status(&self, status: &AerStatus) {
+        let Some(aer) = self.extended_capabilities().aer else {
+            return;
+        };
+        self.write_u32(aer + AER_UNCORRECTABLE_STATUS, status.uncorrectable.bits());
+        self.write_u32(aer + AER_CORRECTABLE_STATUS, status.correctable.bits());
+    }
+}
+
+/// Logs and clears any errors `device` has recorded, returns whether there were any.
+pub fn log_aer_errors(device: &PciDevice) -> bool {
+    let Some(status) = device.aer_status() else {
+        return false;
+    };
+    if status.is_empty() {
+        return false;
+    }
+    let fatal = status.uncorrectable & status.uncorrectable_severity;
+    log!(
+        "pci: {} AER uncorrectable {:?} (fatal {:?}), correctable {:?}, header log {:08x?}",
+        device.address(),
+        status.uncorrectable,
+        fatal,
+        status.correctable,
+        status.header_log
+    );
+    device.clear_aer_status(&status);
+    true
+}
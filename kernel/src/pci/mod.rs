@@ -0,0 +1,533 @@
+//! PCI Express devices, accessed through the memory mapped configuration space (ECAM) described by the MCFG.
+
+use alloc::boxed::Box;
+use core::fmt;
+
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::acpi::mcfg::McfgEntry;
+use crate::device::{self, Bus, DeviceDriver, DeviceName, PowerError};
+use crate::init::InitError;
+use crate::{log, ACPI_TABLES, DIRECT_MAP_START};
+
+use self::capabilities::ExtendedCapabilities;
+
+mod aer;
+pub mod capabilities;
+mod msi;
+
+/// The most ECAM regions (one per segment group) that are tracked.
+const MAX_SEGMENTS: usize = 4;
+/// The most devices that are tracked.
+pub const MAX_DEVICES: usize = 128;
+/// Limine only guarantees the first 4GiB of physical memory is in the direct map, ECAM regions above that can't be accessed yet.
+const DIRECT_MAP_GUARANTEED: u64 = 1 << 32;
+
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const REVISION_ID: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0E;
+const BAR_0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const SUBORDINATE_BUS: u16 = 0x1A;
+/// The standard header registers that are saved across suspend, as 32 bit registers.
+const SAVED_HEADER_REGISTERS: usize = 16;
+/// The header type of PCI-to-PCI bridges.
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+/// Set in the header type of function 0 if the device has more than one function.
+const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+
+bitflags! {
+    /// The command register, controls how the function responds on the bus.
+    #[derive(Debug, Clone, Copy)]
+    pub struct PciCommand: u16 {
+        const IO_SPACE = 1;
+        const MEMORY_SPACE = 1 << 1;
+        const BUS_MASTER = 1 << 2;
+        const INTERRUPT_DISABLE = 1 << 10;
+    }
+}
+
+/// A base address register, describing where one of the function's register blocks is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { address: u64, prefetchable: bool },
+    Io { port: u16 },
+}
+
+/// The location of a function in PCI configuration space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// The ECAM region of a segment group.
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl EcamRegion {
+    fn from_mcfg(entry: &McfgEntry) -> Self {
+        EcamRegion {
+            base_address: entry.base_address(),
+            segment: entry.segment_group(),
+            start_bus: entry.start_bus(),
+            end_bus: entry.end_bus(),
+        }
+    }
+
+    /// Gets a pointer to the configuration space of the function at `address`, or None if it is outside this region.
+    fn configuration_space(&self, address: PciAddress) -> Option<*mut u8> {
+        if address.segment != self.segment
+            || address.bus < self.start_bus
+            || address.bus > self.end_bus
+            || address.device >= 32
+            || address.function >= 8
+        {
+            return None;
+        }
+        let physical_address = self.base_address
+            + (((address.bus - self.start_bus) as u64) << 20)
+            + ((address.device as u64) << 15)
+            + ((address.function as u64) << 12);
+        Some((physical_address + DIRECT_MAP_START.get().unwrap()) as *mut u8)
+    }
+}
+
+/// A PCI function found during enumeration.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    address: PciAddress,
+    configuration_space: *mut u8,
+    vendor_id: u16,
+    device_id: u16,
+    class: u8,
+    subclass: u8,
+    prog_if: u8,
+    header_type: u8,
+    extended_capabilities: ExtendedCapabilities,
+}
+
+// This is safe because configuration space accesses are done with single volatile reads and writes
+unsafe impl Send for PciDevice {}
+
+impl PciDevice {
+    /// Reads the identification registers of the function with configuration space at `configuration_space`, returns None if there is no function there.
+    /// Safety: `configuration_space` must point to 4KiB of mapped configuration space.
+    unsafe fn probe(address: PciAddress, configuration_space: *mut u8) -> Option<Self> {
+        let mut device = PciDevice {
+            address,
+            configuration_space,
+            vendor_id: 0,
+            device_id: 0,
+            class: 0,
+            subclass: 0,
+            prog_if: 0,
+            header_type: 0,
+            extended_capabilities: ExtendedCapabilities::default(),
+        };
+        device.vendor_id = device.read_u16(VENDOR_ID);
+        if device.vendor_id == 0xFFFF {
+            return None;
+        }
+        device.device_id = device.read_u16(DEVICE_ID);
+        let class = device.read_u32(REVISION_ID);
+        device.class = (class >> 24) as u8;
+        device.subclass = (class >> 16) as u8;
+        device.prog_if = (class >> 8) as u8;
+        device.header_type = device.read_u8(HEADER_TYPE);
+        device.extended_capabilities = ExtendedCapabilities::parse(&device);
+        Some(device)
+    }
+
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.device_id
+    }
+
+    pub fn class(&self) -> u8 {
+        self.class
+    }
+
+    pub fn subclass(&self) -> u8 {
+        self.subclass
+    }
+
+    pub fn prog_if(&self) -> u8 {
+        self.prog_if
+    }
+
+    /// Gets the layout of the configuration header (0 for endpoints, 1 for PCI-to-PCI bridges), without the multifunction bit.
+    pub fn header_type(&self) -> u8 {
+        self.header_type & !HEADER_TYPE_MULTIFUNCTION
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & HEADER_TYPE_MULTIFUNCTION != 0
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type() == HEADER_TYPE_BRIDGE
+    }
+
+    /// Gets the bus directly behind this bridge, or None if this isn't a bridge or the firmware didn't assign it a bus.
+    pub fn secondary_bus(&self) -> Option<u8> {
+        if !self.is_bridge() {
+            return None;
+        }
+        Some(self.read_u8(SECONDARY_BUS)).filter(|bus| *bus != 0)
+    }
+
+    /// Gets the highest bus number behind this bridge, or None if this isn't a bridge or the firmware didn't assign it a bus.
+    pub fn subordinate_bus(&self) -> Option<u8> {
+        self.secondary_bus()?;
+        Some(self.read_u8(SUBORDINATE_BUS))
+    }
+
+    /// Reads base address register `index`, returns None if it doesn't exist or is unused.
+    /// The upper half of a 64-bit BAR is part of the BAR before it, and reads as None.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let bar_count = match self.header_type() {
+            0 => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        if index >= bar_count {
+            return None;
+        }
+        if index > 0 {
+            let previous = self.read_u32(BAR_0 + 4 * (index as u16 - 1));
+            // the previous BAR is a 64-bit memory BAR, so this is its upper half
+            if previous & 0b111 == 0b100 {
+                return None;
+            }
+        }
+        let bar = self.read_u32(BAR_0 + 4 * index as u16);
+        if bar & 1 != 0 {
+            let port = (bar & !0b11) as u16;
+            return Some(Bar::Io { port }).filter(|_| port != 0);
+        }
+        let mut address = (bar & !0xF) as u64;
+        if bar & 0b110 == 0b100 && index + 1 < bar_count {
+            address |= (self.read_u32(BAR_0 + 4 * (index as u16 + 1)) as u64) << 32;
+        }
+        if address == 0 {
+            return None;
+        }
+        Some(Bar::Memory {
+            address,
+            prefetchable: bar & 0b1000 != 0,
+        })
+    }
+
+    pub fn command(&self) -> PciCommand {
+        PciCommand::from_bits_retain(self.read_u16(COMMAND))
+    }
+
+    /// Sets `flags` in the command register, leaving the other bits alone.
+    pub fn enable(&self, flags: PciCommand) {
+        self.write_u16(COMMAND, (self.command() | flags).bits());
+    }
+
+    pub fn extended_capabilities(&self) -> &ExtendedCapabilities {
+        &self.extended_capabilities
+    }
+
+    /// Reads a register from the configuration space, `offset` must be 4 byte aligned.
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        assert!(offset < 0x1000 && offset % 4 == 0, "invalid configuration space offset");
+        // This is safe because configuration space is 4KiB long
+        unsafe { (self.configuration_space.add(offset as usize) as *const u32).read_volatile() }
+    }
+
+    /// Writes a register in the configuration space, `offset` must be 4 byte aligned.
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        assert!(offset < 0x1000 && offset % 4 == 0, "invalid configuration space offset");
+        unsafe { (self.configuration_space.add(offset as usize) as *mut u32).write_volatile(value) }
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        assert!(offset < 0x1000 && offset % 2 == 0, "invalid configuration space offset");
+        unsafe { (self.configuration_space.add(offset as usize) as *const u16).read_volatile() }
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        assert!(offset < 0x1000 && offset % 2 == 0, "invalid configuration space offset");
+        unsafe { (self.configuration_space.add(offset as usize) as *mut u16).write_volatile(value) }
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        assert!(offset < 0x1000, "invalid configuration space offset");
+        unsafe { self.configuration_space.add(offset as usize).read_volatile() }
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        assert!(offset < 0x1000, "invalid configuration space offset");
+        unsafe { self.configuration_space.add(offset as usize).write_volatile(value) }
+    }
+}
+
+/// The ECAM regions and the devices found in them.
+pub struct Pci {
+    segments: [Option<EcamRegion>; MAX_SEGMENTS],
+    devices: [Option<PciDevice>; MAX_DEVICES],
+    device_count: usize,
+}
+
+pub static PCI: Mutex<Pci> = Mutex::new(Pci::new());
+
+impl Pci {
+    const fn new() -> Self {
+        const NO_DEVICE: Option<PciDevice> = None;
+        Pci {
+            segments: [None; MAX_SEGMENTS],
+            devices: [NO_DEVICE; MAX_DEVICES],
+            device_count: 0,
+        }
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &PciDevice> {
+        self.devices[..self.device_count].iter().flatten()
+    }
+
+    pub fn find(&self, address: PciAddress) -> Option<&PciDevice> {
+        self.devices().find(|device| device.address() == address)
+    }
+
+    /// Adds a device if it isn't already known, returns whether it was added.
+    fn add_device(&mut self, device: PciDevice) -> bool {
+        if self.find(device.address()).is_some() {
+            return false;
+        }
+        if self.device_count == MAX_DEVICES {
+            log!("pci: too many devices, ignoring {}", device.address());
+            return false;
+        }
+        self.devices[self.device_count] = Some(device);
+        self.device_count += 1;
+        true
+    }
+
+    /// Forgets devices that no longer respond, returns how many were removed.
+    fn remove_missing_devices(&mut self) -> usize {
+        let mut kept = 0;
+        for i in 0..self.device_count {
+            let Some(device) = self.devices[i] else {
+                continue;
+            };
+            if self.probe(device.address()).is_some() {
+                self.devices[kept] = Some(device);
+                kept += 1;
+            } else {
+                log!("pci: {} was removed", device.address());
+                if let Some(id) = device::find(device_name(&device).as_str()) {
+                    device::unregister(id);
+                }
+            }
+        }
+        let removed = self.device_count - kept;
+        for device in &mut self.devices[kept..self.device_count] {
+            *device = None;
+        }
+        self.device_count = kept;
+        removed
+    }
+
+    fn region(&self, segment: u16) -> Option<&EcamRegion> {
+        self.segments.iter().flatten().find(|region| region.segment == segment)
+    }
+
+    /// Probes the function at `address`, returns None if there is no function there.
+    pub fn probe(&self, address: PciAddress) -> Option<PciDevice> {
+        let configuration_space = self.region(address.segment)?.configuration_space(address)?;
+        // This is safe because `configuration_space()` only returns pointers in the region
+        unsafe { PciDevice::probe(address, configuration_space) }
+    }
+
+    /// Finds the functions on `bus` and, through the bridges on it, the buses behind it. Returns the number of new devices.
+    /// `visited` has a bit set for each bus of the segment that has already been scanned, which stops misconfigured bridges causing loops.
+    fn scan_bus(&mut self, segment: u16, bus: u8, visited: &mut [u64; 4]) -> usize {
+        if visited[bus as usize / 64] & (1 << (bus % 64)) != 0 {
+            return 0;
+        }
+        visited[bus as usize / 64] |= 1 << (bus % 64);
+
+        let mut added = 0;
+        for device in 0..32 {
+            let address = PciAddress {
+                segment,
+                bus,
+                device,
+                function: 0,
+            };
+            let Some(function_0) = self.probe(address) else {
+                continue;
+            };
+            let functions = if function_0.is_multifunction() { 0..8 } else { 0..1 };
+            for function in functions {
+                let Some(function) = self.probe(PciAddress { function, ..address }) else {
+                    continue;
+                };
+                if self.add_device(function) {
+                    added += 1;
+                }
+                if let Some(secondary_bus) = function.secondary_bus() {
+                    added += self.scan_bus(segment, secondary_bus, visited);
+                } else if function.is_bridge() {
+                    log!("pci: bridge {} has no bus assigned, skipping it", function.address());
+                }
+            }
+        }
+        added
+    }
+
+    /// Finds the devices in each segment group, starting from its first bus and following bridges. Returns the number of new devices.
+    fn enumerate(&mut self) -> usize {
+        let mut added = 0;
+        for i in 0..MAX_SEGMENTS {
+            if let Some(region) = self.segments[i] {
+                let mut visited = [0; 4];
+                added += self.scan_bus(region.segment, region.start_bus, &mut visited);
+            }
+        }
+        added
+    }
+
+    /// Rescans every segment group for devices that were added or removed since the last scan, for example after an ACPI hotplug notification.
+    /// Returns the number of devices (added, removed).
+    pub fn rescan(&mut self) -> (usize, usize) {
+        let removed = self.remove_missing_devices();
+        let added = self.enumerate();
+        for device in self.devices().skip(self.device_count - added) {
+            log!("pci: {} was added", device.address());
+            register_device(device);
+        }
+        (added, removed)
+    }
+}
+
+/// Gets the name of a PCI function in the device tree, its address.
+fn device_name(device: &PciDevice) -> DeviceName {
+    DeviceName::from_fmt(format_args!("{}", device.address()))
+}
+
+/// Saves a function's configuration header on suspend and writes it back on resume, since it is lost when the device loses
+/// power in S3. This is done for every function so its BARs and bus numbers come back whether or not it has a driver.
+struct PciPowerDriver {
+    state: Mutex<(PciDevice, [u32; SAVED_HEADER_REGISTERS])>,
+}
+
+impl DeviceDriver for PciPowerDriver {
+    fn suspend(&self) -> Result<(), PowerError> {
+        let mut state = self.state.lock();
+        let (device, saved) = &mut *state;
+        for (i, register) in saved.iter_mut().enumerate() {
+            *register = device.read_u32(i as u16 * 4);
+        }
+        Ok(())
+    }
+
+    fn resume(&self) -> Result<(), PowerError> {
+        let state = self.state.lock();
+        let (device, saved) = &*state;
+        if device.read_u16(VENDOR_ID) == 0xFFFF {
+            return Err(PowerError::DeviceError);
+        }
+        // The BARs have to be set before the command register enables decoding them, so the header is written backwards.
+        // The identification registers are read only and skipped.
+        for i in (1..SAVED_HEADER_REGISTERS).rev() {
+            if device.read_u32(i as u16 * 4) != saved[i] {
+                device.write_u32(i as u16 * 4, saved[i]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds a PCI function to the device tree, under the `pci` node.
+fn register_device(device: &PciDevice) {
+    let parent = device::find_or_register("pci", Bus::Pci, None);
+    let Some(id) = device::register(device_name(device), Bus::Pci, parent) else {
+        log!("pci: too many devices to add {} to the device tree", device.address());
+        return;
+    };
+    // Removed devices are rare enough that leaking their driver is fine
+    let driver = Box::leak(Box::new(PciPowerDriver {
+        state: Mutex::new((*device, [0; SAVED_HEADER_REGISTERS])),
+    }));
+    device::set_driver(id, driver);
+}
+
+/// Finds the ECAM regions in the MCFG and enumerates the devices in them.
+pub fn init_pci() -> Result<(), InitError> {
+    let mcfg = ACPI_TABLES
+        .get()
+        .ok_or(InitError::new("ACPI tables have not been discovered"))?
+        .mcfg()
+        .ok_or(InitError::new("MCFG not found"))?;
+    let mut pci = PCI.lock();
+    let mut segment_count = 0;
+    for entry in mcfg.entries() {
+        let region = EcamRegion::from_mcfg(&entry);
+        let size = ((region.end_bus - region.start_bus) as u64 + 1) << 20;
+        if region.base_address + size > DIRECT_MAP_GUARANTEED {
+            log!("pci: ECAM region of segment {} is above 4GiB, skipping it", region.segment);
+            continue;
+        }
+        if segment_count == MAX_SEGMENTS {
+            log!("pci: too many segment groups, skipping segment {}", region.segment);
+            continue;
+        }
+        pci.segments[segment_count] = Some(region);
+        segment_count += 1;
+    }
+    pci.enumerate();
+
+    for device in pci.devices() {
+        log!(
+            "pci: {} {:04x}:{:04x} class {:02x}:{:02x}",
+            device.address(),
+            device.vendor_id(),
+            device.device_id(),
+            device.class(),
+            device.subclass()
+        );
+        register_device(device);
+    }
+    // report errors that happened before we were watching
+    for device in pci.devices() {
+        capabilities::log_aer_errors(device);
+    }
+    aer::init_error_interrupts(&pci);
+    Ok(())
+}
+
+/// Logs and clears the AER errors reported by every device. Runs as deferred work after a root port's error interrupt.
+fn log_all_aer_errors() {
+    let pci = PCI.lock();
+    for device in pci.devices() {
+        capabilities::log_aer_errors(device);
+    }
+}
@@ -0,0 +1,55 @@
+//! Message signaled interrupts, where a function interrupts by writing a message to a local APIC instead of asserting a line.
+//! Only a single message is used, delivered to one local APIC in physical destination mode, so the destination has to be a
+//! local APIC with an id below 256.
+
+use super::capabilities::CAPABILITY_MSI;
+use super::{PciCommand, PciDevice};
+
+// Register offsets in the MSI capability
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+/// The data register follows the address, which is one or two registers long.
+const MSI_DATA_32_BIT: u16 = 0x08;
+const MSI_DATA_64_BIT: u16 = 0x0C;
+
+const MSI_CONTROL_ENABLE: u16 = 1;
+/// How many messages the function may send as a power of two, 0 for one.
+const MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+/// Set if the address is 64 bits long.
+const MSI_CONTROL_64_BIT: u16 = 1 << 7;
+
+/// The address local APICs receive messages at, the destination APIC id goes in bits 12 to 19.
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+impl PciDevice {
+    /// Makes the function interrupt with `vector` on the local APIC with id `apic_id`, with a fixed, edge triggered message.
+    /// Returns false if it doesn't support MSI.
+    pub fn enable_msi(&self, vector: u8, apic_id: u8) -> bool {
+        let Some(msi) = self.capability(CAPABILITY_MSI) else {
+            return false;
+        };
+        let control = self.read_u16(msi + MSI_CONTROL);
+        self.write_u32(msi + MSI_ADDRESS, MSI_ADDRESS_BASE | (apic_id as u32) << 12);
+        if control & MSI_CONTROL_64_BIT != 0 {
+            self.write_u32(msi + MSI_ADDRESS_HIGH, 0);
+            self.write_u16(msi + MSI_DATA_64_BIT, vector as u16);
+        } else {
+            self.write_u16(msi + MSI_DATA_32_BIT, vector as u16);
+        }
+        self.write_u16(msi + MSI_CONTROL, (control & !MSI_CONTROL_MULTIPLE_MESSAGE_ENABLE) | MSI_CONTROL_ENABLE);
+        // a function that uses MSI mustn't also assert its line
+        self.enable(PciCommand::INTERRUPT_DISABLE);
+        true
+    }
+
+    /// Stops or restarts the function's messages, for masking it. Without per-vector masking, which is optional, clearing the
+    /// enable bit is the only way.
+    pub fn set_msi_enabled(&self, enabled: bool) {
+        let Some(msi) = self.capability(CAPABILITY_MSI) else {
+            return;
+        };
+        let control = self.read_u16(msi + MSI_CONTROL) & !MSI_CONTROL_ENABLE;
+        self.write_u16(msi + MSI_CONTROL, if enabled { control | MSI_CONTROL_ENABLE } else { control });
+    }
+}
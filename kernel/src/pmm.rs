@@ -1,10 +1,16 @@
+use core::arch::asm;
+use core::ops::Range;
 use core::ptr::null_mut;
 
 use limine::{MemmapEntry, MemoryMapEntryType, NonNullPtr};
 
-use crate::{memory::PhysicalAddress, DEBUG_SERIAL_PORT};
+use crate::memory::{DirectMappedAddress, PhysicalAddress};
+use crate::page_info::{self, PageInfoFlags};
+use crate::sync::boot_once::BootOnce;
+use crate::x64::cpuid::supports_erms;
+use crate::{mapping_assert, FRAME_ALLOCATOR};
 
-use core::fmt::Write;
+use core::mem::{align_of, size_of};
 
 #[derive(Debug)]
 pub struct Frame {
@@ -12,13 +18,12 @@ pub struct Frame {
 }
 impl Frame {
     pub fn from_starting_address(physical_address: PhysicalAddress) -> Self {
-        assert!(
+        mapping_assert!(
             physical_address.is_frame_aligned(),
             "Attempted to create Frame with unaligned starting address."
         );
-        assert_ne!(
-            physical_address.get_address(),
-            0,
+        mapping_assert!(
+            physical_address.get_address() != 0,
             "Attempted to create null frame!"
         );
         Self {
@@ -27,7 +32,8 @@ impl Frame {
     }
 
     pub fn get_starting_address(&self) -> PhysicalAddress {
-        PhysicalAddress::new(self.starting_address)
+        // The address was checked when the frame was created, and frames of device memory are above the end of RAM
+        PhysicalAddress::device(self.starting_address)
     }
 }
 
@@ -38,32 +44,99 @@ pub trait FrameAllocator {
     fn free(&mut self, frame: Frame);
 }
 
+/// The memory map entry types, in the order `MemoryStats::bytes_by_type` uses.
+pub const MEMORY_TYPES: [MemoryMapEntryType; 8] = [
+    MemoryMapEntryType::Usable,
+    MemoryMapEntryType::Reserved,
+    MemoryMapEntryType::AcpiReclaimable,
+    MemoryMapEntryType::AcpiNvs,
+    MemoryMapEntryType::BadMemory,
+    MemoryMapEntryType::BootloaderReclaimable,
+    MemoryMapEntryType::KernelAndModules,
+    MemoryMapEntryType::Framebuffer,
+];
+
+/// How much memory the frame allocator manages and has left, and what the memory map says the rest of memory is.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// The frames the allocator was given.
+    pub total_frames: u64,
+    pub free_frames: u64,
+    pub used_frames: u64,
+    /// The bytes of each memory map entry type, in the order of `MEMORY_TYPES`.
+    pub bytes_by_type: [u64; MEMORY_TYPES.len()],
+}
+
+impl MemoryStats {
+    /// Gets the bytes of memory of type `typ` in the memory map.
+    pub fn bytes_of(&self, typ: MemoryMapEntryType) -> u64 {
+        MEMORY_TYPES
+            .iter()
+            .position(|&other| other == typ)
+            .map_or(0, |index| self.bytes_by_type[index])
+    }
+}
+
 #[derive(Debug)]
 pub struct MemoryMapAllocator {
-    /// The memory map provided by the bootloader
     /// The address at which physical memory is mapped
     physical_memory_offset: u64,
     /// The physical address of the first node in the linked list.
     first_node: *mut LinkedListNode,
+    /// The number of frames the allocator was given.
+    total_frames: u64,
+    /// The bytes of each memory map entry type, in the order of `MEMORY_TYPES`.
+    bytes_by_type: [u64; MEMORY_TYPES.len()],
 }
 
 // This is probably fine because first_node shouldn't be aliased
 unsafe impl Send for MemoryMapAllocator{}
 
 impl MemoryMapAllocator {
-    pub fn new(memory_map: &[NonNullPtr<MemmapEntry>], physical_memory_offset: u64) -> Self {
+    /// Creates an allocator for the usable memory in `memory_map`, except for the `reserved` ranges.
+    /// Each reserved range must be at the start or end of a usable region, like the boot allocator's memory and the buddy zone.
+    pub fn new(
+        memory_map: &[NonNullPtr<MemmapEntry>],
+        physical_memory_offset: u64,
+        reserved: &[Range<u64>],
+    ) -> Self {
         let mut first_node: *mut LinkedListNode = null_mut();
+        let mut last_node: *mut LinkedListNode = null_mut();
+        let mut total_frames = 0;
+        let mut bytes_by_type = [0; MEMORY_TYPES.len()];
+        for entry in memory_map {
+            if let Some(index) = MEMORY_TYPES.iter().position(|&typ| typ == entry.typ) {
+                bytes_by_type[index] += entry.len;
+            }
+        }
 
         let iter = memory_map
             .iter()
             .filter(|entry| entry.typ == MemoryMapEntryType::Usable);
 
         for entry in iter {
-            let physical_address = entry.base;
-            let size = entry.len >> 12; // convert bytes to pages
+            let mut physical_address = entry.base;
+            let mut end = entry.base + entry.len;
+            for reserved in reserved.iter().filter(|reserved| !reserved.is_empty()) {
+                if reserved.start <= physical_address && reserved.end > physical_address {
+                    physical_address = reserved.end.min(end);
+                } else if reserved.start < end && reserved.end >= end {
+                    end = reserved.start.max(physical_address);
+                } else {
+                    assert!(
+                        reserved.end <= physical_address || reserved.start >= end,
+                        "Reserved memory in the middle of a usable region"
+                    );
+                }
+            }
+            if physical_address >= end {
+                continue;
+            }
+            let size = (end - physical_address) >> 12; // convert bytes to pages
+            total_frames += size;
             let virtual_address = physical_address + physical_memory_offset;
             let new_node = unsafe {
-                assert_ne!(entry.base, 0);
+                assert_ne!(physical_address, 0);
                 (virtual_address as *mut LinkedListNode).write(LinkedListNode {
                     size,
                     next: null_mut(),
@@ -71,18 +144,73 @@ impl MemoryMapAllocator {
                 virtual_address as *mut LinkedListNode
             };
 
+            // keep the list sorted by address so freed frames can be merged with their neighbours
             if first_node.is_null() {
                 first_node = new_node;
+            } else if (last_node as u64) < (new_node as u64) {
+                unsafe { (*last_node).next = new_node };
             } else {
-                unsafe { *new_node }.next = first_node;
-                first_node = new_node;
+                // the memory map is sorted, but don't rely on it
+                unsafe { Self::insert_sorted(&mut first_node, new_node) };
+                continue;
             }
+            last_node = new_node;
         }
 
         Self {
             physical_memory_offset,
             first_node,
+            total_frames,
+            bytes_by_type,
+        }
+    }
+
+    /// Inserts `node` into the list starting at `first_node`, keeping it sorted by address.
+    /// Safety: `node` and every node in the list must be valid and must not overlap.
+    unsafe fn insert_sorted(first_node: &mut *mut LinkedListNode, node: *mut LinkedListNode) {
+        let mut link: *mut *mut LinkedListNode = first_node;
+        while !(*link).is_null() && (*link as u64) < node as u64 {
+            link = &mut (**link).next;
         }
+        (*node).next = *link;
+        *link = node;
+    }
+
+    /// Returns the number of separate free regions.
+    fn node_count(&self) -> usize {
+        let mut count = 0;
+        let mut node = self.first_node;
+        while !node.is_null() {
+            count += 1;
+            // This is safe because every node in the list is a free region owned by the allocator
+            node = unsafe { (*node).next };
+        }
+        count
+    }
+
+    /// Returns how much memory is managed and free, and the memory map's totals for each type of memory.
+    pub fn stats(&self) -> MemoryStats {
+        let free_frames = self.free_frames();
+        MemoryStats {
+            total_frames: self.total_frames,
+            free_frames,
+            used_frames: self.total_frames - free_frames,
+            bytes_by_type: self.bytes_by_type,
+        }
+    }
+
+    /// Returns the number of free frames.
+    pub fn free_frames(&self) -> u64 {
+        let mut count = 0;
+        let mut node = self.first_node;
+        while !node.is_null() {
+            // This is safe because every node in the list is a free region owned by the allocator
+            unsafe {
+                count += (*node).size;
+                node = (*node).next;
+            }
+        }
+        count
     }
 }
 
@@ -93,7 +221,7 @@ impl FrameAllocator for MemoryMapAllocator {
         } else {
             // This is safe because no other references to first_node can exist
             let first_node = unsafe { &mut *self.first_node };
-            if first_node.size == 1 {
+            let frame = if first_node.size == 1 {
                 let frame = Frame::from_starting_address(PhysicalAddress::new(
                     self.first_node as u64 - self.physical_memory_offset,
                 ));
@@ -102,18 +230,68 @@ impl FrameAllocator for MemoryMapAllocator {
                 // clear the node in the returned page
                 first_node.size = 0;
                 first_node.next = null_mut();
-                Some(frame)
+                frame
             } else {
                 first_node.size -= 1;
-                Some(Frame::from_starting_address(PhysicalAddress::new(
+                Frame::from_starting_address(PhysicalAddress::new(
                     self.first_node as u64 - self.physical_memory_offset + 0x1000 * first_node.size,
-                )))
-            }
+                ))
+            };
+            page_info::allocated(frame.get_starting_address().get_address(), 1, PageInfoFlags::empty());
+            Some(frame)
         }
     }
 
+    /// Returns `frame` to the free list, merging it with the free regions before and after it.
+    /// Panics if the frame is already free.
     fn free(&mut self, frame: Frame) {
-        todo!()
+        page_info::freed(frame.get_starting_address().get_address(), 1);
+        let virtual_address = frame.get_starting_address().get_address() + self.physical_memory_offset;
+        // find the free regions on either side of the frame
+        let mut previous: *mut LinkedListNode = null_mut();
+        let mut next = self.first_node;
+        // This is safe because every node in the list is a free region owned by the allocator, and the frame is no longer in use
+        unsafe {
+            while !next.is_null() && (next as u64) < virtual_address {
+                previous = next;
+                next = (*next).next;
+            }
+            assert!(
+                previous.is_null() || previous as u64 + 0x1000 * (*previous).size <= virtual_address,
+                "Attempted to free a frame that is already free"
+            );
+            assert_ne!(next as u64, virtual_address, "Attempted to free a frame that is already free");
+
+            // the frame becomes a node of its own, absorbing the next region if it starts right after the frame
+            let node = virtual_address as *mut LinkedListNode;
+            if !next.is_null() && virtual_address + 0x1000 == next as u64 {
+                node.write(LinkedListNode {
+                    size: 1 + (*next).size,
+                    next: (*next).next,
+                });
+                // clear the absorbed node so it doesn't look like a node in memory that gets handed out
+                next.write(LinkedListNode {
+                    size: 0,
+                    next: null_mut(),
+                });
+            } else {
+                node.write(LinkedListNode { size: 1, next });
+            }
+
+            // and is absorbed by the previous region if it ends right before the frame
+            if previous.is_null() {
+                self.first_node = node;
+            } else if previous as u64 + 0x1000 * (*previous).size == virtual_address {
+                (*previous).size += (*node).size;
+                (*previous).next = (*node).next;
+                node.write(LinkedListNode {
+                    size: 0,
+                    next: null_mut(),
+                });
+            } else {
+                (*previous).next = node;
+            }
+        }
     }
 }
 
@@ -124,3 +302,102 @@ struct LinkedListNode {
     size: u64,
     next: *mut LinkedListNode,
 }
+
+/// Moves `value` into a newly allocated frame, giving it a static lifetime. Used for long lived kernel objects since there is no heap.
+/// Returns None if there are no free frames.
+pub fn leak_in_frame<T>(value: T) -> Option<&'static mut T> {
+    assert!(
+        size_of::<T>() <= 0x1000 && align_of::<T>() <= 0x1000,
+        "Attempted to place a value larger than a frame in a frame"
+    );
+    let frame = FRAME_ALLOCATOR.get()?.lock().allocate()?;
+    let pointer = DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<T>();
+    // This is safe because the frame was just allocated, so nothing else references it
+    unsafe {
+        pointer.write(value);
+        Some(&mut *pointer)
+    }
+}
+
+const FRAME_SIZE: usize = 0x1000;
+
+/// Whether the CPU has ERMS, checked on first use since CPUID is slow under virtualization.
+static ERMS: BootOnce<bool> = BootOnce::new();
+
+fn has_erms() -> bool {
+    if let Some(&erms) = ERMS.get() {
+        return erms;
+    }
+    let erms = supports_erms();
+    let _ = ERMS.set(erms);
+    erms
+}
+
+/// Zeroes `frame` with `rep stosb` on CPUs with ERMS, and `rep stosq` on others.
+/// Caller must ensure that nothing else is using the frame.
+pub unsafe fn memzero_frame(frame: &Frame) {
+    let pointer = DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<u8>();
+    if has_erms() {
+        asm!("rep stosb", inout("rdi") pointer => _, inout("rcx") FRAME_SIZE => _, in("al") 0u8, options(nostack));
+    } else {
+        asm!("rep stosq", inout("rdi") pointer => _, inout("rcx") FRAME_SIZE / 8 => _, in("rax") 0u64, options(nostack));
+    }
+}
+
+/// Copies the contents of `source` to `destination` with `rep movsb` on CPUs with ERMS, and `rep movsq` on others.
+/// Caller must ensure that nothing else is using `destination` and nothing is writing to `source`.
+pub unsafe fn copy_frame(destination: &Frame, source: &Frame) {
+    let destination = DirectMappedAddress::from_physical(destination.get_starting_address()).as_pointer::<u8>();
+    let source = DirectMappedAddress::from_physical(source.get_starting_address()).as_pointer::<u8>();
+    if has_erms() {
+        asm!(
+            "rep movsb",
+            inout("rdi") destination => _,
+            inout("rsi") source => _,
+            inout("rcx") FRAME_SIZE => _,
+            options(nostack)
+        );
+    } else {
+        asm!(
+            "rep movsq",
+            inout("rdi") destination => _,
+            inout("rsi") source => _,
+            inout("rcx") FRAME_SIZE / 8 => _,
+            options(nostack)
+        );
+    }
+}
+
+/// Checks that freed frames go back on the free list and are merged with their neighbours.
+pub fn self_check() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let free_before = allocator.free_frames();
+    let nodes_before = allocator.node_count();
+    let stats = allocator.stats();
+    assert_eq!(stats.free_frames, free_before);
+    assert_eq!(stats.free_frames + stats.used_frames, stats.total_frames);
+    let frames = [(); 4].map(|_| allocator.allocate().unwrap());
+    assert_eq!(allocator.free_frames(), free_before - 4);
+    // free out of order so both kinds of merge happen
+    let [a, b, c, d] = frames;
+    for frame in [b, d, a, c] {
+        allocator.free(frame);
+    }
+    assert_eq!(allocator.free_frames(), free_before);
+    assert_eq!(allocator.node_count(), nodes_before);
+
+    let [source, destination] = [(); 2].map(|_| allocator.allocate().unwrap());
+    let bytes = |frame: &Frame| {
+        let pointer = DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<u8>();
+        // This is safe because the frames were just allocated
+        unsafe { core::slice::from_raw_parts_mut(pointer, FRAME_SIZE) }
+    };
+    bytes(&source).iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+    // This is safe because the frames were just allocated
+    unsafe { copy_frame(&destination, &source) };
+    assert!(bytes(&destination).iter().enumerate().all(|(i, &byte)| byte == i as u8));
+    unsafe { memzero_frame(&destination) };
+    assert!(bytes(&destination).iter().all(|&byte| byte == 0));
+    allocator.free(source);
+    allocator.free(destination);
+}
@@ -0,0 +1,139 @@
+//! A copy of the kernel log in RAM that survives warm reboots, so the log of a boot that hung can be read after the reset.
+//! The region is at the end of the largest usable region, which is at the same place every boot as long as the memory map
+//! doesn't change. It holds a header and a ring of log bytes. If a valid header is found at boot, the previous boot's log is
+//! printed before the region is reset for this boot.
+//! `pstore=<SIZE>` on the command line sets the size of the region, `pstore=0` turns it off.
+
+use core::mem::size_of;
+use core::ops::Range;
+
+use limine::{MemmapEntry, MemoryMapEntryType, NonNullPtr};
+use spin::Mutex;
+
+use crate::{cmdline, log, DEBUG_SERIAL_PORT};
+
+const DEFAULT_SIZE: u64 = 64 * 1024;
+const MAGIC: u64 = u64::from_le_bytes(*b"REXPSTOR");
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// The size of the ring after the header.
+    capacity: u64,
+    /// The number of bytes ever written, the ring position is this modulo the capacity.
+    written: u64,
+    /// Catches a header that was partly overwritten.
+    check: u64,
+}
+
+impl Header {
+    fn check_value(&self) -> u64 {
+        self.magic ^ self.capacity.rotate_left(17) ^ self.written.rotate_left(41)
+    }
+}
+
+struct Pstore {
+    header: *mut Header,
+    ring: *mut u8,
+}
+
+// This is safe because the region is only accessed with the lock held
+unsafe impl Send for Pstore {}
+
+static PSTORE: Mutex<Option<Pstore>> = Mutex::new(None);
+
+impl Pstore {
+    fn write(&mut self, bytes: &[u8]) {
+        // This is safe because the header and ring are in the reserved region, which nothing else uses
+        unsafe {
+            let header = &mut *self.header;
+            for &byte in bytes {
+                self.ring.add((header.written % header.capacity) as usize).write_volatile(byte);
+                header.written += 1;
+            }
+            header.check = header.check_value();
+        }
+    }
+}
+
+/// Picks the region at the end of the largest usable region and returns it, so the frame allocator and the buddy zone leave it
+/// alone. Prints the previous boot's log if the region holds one, then starts recording this boot's log.
+pub fn reserve(memory_map: &[NonNullPtr<MemmapEntry>], physical_memory_offset: u64) -> Option<Range<u64>> {
+    let size = cmdline::option("pstore")
+        .and_then(cmdline::parse_size)
+        .unwrap_or(DEFAULT_SIZE)
+        & !0xFFF;
+    if size <= size_of::<Header>() as u64 {
+        return None;
+    }
+    let region = memory_map
+        .iter()
+        .filter(|entry| entry.typ == MemoryMapEntryType::Usable)
+        .max_by_key(|entry| entry.len)?;
+    let end = (region.base + region.len) & !0xFFF;
+    // leave most of the region to the frame allocator
+    if size > region.len / 8 {
+        return None;
+    }
+    let start = end - size;
+    let header = (start + physical_memory_offset) as *mut Header;
+    let ring = (start + physical_memory_offset + size_of::<Header>() as u64) as *mut u8;
+    let capacity = size - size_of::<Header>() as u64;
+
+    // This is safe because the region is usable memory that nothing has been given yet
+    unsafe {
+        let previous = &*header;
+        if previous.magic == MAGIC && previous.capacity == capacity && previous.check == previous.check_value() {
+            print_previous(ring, capacity, previous.written);
+        }
+        header.write(Header {
+            magic: MAGIC,
+            capacity,
+            written: 0,
+            check: 0,
+        });
+        (*header).check = (*header).check_value();
+    }
+    *PSTORE.lock() = Some(Pstore { header, ring });
+    log!("pstore: recording the log at {:#x}-{:#x}", start, end);
+    Some(start..end)
+}
+
+/// Prints the log a previous boot left in the ring.
+/// Safety: `ring` must point to `capacity` bytes.
+unsafe fn print_previous(ring: *const u8, capacity: u64, written: u64) {
+    log!("pstore: log of the previous boot ({} bytes written):", written);
+    let mut serial_port = DEBUG_SERIAL_PORT.lock();
+    let start = written.saturating_sub(capacity);
+    for position in start..written {
+        serial_port.send(ring.add((position % capacity) as usize).read_volatile());
+    }
+    drop(serial_port);
+    log!("pstore: end of the previous boot's log");
+}
+
+/// Appends to the recorded log without waiting for the lock, for the panic handler.
+/// Safety: nothing else may be writing to the region, which is the case once the other CPUs have been stopped (a write this CPU
+/// was interrupted in is abandoned).
+pub unsafe fn write_unlocked(bytes: &[u8]) {
+    if PSTORE.is_locked() {
+        PSTORE.force_unlock();
+    }
+    write(bytes);
+}
+
+/// Appends to the recorded log if there is a region and nothing else is writing to it, for code that can't wait for the lock.
+pub fn try_write(bytes: &[u8]) {
+    if let Some(mut pstore) = PSTORE.try_lock() {
+        if let Some(pstore) = pstore.as_mut() {
+            pstore.write(bytes);
+        }
+    }
+}
+
+/// Appends to the recorded log, if there is a region. Called by the logger with the serial port locked.
+pub fn write(bytes: &[u8]) {
+    if let Some(pstore) = PSTORE.lock().as_mut() {
+        pstore.write(bytes);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::delay::{mdelay, poll_until};
+use crate::device;
+use crate::x64::idt::Idtr;
+use crate::x64::port::{inb, outb};
+use crate::{log, ACPI_TABLES};
+
+/// The keyboard controller's command/status port.
+const PS2_COMMAND_PORT: u16 = 0x64;
+/// Set in the status register while the controller has not consumed the last byte written to it.
+const PS2_STATUS_INPUT_FULL: u8 = 1 << 1;
+/// Pulses the CPU reset line.
+const PS2_COMMAND_RESET: u8 = 0xFE;
+
+/// How long to wait for each method to take effect before trying the next one, in milliseconds.
+const RESET_WAIT_MS: u64 = 500;
+/// How long to wait for the keyboard controller to accept a command, in microseconds.
+const PS2_READY_TIMEOUT_US: u64 = 100_000;
+
+/// A way of resetting the system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootMethod {
+    /// Writes the reset value to the reset register described by the FADT.
+    AcpiResetRegister,
+    /// Pulses the reset line through the 8042 keyboard controller.
+    KeyboardController,
+    /// Loads an empty IDT and causes an exception, which triple faults. This always works.
+    TripleFault,
+}
+
+/// The order `reboot()` tries reset methods in.
+pub const DEFAULT_REBOOT_METHODS: [RebootMethod; 3] = [
+    RebootMethod::AcpiResetRegister,
+    RebootMethod::KeyboardController,
+    RebootMethod::TripleFault,
+];
+
+/// Resets the system, trying each method in `DEFAULT_REBOOT_METHODS` until one works.
+pub fn reboot() -> ! {
+    reboot_with(&DEFAULT_REBOOT_METHODS)
+}
+
+/// Resets the system, trying each of `methods` in order and falling back to a triple fault if none of them work.
+pub fn reboot_with(methods: &[RebootMethod]) -> ! {
+    device::shutdown_all();
+    for &method in methods {
+        log!("reboot: trying {:?}", method);
+        let attempted = match method {
+            RebootMethod::AcpiResetRegister => acpi_reset(),
+            RebootMethod::KeyboardController => keyboard_controller_reset(),
+            RebootMethod::TripleFault => triple_fault(),
+        };
+        if attempted {
+            mdelay(RESET_WAIT_MS);
+            log!("reboot: {:?} did not reset the system", method);
+        } else {
+            log!("reboot: {:?} is not supported", method);
+        }
+    }
+    triple_fault();
+    unreachable!("triple fault did not reset the system");
+}
+
+/// Writes the ACPI reset value to the reset register, returns false if the FADT does not describe a usable reset register.
+fn acpi_reset() -> bool {
+    let Some(fadt) = ACPI_TABLES.get().and_then(|tables| tables.fadt()) else {
+        return false;
+    };
+    match fadt.reset_register() {
+        Some((reset_register, reset_value)) if reset_register.is_accessible() => {
+            // This is safe because we are resetting the system
+            unsafe { reset_register.write(reset_value as u64) };
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Pulses the reset line through the keyboard controller, returns false if the FADT says there is no keyboard controller.
+fn keyboard_controller_reset() -> bool {
+    let has_controller = ACPI_TABLES
+        .get()
+        .and_then(|tables| tables.fadt())
+        .map_or(true, |fadt| fadt.has_ps2_controller());
+    if !has_controller {
+        return false;
+    }
+    unsafe {
+        // wait (for a bounded time) for the controller to be ready to accept a command
+        poll_until(PS2_READY_TIMEOUT_US, || inb(PS2_COMMAND_PORT) & PS2_STATUS_INPUT_FULL == 0);
+        outb(PS2_COMMAND_PORT, PS2_COMMAND_RESET);
+    }
+    true
+}
+
+/// Loads an empty IDT and triggers a breakpoint, since the breakpoint can't be delivered the CPU triple faults and resets.
+fn triple_fault() -> bool {
+    let idtr = Idtr { size: 0, base: 0 };
+    unsafe {
+        idtr.load();
+        core::arch::asm!("int3");
+    }
+    false
+}
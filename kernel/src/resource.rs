@@ -0,0 +1,150 @@
+//! A record of which driver owns which I/O ports and MMIO ranges, so two drivers can't both program the same hardware.
+//! Drivers claim a range before they touch it. A claim that overlaps an existing one fails and names the owner of that one,
+//! so a conflict (a UART used both as a serial device and as the debug console, the PIT's ports used by two timers) is reported
+//! instead of the drivers silently undoing each other's settings. Claims last until they are released.
+//! Nothing enforces the claims, they only help as far as drivers make them. The shell's `resources` command prints the map.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Mutex;
+
+/// The most ranges that can be claimed at once.
+const MAX_RESOURCES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResourceKind {
+    Io,
+    Mmio,
+}
+
+/// A claimed range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resource {
+    pub kind: ResourceKind,
+    pub start: u64,
+    /// The length in ports or bytes.
+    pub length: u64,
+    pub owner: &'static str,
+}
+
+impl Resource {
+    fn end(&self) -> u64 {
+        self.start + self.length
+    }
+
+    fn overlaps(&self, other: &Resource) -> bool {
+        self.kind == other.kind && self.start < other.end() && other.start < self.end()
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ResourceKind::Io => write!(f, "io   {:#06x}-{:#06x}", self.start, self.end() - 1)?,
+            ResourceKind::Mmio => write!(f, "mmio {:#014x}-{:#014x}", self.start, self.end() - 1)?,
+        }
+        write!(f, " {}", self.owner)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceError {
+    /// The range overlaps this claim.
+    Conflict(Resource),
+    /// The range is empty or runs past the end of its address space.
+    InvalidRange,
+    /// There are already `MAX_RESOURCES` claims.
+    TooMany,
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceError::Conflict(existing) => write!(f, "already claimed: {}", existing),
+            ResourceError::InvalidRange => write!(f, "invalid range"),
+            ResourceError::TooMany => write!(f, "too many claims"),
+        }
+    }
+}
+
+const NO_RESOURCE: Option<Resource> = None;
+static RESOURCES: Mutex<[Option<Resource>; MAX_RESOURCES]> = Mutex::new([NO_RESOURCE; MAX_RESOURCES]);
+
+fn claim(resource: Resource, limit: u64) -> Result<(), ResourceError> {
+    if resource.length == 0 || resource.start.checked_add(resource.length).map_or(true, |end| end > limit) {
+        return Err(ResourceError::InvalidRange);
+    }
+    let mut resources = RESOURCES.lock();
+    if let Some(existing) = resources.iter().flatten().find(|existing| existing.overlaps(&resource)) {
+        return Err(ResourceError::Conflict(*existing));
+    }
+    let slot = resources.iter_mut().find(|slot| slot.is_none()).ok_or(ResourceError::TooMany)?;
+    *slot = Some(resource);
+    Ok(())
+}
+
+/// Claims `length` I/O ports from `start` for `owner`.
+pub fn claim_io(start: u16, length: u16, owner: &'static str) -> Result<(), ResourceError> {
+    let resource = Resource {
+        kind: ResourceKind::Io,
+        start: start as u64,
+        length: length as u64,
+        owner,
+    };
+    claim(resource, u16::MAX as u64 + 1)
+}
+
+/// Claims `length` bytes of MMIO at the physical address `start` for `owner`.
+pub fn claim_mmio(start: u64, length: u64, owner: &'static str) -> Result<(), ResourceError> {
+    let resource = Resource {
+        kind: ResourceKind::Mmio,
+        start,
+        length,
+        owner,
+    };
+    claim(resource, u64::MAX)
+}
+
+/// Releases the claim of `kind` that starts at `start`, returns whether there was one.
+pub fn release(kind: ResourceKind, start: u64) -> bool {
+    let mut resources = RESOURCES.lock();
+    match resources
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|resource| resource.kind == kind && resource.start == start))
+    {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Gets the claimed ranges, I/O ports first, in address order.
+pub fn resources() -> Vec<Resource> {
+    let mut resources: Vec<Resource> = RESOURCES.lock().iter().flatten().copied().collect();
+    resources.sort_unstable_by_key(|resource| (resource.kind, resource.start));
+    resources
+}
+
+/// Checks that overlapping claims are refused with the existing owner, and that adjacent claims and releasing work.
+pub fn self_check() {
+    // ports at the top of the I/O space, which no device here uses
+    assert_eq!(claim_io(0xFFF0, 8, "test a"), Ok(()));
+    let Err(ResourceError::Conflict(existing)) = claim_io(0xFFF7, 2, "test b") else {
+        panic!("an overlapping claim succeeded");
+    };
+    assert_eq!((existing.owner, existing.start), ("test a", 0xFFF0));
+    assert_eq!(claim_io(0xFFF8, 8, "test b"), Ok(()));
+    assert_eq!(claim_io(0xFFF8, 9, "test c"), Err(ResourceError::InvalidRange));
+    assert_eq!(claim_io(0xFFF0, 0, "test c"), Err(ResourceError::InvalidRange));
+    // the same numbers in the other address space don't conflict
+    assert_eq!(claim_mmio(0xFFF0, 8, "test c"), Ok(()));
+
+    assert!(release(ResourceKind::Io, 0xFFF0));
+    assert!(release(ResourceKind::Io, 0xFFF8));
+    assert!(release(ResourceKind::Mmio, 0xFFF0));
+    assert!(!release(ResourceKind::Io, 0xFFF0));
+    assert!(resources().iter().all(|resource| !resource.owner.starts_with("test")));
+}
@@ -0,0 +1,240 @@
+use core::fmt::Write;
+
+/// The maximum length of a line, further input is ignored.
+pub const MAX_LINE_LENGTH: usize = 128;
+/// The number of lines kept in the history.
+const HISTORY_LENGTH: usize = 16;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const ESCAPE: u8 = 0x1B;
+const TAB: u8 = b'\t';
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+
+/// A single line of input.
+#[derive(Clone, Copy)]
+struct Line {
+    bytes: [u8; MAX_LINE_LENGTH],
+    length: usize,
+}
+
+impl Line {
+    const fn empty() -> Self {
+        Self {
+            bytes: [0; MAX_LINE_LENGTH],
+            length: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // only printable ASCII is ever inserted into a line
+        core::str::from_utf8(&self.bytes[..self.length]).unwrap()
+    }
+}
+
+/// The state of an in-progress ANSI escape sequence.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    None,
+    /// Received ESC
+    Escape,
+    /// Received ESC [
+    ControlSequence,
+}
+
+/// A line discipline for a serial terminal supporting backspace, history (up and down arrows) and tab completion.
+pub struct LineEditor {
+    line: Line,
+    history: [Line; HISTORY_LENGTH],
+    /// The number of valid lines in `history`.
+    history_count: usize,
+    /// The index in `history` that the next line will be stored at.
+    history_next: usize,
+    /// How far back in the history the user has scrolled, 0 is the line currently being edited.
+    history_position: usize,
+    /// The line being edited before the user started scrolling through the history.
+    saved_line: Line,
+    escape_state: EscapeState,
+}
+
+/// What the caller should do after a byte is fed to the `LineEditor`.
+pub enum LineEvent<'a> {
+    /// The line is not finished yet.
+    Pending,
+    /// The user pressed enter.
+    Finished(&'a str),
+    /// The user pressed Ctrl-C.
+    Cancelled,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            line: Line::empty(),
+            history: [Line::empty(); HISTORY_LENGTH],
+            history_count: 0,
+            history_next: 0,
+            history_position: 0,
+            saved_line: Line::empty(),
+            escape_state: EscapeState::None,
+        }
+    }
+
+    /// Processes a byte of input, echoing to `output` as needed.
+    /// `completions` is the list of words that tab completion will choose from for the first word of the line.
+    pub fn feed<W: Write>(
+        &mut self,
+        byte: u8,
+        output: &mut W,
+        prompt: &str,
+        completions: &[&str],
+    ) -> LineEvent<'_> {
+        match self.escape_state {
+            EscapeState::Escape => {
+                self.escape_state = if byte == b'[' {
+                    EscapeState::ControlSequence
+                } else {
+                    EscapeState::None
+                };
+                return LineEvent::Pending;
+            }
+            EscapeState::ControlSequence => {
+                self.escape_state = EscapeState::None;
+                match byte {
+                    b'A' => self.history_up(),
+                    b'B' => self.history_down(),
+                    _ => return LineEvent::Pending,
+                }
+                self.redraw(output, prompt);
+                return LineEvent::Pending;
+            }
+            EscapeState::None => {}
+        }
+
+        match byte {
+            ESCAPE => self.escape_state = EscapeState::Escape,
+            b'\r' | b'\n' => {
+                let _ = write!(output, "\r\n");
+                self.push_history();
+                self.history_position = 0;
+                let length = self.line.length;
+                self.line.length = 0;
+                // the bytes are still in the buffer, only the length has been reset for the next line
+                return LineEvent::Finished(
+                    core::str::from_utf8(&self.line.bytes[..length]).unwrap(),
+                );
+            }
+            BACKSPACE | DELETE if self.line.length > 0 => {
+                self.line.length -= 1;
+                let _ = write!(output, "\x08 \x08");
+            }
+            CTRL_C => {
+                let _ = write!(output, "^C\r\n");
+                self.line.length = 0;
+                self.history_position = 0;
+                return LineEvent::Cancelled;
+            }
+            CTRL_U => {
+                self.line.length = 0;
+                self.redraw(output, prompt);
+            }
+            TAB => self.complete(output, prompt, completions),
+            b' '..=b'~' if self.line.length < MAX_LINE_LENGTH => {
+                self.line.bytes[self.line.length] = byte;
+                self.line.length += 1;
+                let _ = output.write_char(byte as char);
+            }
+            // ignore other control characters, and erasing or typing past either end of the line
+            _ => {}
+        }
+        LineEvent::Pending
+    }
+
+    /// Redraws the prompt and current line.
+    fn redraw<W: Write>(&self, output: &mut W, prompt: &str) {
+        // return to the start of the line and clear it
+        let _ = write!(output, "\r\x1b[K{}{}", prompt, self.line.as_str());
+    }
+
+    /// Completes the first word of the line from `completions`.
+    /// If there is a single match it is inserted, if there are several they are listed.
+    fn complete<W: Write>(&mut self, output: &mut W, prompt: &str, completions: &[&str]) {
+        let current_line = self.line;
+        let current = current_line.as_str();
+        // only the command name is completed
+        if current.contains(' ') {
+            return;
+        }
+        let mut matches = completions.iter().filter(|c| c.starts_with(current));
+        let (first, second) = (matches.next(), matches.next());
+        match (first, second) {
+            (Some(completion), None) => {
+                let completion = completion.as_bytes();
+                for &byte in &completion[self.line.length..] {
+                    if self.line.length < MAX_LINE_LENGTH {
+                        self.line.bytes[self.line.length] = byte;
+                        self.line.length += 1;
+                    }
+                }
+                if self.line.length < MAX_LINE_LENGTH {
+                    self.line.bytes[self.line.length] = b' ';
+                    self.line.length += 1;
+                }
+                self.redraw(output, prompt);
+            }
+            (Some(_), Some(_)) => {
+                let _ = write!(output, "\r\n");
+                for completion in completions.iter().filter(|c| c.starts_with(current)) {
+                    let _ = write!(output, "{}  ", completion);
+                }
+                let _ = write!(output, "\r\n");
+                self.redraw(output, prompt);
+            }
+            _ => {}
+        }
+    }
+
+    /// Stores the current line in the history, skipping empty lines and repeats of the previous line.
+    fn push_history(&mut self) {
+        if self.line.length == 0 {
+            return;
+        }
+        if self.history_count > 0 {
+            let previous = &self.history[(self.history_next + HISTORY_LENGTH - 1) % HISTORY_LENGTH];
+            if previous.as_str() == self.line.as_str() {
+                return;
+            }
+        }
+        self.history[self.history_next] = self.line;
+        self.history_next = (self.history_next + 1) % HISTORY_LENGTH;
+        self.history_count = usize::min(self.history_count + 1, HISTORY_LENGTH);
+    }
+
+    /// Replaces the current line with the previous line in the history.
+    fn history_up(&mut self) {
+        if self.history_position == self.history_count {
+            return;
+        }
+        if self.history_position == 0 {
+            self.saved_line = self.line;
+        }
+        self.history_position += 1;
+        self.line = self.history
+            [(self.history_next + HISTORY_LENGTH - self.history_position) % HISTORY_LENGTH];
+    }
+
+    /// Replaces the current line with the next line in the history, or the line being edited before scrolling.
+    fn history_down(&mut self) {
+        if self.history_position == 0 {
+            return;
+        }
+        self.history_position -= 1;
+        self.line = if self.history_position == 0 {
+            self.saved_line
+        } else {
+            self.history
+                [(self.history_next + HISTORY_LENGTH - self.history_position) % HISTORY_LENGTH]
+        };
+    }
+}
@@ -0,0 +1,605 @@
+use alloc::vec;
+use core::fmt::{self, Write};
+use core::str::SplitWhitespace;
+
+use crate::acpi::dump;
+use crate::block::{self, ramdisk};
+use crate::cmdline;
+use crate::device;
+use crate::drivers::{rtc, serial};
+use crate::event;
+use crate::irq;
+use crate::log;
+use crate::pci;
+use crate::reboot::{self, RebootMethod};
+use crate::resource;
+use crate::smp::{self, CpuState};
+use crate::sync::{self, MAX_CPUS};
+use crate::sysctl;
+use crate::time;
+use crate::trace;
+#[cfg(feature = "framebuffer")]
+use crate::tty;
+use crate::version;
+use crate::watch;
+use crate::x64::debug_registers::BreakpointCondition;
+use crate::x64::registers::get_cr3;
+use crate::xmodem::{self, SerialLink};
+use crate::{halt_loop, ACPI_TABLES, DEBUG_SERIAL_PORT};
+#[cfg(feature = "framebuffer")]
+use crate::DISPLAYS;
+
+use self::line_editor::{LineEditor, LineEvent};
+
+pub mod line_editor;
+
+const PROMPT: &str = "rex> ";
+
+/// A command that can be run from the debug shell.
+struct Command {
+    name: &'static str,
+    /// A short description of the command and its arguments, printed by `help`.
+    help: &'static str,
+    run: fn(&mut Console, SplitWhitespace) -> fmt::Result,
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "lists the available commands",
+        run: help,
+    },
+    Command {
+        name: "acpi",
+        help: "acpi list | acpi dump <SIGNATURE> | acpi dumpall: prints discovered ACPI tables",
+        run: acpi,
+    },
+    #[cfg(feature = "framebuffer")]
+    Command {
+        name: "display",
+        help: "display list | display console <N>: lists displays or selects the one the console draws on",
+        run: display,
+    },
+    #[cfg(feature = "framebuffer")]
+    Command {
+        name: "tty",
+        help: "tty write <TEXT>: writes to the console terminal, with \\e for escape",
+        run: tty,
+    },
+    Command {
+        name: "cpu",
+        help: "cpu list | cpu park <ID> | cpu unpark <ID>: lists CPUs or takes an AP offline and back",
+        run: cpu,
+    },
+    Command {
+        name: "devices",
+        help: "prints the device tree",
+        run: devices,
+    },
+    Command {
+        name: "pci",
+        help: "pci list | pci aer | pci rescan: lists PCI devices and their extended capabilities, reports AER errors or looks for new devices",
+        run: pci,
+    },
+    Command {
+        name: "block",
+        help: "block list | block read <DEVICE> <BLOCK> | block ramdisk <SIZE>: lists block devices, dumps a block or creates a RAM disk",
+        run: block,
+    },
+    Command {
+        name: "xmodem",
+        help: "xmodem log | xmodem block <DEVICE> <START> <COUNT>: sends the recent log or disk blocks with XMODEM",
+        run: xmodem,
+    },
+    Command {
+        name: "vm",
+        help: "vm stats | vm dump user|kernel: counts the current address space's pages and paging structures or lists its mappings",
+        run: vm,
+    },
+    Command {
+        name: "watch",
+        help: "watch add <ADDRESS> [1|2|4|8] [w|rw] | watch list | watch del <N>: dumps the registers whenever an address is written or read",
+        run: watch,
+    },
+    Command {
+        name: "trace",
+        help: "trace start | trace stop | trace dump: records IRQs, syscalls and lock contention per CPU, or prints the records",
+        run: trace,
+    },
+    Command {
+        name: "uname",
+        help: "prints the kernel version, the commit it was built from and its features",
+        run: uname,
+    },
+    Command {
+        name: "date",
+        help: "prints the date and time from the RTC, and the time since boot",
+        run: date,
+    },
+    Command {
+        name: "sysctl",
+        help: "sysctl [NAME | NAME=VALUE]: lists the kernel tunables, or prints or sets one",
+        run: sysctl,
+    },
+    Command {
+        name: "resources",
+        help: "prints the I/O ports and MMIO ranges drivers have claimed",
+        run: resources,
+    },
+    Command {
+        name: "irq",
+        help: "prints the interrupt counts of each vector, in total and per CPU",
+        run: irq,
+    },
+    Command {
+        name: "events",
+        help: "prints the recent system events",
+        run: events,
+    },
+    Command {
+        name: "reboot",
+        help: "reboot [acpi|kbd|triple]: resets the system, optionally with a specific method",
+        run: reboot,
+    },
+    Command {
+        name: "halt",
+        help: "halts the system",
+        run: halt,
+    },
+];
+
+/// Writes to the debug serial port, locking it for each write so other code can still log while the shell is running.
+/// Line feeds are translated to CRLF since terminal emulators put the serial line in raw mode.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut serial_port = DEBUG_SERIAL_PORT.lock();
+        for (i, line) in s.split('\n').enumerate() {
+            if i != 0 {
+                serial_port.write_str("\r\n")?;
+            }
+            serial_port.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the debug shell on the debug serial port, forever.
+pub fn run() -> ! {
+    let mut console = Console;
+    let mut line_editor = LineEditor::new();
+    let mut command_names = [""; COMMANDS.len()];
+    for (name, command) in command_names.iter_mut().zip(COMMANDS) {
+        *name = command.name;
+    }
+
+    let _ = write!(console, "{}", PROMPT);
+    loop {
+        // Waiting for input is idle time
+        sync::rcu::quiescent_state();
+        let byte = serial::receive();
+        match line_editor.feed(byte, &mut console, PROMPT, &command_names) {
+            LineEvent::Pending => continue,
+            LineEvent::Finished(line) => execute(&mut console, line),
+            LineEvent::Cancelled => {}
+        }
+        let _ = write!(console, "{}", PROMPT);
+    }
+}
+
+/// Runs a single line of input.
+fn execute(console: &mut Console, line: &str) {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => {
+            let _ = (command.run)(console, words);
+        }
+        None => {
+            let _ = writeln!(console, "unknown command: {}", name);
+        }
+    }
+}
+
+fn help(console: &mut Console, _: SplitWhitespace) -> fmt::Result {
+    for command in COMMANDS {
+        writeln!(console, "{:8} {}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn acpi(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    let Some(tables) = ACPI_TABLES.get() else {
+        return writeln!(console, "ACPI tables have not been discovered");
+    };
+    match (args.next(), args.next()) {
+        (Some("list"), None) => dump::dump_all(console, tables.xsdt(), false),
+        (Some("dumpall"), None) => dump::dump_all(console, tables.xsdt(), true),
+        (Some("dump"), Some(signature)) => {
+            let Ok(signature) = <[u8; 4]>::try_from(signature.as_bytes()) else {
+                return writeln!(console, "table signatures are 4 characters long");
+            };
+            match tables.table(signature) {
+                Some(header) => dump::dump_table(console, header),
+                None => writeln!(console, "table not found"),
+            }
+        }
+        _ => writeln!(console, "usage: acpi list | acpi dump <SIGNATURE> | acpi dumpall"),
+    }
+}
+
+#[cfg(feature = "framebuffer")]
+fn display(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    let Some(displays) = DISPLAYS.get() else {
+        return writeln!(console, "no displays have been set up");
+    };
+    match (args.next(), args.next()) {
+        (Some("list"), None) => {
+            for (i, display) in displays.iter().enumerate() {
+                let surface = display.surface().lock();
+                let marker = if i == displays.console_index() { "*" } else { " " };
+                writeln!(
+                    console,
+                    "{}{}: framebuffer {}, {}x{}",
+                    marker,
+                    i,
+                    display.framebuffer_index(),
+                    surface.width(),
+                    surface.height()
+                )?;
+            }
+            Ok(())
+        }
+        (Some("console"), Some(index)) => {
+            let Ok(index) = index.parse() else {
+                return writeln!(console, "invalid display number");
+            };
+            match displays.select_console(index) {
+                Ok(()) => Ok(()),
+                Err(_) => writeln!(console, "no display {}", index),
+            }
+        }
+        _ => writeln!(console, "usage: display list | display console <N>"),
+    }
+}
+
+#[cfg(feature = "framebuffer")]
+fn tty(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    if args.next() != Some("write") {
+        return writeln!(console, "usage: tty write <TEXT>");
+    }
+    for (i, word) in args.enumerate() {
+        if i != 0 {
+            tty::write(b" ");
+        }
+        for (j, part) in word.split("\\e").enumerate() {
+            if j != 0 {
+                tty::write(b"\x1b");
+            }
+            tty::write(part.as_bytes());
+        }
+    }
+    tty::write(b"\n");
+    Ok(())
+}
+
+fn cpu(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    let result = match (args.next(), args.next().map(str::parse::<usize>)) {
+        (Some("list"), None) => {
+            for cpu in 0..MAX_CPUS {
+                let state = smp::cpu_state(cpu);
+                if state != CpuState::Offline {
+                    writeln!(console, "{:4} {:?}", cpu, state)?;
+                }
+            }
+            return Ok(());
+        }
+        (Some("park"), Some(Ok(cpu))) => smp::park(cpu),
+        (Some("unpark"), Some(Ok(cpu))) => smp::unpark(cpu),
+        _ => return writeln!(console, "usage: cpu list | cpu park <ID> | cpu unpark <ID>"),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(error) => writeln!(console, "failed: {:?}", error),
+    }
+}
+
+fn pci(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    let mut pci = pci::PCI.lock();
+    match (args.next(), args.next()) {
+        (Some("list"), None) => {
+            for device in pci.devices() {
+                write!(
+                    console,
+                    "{} {:04x}:{:04x} class {:02x}:{:02x}.{:02x}",
+                    device.address(),
+                    device.vendor_id(),
+                    device.device_id(),
+                    device.class(),
+                    device.subclass(),
+                    device.prog_if()
+                )?;
+                let capabilities = device.extended_capabilities();
+                if capabilities.aer.is_some() {
+                    write!(console, " aer")?;
+                }
+                if let Some(serial_number) = capabilities.serial_number {
+                    write!(console, " serial={:016x}", serial_number)?;
+                }
+                if let Some(sriov) = capabilities.sriov {
+                    write!(console, " sriov={}/{}", sriov.num_vfs, sriov.total_vfs)?;
+                }
+                writeln!(console)?;
+            }
+            Ok(())
+        }
+        (Some("aer"), None) => {
+            for device in pci.devices() {
+                if let Some(status) = device.aer_status() {
+                    writeln!(
+                        console,
+                        "{} uncorrectable {:?}, correctable {:?}",
+                        device.address(),
+                        status.uncorrectable,
+                        status.correctable
+                    )?;
+                }
+            }
+            Ok(())
+        }
+        (Some("rescan"), None) => {
+            let (added, removed) = pci.rescan();
+            writeln!(console, "{} devices added, {} removed", added, removed)
+        }
+        _ => writeln!(console, "usage: pci list | pci aer | pci rescan"),
+    }
+}
+
+fn block(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    match (args.next(), args.next(), args.next().map(str::parse::<u64>)) {
+        (Some("ramdisk"), Some(size), None) => {
+            let Some(size) = cmdline::parse_size(size) else {
+                return writeln!(console, "invalid size");
+            };
+            match ramdisk::create(size) {
+                Ok(name) => writeln!(console, "created {}", name),
+                Err(error) => writeln!(console, "failed: {:?}", error),
+            }
+        }
+        (Some("list"), None, None) => {
+            let mut result = Ok(());
+            block::for_each(|registered| {
+                let device = registered.device.lock();
+                result = result.and(writeln!(
+                    console,
+                    "{:8} {} blocks of {} bytes",
+                    registered.name,
+                    device.block_count(),
+                    device.block_size()
+                ));
+            });
+            result
+        }
+        (Some("read"), Some(name), Some(Ok(start))) => {
+            let Some(device) = block::find(name) else {
+                return writeln!(console, "no block device {}", name);
+            };
+            let mut device = device.lock();
+            let mut buffer = [0; 4096];
+            let Some(buffer) = buffer.get_mut(..device.block_size()) else {
+                return writeln!(console, "blocks are too large to dump");
+            };
+            match device.read_blocks(start, buffer) {
+                Ok(()) => dump::hexdump(console, buffer),
+                Err(error) => writeln!(console, "failed: {:?}", error),
+            }
+        }
+        _ => writeln!(console, "usage: block list | block read <DEVICE> <BLOCK> | block ramdisk <SIZE>"),
+    }
+}
+
+fn xmodem(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    /// Bounds what `xmodem block` reads into memory at once.
+    const MAX_BLOCK_BYTES: u64 = 16 * 1024 * 1024;
+
+    let data = match (args.next(), args.next(), args.next().map(str::parse::<u64>), args.next().map(str::parse::<u64>)) {
+        (Some("log"), None, None, None) => log::recent(),
+        (Some("block"), Some(name), Some(Ok(start)), Some(Ok(count))) => {
+            let Some(device) = block::find(name) else {
+                return writeln!(console, "no block device {}", name);
+            };
+            let mut device = device.lock();
+            let length = count * device.block_size() as u64;
+            if length > MAX_BLOCK_BYTES {
+                return writeln!(console, "at most {} bytes can be sent at once", MAX_BLOCK_BYTES);
+            }
+            let mut data = vec![0; length as usize];
+            if let Err(error) = device.read_blocks(start, &mut data) {
+                return writeln!(console, "failed: {:?}", error);
+            }
+            data
+        }
+        _ => return writeln!(console, "usage: xmodem log | xmodem block <DEVICE> <START> <COUNT>"),
+    };
+    writeln!(console, "sending {} bytes, start the XMODEM receiver", data.len())?;
+    let result = xmodem::send(&mut SerialLink::new(), &data);
+    match result {
+        Ok(()) => writeln!(console, "sent"),
+        Err(error) => writeln!(console, "failed: {:?}", error),
+    }
+}
+
+fn devices(console: &mut Console, _: SplitWhitespace) -> fmt::Result {
+    let mut result = Ok(());
+    device::walk(|_, device, depth| {
+        let driver = if device.driver.is_some() { " (driver)" } else { "" };
+        result = result.and(writeln!(
+            console,
+            "{:indent$}{} [{:?}]{}",
+            "",
+            device.name,
+            device.bus,
+            driver,
+            indent = depth * 2
+        ));
+    });
+    result
+}
+
+fn vm(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    let cr3 = get_cr3();
+    let pml4 = cr3.pml4();
+    match (args.next(), args.next()) {
+        (Some("stats"), None) => {
+            for (half, entries) in [("user", 0..256), ("kernel", 256..512)] {
+                let stats = pml4.stats(entries);
+                writeln!(
+                    console,
+                    "{:6} {} KiB in {} 4KiB, {} 2MiB and {} 1GiB pages, {} pdpts, {} page directories, {} page tables",
+                    half,
+                    stats.mapped_bytes() / 1024,
+                    stats.pages_4kb,
+                    stats.pages_2mb,
+                    stats.pages_1gb,
+                    stats.pdpts,
+                    stats.page_directories,
+                    stats.page_tables
+                )?;
+            }
+            Ok(())
+        }
+        (Some("dump"), Some("user")) => pml4.dump(0..256, console),
+        (Some("dump"), Some("kernel")) => pml4.dump(256..512, console),
+        _ => writeln!(console, "usage: vm stats | vm dump user|kernel"),
+    }
+}
+
+fn watch(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    const USAGE: &str = "usage: watch add <ADDRESS> [1|2|4|8] [w|rw] | watch list | watch del <N>";
+
+    match (args.next(), args.next()) {
+        (Some("add"), Some(address)) => {
+            let address = address.trim_start_matches("0x");
+            let (length, condition) = (args.next().unwrap_or("8"), args.next().unwrap_or("w"));
+            let condition = match condition {
+                "w" => BreakpointCondition::Write,
+                "rw" => BreakpointCondition::ReadWrite,
+                _ => return writeln!(console, "{}", USAGE),
+            };
+            let (Ok(address), Ok(length)) = (u64::from_str_radix(address, 16), length.parse()) else {
+                return writeln!(console, "{}", USAGE);
+            };
+            match watch::add(address, length, condition) {
+                Ok(index) => writeln!(console, "added watch {}", index),
+                Err(error) => writeln!(console, "failed: {:?}", error),
+            }
+        }
+        (Some("list"), None) => {
+            for (index, watch) in watch::list().iter().enumerate() {
+                if let Some(watch) = watch {
+                    writeln!(console, "{} {:#x} {} bytes {:?}", index, watch.address, watch.length, watch.condition)?;
+                }
+            }
+            writeln!(console, "{} hits", watch::hits())
+        }
+        (Some("del"), Some(index)) => {
+            let Ok(index) = index.parse() else {
+                return writeln!(console, "{}", USAGE);
+            };
+            match watch::remove(index) {
+                Ok(()) => Ok(()),
+                Err(error) => writeln!(console, "failed: {:?}", error),
+            }
+        }
+        _ => writeln!(console, "{}", USAGE),
+    }
+}
+
+fn trace(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    match args.next() {
+        Some("start") => trace::start(),
+        Some("stop") => trace::stop(),
+        Some("dump") => return trace::dump(console),
+        _ => return writeln!(console, "usage: trace start | trace stop | trace dump"),
+    }
+    Ok(())
+}
+
+fn uname(console: &mut Console, _: SplitWhitespace) -> fmt::Result {
+    writeln!(console, "{}", version::BUILD_INFO)
+}
+
+fn date(console: &mut Console, _: SplitWhitespace) -> fmt::Result {
+    match rtc::read() {
+        Ok(now) => writeln!(console, "{}", now)?,
+        Err(error) => writeln!(console, "can't read the RTC: {:?}", error)?,
+    }
+    let uptime = time::uptime();
+    writeln!(console, "up {}.{:03}s ({})", uptime.as_secs(), uptime.subsec_millis(), time::source_name())
+}
+
+fn sysctl(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    match args.next() {
+        None => {
+            for tunable in sysctl::tunables().into_iter().flatten() {
+                writeln!(console, "{} = {}", tunable.name(), tunable)?;
+            }
+            Ok(())
+        }
+        Some(argument) => match argument.split_once('=') {
+            Some((name, value)) => match sysctl::set(name, value) {
+                Ok(()) => Ok(()),
+                Err(error) => writeln!(console, "failed: {:?}", error),
+            },
+            None => match sysctl::find(argument) {
+                Some(tunable) => writeln!(
+                    console,
+                    "{} = {} ({}, {:?})",
+                    tunable.name(),
+                    tunable,
+                    tunable.description(),
+                    tunable.kind()
+                ),
+                None => writeln!(console, "no tunable named {}", argument),
+            },
+        },
+    }
+}
+
+fn resources(console: &mut Console, _: SplitWhitespace) -> fmt::Result {
+    for resource in resource::resources() {
+        writeln!(console, "{}", resource)?;
+    }
+    Ok(())
+}
+
+fn irq(console: &mut Console, _: SplitWhitespace) -> fmt::Result {
+    irq::dump_irq_stats(console)
+}
+
+fn events(console: &mut Console, _: SplitWhitespace) -> fmt::Result {
+    let mut result = Ok(());
+    event::read_since(0, |sequence, event| {
+        result = result.and(writeln!(console, "{:6} {}", sequence, event));
+    });
+    result
+}
+
+fn reboot(console: &mut Console, mut args: SplitWhitespace) -> fmt::Result {
+    let method = match args.next() {
+        None => reboot::reboot(),
+        Some("acpi") => RebootMethod::AcpiResetRegister,
+        Some("kbd") => RebootMethod::KeyboardController,
+        Some("triple") => RebootMethod::TripleFault,
+        Some(_) => return writeln!(console, "usage: reboot [acpi|kbd|triple]"),
+    };
+    reboot::reboot_with(&[method]);
+}
+
+fn halt(console: &mut Console, _: SplitWhitespace) -> fmt::Result {
+    writeln!(console, "halting")?;
+    halt_loop();
+}
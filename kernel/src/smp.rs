@@ -0,0 +1,216 @@
+//! Starting the application processors (APs) and taking them offline and back.
+//! Limine starts every AP and leaves it waiting for a `goto_address`. Once started, an AP idles in `ap_main()` until it is asked to park,
+//! at which point it stops taking part in RCU and halts until it is unparked.
+//! Interrupts are all routed to the BSP and there are no run queues yet, so parking needs no draining.
+//! Idle and parked APs halt with interrupts enabled, and `wake()` sends them an IPI when there is something for them to do.
+
+use core::arch::asm;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use limine::SmpInfo;
+
+use crate::delay::poll_until;
+use crate::init::InitError;
+use crate::irq::{self, IrqReturn};
+use crate::sync::{current_cpu, rcu, MAX_CPUS};
+use crate::x64::vectors::{self, VectorClass};
+use crate::x64::{apic, gdt, tlb, tss};
+use crate::{log, watch, work, IDT, SMP_REQUEST};
+
+/// How long to wait for an AP to acknowledge being parked or unparked, in microseconds.
+const STATE_CHANGE_TIMEOUT_US: u64 = 100_000;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    /// The CPU doesn't exist or hasn't been started.
+    Offline = 0,
+    /// The CPU is the BSP, which can't be parked.
+    Bootstrap = 1,
+    Running = 2,
+    ParkRequested = 3,
+    Parked = 4,
+    UnparkRequested = 5,
+}
+
+impl CpuState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            1 => CpuState::Bootstrap,
+            2 => CpuState::Running,
+            3 => CpuState::ParkRequested,
+            4 => CpuState::Parked,
+            5 => CpuState::UnparkRequested,
+            _ => CpuState::Offline,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    /// There is no CPU with that id, or it was never started.
+    NoSuchCpu,
+    /// The BSP runs everything else and can't be parked.
+    IsBootstrapProcessor,
+    /// The CPU is not in a state where the request makes sense (for example parking a parked CPU).
+    WrongState(CpuState),
+    /// The CPU did not respond in time.
+    Timeout,
+}
+
+/// The state of each CPU, indexed by local APIC id.
+static CPU_STATES: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(CpuState::Offline as u8) }; MAX_CPUS];
+
+/// The vector of the IPI that wakes a halted CPU, 0 until `init_smp` allocates it.
+static WAKE_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+/// Gets the state of the CPU with local APIC id `cpu`.
+pub fn cpu_state(cpu: usize) -> CpuState {
+    match CPU_STATES.get(cpu) {
+        Some(state) => CpuState::from_u8(state.load(Ordering::SeqCst)),
+        None => CpuState::Offline,
+    }
+}
+
+fn set_state(cpu: usize, state: CpuState) {
+    CPU_STATES[cpu].store(state as u8, Ordering::SeqCst);
+}
+
+/// Atomically changes the state of `cpu` from `from` to `to`, returns the state it was actually in on failure.
+fn transition(cpu: usize, from: CpuState, to: CpuState) -> Result<(), CpuState> {
+    CPU_STATES[cpu]
+        .compare_exchange(from as u8, to as u8, Ordering::SeqCst, Ordering::SeqCst)
+        .map(|_| ())
+        .map_err(CpuState::from_u8)
+}
+
+/// Wakes `cpu` if it is halted, so it checks whether its state or the watches changed. Does nothing for the current CPU.
+pub fn wake(cpu: usize) {
+    let vector = WAKE_VECTOR.load(Ordering::SeqCst);
+    if vector != 0 && cpu != current_cpu() && cpu_state(cpu) != CpuState::Offline {
+        apic::send_ipi(cpu as u32, vector);
+    }
+}
+
+/// Wakes every other CPU that has been started.
+pub fn wake_others() {
+    for cpu in 0..MAX_CPUS {
+        wake(cpu);
+    }
+}
+
+/// The wake IPI only has to interrupt the `hlt`, the woken CPU's loop does the rest.
+fn wake_interrupt(_vector: u8) -> IrqReturn {
+    IrqReturn::Handled
+}
+
+/// Starts every AP reported by the bootloader.
+pub fn init_smp() -> Result<(), InitError> {
+    let response = SMP_REQUEST
+        .get_response()
+        .as_ptr()
+        .ok_or(InitError::new("SMP response not received!"))?;
+    // This is safe because the response is only used here
+    let response = unsafe { &mut *response };
+    let bsp_lapic_id = response.bsp_lapic_id as usize;
+    set_state(bsp_lapic_id, CpuState::Bootstrap);
+    let vector = vectors::allocate(VectorClass::Ipi).ok_or(InitError::new("no free vector for the wake IPI"))?;
+    // the vector was just allocated, so nothing else handles it
+    let _ = irq::set_handler(vector, wake_interrupt);
+    irq::set_line_control(vector, &apic::IPI_LINE_CONTROL);
+    WAKE_VECTOR.store(vector, Ordering::SeqCst);
+    tlb::init_shootdown()?;
+
+    for cpu in response.cpus() {
+        let lapic_id = cpu.lapic_id as usize;
+        if lapic_id == bsp_lapic_id {
+            continue;
+        }
+        if lapic_id >= MAX_CPUS {
+            log!("smp: ignoring CPU with local APIC id {}", lapic_id);
+            continue;
+        }
+        let info = cpu.as_ptr();
+        // Limine polls goto_address and jumps there with a pointer to the SmpInfo, writing it starts the AP
+        unsafe {
+            (addr_of_mut!((*info).goto_address) as *mut u64).write_volatile(ap_entry as *const () as u64)
+        };
+        if !poll_until(STATE_CHANGE_TIMEOUT_US, || cpu_state(lapic_id) == CpuState::Running) {
+            log!("smp: CPU {} did not start", lapic_id);
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn ap_entry(_info: *const SmpInfo) -> ! {
+    gdt::load();
+    if let Err(error) = tss::load() {
+        panic!("smp: failed to allocate the IST stacks: {:?}", error);
+    }
+    irq::register_cpu();
+    // This is safe because the IDT is in a static and will never be moved
+    unsafe { IDT.lock().get_idtr().load() };
+    apic::enable_this_cpu();
+    rcu::register_cpu();
+    set_state(current_cpu(), CpuState::Running);
+    log!("smp: CPU online");
+    ap_main()
+}
+
+/// The idle loop of an AP.
+fn ap_main() -> ! {
+    let cpu = current_cpu();
+    loop {
+        rcu::quiescent_state();
+        watch::update_this_cpu();
+        work::run_pending();
+        // This is safe because `park_this_cpu` and `idle_halt` enable interrupts again, and the IDT is loaded
+        unsafe { asm!("cli") };
+        if cpu_state(cpu) == CpuState::ParkRequested {
+            park_this_cpu(cpu);
+            // This is safe because interrupts were enabled before the `cli`
+            unsafe { asm!("sti") };
+        } else {
+            work::idle_halt();
+        }
+    }
+}
+
+/// Halts until this CPU is asked to unpark, with interrupts enabled while halted so IPIs are still handled.
+/// Called with interrupts disabled, so a wake IPI after the check still ends the `hlt`.
+fn park_this_cpu(cpu: usize) {
+    rcu::unregister_cpu();
+    set_state(cpu, CpuState::Parked);
+    while cpu_state(cpu) != CpuState::UnparkRequested {
+        // This is safe because `sti` only takes effect after the `hlt`, and the interrupt is handled before the `cli`
+        unsafe { asm!("sti", "hlt", "cli") };
+    }
+    rcu::register_cpu();
+    set_state(cpu, CpuState::Running);
+}
+
+/// Takes the AP with local APIC id `cpu` offline, it is parked in a wait loop until `unpark()` is called.
+pub fn park(cpu: usize) -> Result<(), SmpError> {
+    change_state(cpu, CpuState::Running, CpuState::ParkRequested, CpuState::Parked)
+}
+
+/// Brings a parked AP back online.
+pub fn unpark(cpu: usize) -> Result<(), SmpError> {
+    change_state(cpu, CpuState::Parked, CpuState::UnparkRequested, CpuState::Running)
+}
+
+fn change_state(cpu: usize, from: CpuState, request: CpuState, target: CpuState) -> Result<(), SmpError> {
+    match cpu_state(cpu) {
+        CpuState::Offline => return Err(SmpError::NoSuchCpu),
+        CpuState::Bootstrap => return Err(SmpError::IsBootstrapProcessor),
+        _ => {}
+    }
+    transition(cpu, from, request).map_err(SmpError::WrongState)?;
+    wake(cpu);
+    if poll_until(STATE_CHANGE_TIMEOUT_US, || cpu_state(cpu) == target) {
+        Ok(())
+    } else {
+        Err(SmpError::Timeout)
+    }
+}
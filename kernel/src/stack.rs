@@ -0,0 +1,204 @@
+//! Kernel stacks with an unmapped guard page below them, so an overflow faults instead of silently corrupting memory.
+//! Stacks live in their own region, each in a fixed size slot with the stack at the top and everything below it unmapped.
+//! A fault on an unmapped page anywhere in the region is therefore a stack overflow, which the page fault handler reports.
+//! Each CPU also reserves an emergency stack when it starts, for the panic handler to switch to when the stack it panicked on
+//! can't be trusted and there may be no memory left to allocate another.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::memory::VirtualAddress;
+use crate::pmm::FrameAllocator;
+use crate::sync::{current_cpu, MAX_CPUS};
+use crate::x64::mmio::{MMIO_MAX_SIZE, MMIO_START};
+use crate::x64::page_table::PageFlags;
+use crate::x64::registers::get_cr3;
+use crate::FRAME_ALLOCATOR;
+
+/// The start of the region stacks are mapped in, after the MMIO region and in the same PML4 entry as the heap.
+pub const STACK_REGION_START: u64 = MMIO_START + MMIO_MAX_SIZE;
+pub const STACK_REGION_SIZE: u64 = 1 << 30;
+/// The space given to each stack, including its guard page.
+const SLOT_SIZE: u64 = 64 * 1024;
+/// The largest stack, which leaves at least one guard page in the slot.
+pub const MAX_STACK_PAGES: usize = (SLOT_SIZE / PAGE_SIZE) as usize - 1;
+
+const PAGE_SIZE: u64 = 0x1000;
+const EMERGENCY_STACK_PAGES: usize = 4;
+
+struct Slots {
+    /// The start of the first slot that has never been used.
+    next: u64,
+    /// Slots of stacks that were freed.
+    free: Vec<u64>,
+}
+
+static SLOTS: Mutex<Slots> = Mutex::new(Slots {
+    next: STACK_REGION_START,
+    free: Vec::new(),
+});
+
+/// The top of each CPU's emergency stack, 0 until it is reserved.
+static EMERGENCY_STACKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    TooLarge,
+    /// The stack region is full.
+    NoSlot,
+    OutOfMemory,
+}
+
+/// A kernel stack, unmapped and freed when dropped.
+pub struct KernelStack {
+    slot: u64,
+    pages: usize,
+}
+
+impl KernelStack {
+    /// Allocates a stack of `pages` 4KiB pages.
+    pub fn new(pages: usize) -> Result<Self, StackError> {
+        if pages == 0 || pages > MAX_STACK_PAGES {
+            return Err(StackError::TooLarge);
+        }
+        let slot = {
+            let mut slots = SLOTS.lock();
+            match slots.free.pop() {
+                Some(slot) => slot,
+                None if slots.next + SLOT_SIZE <= STACK_REGION_START + STACK_REGION_SIZE => {
+                    slots.next += SLOT_SIZE;
+                    slots.next - SLOT_SIZE
+                }
+                None => return Err(StackError::NoSlot),
+            }
+        };
+        // created empty so a failure part way through unmaps what was mapped
+        let mut stack = KernelStack { slot, pages: 0 };
+        let cr3 = get_cr3();
+        let pml4 = cr3.pml4();
+        for page in 0..pages {
+            let frame = FRAME_ALLOCATOR
+                .get()
+                .and_then(|allocator| allocator.lock().allocate())
+                .ok_or(StackError::OutOfMemory)?;
+            stack.pages += 1;
+            // Freed slots are unmapped, so nothing is mapped in the slot yet
+            pml4.map(frame, VirtualAddress::create(stack.top() - (page as u64 + 1) * PAGE_SIZE), PageFlags::KERNEL_DATA)
+                .unwrap();
+        }
+        Ok(stack)
+    }
+
+    /// Gets the number of the stack's slot, which identifies it until it is dropped.
+    pub fn id(&self) -> usize {
+        ((self.slot - STACK_REGION_START) / SLOT_SIZE) as usize
+    }
+
+    /// Gets the address just above the stack, the initial stack pointer.
+    pub fn top(&self) -> u64 {
+        self.slot + SLOT_SIZE
+    }
+
+    /// Gets the lowest mapped address of the stack.
+    pub fn bottom(&self) -> u64 {
+        self.top() - self.pages as u64 * PAGE_SIZE
+    }
+
+    /// Gets the address of the guard page right below the stack.
+    pub fn guard_page(&self) -> u64 {
+        self.bottom() - PAGE_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let cr3 = get_cr3();
+        let pml4 = cr3.pml4();
+        for page in (self.bottom()..self.top()).step_by(PAGE_SIZE as usize) {
+            let frame = pml4.unmap(VirtualAddress::create(page)).unwrap();
+            FRAME_ALLOCATOR.get().unwrap().lock().free(frame);
+        }
+        SLOTS.lock().free.push(self.slot);
+    }
+}
+
+/// Allocates this CPU's emergency stack, if it doesn't have one yet. It is never freed.
+pub fn reserve_emergency_stack() -> Result<(), StackError> {
+    let slot = &EMERGENCY_STACKS[current_cpu()];
+    if slot.load(Ordering::Relaxed) == 0 {
+        let stack = KernelStack::new(EMERGENCY_STACK_PAGES)?;
+        slot.store(stack.top(), Ordering::Relaxed);
+        core::mem::forget(stack);
+    }
+    Ok(())
+}
+
+/// Gets the top of `cpu`'s emergency stack, None if it hasn't reserved one.
+pub fn emergency_stack_top(cpu: usize) -> Option<u64> {
+    match EMERGENCY_STACKS[cpu].load(Ordering::Relaxed) {
+        0 => None,
+        top => Some(top),
+    }
+}
+
+/// Returns whether a page fault on a non-present page at `address` is a stack overflow.
+pub fn is_stack_overflow(address: u64) -> bool {
+    (STACK_REGION_START..STACK_REGION_START + STACK_REGION_SIZE).contains(&address)
+}
+
+/// A stack overflow found by `find_overflow()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackOverflow {
+    /// The `KernelStack::id()` of the stack that overflowed.
+    pub id: usize,
+    /// The lowest mapped address of the stack.
+    pub bottom: u64,
+    /// How far below the bottom of the stack the address was.
+    pub depth: u64,
+}
+
+/// Checks whether `address` (a stack pointer or a faulting address) is below the bottom of a kernel stack.
+/// Only reads the page tables, so it can be used from the double fault handler.
+pub fn find_overflow(address: u64) -> Option<StackOverflow> {
+    if !is_stack_overflow(address) {
+        return None;
+    }
+    let slot = address & !(SLOT_SIZE - 1);
+    let cr3 = get_cr3();
+    let pml4 = cr3.pml4();
+    // stacks are mapped down from the top of their slot, so the bottom is where the mapped pages stop
+    let mut bottom = slot + SLOT_SIZE;
+    while bottom > slot && pml4.translate(VirtualAddress::create(bottom - PAGE_SIZE)).is_some() {
+        bottom -= PAGE_SIZE;
+    }
+    (address < bottom && bottom < slot + SLOT_SIZE).then_some(StackOverflow {
+        id: ((slot - STACK_REGION_START) / SLOT_SIZE) as usize,
+        bottom,
+        depth: bottom - address,
+    })
+}
+
+/// Checks that a stack is mapped with an unmapped guard page below it, and that dropping it frees its frames.
+pub fn self_check() {
+    let free_before = FRAME_ALLOCATOR.get().unwrap().lock().free_frames();
+    let stack = KernelStack::new(4).unwrap();
+    assert_eq!(stack.top() - stack.bottom(), 4 * PAGE_SIZE);
+    // This is safe because the stack's pages were just mapped
+    unsafe {
+        ((stack.top() - 8) as *mut u64).write_volatile(0x1234);
+        (stack.bottom() as *mut u64).write_volatile(0x5678);
+    }
+    let cr3 = get_cr3();
+    let pml4 = cr3.pml4();
+    assert!(pml4.translate(VirtualAddress::create(stack.guard_page())).is_none());
+    assert!(is_stack_overflow(stack.guard_page()));
+    let overflow = find_overflow(stack.guard_page() + 8).unwrap();
+    assert_eq!((overflow.id, overflow.bottom, overflow.depth), (stack.id(), stack.bottom(), PAGE_SIZE - 8));
+    assert!(find_overflow(stack.bottom()).is_none());
+    assert_eq!(KernelStack::new(MAX_STACK_PAGES + 1).err(), Some(StackError::TooLarge));
+    drop(stack);
+    // the slot's paging structures are kept, so at most those frames are still in use
+    assert!(FRAME_ALLOCATOR.get().unwrap().lock().free_frames() + 2 >= free_before);
+}
@@ -0,0 +1,64 @@
+//! Values that are set once during boot and read everywhere afterwards, like the start of the direct map.
+//! Reading is a single acquire load, without the lock a `OnceCell` takes to check whether it is initialized. Setting never
+//! waits either: an interrupt handler that tries to set a value while it is being set gets an error instead of deadlocking,
+//! and readers see it as unset until it is finished.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const UNSET: u8 = 0;
+const SETTING: u8 = 1;
+const SET: u8 = 2;
+
+pub struct BootOnce<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// This is safe because the value is only written once, before `state` says it can be read
+unsafe impl<T: Send + Sync> Sync for BootOnce<T> {}
+
+impl<T> BootOnce<T> {
+    pub const fn new() -> Self {
+        BootOnce {
+            state: AtomicU8::new(UNSET),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Sets the value, or gives it back if a value was already set (or is being set).
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(UNSET, SETTING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(value);
+        }
+        // This is safe because winning the exchange makes this the only writer, and readers wait for SET
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(SET, Ordering::Release);
+        Ok(())
+    }
+
+    /// Gets the value, or None if it hasn't been set yet.
+    #[inline]
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == SET {
+            // This is safe because the value was written before the state became SET, and is never written again
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Drop for BootOnce<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == SET {
+            // This is safe because the value was set
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
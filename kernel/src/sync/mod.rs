@@ -0,0 +1,29 @@
+//! Synchronization primitives beyond the spinlocks provided by `spin`.
+
+pub mod boot_once;
+pub mod owned_mutex;
+pub mod preempt;
+pub mod rcu;
+pub mod seqlock;
+
+/// The most CPUs per-CPU state is kept for, CPUs are indexed by their initial APIC id.
+pub const MAX_CPUS: usize = 256;
+
+/// Gets the index of the CPU this runs on, for indexing per-CPU state.
+pub fn current_cpu() -> usize {
+    crate::x64::cpuid::get_initial_apic_id() as usize
+}
+
+/// Runs `f` with interrupts disabled on this CPU, for taking locks that interrupt handlers also take.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    use crate::x64::registers::{get_rflags, RFlags};
+
+    let enabled = get_rflags().contains(RFlags::interrupt_enable);
+    // This is safe because interrupts are enabled again below if they were enabled before
+    unsafe { core::arch::asm!("cli") };
+    let result = f();
+    if enabled {
+        unsafe { core::arch::asm!("sti") };
+    }
+    result
+}
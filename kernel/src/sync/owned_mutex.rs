@@ -0,0 +1,89 @@
+//! Spinlocks that remember which CPU holds them, for locks that are also taken from exception handlers.
+//! A handler that interrupted the holder can't wait for the lock, it would never be released, but it can wait for a holder on
+//! another CPU. `held_by_this_cpu()` tells the two apart.
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::{Mutex, MutexGuard};
+
+use super::current_cpu;
+
+/// `owner` when nobody holds the lock.
+const NO_OWNER: usize = usize::MAX;
+
+#[derive(Debug)]
+pub struct OwnedMutex<T> {
+    mutex: Mutex<T>,
+    owner: AtomicUsize,
+}
+
+pub struct OwnedMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    owner: &'a AtomicUsize,
+}
+
+impl<T> OwnedMutex<T> {
+    pub const fn new(value: T) -> Self {
+        OwnedMutex {
+            mutex: Mutex::new(value),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> OwnedMutexGuard<'_, T> {
+        self.owned(self.mutex.lock())
+    }
+
+    pub fn try_lock(&self) -> Option<OwnedMutexGuard<'_, T>> {
+        self.mutex.try_lock().map(|guard| self.owned(guard))
+    }
+
+    fn owned<'a>(&'a self, guard: MutexGuard<'a, T>) -> OwnedMutexGuard<'a, T> {
+        self.owner.store(current_cpu(), Ordering::Relaxed);
+        OwnedMutexGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    /// Returns whether this CPU holds the lock. Only this CPU sets or clears its own ownership, so the answer is exact.
+    pub fn held_by_this_cpu(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == current_cpu()
+    }
+}
+
+impl<T> Deref for OwnedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for OwnedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for OwnedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // cleared while the lock is still held, so this can't clear the next holder's ownership
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+
+/// Checks that ownership follows the guard.
+pub fn self_check() {
+    let mutex = OwnedMutex::new(0);
+    assert!(!mutex.held_by_this_cpu());
+    {
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(mutex.held_by_this_cpu());
+        assert!(mutex.try_lock().is_none());
+    }
+    assert!(!mutex.held_by_this_cpu());
+    assert_eq!(*mutex.try_lock().unwrap(), 1);
+}
@@ -0,0 +1,72 @@
+//! Per-CPU preemption control.
+//! Code that must not be moved to another CPU or interleaved with another task on the same CPU (per-CPU data, RCU readers, spinlock holders)
+//! disables preemption, and a scheduler tick must only switch tasks when `preemptible()` is true.
+//! There is no scheduler yet, so for now the count catches blocking where it would deadlock: RCU readers disable preemption,
+//! and `might_sleep()` and `rcu::synchronize()` check it.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::x64::registers::{get_rflags, RFlags};
+
+use super::{current_cpu, MAX_CPUS};
+
+/// How many times preemption has been disabled on each CPU.
+static PREEMPT_COUNT: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Disables preemption until `preempt_enable()` is called. Calls nest.
+pub fn preempt_disable() {
+    PREEMPT_COUNT[current_cpu()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Undoes a `preempt_disable()`.
+pub fn preempt_enable() {
+    let previous = PREEMPT_COUNT[current_cpu()].fetch_sub(1, Ordering::Relaxed);
+    debug_assert!(previous != 0, "preempt_enable() without preempt_disable()");
+}
+
+/// Gets how many times preemption is disabled on the current CPU.
+pub fn preempt_count() -> u32 {
+    PREEMPT_COUNT[current_cpu()].load(Ordering::Relaxed)
+}
+
+/// Returns whether the current task may be preempted, which requires preemption to be enabled and interrupts to be enabled.
+pub fn preemptible() -> bool {
+    preempt_count() == 0 && get_rflags().contains(RFlags::interrupt_enable)
+}
+
+/// Disables preemption for as long as it exists.
+pub struct PreemptGuard {
+    // The guard must be dropped on the CPU that created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        preempt_disable();
+        PreemptGuard {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
+
+/// Checks that the caller may sleep, call this at the start of functions that can block.
+/// Sleeping in atomic context (with preemption or interrupts disabled) would deadlock or corrupt per-CPU state.
+#[track_caller]
+pub fn might_sleep() {
+    debug_assert!(
+        preempt_count() == 0,
+        "sleeping function called with preemption disabled (count {})",
+        preempt_count()
+    );
+    debug_assert!(
+        get_rflags().contains(RFlags::interrupt_enable),
+        "sleeping function called with interrupts disabled"
+    );
+}
@@ -0,0 +1,191 @@
+//! Read-copy-update for read-mostly data.
+//! Readers access the current version of the data without taking a lock, writers publish a new version and then wait for a grace period
+//! (every CPU passing through a quiescent state) before the old version can be reused.
+//!
+//! The kernel is not preemptive, so a CPU is in a quiescent state whenever it isn't inside a read-side critical section.
+//! CPUs report this by calling `quiescent_state()` when they switch context or go idle.
+//! A CPU that halts until an interrupt calls `enter_idle()` first, so grace periods don't wait for it to wake up. While it is
+//! idle only its read-side critical sections (in interrupt handlers) hold up a grace period.
+
+use alloc::boxed::Box;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::preempt::{preempt_count, PreemptGuard};
+use super::{current_cpu, MAX_CPUS};
+
+/// The quiescent epoch of an idle CPU, which is past every grace period.
+const IDLE: u64 = u64::MAX;
+
+/// The current grace period, incremented at the start of each `synchronize()`.
+static EPOCH: AtomicU64 = AtomicU64::new(1);
+
+/// Set for CPUs that take part in grace periods.
+static ONLINE: [AtomicU64; MAX_CPUS / 64] = [const { AtomicU64::new(0) }; MAX_CPUS / 64];
+
+/// The last epoch each CPU reported a quiescent state in, or `IDLE`.
+static QUIESCENT_EPOCH: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// How deeply each CPU is nested in read-side critical sections.
+static READ_NESTING: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Serializes writers so each `synchronize()` waits for a whole grace period of its own.
+static WRITER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Makes the current CPU take part in grace periods, it must report quiescent states from now on.
+pub fn register_cpu() {
+    let cpu = current_cpu();
+    QUIESCENT_EPOCH[cpu].store(EPOCH.load(Ordering::SeqCst), Ordering::SeqCst);
+    ONLINE[cpu / 64].fetch_or(1 << (cpu % 64), Ordering::SeqCst);
+}
+
+/// Stops the current CPU taking part in grace periods, for example before it is parked.
+pub fn unregister_cpu() {
+    let cpu = current_cpu();
+    ONLINE[cpu / 64].fetch_and(!(1 << (cpu % 64)), Ordering::SeqCst);
+}
+
+fn is_online(cpu: usize) -> bool {
+    ONLINE[cpu / 64].load(Ordering::SeqCst) & (1 << (cpu % 64)) != 0
+}
+
+/// Reports that the current CPU holds no references to RCU protected data.
+/// Called when switching context and when idle, this also ends `enter_idle()`.
+pub fn quiescent_state() {
+    let cpu = current_cpu();
+    debug_assert_eq!(
+        READ_NESTING[cpu].load(Ordering::Relaxed),
+        0,
+        "quiescent state reported inside an RCU read-side critical section"
+    );
+    QUIESCENT_EPOCH[cpu].store(EPOCH.load(Ordering::SeqCst), Ordering::SeqCst);
+}
+
+/// Marks the current CPU idle until it next calls `quiescent_state()`. Called right before halting, so a grace period only
+/// waits for the read-side critical sections of interrupt handlers that run on it in the meantime.
+pub fn enter_idle() {
+    let cpu = current_cpu();
+    debug_assert_eq!(
+        READ_NESTING[cpu].load(Ordering::Relaxed),
+        0,
+        "idle inside an RCU read-side critical section"
+    );
+    QUIESCENT_EPOCH[cpu].store(IDLE, Ordering::SeqCst);
+}
+
+/// Returns whether `cpu` has passed through a quiescent state since `epoch` started.
+fn has_passed(cpu: usize, epoch: u64) -> bool {
+    match QUIESCENT_EPOCH[cpu].load(Ordering::SeqCst) {
+        // A reader that enters after this saw no nesting also sees the version published before the grace period
+        IDLE => READ_NESTING[cpu].load(Ordering::SeqCst) == 0,
+        quiescent => quiescent >= epoch,
+    }
+}
+
+/// Marks a read-side critical section, references obtained through `Rcu::read()` live as long as the guard.
+/// Preemption is disabled inside the critical section, so a reader is never switched out while holding references.
+pub struct ReadGuard {
+    _preempt: PreemptGuard,
+}
+
+/// Enters a read-side critical section.
+pub fn read_lock() -> ReadGuard {
+    let preempt = PreemptGuard::new();
+    READ_NESTING[current_cpu()].fetch_add(1, Ordering::SeqCst);
+    ReadGuard { _preempt: preempt }
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        READ_NESTING[current_cpu()].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits until every online CPU has passed through a quiescent state, after which nothing can still reference data that was unpublished before the call.
+/// Must not be called inside a read-side critical section.
+pub fn synchronize() {
+    // a reader disables preemption, so this catches waiting for a grace period that can't end
+    debug_assert_eq!(preempt_count(), 0, "synchronize() with preemption disabled");
+    let _writer = WRITER_LOCK.lock();
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst) + 1;
+    // Not being in a read-side critical section is a quiescent state for the calling CPU
+    quiescent_state();
+    for cpu in 0..MAX_CPUS {
+        while is_online(cpu) && !has_passed(cpu, epoch) {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// A pointer to data that is read without locking and replaced with read-copy-update.
+/// It owns each version, the version a replacement unpublishes is handed back after the grace period.
+pub struct Rcu<T: 'static> {
+    /// The current version, from `Box::into_raw`, or null if there is none yet.
+    current: AtomicPtr<T>,
+}
+
+impl<T: Sync + 'static> Rcu<T> {
+    pub fn new(initial: Box<T>) -> Self {
+        Rcu {
+            current: AtomicPtr::new(Box::into_raw(initial)),
+        }
+    }
+
+    /// Creates an `Rcu` without a version, for statics.
+    pub const fn empty() -> Self {
+        Rcu {
+            current: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Gets the current version of the data, it stays valid for as long as the read-side critical section.
+    pub fn read<'a>(&self, _guard: &'a ReadGuard) -> Option<&'a T> {
+        // This is safe because old versions are only freed or reused after a grace period, which can't end while `_guard` exists
+        unsafe { self.current.load(Ordering::SeqCst).as_ref() }
+    }
+
+    /// Publishes a new version of the data and waits for a grace period.
+    /// The old version is returned once no reader can reference it, so it can be modified and reused or dropped.
+    /// Writers have to be serialized by the caller, so one doesn't lose another's changes.
+    pub fn replace(&self, new: Box<T>) -> Option<Box<T>> {
+        let old = self.current.swap(Box::into_raw(new), Ordering::SeqCst);
+        synchronize();
+        // This is safe because the old version came from `Box::into_raw` and the grace period has ended all references to it
+        (!old.is_null()).then(|| unsafe { Box::from_raw(old) })
+    }
+}
+
+impl<T: 'static> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let current = *self.current.get_mut();
+        if !current.is_null() {
+            // This is safe because `&mut self` means there are no readers left, and the version came from `Box::into_raw`
+            drop(unsafe { Box::from_raw(current) });
+        }
+    }
+}
+
+/// Checks that readers see the published version and replacements hand back the old one.
+pub fn self_check() {
+    let rcu: Rcu<u64> = Rcu::empty();
+    assert!(rcu.read(&read_lock()).is_none());
+    assert!(rcu.replace(Box::new(1)).is_none());
+    {
+        let guard = read_lock();
+        assert_eq!(rcu.read(&guard), Some(&1));
+        // nested read-side critical sections
+        assert_eq!(rcu.read(&read_lock()), Some(&1));
+    }
+    assert_eq!(rcu.replace(Box::new(2)).as_deref(), Some(&1));
+    assert_eq!(rcu.read(&read_lock()), Some(&2));
+    // an idle CPU doesn't hold up a grace period, but one of its readers does until it leaves the critical section
+    enter_idle();
+    assert!(has_passed(current_cpu(), EPOCH.load(Ordering::SeqCst) + 1));
+    let guard = read_lock();
+    assert!(!has_passed(current_cpu(), EPOCH.load(Ordering::SeqCst) + 1));
+    drop(guard);
+    quiescent_state();
+    assert!(!has_passed(current_cpu(), EPOCH.load(Ordering::SeqCst) + 1));
+}
@@ -0,0 +1,69 @@
+//! Sequence locks, for small data that is read far more often than it is written, like timekeeping parameters.
+//! Readers never block and never make writers wait: they copy the data and retry if a write happened while they were copying.
+//! Writers are serialized with a spinlock, and bump a sequence number before and after writing so readers can tell.
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+pub struct SeqLock<T: Copy> {
+    /// Odd while a write is in progress
+    sequence: AtomicU64,
+    data: UnsafeCell<T>,
+    writer: Mutex<()>,
+}
+
+// This is safe because readers only copy the data out and retry if it changed underneath them, and writers are serialized
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> Self {
+        SeqLock {
+            sequence: AtomicU64::new(0),
+            data: UnsafeCell::new(data),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Reads a consistent copy of the data, retrying while writes are in progress.
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                spin_loop();
+                continue;
+            }
+            // The copy may be torn if a writer is running, that is detected below and the copy thrown away.
+            // It is volatile so it isn't assumed to be stable across the retry.
+            let data = unsafe { self.data.get().read_volatile() };
+            // keep the copy from moving after the second sequence read
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return data;
+            }
+        }
+    }
+
+    /// Replaces the data.
+    pub fn write(&self, data: T) {
+        self.update(|old| *old = data);
+    }
+
+    /// Changes the data in place with `f`. Readers retry until `f` is finished.
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let _writer = self.writer.lock();
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        // keep the data writes from moving before the sequence becomes odd
+        fence(Ordering::Release);
+        // This is safe because writers are serialized by the lock, and readers discard anything they read during this
+        f(unsafe { &mut *self.data.get() });
+        self.sequence.fetch_add(1, Ordering::Release);
+    }
+
+    /// Gets the sequence number, which changes by 2 with each write.
+    pub fn sequence(&self) -> u64 {
+        self.sequence.load(Ordering::Acquire)
+    }
+}
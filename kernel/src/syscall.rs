@@ -0,0 +1,219 @@
+//! System calls. There is only the `int 0x80` entry so far; a `syscall`/`sysret` entry can share the dispatch table.
+//! The calling convention matches the x86-64 `syscall` one: the number is in rax, the arguments are in rdi, rsi, rdx, r10, r8
+//! and r9, and the result is returned in rax. Negative results are errors.
+
+use core::arch::global_asm;
+use core::mem::MaybeUninit;
+
+use crate::memory::VirtualAddress;
+use crate::sync::current_cpu;
+use crate::trace::{self, TraceEvent};
+use crate::version::{self, Utsname};
+use crate::x64::gdt::SegmentSelector;
+use crate::x64::idt::{GateOptions, Idt, InterruptStackFrame};
+use crate::x64::vectors;
+use crate::log;
+
+/// The vector of the `int` syscall gate.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// The syscall number doesn't exist.
+pub const ENOSYS: i64 = -38;
+/// An argument is invalid.
+pub const EINVAL: i64 = -22;
+
+/// The longest message `SYS_LOG` accepts.
+const MAX_LOG_LENGTH: usize = 256;
+
+/// The registers saved by the syscall entry, in the order they are on the stack.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub interrupt_stack_frame: InterruptStackFrame,
+}
+
+impl SyscallFrame {
+    /// Gets the six syscall arguments.
+    pub fn arguments(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// Returns whether the syscall was made from user mode, going by the privilege level of the caller's code segment.
+    pub fn is_from_user(&self) -> bool {
+        self.interrupt_stack_frame.code_segment & 3 == 3
+    }
+}
+
+type SyscallHandler = fn(&SyscallFrame) -> i64;
+
+/// The syscalls, indexed by number.
+static SYSCALLS: &[SyscallHandler] = &[sys_log, sys_cpu_id, sys_uname];
+
+pub const SYS_LOG: u64 = 0;
+pub const SYS_CPU_ID: u64 = 1;
+pub const SYS_UNAME: u64 = 2;
+
+/// Checks that a buffer is non-null and canonical, and for a caller in user mode that it is in the lower half, the most that can
+/// be checked without a user address space.
+fn is_valid_buffer(pointer: u64, length: u64, from_user: bool) -> bool {
+    if pointer == 0 {
+        return false;
+    }
+    // a buffer that is non canonical, or crosses the hole between the halves, would fault in the kernel
+    let start = VirtualAddress::try_create(pointer);
+    let end = VirtualAddress::try_create(pointer.wrapping_add(length.saturating_sub(1)));
+    let (Ok(start), Ok(end)) = (start, end) else {
+        return false;
+    };
+    if from_user {
+        // user mode must not get the kernel to read or write kernel memory for it
+        start.is_user() && end.is_user()
+    } else {
+        start.is_user() == end.is_user()
+    }
+}
+
+/// Writes a message to the kernel log: (pointer, length). Returns the length.
+/// There is no user address space yet, so the pointer isn't checked beyond what `is_valid_buffer` does.
+fn sys_log(frame: &SyscallFrame) -> i64 {
+    let [pointer, length, ..] = frame.arguments();
+    if length as usize > MAX_LOG_LENGTH || !is_valid_buffer(pointer, length, frame.is_from_user()) {
+        return EINVAL;
+    }
+    // This is safe as long as the caller passed a valid buffer, which is all that can be checked without user memory
+    let bytes = unsafe { core::slice::from_raw_parts(pointer as *const u8, length as usize) };
+    match core::str::from_utf8(bytes) {
+        Ok(message) => {
+            log!("syscall log: {}", message);
+            length as i64
+        }
+        Err(_) => EINVAL,
+    }
+}
+
+/// Returns the id of the calling CPU.
+fn sys_cpu_id(_: &SyscallFrame) -> i64 {
+    current_cpu() as i64
+}
+
+/// Fills in a `Utsname` identifying the kernel: (pointer). Returns 0.
+fn sys_uname(frame: &SyscallFrame) -> i64 {
+    let [pointer, ..] = frame.arguments();
+    if pointer % core::mem::align_of::<Utsname>() as u64 != 0
+        || !is_valid_buffer(pointer, core::mem::size_of::<Utsname>() as u64, frame.is_from_user())
+    {
+        return EINVAL;
+    }
+    // This is safe as long as the caller passed a valid buffer, which is all that can be checked without user memory
+    unsafe { (pointer as *mut Utsname).write(version::utsname()) };
+    0
+}
+
+/// Runs the syscall described by `frame`, storing its result in rax. Called by every syscall entry.
+extern "C" fn dispatch(frame: &mut SyscallFrame) {
+    trace::record(TraceEvent::SyscallEntry, frame.rax);
+    let result = match SYSCALLS.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => ENOSYS,
+    };
+    frame.rax = result as u64;
+    trace::record(TraceEvent::SyscallExit, frame.rax);
+}
+
+// The `int 0x80` entry. It saves the registers that aren't preserved across calls, in the layout of `SyscallFrame`.
+// The CPU pushed 5 words on a 16 byte aligned stack, so after 9 more the stack is aligned for the call.
+global_asm!(
+    ".global syscall_interrupt_entry",
+    "syscall_interrupt_entry:",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rax",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "pop rax",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "iretq",
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn syscall_interrupt_entry();
+}
+
+/// Installs the `int 0x80` gate, with DPL 3 so user mode can use it.
+pub fn install(idt: &mut Idt, cs: SegmentSelector) {
+    assert!(vectors::reserve(SYSCALL_VECTOR), "syscall vector is already in use");
+    idt.set_handler(
+        SYSCALL_VECTOR,
+        syscall_interrupt_entry as *const () as u64,
+        cs,
+        GateOptions::interrupt().with_dpl(3),
+    );
+}
+
+/// Makes a syscall through the `int 0x80` gate.
+pub fn int_syscall(number: u64, arguments: [u64; 6]) -> i64 {
+    let result: u64;
+    // This is safe because the entry preserves every register except rax
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            inlateout("rax") number => result,
+            in("rdi") arguments[0],
+            in("rsi") arguments[1],
+            in("rdx") arguments[2],
+            in("r10") arguments[3],
+            in("r8") arguments[4],
+            in("r9") arguments[5],
+        );
+    }
+    result as i64
+}
+
+/// Checks that syscalls made through the `int 0x80` gate reach their handlers and return their results.
+pub fn self_check() {
+    assert_eq!(int_syscall(SYS_CPU_ID, [0; 6]), current_cpu() as i64);
+    let message = "int 0x80 self check";
+    assert_eq!(
+        int_syscall(SYS_LOG, [message.as_ptr() as u64, message.len() as u64, 0, 0, 0, 0]),
+        message.len() as i64
+    );
+    assert_eq!(int_syscall(SYS_LOG, [0; 6]), EINVAL);
+    assert_eq!(int_syscall(SYS_LOG, [0x8000_0000_0000, 1, 0, 0, 0, 0]), EINVAL);
+    let mut utsname = MaybeUninit::<Utsname>::uninit();
+    assert_eq!(int_syscall(SYS_UNAME, [utsname.as_mut_ptr() as u64, 0, 0, 0, 0, 0]), 0);
+    // This is safe because the syscall succeeded, so it filled in the structure
+    let utsname = unsafe { utsname.assume_init() };
+    assert!(utsname.sysname.starts_with(b"rex\0"));
+    assert_eq!(utsname, version::utsname());
+    assert_eq!(int_syscall(SYS_UNAME, [0; 6]), EINVAL);
+    assert_eq!(int_syscall(u64::MAX, [0; 6]), ENOSYS);
+    // the self check runs in the kernel, so user mode's stricter buffer check is tried directly
+    assert!(is_valid_buffer(message.as_ptr() as u64, message.len() as u64, false));
+    assert!(!is_valid_buffer(message.as_ptr() as u64, message.len() as u64, true));
+    assert!(is_valid_buffer(0x1000, 0x1000, true));
+    assert!(!is_valid_buffer(0x7FFF_FFFF_F000, 0x2000, true));
+}
@@ -0,0 +1,223 @@
+//! Kernel parameters that can be changed at runtime, called tunables: named values of a known type with limits.
+//! A tunable is a static in the module it controls, which reads it with `get()`. `register` adds it to the registry and takes
+//! the value given on the kernel command line under its name, if there is one, as its initial value. After that it can be set
+//! by name with `set`, which is what the shell's `sysctl` command does (and what a procfs-style file will do once there is a
+//! filesystem and userspace).
+//! A tunable can have an `apply` function, called with each value set at runtime before it is stored, for settings that have
+//! to be pushed to hardware. The command line value isn't applied that way, the module uses it when it starts.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::{cmdline, log};
+
+/// The most tunables that can be registered.
+const MAX_TUNABLES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunableKind {
+    /// `on`/`off`, stored as 1 or 0.
+    Bool,
+    Integer { min: u64, max: u64 },
+    /// A size in bytes, which can be written with a `K`, `M` or `G` suffix.
+    Size { min: u64, max: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysctlError {
+    NotFound,
+    /// The value can't be parsed as the tunable's kind.
+    Invalid,
+    OutOfRange,
+    /// The tunable's `apply` function refused the value.
+    Rejected,
+    /// There are already `MAX_TUNABLES` tunables.
+    TooMany,
+    /// A tunable with the same name is registered.
+    AlreadyRegistered,
+}
+
+/// Applies a new value of a tunable, returns Err to keep the old one.
+pub type ApplyFn = fn(u64) -> Result<(), ()>;
+
+pub struct Tunable {
+    name: &'static str,
+    description: &'static str,
+    kind: TunableKind,
+    value: AtomicU64,
+    apply: Option<ApplyFn>,
+}
+
+impl Tunable {
+    pub const fn bool(name: &'static str, description: &'static str, default: bool) -> Self {
+        Tunable {
+            name,
+            description,
+            kind: TunableKind::Bool,
+            value: AtomicU64::new(default as u64),
+            apply: None,
+        }
+    }
+
+    pub const fn integer(name: &'static str, description: &'static str, default: u64, min: u64, max: u64) -> Self {
+        Tunable {
+            name,
+            description,
+            kind: TunableKind::Integer { min, max },
+            value: AtomicU64::new(default),
+            apply: None,
+        }
+    }
+
+    pub const fn size(name: &'static str, description: &'static str, default: u64, min: u64, max: u64) -> Self {
+        Tunable {
+            name,
+            description,
+            kind: TunableKind::Size { min, max },
+            value: AtomicU64::new(default),
+            apply: None,
+        }
+    }
+
+    /// Sets the function that applies values set at runtime.
+    pub const fn with_apply(mut self, apply: ApplyFn) -> Self {
+        self.apply = Some(apply);
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+
+    pub fn kind(&self) -> TunableKind {
+        self.kind
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn get_bool(&self) -> bool {
+        self.get() != 0
+    }
+
+    /// Parses a value written for this tunable. A bare command line option is "", which is `on` for a bool.
+    pub fn parse(&self, text: &str) -> Result<u64, SysctlError> {
+        let value = match self.kind {
+            TunableKind::Bool => match text {
+                "" | "1" | "on" | "true" => 1,
+                "0" | "off" | "false" => 0,
+                _ => return Err(SysctlError::Invalid),
+            },
+            TunableKind::Integer { .. } => text.parse().map_err(|_| SysctlError::Invalid)?,
+            TunableKind::Size { .. } => cmdline::parse_size(text).ok_or(SysctlError::Invalid)?,
+        };
+        match self.kind {
+            TunableKind::Integer { min, max } | TunableKind::Size { min, max } if !(min..=max).contains(&value) => {
+                Err(SysctlError::OutOfRange)
+            }
+            _ => Ok(value),
+        }
+    }
+
+    /// Parses and applies a new value.
+    pub fn set(&self, text: &str) -> Result<(), SysctlError> {
+        let value = self.parse(text)?;
+        if let Some(apply) = self.apply {
+            apply(value).map_err(|_| SysctlError::Rejected)?;
+        }
+        self.value.store(value, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Tunable {
+    /// Formats the value the way it would be written.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            TunableKind::Bool if self.get_bool() => write!(f, "on"),
+            TunableKind::Bool => write!(f, "off"),
+            TunableKind::Integer { .. } => write!(f, "{}", self.get()),
+            TunableKind::Size { .. } => {
+                let value = self.get();
+                match value.trailing_zeros() {
+                    _ if value == 0 => write!(f, "0"),
+                    30.. => write!(f, "{}G", value >> 30),
+                    20.. => write!(f, "{}M", value >> 20),
+                    10.. => write!(f, "{}K", value >> 10),
+                    _ => write!(f, "{}", value),
+                }
+            }
+        }
+    }
+}
+
+const NO_TUNABLE: Option<&'static Tunable> = None;
+static TUNABLES: Mutex<[Option<&'static Tunable>; MAX_TUNABLES]> = Mutex::new([NO_TUNABLE; MAX_TUNABLES]);
+
+/// Adds a tunable to the registry and sets it to its value on the command line, if it has a valid one there.
+pub fn register(tunable: &'static Tunable) -> Result<(), SysctlError> {
+    {
+        let mut tunables = TUNABLES.lock();
+        if tunables.iter().flatten().any(|other| other.name == tunable.name) {
+            return Err(SysctlError::AlreadyRegistered);
+        }
+        let slot = tunables.iter_mut().find(|slot| slot.is_none()).ok_or(SysctlError::TooMany)?;
+        *slot = Some(tunable);
+    }
+    if let Some(text) = cmdline::option(tunable.name) {
+        match tunable.parse(text) {
+            Ok(value) => tunable.value.store(value, Ordering::Relaxed),
+            Err(error) => log!("sysctl: ignoring {}={} on the command line: {:?}", tunable.name, text, error),
+        }
+    }
+    Ok(())
+}
+
+/// Finds a registered tunable by name.
+pub fn find(name: &str) -> Option<&'static Tunable> {
+    TUNABLES.lock().iter().flatten().find(|tunable| tunable.name == name).copied()
+}
+
+/// Sets the tunable `name` to the value written in `text`.
+pub fn set(name: &str, text: &str) -> Result<(), SysctlError> {
+    find(name).ok_or(SysctlError::NotFound)?.set(text)
+}
+
+/// Gets the registered tunables.
+pub fn tunables() -> [Option<&'static Tunable>; MAX_TUNABLES] {
+    *TUNABLES.lock()
+}
+
+/// Checks parsing, limits, formatting and `apply`, on tunables that aren't registered.
+pub fn self_check() {
+    let flag = Tunable::bool("test.flag", "", false);
+    assert_eq!(flag.set(""), Ok(()));
+    assert!(flag.get_bool());
+    assert_eq!(flag.set("maybe"), Err(SysctlError::Invalid));
+    assert!(flag.get_bool());
+
+    let limit = Tunable::integer("test.limit", "", 5, 1, 10);
+    assert_eq!(limit.set("11"), Err(SysctlError::OutOfRange));
+    assert_eq!(limit.set("7"), Ok(()));
+    assert_eq!(limit.get(), 7);
+
+    let size = Tunable::size("test.size", "", 0, 0, 1 << 30);
+    assert_eq!(size.set("64K"), Ok(()));
+    assert_eq!(size.get(), 64 << 10);
+    assert_eq!(alloc::format!("{}", size), "64K");
+
+    let even = Tunable::integer("test.even", "", 0, 0, 100).with_apply(|value| (value % 2 == 0).then_some(()).ok_or(()));
+    assert_eq!(even.set("3"), Err(SysctlError::Rejected));
+    assert_eq!(even.set("4"), Ok(()));
+    assert_eq!(even.get(), 4);
+
+    assert_eq!(set("test.missing", "1"), Err(SysctlError::NotFound));
+    assert!(find("log.timestamps").is_some());
+}
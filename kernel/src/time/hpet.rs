@@ -0,0 +1,117 @@
+//! The HPET's main counter as a clock source. It runs at 10 MHz or more and is read with a single memory access, but that
+//! access is still far slower than rdtsc, so an invariant TSC is preferred.
+//! The ACPI HPET table gives the address of the registers. Only the main counter is used, the HPET's timers stay disabled.
+
+use generic_once_cell::OnceCell;
+use spin::Mutex;
+
+use crate::acpi::bytes::{self, TableBytes};
+use crate::acpi_signature;
+use crate::log;
+use crate::memory::PhysicalAddress;
+use crate::resource::{self, ResourceKind};
+use crate::x64::mmio::{map_mmio, Mmio};
+use crate::ACPI_TABLES;
+
+use super::{ClockSource, ExtendedCounter};
+
+/// The length of the HPET table, up to the page protection field.
+const TABLE_LENGTH: usize = 56;
+/// The offsets of the address space and address of the registers' generic address structure.
+const ADDRESS_SPACE_OFFSET: usize = 40;
+const ADDRESS_OFFSET: usize = 44;
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+const REGISTERS_SIZE: u64 = 0x400;
+/// Register offsets.
+const CAPABILITIES: usize = 0x00;
+const CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xF0;
+
+/// The main counter is 64 bits, otherwise it is 32.
+const CAPABILITY_64_BIT_COUNTER: u64 = 1 << 13;
+/// Starts the main counter.
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+/// The longest period the specification allows, 100ns in femtoseconds.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+
+pub struct Hpet {
+    registers: Mmio,
+    /// The length of a tick in femtoseconds.
+    period_fs: u64,
+    /// Set for a 64 bit counter, which can't wrap in practice.
+    wide: bool,
+    counter: Mutex<ExtendedCounter>,
+}
+
+// This is safe because the main counter is only read, which any CPU can do at any time
+unsafe impl Sync for Hpet {}
+
+impl Hpet {
+    fn read_counter(&self) -> u64 {
+        if self.wide {
+            self.registers.read64(MAIN_COUNTER)
+        } else {
+            self.registers.read32(MAIN_COUNTER) as u64
+        }
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn read_ns(&self) -> u64 {
+        let ticks = if self.wide {
+            self.read_counter()
+        } else {
+            self.counter.lock().update(self.read_counter(), u32::MAX as u64)
+        };
+        (ticks as u128 * self.period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND) as u64
+    }
+}
+
+static HPET: OnceCell<Mutex<()>, Hpet> = OnceCell::new();
+
+/// Finds the HPET in the ACPI tables, maps its registers and starts its main counter. Returns None if there is no HPET or
+/// it isn't usable.
+pub fn find() -> Option<&'static Hpet> {
+    if let Some(hpet) = HPET.get() {
+        return Some(hpet);
+    }
+    let header = ACPI_TABLES.get()?.xsdt().get_table(acpi_signature!('H', 'P', 'E', 'T'))?;
+    // This is safe because `XSDT::get_table()` returns pointers to entire tables in the direct map
+    let table = bytes::table(unsafe { &*header }.bytes(), acpi_signature!('H', 'P', 'E', 'T'), TABLE_LENGTH).ok()?;
+    if table.u8_at(ADDRESS_SPACE_OFFSET)? != ADDRESS_SPACE_MEMORY {
+        return None;
+    }
+    let address = table.u64_at(ADDRESS_OFFSET)?;
+    if let Err(error) = resource::claim_mmio(address, REGISTERS_SIZE, "hpet") {
+        log!("hpet: can't claim the registers: {}", error);
+        return None;
+    }
+    // This is safe because the HPET table says these are the HPET's registers
+    let Some(registers) = (unsafe { map_mmio(PhysicalAddress::device(address), REGISTERS_SIZE) }) else {
+        resource::release(ResourceKind::Mmio, address);
+        return None;
+    };
+    let capabilities = registers.read64(CAPABILITIES);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        resource::release(ResourceKind::Mmio, address);
+        return None;
+    }
+    registers.write64(CONFIGURATION, registers.read64(CONFIGURATION) | CONFIGURATION_ENABLE);
+    Some(HPET.get_or_init(|| Hpet {
+        registers,
+        period_fs,
+        wide: capabilities & CAPABILITY_64_BIT_COUNTER != 0,
+        counter: Mutex::new(ExtendedCounter::default()),
+    }))
+}
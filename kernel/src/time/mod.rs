@@ -0,0 +1,181 @@
+//! Time since boot, from the best clock source the machine has.
+//! A clock source is a free running counter of known frequency read as nanoseconds. `init_clock` registers the ones that
+//! are present (the TSC, the HPET, the ACPI PM timer and the PIT) and picks the one with the highest rating, which is what
+//! `monotonic_ns()` and `uptime()` read from then on. Before that they read the TSC, which `delay` has calibrated.
+//! Drivers should take their timestamps from here rather than reading a timer of their own.
+//! The wall clock is the Unix time at boot, set from the RTC, plus the time since boot, so it never jumps once it is set.
+
+pub mod hpet;
+pub mod pit;
+pub mod pm_timer;
+pub mod tsc;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::delay::mdelay;
+use crate::init::InitError;
+use crate::log;
+use crate::sync::boot_once::BootOnce;
+
+/// The most clock sources that can be registered.
+const MAX_SOURCES: usize = 8;
+
+/// A counter that can be read as nanoseconds, which never go backwards.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// How good the source is, the highest rated one is used. An invariant TSC is 300, a source that is slow to read or
+    /// wraps quickly is lower.
+    fn rating(&self) -> u32;
+
+    /// Gets the nanoseconds since some point before the source was registered.
+    fn read_ns(&self) -> u64;
+}
+
+/// Extends a counter that wraps around to 64 bits, as long as it is read at least once per wrap.
+#[derive(Debug, Default)]
+struct ExtendedCounter {
+    last: u64,
+    total: u64,
+}
+
+impl ExtendedCounter {
+    /// Takes a new reading of the counter, which has the bits in `mask`, and returns the ticks since the first reading.
+    fn update(&mut self, counter: u64, mask: u64) -> u64 {
+        self.total += counter.wrapping_sub(self.last) & mask;
+        self.last = counter;
+        self.total
+    }
+}
+
+/// Converts `ticks` of a counter running at `frequency` Hz to nanoseconds.
+fn ticks_to_ns(ticks: u64, frequency: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / frequency as u128) as u64
+}
+
+const NO_SOURCE: Option<&'static dyn ClockSource> = None;
+static SOURCES: Mutex<[Option<&'static dyn ClockSource>; MAX_SOURCES]> = Mutex::new([NO_SOURCE; MAX_SOURCES]);
+
+/// The source `monotonic_ns()` reads, and where it started.
+struct Clock {
+    source: &'static dyn ClockSource,
+    /// The source's reading when it was picked.
+    source_start: u64,
+    /// The nanoseconds since boot when it was picked.
+    start: u64,
+}
+
+static CLOCK: BootOnce<Clock> = BootOnce::new();
+
+/// The Unix time at boot in nanoseconds, 0 until the wall clock is set.
+static BOOT_UNIX_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Adds a clock source, returns false if there are too many.
+pub fn register(source: &'static dyn ClockSource) -> bool {
+    let mut sources = SOURCES.lock();
+    match sources.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(source);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Gets the registered clock sources.
+pub fn sources() -> [Option<&'static dyn ClockSource>; MAX_SOURCES] {
+    *SOURCES.lock()
+}
+
+/// Registers the clock sources that are present and picks the best one. Must run after `delay` has calibrated the TSC.
+pub fn init_clock() -> Result<(), InitError> {
+    tsc::init();
+    register(&tsc::TSC);
+    if let Some(hpet) = hpet::find() {
+        register(hpet);
+    }
+    if let Some(pm_timer) = pm_timer::find() {
+        register(pm_timer);
+    }
+    if let Some(pit) = pit::start() {
+        register(pit);
+    }
+
+    let best = sources()
+        .into_iter()
+        .flatten()
+        .max_by_key(|source| source.rating())
+        .ok_or(InitError::new("no clock sources"))?;
+    for source in sources().into_iter().flatten() {
+        log!("time: clock source {} rated {}", source.name(), source.rating());
+    }
+    let start = monotonic_ns();
+    CLOCK
+        .set(Clock {
+            source: best,
+            source_start: best.read_ns(),
+            start,
+        })
+        .map_err(|_| InitError::new("the clock was already started"))?;
+    log!("time: using {}", best.name());
+    Ok(())
+}
+
+/// Gets the nanoseconds since boot. They never go backwards on one CPU.
+pub fn monotonic_ns() -> u64 {
+    match CLOCK.get() {
+        Some(clock) => clock.start + clock.source.read_ns().saturating_sub(clock.source_start),
+        None => tsc::now_ns(),
+    }
+}
+
+/// Gets the time since boot.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(monotonic_ns())
+}
+
+/// Sets the wall clock, given the seconds since the Unix epoch now.
+pub fn set_wall_clock(unix_seconds: u64) {
+    let unix_ns = unix_seconds.saturating_mul(1_000_000_000);
+    BOOT_UNIX_NS.store(unix_ns.saturating_sub(monotonic_ns()), Ordering::Relaxed);
+}
+
+/// Gets the time since the Unix epoch, None if the wall clock hasn't been set.
+pub fn wall_clock() -> Option<Duration> {
+    match BOOT_UNIX_NS.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(Duration::from_nanos(boot + monotonic_ns())),
+    }
+}
+
+/// Gets the name of the clock source in use.
+pub fn source_name() -> &'static str {
+    CLOCK.get().map_or("tsc", |clock| clock.source.name())
+}
+
+/// Checks that every clock source advances by about as much as a delay waits, and that the clock doesn't go backwards.
+pub fn self_check() {
+    tsc::self_check();
+    for source in sources().into_iter().flatten() {
+        let start = source.read_ns();
+        mdelay(10);
+        let elapsed = source.read_ns() - start;
+        // the delay is only as accurate as the TSC calibration
+        assert!(
+            (5_000_000..100_000_000).contains(&elapsed),
+            "clock source {} measured a 10ms delay as {}ns",
+            source.name(),
+            elapsed
+        );
+    }
+    let before = monotonic_ns();
+    assert!(monotonic_ns() >= before);
+    assert!(uptime() >= Duration::from_nanos(before));
+
+    let mut counter = ExtendedCounter::default();
+    assert_eq!(counter.update(0xFFF0, 0xFFFF), 0xFFF0);
+    assert_eq!(counter.update(0x10, 0xFFFF), 0x10010);
+}
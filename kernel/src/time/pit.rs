@@ -0,0 +1,70 @@
+//! The PIT as a clock source. Channel 0 is set to count down from 65536 over and over, which it does at 1.193182 MHz, so it
+//! wraps every 55ms and is only right if it is read at least that often. Reading it also takes three port accesses, so it is
+//! the source of last resort.
+//! Channel 0's IRQ is never unmasked, so counting doesn't cause interrupts.
+//! Only channel 0's data port is claimed, the command port is shared by every channel.
+
+use spin::Mutex;
+
+use crate::log;
+use crate::resource;
+use crate::x64::port::{inb, outb};
+
+use super::{ticks_to_ns, ClockSource, ExtendedCounter};
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, low byte then high byte, mode 2 (rate generator, which reloads the count when it runs out).
+const PIT_CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Latches channel 0's count, so both bytes are from the same moment.
+const PIT_LATCH_CHANNEL_0: u8 = 0b0000_0000;
+const COUNTER_MASK: u64 = 0xFFFF;
+
+pub struct Pit {
+    counter: Mutex<ExtendedCounter>,
+}
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn read_ns(&self) -> u64 {
+        let mut counter = self.counter.lock();
+        // This is safe because latching and reading channel 0 doesn't change how it counts
+        let count = unsafe {
+            outb(PIT_COMMAND, PIT_LATCH_CHANNEL_0);
+            let low = inb(PIT_CHANNEL_0) as u64;
+            let high = inb(PIT_CHANNEL_0) as u64;
+            high << 8 | low
+        };
+        // the PIT counts down
+        let ticks = counter.update(COUNTER_MASK - count, COUNTER_MASK);
+        ticks_to_ns(ticks, PIT_FREQUENCY)
+    }
+}
+
+static PIT: Pit = Pit {
+    counter: Mutex::new(ExtendedCounter { last: 0, total: 0 }),
+};
+
+/// Starts channel 0 counting and returns it as a clock source, None if another driver has claimed channel 0.
+pub fn start() -> Option<&'static Pit> {
+    if let Err(error) = resource::claim_io(PIT_CHANNEL_0, 1, "pit clock source") {
+        log!("pit: can't claim channel 0: {}", error);
+        return None;
+    }
+    // This is safe because channel 0 only interrupts through IRQ 0, which is masked
+    unsafe {
+        outb(PIT_COMMAND, PIT_CHANNEL_0_RATE_GENERATOR);
+        // a count of 0 is 65536
+        outb(PIT_CHANNEL_0, 0);
+        outb(PIT_CHANNEL_0, 0);
+    }
+    Some(&PIT)
+}
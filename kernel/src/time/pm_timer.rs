@@ -0,0 +1,41 @@
+//! The ACPI PM timer as a clock source. It runs at 3.579545 MHz and is 24 or 32 bits wide, so it wraps every 4.7 seconds
+//! at worst, and is read through an I/O port (or, rarely, memory).
+
+use generic_once_cell::OnceCell;
+use spin::Mutex;
+
+use crate::delay::{PmTimer, PM_TIMER_FREQUENCY};
+
+use super::{ticks_to_ns, ClockSource, ExtendedCounter};
+
+pub struct PmTimerSource {
+    timer: PmTimer,
+    counter: Mutex<ExtendedCounter>,
+}
+
+impl ClockSource for PmTimerSource {
+    fn name(&self) -> &'static str {
+        "acpi_pm"
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn read_ns(&self) -> u64 {
+        let mut counter = self.counter.lock();
+        let ticks = counter.update(self.timer.read(), self.timer.mask());
+        ticks_to_ns(ticks, PM_TIMER_FREQUENCY)
+    }
+}
+
+static PM_TIMER: OnceCell<Mutex<()>, PmTimerSource> = OnceCell::new();
+
+/// Finds the PM timer in the FADT, returns None if there is none.
+pub fn find() -> Option<&'static PmTimerSource> {
+    let timer = PmTimer::find()?;
+    Some(PM_TIMER.get_or_init(|| PmTimerSource {
+        timer,
+        counter: Mutex::new(ExtendedCounter::default()),
+    }))
+}
@@ -0,0 +1,94 @@
+//! The TSC clock source, nanoseconds since boot read with rdtsc.
+//! `now_ns()` is a rdtsc and a multiply, so it is cheap enough for timestamps anywhere. It is 0 until `init` runs, which
+//! needs the TSC frequency from `delay`.
+//! Only an invariant TSC ticks at a constant rate through frequency changes and sleep states; without one it can run slow, so
+//! it is rated below every other source. Each CPU has its own TSC, which the firmware starts together, so readings from
+//! different CPUs are only as comparable as the TSCs are synchronized.
+
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::delay::{mdelay, tsc_khz};
+use crate::log;
+use crate::x64::cpuid::supports_invariant_tsc;
+
+use super::ClockSource;
+
+/// The shift of `MULTIPLIER`, the fraction of a nanosecond it keeps.
+const SHIFT: u32 = 32;
+
+/// The TSC at boot, `now_ns()` counts from here.
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds per TSC cycle, shifted left by `SHIFT`. 0 until `init`.
+static MULTIPLIER: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+pub struct Tsc;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        if is_invariant() {
+            300
+        } else {
+            50
+        }
+    }
+
+    fn read_ns(&self) -> u64 {
+        now_ns()
+    }
+}
+
+pub static TSC: Tsc = Tsc;
+
+/// Records the TSC at boot, as early as possible so the clock covers all of it.
+pub fn mark_boot() {
+    // This is safe because rdtsc has no side effects
+    BOOT_TSC.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+}
+
+/// Starts converting the TSC at the frequency `delay` found.
+pub fn init() {
+    let khz = tsc_khz();
+    let invariant = supports_invariant_tsc();
+    INVARIANT.store(invariant, Ordering::Relaxed);
+    MULTIPLIER.store((1_000_000 << SHIFT) / khz, Ordering::Relaxed);
+    if !invariant {
+        log!("time: the TSC isn't invariant, it may run slow while the CPU is throttled or asleep");
+    }
+}
+
+/// Converts TSC cycles to nanoseconds.
+fn cycles_to_ns(cycles: u64) -> u64 {
+    ((cycles as u128 * MULTIPLIER.load(Ordering::Relaxed) as u128) >> SHIFT) as u64
+}
+
+/// Gets the nanoseconds since boot, by the TSC.
+pub fn now_ns() -> u64 {
+    // This is safe because rdtsc has no side effects
+    let now = unsafe { _rdtsc() };
+    cycles_to_ns(now.saturating_sub(BOOT_TSC.load(Ordering::Relaxed)))
+}
+
+/// Checks whether the TSC ticks at a constant rate, so `now_ns()` keeps time even when the CPU is throttled or asleep.
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Checks that the TSC clock advances by about as much as a delay waits.
+pub fn self_check() {
+    if MULTIPLIER.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let start = now_ns();
+    mdelay(10);
+    let elapsed = now_ns() - start;
+    assert!(elapsed >= 9_999_000, "clock ran slower than the delay: {}ns", elapsed);
+    assert!(elapsed < 100_000_000, "clock ran much faster than the delay: {}ns", elapsed);
+    // the multiplier is truncated, which loses less than a nanosecond per second
+    assert!(cycles_to_ns(tsc_khz() * 1000).abs_diff(1_000_000_000) <= 1);
+}
@@ -0,0 +1,286 @@
+//! Software timers, callbacks that run once after a delay or periodically, driven by the kernel tick.
+//! Timers are kept in a hashed timing wheel with a bucket per tick: a timer is in the bucket of the tick it expires on, modulo
+//! the size of the wheel, so adding or cancelling one only touches its bucket and each tick only looks at one bucket, skipping
+//! the timers there that are whole turns of the wheel away.
+//! Callbacks run in the tick interrupt with no locks held. They may add and cancel timers, but like any interrupt handler they
+//! must be quick, mustn't take locks that are held with interrupts enabled, and mustn't log. Only the BSP's local APIC timer
+//! ticks, so every callback runs on the BSP, and only while interrupts are enabled there.
+//! A timer never fires early, but it can fire up to a tick late, or later if the tick is stopped.
+
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::delay::{mdelay, poll_until};
+use crate::sync::{current_cpu, without_interrupts};
+use crate::x64::apic_timer::tick_hz;
+
+/// The most timers that can be pending at once.
+const MAX_TIMERS: usize = 64;
+/// The number of buckets, timers further away than this many ticks wait for the wheel to come around.
+const WHEEL_SIZE: usize = 256;
+/// Ends a bucket's list.
+const NO_TIMER: u16 = u16::MAX;
+/// `RUNNING` when no callback is running.
+const NOT_RUNNING: u64 = u64::MAX;
+
+/// Called when a timer fires, with the data it was added with.
+pub type TimerCallback = fn(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// The kernel tick is stopped, so timers would never fire.
+    NoTick,
+    /// There are already `MAX_TIMERS` timers.
+    TooMany,
+}
+
+/// Identifies a timer for `cancel`. A handle stays invalid once its timer is gone, even if the slot is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    index: u16,
+    generation: u32,
+}
+
+impl TimerHandle {
+    fn pack(&self) -> u64 {
+        (self.generation as u64) << 16 | self.index as u64
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    /// The tick the timer fires on.
+    expires: u64,
+    /// The ticks between firings, 0 for a one-shot timer.
+    period: u64,
+    callback: TimerCallback,
+    data: usize,
+    /// The next timer in the bucket.
+    next: u16,
+}
+
+struct Wheel {
+    timers: [Option<Timer>; MAX_TIMERS],
+    /// Incremented each time a slot is used, so old handles don't match a new timer.
+    generations: [u32; MAX_TIMERS],
+    /// The first timer in each bucket.
+    buckets: [u16; WHEEL_SIZE],
+    /// The number of ticks the wheel has seen.
+    now: u64,
+}
+
+const NO_TIMER_SLOT: Option<Timer> = None;
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    timers: [NO_TIMER_SLOT; MAX_TIMERS],
+    generations: [0; MAX_TIMERS],
+    buckets: [NO_TIMER; WHEEL_SIZE],
+    now: 0,
+});
+
+/// The packed handle of the timer whose callback is running, and the CPU it runs on, so `cancel` can wait for it to finish.
+static RUNNING: AtomicU64 = AtomicU64::new(NOT_RUNNING);
+static RUNNING_CPU: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+impl Wheel {
+    fn bucket(expires: u64) -> usize {
+        (expires % WHEEL_SIZE as u64) as usize
+    }
+
+    /// Adds the timer in slot `index` to the bucket of the tick it expires on.
+    fn link(&mut self, index: u16) {
+        let timer = self.timers[index as usize].as_mut().unwrap();
+        let bucket = Self::bucket(timer.expires);
+        timer.next = self.buckets[bucket];
+        self.buckets[bucket] = index;
+    }
+
+    /// Removes the timer in slot `index` from its bucket, leaving it in its slot.
+    fn unlink(&mut self, index: u16) {
+        let expires = self.timers[index as usize].unwrap().expires;
+        let bucket = Self::bucket(expires);
+        let next = self.timers[index as usize].unwrap().next;
+        if self.buckets[bucket] == index {
+            self.buckets[bucket] = next;
+            return;
+        }
+        let mut previous = self.buckets[bucket];
+        while previous != NO_TIMER {
+            let timer = self.timers[previous as usize].as_mut().unwrap();
+            if timer.next == index {
+                timer.next = next;
+                return;
+            }
+            previous = timer.next;
+        }
+    }
+
+    fn add(&mut self, ticks: u64, period: u64, callback: TimerCallback, data: usize) -> Result<TimerHandle, TimerError> {
+        let index = self.timers.iter().position(Option::is_none).ok_or(TimerError::TooMany)?;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        // the current tick is already partly over, so waiting a whole `ticks` means expiring on the tick after
+        self.timers[index] = Some(Timer {
+            expires: self.now + ticks + 1,
+            period,
+            callback,
+            data,
+            next: NO_TIMER,
+        });
+        self.link(index as u16);
+        Ok(TimerHandle {
+            index: index as u16,
+            generation: self.generations[index],
+        })
+    }
+
+    /// Removes the timer `handle` refers to, returns whether it was pending.
+    fn remove(&mut self, handle: TimerHandle) -> bool {
+        let index = handle.index as usize;
+        if self.generations[index] != handle.generation || self.timers[index].is_none() {
+            return false;
+        }
+        self.unlink(handle.index);
+        self.timers[index] = None;
+        true
+    }
+
+    /// Takes a timer in the current tick's bucket that has expired. A periodic timer is put back for its next firing, a
+    /// one-shot timer is freed.
+    fn take_expired(&mut self) -> Option<(TimerHandle, TimerCallback, usize)> {
+        let mut index = self.buckets[Self::bucket(self.now)];
+        while index != NO_TIMER {
+            let timer = self.timers[index as usize].unwrap();
+            if timer.expires <= self.now {
+                self.unlink(index);
+                if timer.period == 0 {
+                    self.timers[index as usize] = None;
+                } else {
+                    self.timers[index as usize].as_mut().unwrap().expires = self.now + timer.period;
+                    self.link(index);
+                }
+                let handle = TimerHandle {
+                    index,
+                    generation: self.generations[index as usize],
+                };
+                return Some((handle, timer.callback, timer.data));
+            }
+            index = timer.next;
+        }
+        None
+    }
+}
+
+/// Converts a duration to ticks of the running kernel tick, rounding up.
+fn duration_to_ticks(duration: Duration) -> Result<u64, TimerError> {
+    match tick_hz() {
+        0 => Err(TimerError::NoTick),
+        hz => Ok((duration.as_nanos() * hz as u128).div_ceil(1_000_000_000) as u64),
+    }
+}
+
+/// Calls `callback` with `data` once, at least `delay` from now.
+pub fn after(delay: Duration, callback: TimerCallback, data: usize) -> Result<TimerHandle, TimerError> {
+    let ticks = duration_to_ticks(delay)?;
+    without_interrupts(|| WHEEL.lock().add(ticks, 0, callback, data))
+}
+
+/// Calls `callback` with `data` every `period`, starting a `period` from now, until the timer is cancelled.
+pub fn every(period: Duration, callback: TimerCallback, data: usize) -> Result<TimerHandle, TimerError> {
+    let ticks = duration_to_ticks(period)?.max(1);
+    without_interrupts(|| WHEEL.lock().add(ticks, ticks, callback, data))
+}
+
+/// Cancels a timer, returns whether it was still pending (a one-shot timer that has fired isn't).
+/// Once this returns the callback isn't running and won't run again, unless this is called from the callback itself.
+pub fn cancel(handle: TimerHandle) -> bool {
+    let removed = without_interrupts(|| WHEEL.lock().remove(handle));
+    while RUNNING.load(Ordering::SeqCst) == handle.pack() && RUNNING_CPU.load(Ordering::SeqCst) != current_cpu() {
+        spin_loop();
+    }
+    removed
+}
+
+/// Advances the wheel by a tick and runs the callbacks of the timers that expired, called by the tick handler.
+pub fn tick() {
+    WHEEL.lock().now += 1;
+    loop {
+        // the timer is marked as running before the lock is dropped, so `cancel` either removes it first or waits for it
+        let expired = {
+            let mut wheel = WHEEL.lock();
+            let expired = wheel.take_expired();
+            if let Some((handle, _, _)) = expired {
+                RUNNING_CPU.store(current_cpu(), Ordering::SeqCst);
+                RUNNING.store(handle.pack(), Ordering::SeqCst);
+            }
+            expired
+        };
+        let Some((_, callback, data)) = expired else {
+            break;
+        };
+        callback(data);
+        RUNNING.store(NOT_RUNNING, Ordering::SeqCst);
+    }
+}
+
+/// Gets the number of pending timers.
+pub fn pending() -> usize {
+    without_interrupts(|| WHEEL.lock().timers.iter().flatten().count())
+}
+
+/// Set when `self_check` leaves a timer pending for `check_after_init`, and by that timer when it fires.
+static AFTER_INIT_ADDED: AtomicBool = AtomicBool::new(false);
+static AFTER_INIT_FIRED: AtomicBool = AtomicBool::new(false);
+
+/// Checks that one-shot and periodic timers fire, and that cancelled timers don't. Also leaves a timer for
+/// `check_after_init`.
+pub fn self_check() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    static PERIODIC: AtomicUsize = AtomicUsize::new(0);
+    fn add_data(data: usize) {
+        FIRED.fetch_add(data, Ordering::SeqCst);
+    }
+    fn count(_: usize) {
+        PERIODIC.fetch_add(1, Ordering::SeqCst);
+    }
+
+    if tick_hz() == 0 {
+        assert_eq!(after(Duration::from_millis(1), add_data, 1), Err(TimerError::NoTick));
+        return;
+    }
+    let pending_before = pending();
+    let one_shot = after(Duration::from_millis(5), add_data, 3).unwrap();
+    let cancelled = after(Duration::from_millis(5), add_data, 100).unwrap();
+    let periodic = every(Duration::from_millis(1), count, 0).unwrap();
+    assert!(cancel(cancelled));
+    assert!(!cancel(cancelled));
+
+    // This is safe because the vectors that can be delivered have handlers, interrupts are disabled again right after
+    unsafe { asm!("sti") };
+    let fired = poll_until(500_000, || FIRED.load(Ordering::SeqCst) != 0 && PERIODIC.load(Ordering::SeqCst) >= 3);
+    unsafe { asm!("cli") };
+    assert!(fired, "timers didn't fire");
+    assert_eq!(FIRED.load(Ordering::SeqCst), 3);
+    assert!(!cancel(one_shot), "a one-shot timer was still pending after firing");
+
+    assert!(cancel(periodic));
+    let count_after_cancel = PERIODIC.load(Ordering::SeqCst);
+    unsafe { asm!("sti") };
+    mdelay(20);
+    unsafe { asm!("cli") };
+    assert_eq!(PERIODIC.load(Ordering::SeqCst), count_after_cancel, "a cancelled timer fired");
+    assert_eq!(pending(), pending_before);
+
+    after(Duration::from_millis(1), |_| AFTER_INIT_FIRED.store(true, Ordering::SeqCst), 0).unwrap();
+    AFTER_INIT_ADDED.store(true, Ordering::SeqCst);
+}
+
+/// Checks that the timer `self_check` left fires once init has enabled interrupts, which nothing here does for it.
+pub fn check_after_init() {
+    if AFTER_INIT_ADDED.load(Ordering::SeqCst) {
+        let fired = poll_until(500_000, || AFTER_INIT_FIRED.load(Ordering::SeqCst));
+        assert!(fired, "a timer didn't fire with interrupts enabled after init");
+    }
+}
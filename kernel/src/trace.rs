@@ -0,0 +1,214 @@
+//! Tracepoints, which record what the kernel is doing into a ring per CPU for finding out where latency comes from.
+//! Each record is a TSC timestamp, an event and an argument, written without locks so tracepoints can be hit from interrupt
+//! handlers. Tracing is off until `start()` allocates the rings, and costs a load and a branch per tracepoint while off.
+//! `dump()` writes the records as text with a begin, end or instant phase for each event, which maps directly onto the Chrome
+//! trace event format (for a timeline) or can be folded into stacks (for a flamegraph) on the host.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+use core::fmt::{self, Write};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::delay::tsc_khz;
+use crate::smp::{cpu_state, CpuState};
+use crate::sync::{current_cpu, MAX_CPUS};
+
+/// The number of records in each CPU's ring.
+const RECORDS_PER_CPU: usize = 4096;
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// A task switch, the argument is the task switched from in the high half and the one switched to in the low half.
+    ContextSwitch = 0,
+    /// The argument is the vector.
+    IrqEntry = 1,
+    IrqExit = 2,
+    /// The argument is the syscall number on entry and the result on exit.
+    SyscallEntry = 3,
+    SyscallExit = 4,
+    /// A lock was found held, the argument is the lock's address.
+    LockContended = 5,
+    /// A contended lock was taken.
+    LockAcquired = 6,
+}
+
+impl TraceEvent {
+    fn from_u64(event: u64) -> Option<Self> {
+        Some(match event {
+            0 => TraceEvent::ContextSwitch,
+            1 => TraceEvent::IrqEntry,
+            2 => TraceEvent::IrqExit,
+            3 => TraceEvent::SyscallEntry,
+            4 => TraceEvent::SyscallExit,
+            5 => TraceEvent::LockContended,
+            6 => TraceEvent::LockAcquired,
+            _ => return None,
+        })
+    }
+
+    /// Gets the name of the span the event begins or ends, and its phase: B for begin, E for end or i for instant.
+    fn span(&self) -> (&'static str, char) {
+        match self {
+            TraceEvent::ContextSwitch => ("switch", 'i'),
+            TraceEvent::IrqEntry => ("irq", 'B'),
+            TraceEvent::IrqExit => ("irq", 'E'),
+            TraceEvent::SyscallEntry => ("syscall", 'B'),
+            TraceEvent::SyscallExit => ("syscall", 'E'),
+            TraceEvent::LockContended => ("lock", 'B'),
+            TraceEvent::LockAcquired => ("lock", 'E'),
+        }
+    }
+}
+
+/// A record, in atomics so a dump running on another CPU reads torn records rather than undefined behaviour.
+struct Record {
+    tsc: AtomicU64,
+    event: AtomicU64,
+    argument: AtomicU64,
+}
+
+struct Ring {
+    /// The number of records ever written, the next record goes in this modulo the ring's length.
+    written: AtomicU64,
+    records: Box<[Record]>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The ring of each CPU, allocated by `start()` and never freed.
+static RINGS: [AtomicPtr<Ring>; MAX_CPUS] = [const { AtomicPtr::new(null_mut()) }; MAX_CPUS];
+
+/// Allocates a ring for each CPU that doesn't have one and starts tracing.
+pub fn start() {
+    for (cpu, ring) in RINGS.iter().enumerate() {
+        if cpu_state(cpu) == CpuState::Offline && cpu != current_cpu() {
+            continue;
+        }
+        if !ring.load(Ordering::SeqCst).is_null() {
+            continue;
+        }
+        let records = (0..RECORDS_PER_CPU)
+            .map(|_| Record {
+                tsc: AtomicU64::new(0),
+                event: AtomicU64::new(0),
+                argument: AtomicU64::new(0),
+            })
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        let new = Box::leak(Box::new(Ring {
+            written: AtomicU64::new(0),
+            records,
+        }));
+        ring.store(new, Ordering::SeqCst);
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops tracing, the recorded events are kept until tracing is started again.
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Records `event` in this CPU's ring if tracing is on.
+pub fn record(event: TraceEvent, argument: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    // This is safe because rings are never freed
+    let Some(ring) = (unsafe { RINGS[current_cpu()].load(Ordering::Acquire).as_ref() }) else {
+        return;
+    };
+    // an interrupt between reserving the slot and filling it records in the next slot, so nested events don't collide
+    let index = ring.written.fetch_add(1, Ordering::Relaxed) as usize % ring.records.len();
+    let record = &ring.records[index];
+    // This is safe because rdtsc has no side effects
+    record.tsc.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    record.event.store(event as u64, Ordering::Relaxed);
+    record.argument.store(argument, Ordering::Release);
+}
+
+/// Records a task switch, for the scheduler.
+pub fn context_switch(from: u32, to: u32) {
+    record(TraceEvent::ContextSwitch, (from as u64) << 32 | to as u64);
+}
+
+/// Locks `mutex`, recording how long it waited if it was held.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    if let Some(guard) = mutex.try_lock() {
+        return guard;
+    }
+    let address = mutex as *const Mutex<T> as u64;
+    record(TraceEvent::LockContended, address);
+    let guard = mutex.lock();
+    record(TraceEvent::LockAcquired, address);
+    guard
+}
+
+/// Writes every CPU's records, oldest first, one per line as `<CPU> <TSC> <PHASE> <NAME> <ARGUMENT>`.
+/// Records written while dumping may be torn, so tracing should be stopped first.
+pub fn dump(writer: &mut impl Write) -> fmt::Result {
+    writeln!(writer, "# rex trace, tsc_khz={}", tsc_khz())?;
+    for (cpu, ring) in RINGS.iter().enumerate() {
+        // This is safe because rings are never freed
+        let Some(ring) = (unsafe { ring.load(Ordering::Acquire).as_ref() }) else {
+            continue;
+        };
+        let written = ring.written.load(Ordering::Acquire) as usize;
+        let length = ring.records.len();
+        for position in written.saturating_sub(length)..written {
+            let record = &ring.records[position % length];
+            let argument = record.argument.load(Ordering::Acquire);
+            let Some(event) = TraceEvent::from_u64(record.event.load(Ordering::Relaxed)) else {
+                continue;
+            };
+            let (name, phase) = event.span();
+            let tsc = record.tsc.load(Ordering::Relaxed);
+            writeln!(writer, "{} {} {} {} {:#x}", cpu, tsc, phase, name, argument)?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts the records in this CPU's ring.
+fn written_here() -> u64 {
+    // This is safe because rings are never freed
+    unsafe { RINGS[current_cpu()].load(Ordering::Acquire).as_ref() }.map_or(0, |ring| ring.written.load(Ordering::SeqCst))
+}
+
+/// Checks that events are only recorded while tracing, and that they are dumped.
+pub fn self_check() {
+    /// Counts the lines written to it.
+    struct Lines(usize);
+
+    impl Write for Lines {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 += s.matches('\n').count();
+            Ok(())
+        }
+    }
+
+    let was_enabled = ENABLED.load(Ordering::SeqCst);
+    stop();
+    let before = written_here();
+    record(TraceEvent::IrqEntry, 0x42);
+    assert_eq!(written_here(), before, "recorded while stopped");
+
+    start();
+    let before = written_here();
+    let mutex = Mutex::new(());
+    drop(lock(&mutex));
+    record(TraceEvent::SyscallEntry, 7);
+    assert_eq!(written_here(), before + 1, "an uncontended lock was recorded");
+    if !was_enabled {
+        stop();
+    }
+
+    // the header and at least the syscall
+    let mut lines = Lines(0);
+    dump(&mut lines).unwrap();
+    assert!(lines.0 >= 2);
+}
@@ -0,0 +1,559 @@
+//! The terminal on the framebuffer console: a screen that interprets the common VT100/ANSI escape sequences, and a line
+//! discipline for what is typed at it.
+//! The screen handles cursor movement (CUU, CUD, CUF, CUB, CUP), erasing (ED, EL), colors and bold (SGR) and reset (RIS).
+//! A line feed also returns the carriage, like a terminal with `onlcr`. Text is UTF-8, drawn in the console font.
+//! In canonical mode typed input is edited a line at a time (backspace, ^U to erase the line, ^C to drop it) and can only be
+//! read once the line is finished; in raw mode every byte can be read as soon as it is typed.
+//! There is no devfs yet, so the terminal is registered in the device tree as `tty0` and used through `write`, `read` and
+//! `receive`.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use generic_once_cell::OnceCell;
+use spin::Mutex;
+
+use crate::device::{self, Bus, DeviceName};
+use crate::graphics::font::{console_font, Font};
+use crate::graphics::{Color, Rect, Surface};
+use crate::init::InitError;
+use crate::{log, DISPLAYS};
+
+const ESC: u8 = 0x1B;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+/// ^C
+const INTERRUPT: u8 = 0x03;
+/// ^U
+const KILL: u8 = 0x15;
+
+const TAB_WIDTH: usize = 8;
+/// The most parameters kept from a control sequence, later ones are ignored.
+const MAX_PARAMETERS: usize = 8;
+/// The longest line that can be typed in canonical mode.
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// The VGA colors, in SGR order: black, red, green, yellow, blue, magenta, cyan and white, then their bright versions.
+const PALETTE: [Color; 16] = [
+    Color::rgb(0x00, 0x00, 0x00),
+    Color::rgb(0xAA, 0x00, 0x00),
+    Color::rgb(0x00, 0xAA, 0x00),
+    Color::rgb(0xAA, 0x55, 0x00),
+    Color::rgb(0x00, 0x00, 0xAA),
+    Color::rgb(0xAA, 0x00, 0xAA),
+    Color::rgb(0x00, 0xAA, 0xAA),
+    Color::rgb(0xAA, 0xAA, 0xAA),
+    Color::rgb(0x55, 0x55, 0x55),
+    Color::rgb(0xFF, 0x55, 0x55),
+    Color::rgb(0x55, 0xFF, 0x55),
+    Color::rgb(0xFF, 0xFF, 0x55),
+    Color::rgb(0x55, 0x55, 0xFF),
+    Color::rgb(0xFF, 0x55, 0xFF),
+    Color::rgb(0x55, 0xFF, 0xFF),
+    Color::rgb(0xFF, 0xFF, 0xFF),
+];
+const DEFAULT_FOREGROUND: usize = 7;
+const DEFAULT_BACKGROUND: usize = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Normal,
+    /// After ESC.
+    Escape,
+    /// After ESC [, collecting parameters until the final byte.
+    ControlSequence,
+}
+
+/// A grid of character cells drawn on a surface, with a cursor.
+pub struct Terminal {
+    columns: usize,
+    rows: usize,
+    /// The cursor. The column is `columns` after writing the last column, the next character wraps to the next line.
+    column: usize,
+    row: usize,
+    /// Palette indices.
+    foreground: usize,
+    background: usize,
+    /// Bold draws the dark colors bright.
+    bold: bool,
+    state: EscapeState,
+    parameters: [u16; MAX_PARAMETERS],
+    parameter_count: usize,
+    /// The start of a UTF-8 character that hasn't been completed yet.
+    pending: [u8; 4],
+    pending_length: usize,
+}
+
+impl Terminal {
+    /// Creates a terminal with as many cells as fit on `surface` in `font`.
+    pub fn new(surface: &Surface, font: &Font) -> Self {
+        Terminal {
+            columns: (surface.width() / font.width()).max(1),
+            rows: (surface.height() / font.height()).max(1),
+            column: 0,
+            row: 0,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bold: false,
+            state: EscapeState::Normal,
+            parameters: [0; MAX_PARAMETERS],
+            parameter_count: 0,
+            pending: [0; 4],
+            pending_length: 0,
+        }
+    }
+
+    /// Gets the number of columns and rows.
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    /// Gets the column and row of the cursor.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.column, self.row)
+    }
+
+    /// Writes text and escape sequences, drawing on `surface` in `font`.
+    pub fn write(&mut self, surface: &mut Surface, font: &Font, bytes: &[u8]) {
+        for &byte in bytes {
+            match self.state {
+                EscapeState::Normal => self.write_normal(surface, font, byte),
+                EscapeState::Escape => {
+                    self.state = EscapeState::Normal;
+                    match byte {
+                        b'[' => {
+                            self.state = EscapeState::ControlSequence;
+                            self.parameters = [0; MAX_PARAMETERS];
+                            self.parameter_count = 0;
+                        }
+                        b'c' => self.reset(surface, font),
+                        _ => {}
+                    }
+                }
+                EscapeState::ControlSequence => match byte {
+                    b'0'..=b'9' => {
+                        self.parameter_count = self.parameter_count.max(1);
+                        let parameter = &mut self.parameters[self.parameter_count - 1];
+                        *parameter = parameter.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    }
+                    b';' => {
+                        // an empty parameter before the separator is still a parameter
+                        self.parameter_count = (self.parameter_count.max(1) + 1).min(MAX_PARAMETERS);
+                    }
+                    // the final byte
+                    0x40..=0x7E => {
+                        self.state = EscapeState::Normal;
+                        self.control_sequence(surface, font, byte);
+                    }
+                    // private parameter markers and intermediate bytes, which none of the supported sequences use
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    fn write_normal(&mut self, surface: &mut Surface, font: &Font, byte: u8) {
+        if byte >= 0x80 {
+            return self.write_utf8(surface, font, byte);
+        }
+        self.pending_length = 0;
+        match byte {
+            ESC => self.state = EscapeState::Escape,
+            b'\n' => {
+                self.column = 0;
+                self.line_feed(surface, font);
+            }
+            b'\r' => self.column = 0,
+            BACKSPACE => self.column = self.column.min(self.columns - 1).saturating_sub(1),
+            b'\t' => self.column = ((self.column / TAB_WIDTH + 1) * TAB_WIDTH).min(self.columns - 1),
+            0x20..=0x7E => self.put(surface, font, byte as char),
+            // the bell and other control characters
+            _ => {}
+        }
+    }
+
+    /// Collects the bytes of a UTF-8 character and draws it once it is complete, or `?` if it is invalid.
+    fn write_utf8(&mut self, surface: &mut Surface, font: &Font, byte: u8) {
+        // anything but a continuation byte starts a new character
+        if byte & 0xC0 != 0x80 || self.pending_length == self.pending.len() {
+            self.pending_length = 0;
+        }
+        self.pending[self.pending_length] = byte;
+        self.pending_length += 1;
+        match core::str::from_utf8(&self.pending[..self.pending_length]) {
+            Ok(character) => {
+                self.pending_length = 0;
+                let c = character.chars().next().unwrap();
+                self.put(surface, font, c);
+            }
+            // the character isn't complete yet
+            Err(error) if error.error_len().is_none() => {}
+            Err(_) => {
+                self.pending_length = 0;
+                self.put(surface, font, '?');
+            }
+        }
+    }
+
+    fn foreground_color(&self) -> Color {
+        match self.foreground {
+            index @ 0..=7 if self.bold => PALETTE[index + 8],
+            index => PALETTE[index],
+        }
+    }
+
+    fn background_color(&self) -> Color {
+        PALETTE[self.background]
+    }
+
+    /// Draws `c` at the cursor and moves the cursor on, wrapping to the next line first if the line is full.
+    fn put(&mut self, surface: &mut Surface, font: &Font, c: char) {
+        if self.column >= self.columns {
+            self.column = 0;
+            self.line_feed(surface, font);
+        }
+        let (x, y) = (self.column * font.width(), self.row * font.height());
+        let (foreground, background) = (self.foreground_color(), self.background_color());
+        match font.glyph(c).or_else(|| font.glyph('?')) {
+            Some(glyph) => surface.draw_bitmap(x, y, glyph, font.width(), foreground, Some(background)),
+            None => surface.fill_rect(Rect::new(x, y, font.width(), font.height()), background),
+        }
+        self.column += 1;
+    }
+
+    /// Moves the cursor down a row, scrolling the screen up if it is on the last row.
+    fn line_feed(&mut self, surface: &mut Surface, font: &Font) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            surface.scroll_up(font.height(), self.background_color());
+        }
+    }
+
+    /// Clears the cells `columns` of `row` to the background color.
+    fn clear_cells(&self, surface: &mut Surface, font: &Font, row: usize, columns: core::ops::Range<usize>) {
+        let rect = Rect::new(
+            columns.start * font.width(),
+            row * font.height(),
+            (columns.end - columns.start) * font.width(),
+            font.height(),
+        );
+        surface.fill_rect(rect, self.background_color());
+    }
+
+    /// Gets parameter `index` of the control sequence, `default` if it is missing or 0.
+    fn parameter(&self, index: usize, default: usize) -> usize {
+        match self.parameters[index] {
+            0 => default,
+            _ if index >= self.parameter_count => default,
+            parameter => parameter as usize,
+        }
+    }
+
+    fn control_sequence(&mut self, surface: &mut Surface, font: &Font, command: u8) {
+        let count = self.parameter(0, 1);
+        let last_column = self.columns - 1;
+        match command {
+            b'A' => self.row = self.row.saturating_sub(count),
+            b'B' => self.row = (self.row + count).min(self.rows - 1),
+            b'C' => self.column = (self.column + count).min(last_column),
+            b'D' => self.column = self.column.min(last_column).saturating_sub(count),
+            b'H' | b'f' => {
+                self.row = self.parameter(0, 1).min(self.rows) - 1;
+                self.column = self.parameter(1, 1).min(self.columns) - 1;
+            }
+            b'J' => {
+                let (row, column) = (self.row, self.column.min(self.columns));
+                let rows = match self.parameter(0, 0) {
+                    0 => {
+                        self.clear_cells(surface, font, row, column..self.columns);
+                        row + 1..self.rows
+                    }
+                    1 => {
+                        self.clear_cells(surface, font, row, 0..(column + 1).min(self.columns));
+                        0..row
+                    }
+                    _ => 0..self.rows,
+                };
+                for row in rows {
+                    self.clear_cells(surface, font, row, 0..self.columns);
+                }
+            }
+            b'K' => {
+                let column = self.column.min(self.columns);
+                let columns = match self.parameter(0, 0) {
+                    0 => column..self.columns,
+                    1 => 0..(column + 1).min(self.columns),
+                    _ => 0..self.columns,
+                };
+                self.clear_cells(surface, font, self.row, columns);
+            }
+            b'm' => self.select_graphic_rendition(),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        // no parameters means reset
+        for index in 0..self.parameter_count.max(1) {
+            match self.parameters[index] {
+                0 => {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.background = DEFAULT_BACKGROUND;
+                    self.bold = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                color @ 30..=37 => self.foreground = (color - 30) as usize,
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                color @ 40..=47 => self.background = (color - 40) as usize,
+                49 => self.background = DEFAULT_BACKGROUND,
+                color @ 90..=97 => self.foreground = (color - 90 + 8) as usize,
+                color @ 100..=107 => self.background = (color - 100 + 8) as usize,
+                _ => {}
+            }
+        }
+    }
+
+    /// Resets the colors and the cursor and clears the screen.
+    pub fn reset(&mut self, surface: &mut Surface, font: &Font) {
+        self.foreground = DEFAULT_FOREGROUND;
+        self.background = DEFAULT_BACKGROUND;
+        self.bold = false;
+        self.column = 0;
+        self.row = 0;
+        for row in 0..self.rows {
+            self.clear_cells(surface, font, row, 0..self.columns);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyMode {
+    /// Input is edited a line at a time, and can be read once the line is finished.
+    Canonical,
+    /// Input can be read as soon as it is typed.
+    Raw,
+}
+
+/// Turns typed bytes into input that can be read, and says what to echo.
+pub struct LineDiscipline {
+    mode: TtyMode,
+    echo: bool,
+    /// The line being edited, in canonical mode.
+    line: Vec<u8>,
+    /// Input that can be read.
+    ready: VecDeque<u8>,
+}
+
+impl LineDiscipline {
+    pub fn new() -> Self {
+        LineDiscipline {
+            mode: TtyMode::Canonical,
+            echo: true,
+            line: Vec::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    pub fn mode(&self) -> TtyMode {
+        self.mode
+    }
+
+    /// Switches modes, a partly typed line can be read right away in raw mode.
+    pub fn set_mode(&mut self, mode: TtyMode) {
+        if mode == TtyMode::Raw {
+            self.ready.extend(self.line.drain(..));
+        }
+        self.mode = mode;
+    }
+
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Takes a typed byte, calling `echo` with what should be shown for it.
+    pub fn receive(&mut self, byte: u8, mut echo: impl FnMut(&[u8])) {
+        let mut output = |bytes: &[u8]| {
+            if self.echo {
+                echo(bytes)
+            }
+        };
+        if self.mode == TtyMode::Raw {
+            self.ready.push_back(byte);
+            output(&[byte]);
+            return;
+        }
+        match byte {
+            b'\r' | b'\n' => {
+                self.line.push(b'\n');
+                self.ready.extend(self.line.drain(..));
+                output(b"\n");
+            }
+            BACKSPACE | DELETE => {
+                Self::erase_character(&mut self.line, &mut output);
+            }
+            KILL => while Self::erase_character(&mut self.line, &mut output) {},
+            INTERRUPT => {
+                self.line.clear();
+                output(b"^C\n");
+            }
+            _ if self.line.len() < MAX_LINE_LENGTH => {
+                self.line.push(byte);
+                output(&[byte]);
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes the last character of `line` with all of its UTF-8 bytes and echoes erasing it. Returns whether it had one.
+    fn erase_character(line: &mut Vec<u8>, output: &mut impl FnMut(&[u8])) -> bool {
+        while let Some(byte) = line.pop() {
+            if byte & 0xC0 != 0x80 {
+                output(b"\x08 \x08");
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Reads as much input as is ready into `buffer`, returns the number of bytes read.
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let length = buffer.len().min(self.ready.len());
+        for (byte, ready) in buffer.iter_mut().zip(self.ready.drain(..length)) {
+            *byte = ready;
+        }
+        length
+    }
+}
+
+impl Default for LineDiscipline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Tty {
+    terminal: Terminal,
+    discipline: LineDiscipline,
+}
+
+static TTY: OnceCell<Mutex<()>, Mutex<Tty>> = OnceCell::new();
+
+/// Calls `f` with the console display's surface and the console font, if there are both.
+fn with_console(f: impl FnOnce(&mut Surface, &Font)) {
+    let (Some(display), Some(font)) = (DISPLAYS.get().and_then(|displays| displays.console()), console_font()) else {
+        return;
+    };
+    f(&mut display.surface().lock(), font);
+}
+
+/// Creates the terminal on the console display, in the console font.
+pub fn init_tty() -> Result<(), InitError> {
+    let font = console_font().ok_or(InitError::new("no console font"))?;
+    let display = DISPLAYS
+        .get()
+        .and_then(|displays| displays.console())
+        .ok_or(InitError::new("no console display"))?;
+    let terminal = Terminal::new(&display.surface().lock(), font);
+    let (columns, rows) = terminal.size();
+    TTY.set(Mutex::new(Tty {
+        terminal,
+        discipline: LineDiscipline::new(),
+    }))
+    .map_err(|_| InitError::new("the terminal was already created"))?;
+    let platform = device::find_or_register("platform", Bus::Platform, None);
+    device::register(DeviceName::new("tty", 0), Bus::Platform, platform).ok_or(InitError::new("Too many devices"))?;
+    log!("tty: tty0 is {}x{}", columns, rows);
+    Ok(())
+}
+
+/// Writes text and escape sequences to the terminal.
+pub fn write(bytes: &[u8]) {
+    let Some(tty) = TTY.get() else {
+        return;
+    };
+    let mut tty = tty.lock();
+    with_console(|surface, font| tty.terminal.write(surface, font, bytes));
+}
+
+/// Takes a byte typed at the terminal, echoing it if echo is on.
+pub fn receive(byte: u8) {
+    let Some(tty) = TTY.get() else {
+        return;
+    };
+    let Tty { terminal, discipline } = &mut *tty.lock();
+    discipline.receive(byte, |echo| with_console(|surface, font| terminal.write(surface, font, echo)));
+}
+
+/// Reads the input that is ready into `buffer`, returns the number of bytes read.
+pub fn read(buffer: &mut [u8]) -> usize {
+    TTY.get().map_or(0, |tty| tty.lock().discipline.read(buffer))
+}
+
+pub fn set_mode(mode: TtyMode) {
+    if let Some(tty) = TTY.get() {
+        tty.lock().discipline.set_mode(mode);
+    }
+}
+
+pub fn set_echo(echo: bool) {
+    if let Some(tty) = TTY.get() {
+        tty.lock().discipline.set_echo(echo);
+    }
+}
+
+/// Checks escape sequence handling on a surface in memory, and line editing in both modes.
+pub fn self_check() {
+    use crate::graphics::PixelFormat;
+
+    // an 8x8 font whose 'X' has its top row set and every other glyph is blank
+    static FONT: [u8; 4 + 256 * 8] = {
+        let mut font = [0; 4 + 256 * 8];
+        font[0] = 0x36;
+        font[1] = 0x04;
+        font[3] = 8;
+        font[4 + b'X' as usize * 8] = 0xFF;
+        font
+    };
+    let font = Font::parse(&FONT).unwrap();
+    let (width, height) = (10 * 8, 4 * 8);
+    let mut pixels = alloc::vec![0u32; width * height];
+    // This is safe because the surface only draws within `pixels`, which outlives it
+    let mut surface =
+        unsafe { Surface::new(pixels.as_mut_ptr() as *mut u8, width, height, width * 4, PixelFormat::XRGB8888) };
+    let mut terminal = Terminal::new(&surface, &font);
+    assert_eq!(terminal.size(), (10, 4));
+
+    terminal.write(&mut surface, &font, b"\x1b[2;3HX");
+    assert_eq!(terminal.cursor(), (3, 1));
+    assert_eq!(surface.get_pixel(2 * 8, 8), Some(PALETTE[DEFAULT_FOREGROUND]));
+    terminal.write(&mut surface, &font, b"\x1b[1;31mX\x1b[m");
+    assert_eq!(surface.get_pixel(3 * 8, 8), Some(PALETTE[9]));
+    terminal.write(&mut surface, &font, b"\x1b[99B\x1b[99C\x1b[2D");
+    assert_eq!(terminal.cursor(), (7, 3));
+    // wrapping past the last row scrolls the 'X' drawn on row 1 up to row 0
+    terminal.write(&mut surface, &font, b"\r\xc3\xa9XXXXXXXXXX");
+    assert_eq!(terminal.cursor(), (1, 3));
+    assert_eq!(surface.get_pixel(2 * 8, 0), Some(PALETTE[DEFAULT_FOREGROUND]));
+    terminal.write(&mut surface, &font, b"\x1b[H\x1b[2J");
+    assert_eq!(terminal.cursor(), (0, 0));
+    assert_eq!(surface.get_pixel(2 * 8, 0), Some(PALETTE[DEFAULT_BACKGROUND]));
+
+    let mut discipline = LineDiscipline::new();
+    let mut echoed = Vec::new();
+    for &byte in b"ab\x7fc" {
+        discipline.receive(byte, |echo| echoed.extend_from_slice(echo));
+    }
+    let mut buffer = [0; 8];
+    assert_eq!(discipline.read(&mut buffer), 0, "an unfinished line was readable");
+    discipline.receive(b'\r', |echo| echoed.extend_from_slice(echo));
+    assert_eq!(discipline.read(&mut buffer), 3);
+    assert_eq!(&buffer[..3], b"ac\n");
+    assert_eq!(echoed, b"ab\x08 \x08c\n");
+    discipline.set_mode(TtyMode::Raw);
+    discipline.set_echo(false);
+    discipline.receive(DELETE, |_| panic!("echoed with echo off"));
+    assert_eq!(discipline.read(&mut buffer), 1);
+    assert_eq!(buffer[0], DELETE);
+}
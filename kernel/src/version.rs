@@ -0,0 +1,138 @@
+//! What kernel this is: the version, the commit and time it was built from, and the features it was built with.
+//! build.rs embeds the commit and time, so bug reports can name the exact kernel. User code gets the same through `SYS_UNAME`.
+
+use core::fmt::{self, Write};
+
+/// The length of each `Utsname` field, including the terminating nul.
+pub const UTSNAME_FIELD_LENGTH: usize = 65;
+
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    /// The short commit hash, with `-dirty` if there were uncommitted changes, or `unknown` outside a git checkout.
+    pub git_hash: &'static str,
+    /// Seconds since the Unix epoch, or `SOURCE_DATE_EPOCH` for reproducible builds.
+    pub build_time: u64,
+    pub features: &'static [&'static str],
+    pub debug_assertions: bool,
+}
+
+/// Parses a decimal number at compile time, 0 if it isn't one.
+const fn parse_u64(number: &str) -> u64 {
+    let bytes = number.as_bytes();
+    let mut value = 0u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_digit() {
+            return 0;
+        }
+        value = value * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+    value
+}
+
+pub static BUILD_INFO: BuildInfo = BuildInfo {
+    name: "rex",
+    version: env!("CARGO_PKG_VERSION"),
+    git_hash: match option_env!("REX_GIT_HASH") {
+        Some(hash) => hash,
+        None => "unknown",
+    },
+    build_time: match option_env!("REX_BUILD_TIME") {
+        Some(time) => parse_u64(time),
+        None => 0,
+    },
+    features: &[
+        #[cfg(feature = "debug-shell")]
+        "debug-shell",
+        #[cfg(feature = "framebuffer")]
+        "framebuffer",
+        #[cfg(feature = "smp")]
+        "smp",
+        #[cfg(feature = "tests")]
+        "tests",
+        #[cfg(feature = "debug_mappings")]
+        "debug_mappings",
+    ],
+    debug_assertions: cfg!(debug_assertions),
+};
+
+impl fmt::Display for BuildInfo {
+    /// Formats as `rex 0.1.0 (<HASH>, built <TIME>, <PROFILE>) [<FEATURES>]`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({}, built {}, {})",
+            self.name,
+            self.version,
+            self.git_hash,
+            self.build_time,
+            if self.debug_assertions { "debug" } else { "release" }
+        )?;
+        write!(f, " [{}]", Features(self.features))
+    }
+}
+
+/// Formats the features separated by commas.
+struct Features(&'static [&'static str]);
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, feature) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(feature)?;
+        }
+        Ok(())
+    }
+}
+
+/// The system identification returned by `SYS_UNAME`, each field a nul terminated string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Utsname {
+    pub sysname: [u8; UTSNAME_FIELD_LENGTH],
+    pub release: [u8; UTSNAME_FIELD_LENGTH],
+    /// The commit, build time and features.
+    pub version: [u8; UTSNAME_FIELD_LENGTH],
+    pub machine: [u8; UTSNAME_FIELD_LENGTH],
+}
+
+/// Writes into a `Utsname` field, cutting off what doesn't fit and leaving room for the nul.
+struct FieldWriter<'a> {
+    field: &'a mut [u8; UTSNAME_FIELD_LENGTH],
+    length: usize,
+}
+
+impl Write for FieldWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = UTSNAME_FIELD_LENGTH - 1 - self.length;
+        let length = s.len().min(room);
+        self.field[self.length..self.length + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+fn field(arguments: fmt::Arguments) -> [u8; UTSNAME_FIELD_LENGTH] {
+    let mut field = [0; UTSNAME_FIELD_LENGTH];
+    let _ = FieldWriter {
+        field: &mut field,
+        length: 0,
+    }
+    .write_fmt(arguments);
+    field
+}
+
+/// Gets the `Utsname` of this kernel.
+pub fn utsname() -> Utsname {
+    let info = &BUILD_INFO;
+    Utsname {
+        sysname: field(format_args!("{}", info.name)),
+        release: field(format_args!("{}", info.version)),
+        version: field(format_args!("{} {} {}", info.git_hash, info.build_time, Features(info.features))),
+        machine: field(format_args!("x86_64")),
+    }
+}
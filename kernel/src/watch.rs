@@ -0,0 +1,169 @@
+//! Watches, which log a register dump whenever an address is written (or read) and then let the code carry on.
+//! They are data breakpoints in the debug registers, so there are at most 4 and each covers 1, 2, 4 or 8 aligned bytes.
+//! The debug registers are per CPU: a watch is loaded on the CPU that adds it right away, and the APs are woken to load the
+//! current set from their idle loop.
+//! The debug exception handler logs the hit with `emergency_log!`, because the code that hit the watch may hold the debug
+//! serial port's lock.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::exceptions::SavedRegisters;
+use crate::emergency_log;
+use crate::smp;
+use crate::sync::{current_cpu, without_interrupts, MAX_CPUS};
+use crate::x64::debug_registers::{
+    get_breakpoint, get_dr6, set_breakpoint, set_dr6, Breakpoint, BreakpointCondition, BREAKPOINTS, DR6_BREAKPOINT_HIT,
+};
+
+/// The value of DR6 with no debug conditions, its reserved bits are ones.
+const DR6_CLEAR: u64 = 0xFFFF_0FF0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// All the debug registers are in use.
+    Full,
+    /// The length isn't 1, 2, 4 or 8.
+    InvalidLength,
+    /// The address isn't aligned to the length.
+    Misaligned,
+    /// Watches are for data, execute breakpoints need the resume flag handled.
+    NotData,
+    /// There is no watch with that number.
+    NotFound,
+}
+
+const NONE: Option<Breakpoint> = None;
+/// The watches, indexed by the debug register they are in. Never locked by the debug exception handler, which reads the
+/// debug registers instead.
+static WATCHES: Mutex<[Option<Breakpoint>; BREAKPOINTS]> = Mutex::new([NONE; BREAKPOINTS]);
+/// Incremented whenever the watches change, so CPUs can tell that theirs are stale.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+/// The generation of the watches each CPU has in its debug registers.
+static LOADED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// The number of times any watch was hit.
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Watches `length` bytes at `address` and returns the watch's number. Returns `WatchError::Full` if there are already 4.
+pub fn add(address: u64, length: u8, condition: BreakpointCondition) -> Result<usize, WatchError> {
+    if ![1, 2, 4, 8].contains(&length) {
+        return Err(WatchError::InvalidLength);
+    }
+    if address % length as u64 != 0 {
+        return Err(WatchError::Misaligned);
+    }
+    if condition == BreakpointCondition::Execute {
+        return Err(WatchError::NotData);
+    }
+    let index = {
+        let mut watches = WATCHES.lock();
+        let index = watches.iter().position(Option::is_none).ok_or(WatchError::Full)?;
+        watches[index] = Some(Breakpoint {
+            address,
+            length,
+            condition,
+        });
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+        index
+    };
+    update_this_cpu();
+    smp::wake_others();
+    Ok(index)
+}
+
+/// Removes watch `index`.
+pub fn remove(index: usize) -> Result<(), WatchError> {
+    {
+        let mut watches = WATCHES.lock();
+        let watch = watches.get_mut(index).ok_or(WatchError::NotFound)?;
+        if watch.take().is_none() {
+            return Err(WatchError::NotFound);
+        }
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+    }
+    update_this_cpu();
+    smp::wake_others();
+    Ok(())
+}
+
+/// Gets the watches, indexed by their number.
+pub fn list() -> [Option<Breakpoint>; BREAKPOINTS] {
+    *WATCHES.lock()
+}
+
+/// Gets the number of times any watch was hit.
+pub fn hits() -> u64 {
+    HITS.load(Ordering::SeqCst)
+}
+
+/// Loads the current watches into this CPU's debug registers if they changed since it last did.
+pub fn update_this_cpu() {
+    let loaded = &LOADED[current_cpu()];
+    if loaded.load(Ordering::SeqCst) == GENERATION.load(Ordering::SeqCst) {
+        return;
+    }
+    // a hit while the lock is held would be handled without it, but disabling interrupts keeps the lock short
+    without_interrupts(|| {
+        let watches = WATCHES.lock();
+        for (index, watch) in watches.iter().enumerate() {
+            // This is safe because the debug exception handler handles data breakpoints
+            unsafe { set_breakpoint(index, *watch) };
+        }
+        loaded.store(GENERATION.load(Ordering::SeqCst), Ordering::SeqCst);
+    });
+}
+
+/// Handles a debug exception caused by watches, logging the registers and the new value for each one that was hit.
+/// Returns false if no watch caused it.
+pub fn handle(registers: &SavedRegisters) -> bool {
+    let dr6 = get_dr6();
+    if dr6 & DR6_BREAKPOINT_HIT == 0 {
+        return false;
+    }
+    // the CPU never clears the status, so it would still be set on the next debug exception
+    set_dr6(DR6_CLEAR);
+    for index in 0..BREAKPOINTS {
+        if dr6 & (1 << index) == 0 {
+            continue;
+        }
+        let Some(watch) = get_breakpoint(index) else {
+            continue;
+        };
+        HITS.fetch_add(1, Ordering::SeqCst);
+        // The access just succeeded, so the watched bytes are mapped
+        let value = unsafe {
+            match watch.length {
+                1 => (watch.address as *const u8).read_volatile() as u64,
+                2 => (watch.address as *const u16).read_volatile() as u64,
+                4 => (watch.address as *const u32).read_volatile() as u64,
+                _ => (watch.address as *const u64).read_volatile(),
+            }
+        };
+        emergency_log!(
+            "watch {}: {:?} at {:#x}, value is now {:#x}\n{}",
+            index,
+            watch.condition,
+            watch.address,
+            value,
+            registers
+        );
+    }
+    true
+}
+
+/// Checks that writing a watched address is caught and execution continues.
+pub fn self_check() {
+    static WATCHED: AtomicU64 = AtomicU64::new(0);
+
+    let before = hits();
+    let index = add(WATCHED.as_ptr() as u64, 8, BreakpointCondition::Write).expect("no free debug register");
+    WATCHED.store(0x1234, Ordering::SeqCst);
+    assert_eq!(hits(), before + 1, "watch wasn't hit");
+    assert_eq!(WATCHED.load(Ordering::SeqCst), 0x1234);
+    remove(index).unwrap();
+    WATCHED.store(0, Ordering::SeqCst);
+    assert_eq!(hits(), before + 1, "removed watch was hit");
+    assert_eq!(add(WATCHED.as_ptr() as u64 + 1, 2, BreakpointCondition::Write), Err(WatchError::Misaligned));
+    assert_eq!(remove(index), Err(WatchError::NotFound));
+}
@@ -0,0 +1,95 @@
+//! Deferred work, for what interrupt handlers can't do themselves: they can't log or take locks that are held with interrupts
+//! enabled, so they schedule a `Work` and its function runs later from an idle loop, with interrupts enabled.
+//! Scheduling is lock-free. A `Work` that is scheduled again before it runs only runs once, and pending work runs in no
+//! particular order.
+
+use core::arch::asm;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::sync::rcu;
+
+/// A function to run outside interrupt context.
+pub struct Work {
+    function: fn(),
+    pending: AtomicBool,
+    /// The next pending work, while this one is pending.
+    next: AtomicPtr<Work>,
+}
+
+/// The pending work, a lock-free stack linked through `Work::next`.
+static PENDING: AtomicPtr<Work> = AtomicPtr::new(null_mut());
+
+impl Work {
+    pub const fn new(function: fn()) -> Self {
+        Work {
+            function,
+            pending: AtomicBool::new(false),
+            next: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Makes the function run from the next idle loop, unless it is already pending. Safe to call from interrupt handlers.
+    pub fn schedule(&'static self) {
+        if self.pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let this = self as *const Work as *mut Work;
+        let mut head = PENDING.load(Ordering::SeqCst);
+        loop {
+            self.next.store(head, Ordering::SeqCst);
+            match PENDING.compare_exchange(head, this, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+/// Runs the pending work. Called by idle loops with interrupts enabled.
+pub fn run_pending() {
+    // taking the whole stack means nothing else can pop from it, so it can't change under us
+    let mut work = PENDING.swap(null_mut(), Ordering::SeqCst);
+    // This is safe because only `&'static Work`s are pushed
+    while let Some(current) = unsafe { work.as_ref() } {
+        work = current.next.load(Ordering::SeqCst);
+        // cleared first, so scheduling it again while it runs makes it run again
+        current.pending.store(false, Ordering::SeqCst);
+        (current.function)();
+    }
+}
+
+/// Halts until the next interrupt, unless work is pending. Called by idle loops with interrupts disabled after they checked
+/// whatever they are waiting for, so an interrupt after the check still wakes them. Returns with interrupts enabled.
+/// The CPU counts as idle for RCU while it is halted.
+pub fn idle_halt() {
+    if !PENDING.load(Ordering::SeqCst).is_null() {
+        // This is safe because the caller had interrupts enabled before it checked
+        unsafe { asm!("sti") };
+        return;
+    }
+    rcu::enter_idle();
+    // This is safe because the caller had interrupts enabled, and `sti` only takes effect after the `hlt`
+    unsafe { asm!("sti", "hlt") };
+    rcu::quiescent_state();
+}
+
+/// Checks that scheduled work runs once, and can be scheduled again.
+pub fn self_check() {
+    use core::sync::atomic::AtomicUsize;
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static WORK: Work = Work::new(|| {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+    });
+
+    WORK.schedule();
+    WORK.schedule();
+    run_pending();
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    run_pending();
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    WORK.schedule();
+    run_pending();
+    assert_eq!(RUNS.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,243 @@
+//! The local APIC of each CPU, which delivers interrupts to it.
+//! The firmware leaves it enabled in xAPIC or x2APIC mode. In xAPIC mode its registers are memory mapped at the address in
+//! the MADT (which every CPU's local APIC answers to for itself), in x2APIC mode they are MSRs. `init_local_apic` maps the
+//! registers and software enables the BSP's local APIC, which the firmware may have left disabled; the APs enable theirs
+//! with `enable_this_cpu` as they start. Enabling a local APIC also sets up the LINT pins that the MADT connects NMIs to.
+
+use generic_once_cell::OnceCell;
+use spin::Mutex;
+
+use crate::init::InitError;
+use crate::irq::LineControl;
+use crate::log;
+use crate::memory::PhysicalAddress;
+use crate::resource;
+use crate::sync::without_interrupts;
+use crate::x64::vectors::SPURIOUS_VECTOR;
+use crate::acpi::madt::{NmiSource, Polarity, TriggerMode};
+use crate::ACPI_TABLES;
+
+use super::cpuid::get_initial_apic_id;
+use super::mmio::{map_mmio, Mmio};
+use super::msr::{rdmsr, wrmsr};
+use super::pic;
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+pub const APIC_X2APIC_ENABLE: u64 = 1 << 10;
+/// The mask of the physical address of the xAPIC's registers in the APIC base MSR.
+pub const APIC_BASE_ADDRESS: u64 = 0xF_FFFF_F000;
+
+/// The size of the xAPIC's registers.
+const REGISTERS_SIZE: u64 = 0x1000;
+/// The first x2APIC MSR, the MSR of an xAPIC register is this plus its offset divided by 16.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Register offsets, in the xAPIC's memory mapped registers.
+const ID: usize = 0x20;
+const EOI: usize = 0xB0;
+const SPURIOUS_INTERRUPT_VECTOR: usize = 0xF0;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+/// The software enable bit in the spurious interrupt vector register.
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+/// The NMI delivery mode of an LVT entry, which ignores the vector.
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+/// The level bit of the interrupt command register, which must be set for everything but INIT deasserts.
+const ICR_ASSERT: u32 = 1 << 14;
+/// Set in the interrupt command register while the xAPIC is still sending the last IPI.
+const ICR_SEND_PENDING: u32 = 1 << 12;
+
+/// The line control of IPI vectors. There is no line to mask, so they only need their EOI.
+pub static IPI_LINE_CONTROL: LineControl = LineControl {
+    mask: |_| {},
+    unmask: |_| {},
+    eoi: |_| eoi(),
+};
+
+pub(super) struct LocalApic {
+    /// The memory mapped registers, or None in x2APIC mode.
+    registers: Option<Mmio>,
+}
+
+// This is safe because every CPU accesses its own local APIC through the same registers
+unsafe impl Sync for LocalApic {}
+
+impl LocalApic {
+    /// Reads a register, by its offset in the xAPIC's memory mapped registers.
+    pub(super) fn read(&self, register: usize) -> u32 {
+        match self.registers {
+            Some(registers) => registers.read32(register),
+            // This is safe because the x2APIC MSRs exist in x2APIC mode
+            None => unsafe { rdmsr(X2APIC_MSR_BASE + register as u32 / 16) as u32 },
+        }
+    }
+
+    pub(super) fn write(&self, register: usize, value: u32) {
+        match self.registers {
+            Some(registers) => registers.write32(register, value),
+            // This is safe because the x2APIC MSRs exist in x2APIC mode, what the write does is up to the caller
+            None => unsafe { wrmsr(X2APIC_MSR_BASE + register as u32 / 16, value as u64) },
+        }
+    }
+}
+
+static LOCAL_APIC: OnceCell<Mutex<()>, LocalApic> = OnceCell::new();
+
+/// Gets the registers of this CPU's local APIC, None before `init_local_apic`.
+pub(super) fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// Maps the local APIC's registers, enables the BSP's local APIC and masks the legacy PICs if there are any.
+pub fn init_local_apic() -> Result<(), InitError> {
+    let madt = ACPI_TABLES
+        .get()
+        .and_then(|tables| tables.madt())
+        .ok_or(InitError::new("no MADT"))?;
+    // This is safe because the APIC base MSR exists on every x86-64 CPU
+    let apic_base = unsafe { rdmsr(IA32_APIC_BASE) };
+    let registers = if apic_base & APIC_X2APIC_ENABLE != 0 {
+        None
+    } else {
+        let address = madt.local_apic_address();
+        if address != apic_base & APIC_BASE_ADDRESS {
+            log!(
+                "apic: the MADT puts the local APIC at {:#x}, the APIC base MSR at {:#x}",
+                address,
+                apic_base & APIC_BASE_ADDRESS
+            );
+        }
+        if let Err(error) = resource::claim_mmio(address, REGISTERS_SIZE, "local apic") {
+            log!("apic: can't claim the registers: {}", error);
+            return Err(InitError::new("the local APIC's registers are claimed"));
+        }
+        // This is safe because the MADT says these are the local APIC's registers
+        let registers = unsafe { map_mmio(PhysicalAddress::device(address), REGISTERS_SIZE) }
+            .ok_or(InitError::new("out of MMIO space"))?;
+        Some(registers)
+    };
+    LOCAL_APIC
+        .set(LocalApic { registers })
+        .map_err(|_| InitError::new("the local APIC was already initialized"))?;
+    enable_this_cpu();
+
+    if madt.has_legacy_pics() {
+        // the I/O APICs deliver the ISA IRQs instead
+        pic::mask_all();
+    }
+    log!(
+        "apic: local APIC {} in {} mode",
+        id(),
+        if registers.is_some() { "xAPIC" } else { "x2APIC" }
+    );
+    Ok(())
+}
+
+/// Enables this CPU's local APIC, with spurious interrupts going to `SPURIOUS_VECTOR`.
+pub fn enable_this_cpu() {
+    let Some(local_apic) = LOCAL_APIC.get() else {
+        return;
+    };
+    // This is safe because setting the global enable bit (which is already set unless the firmware disabled the local APIC)
+    // keeps the APIC base and mode
+    unsafe {
+        let apic_base = rdmsr(IA32_APIC_BASE);
+        if apic_base & APIC_GLOBAL_ENABLE == 0 {
+            wrmsr(IA32_APIC_BASE, apic_base | APIC_GLOBAL_ENABLE);
+        }
+    }
+    local_apic.write(SPURIOUS_INTERRUPT_VECTOR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    set_nmi_pins(local_apic);
+}
+
+/// Sets up the LINT pins that the MADT connects NMIs to on this CPU, so they deliver NMIs. The other pins keep what the
+/// firmware left in them.
+fn set_nmi_pins(local_apic: &LocalApic) {
+    let Some(madt) = ACPI_TABLES.get().and_then(|tables| tables.madt()) else {
+        return;
+    };
+    let this_processor = madt.acpi_processor_id(id());
+    for nmi in madt.nmi_sources() {
+        let NmiSource::LocalApic {
+            acpi_processor_id,
+            lint,
+            polarity,
+            trigger_mode,
+        } = nmi
+        else {
+            continue;
+        };
+        if acpi_processor_id.is_some() && acpi_processor_id != this_processor {
+            continue;
+        }
+        let register = match lint {
+            0 => LVT_LINT0,
+            1 => LVT_LINT1,
+            // there are only two pins
+            _ => continue,
+        };
+        // the pins are ISA style, edge triggered and active high, unless the MADT says otherwise
+        let mut entry = LVT_DELIVERY_NMI;
+        if polarity == Polarity::ActiveLow {
+            entry |= LVT_ACTIVE_LOW;
+        }
+        if trigger_mode == TriggerMode::Level {
+            entry |= LVT_LEVEL_TRIGGERED;
+        }
+        local_apic.write(register, entry);
+    }
+}
+
+/// Gets the id of this CPU's local APIC, which can be wider than the initial APIC id from CPUID in x2APIC mode.
+pub fn id() -> u32 {
+    match LOCAL_APIC.get() {
+        Some(local_apic @ LocalApic { registers: Some(_) }) => local_apic.read(ID) >> 24,
+        Some(local_apic) => local_apic.read(ID),
+        None => get_initial_apic_id() as u32,
+    }
+}
+
+/// Tells this CPU's local APIC that the interrupt being handled is done, so it can deliver interrupts of the same or lower
+/// priority. Every interrupt the local APIC delivers needs one, except spurious interrupts.
+pub fn eoi() {
+    if let Some(local_apic) = LOCAL_APIC.get() {
+        // writing 0 to the EOI register only ends the current interrupt
+        local_apic.write(EOI, 0);
+    }
+}
+
+/// Sends a fixed interrupt with `vector` to the CPU whose local APIC has id `destination`. Does nothing before
+/// `init_local_apic`.
+pub fn send_ipi(destination: u32, vector: u8) {
+    let Some(local_apic) = LOCAL_APIC.get() else {
+        return;
+    };
+    let command = ICR_ASSERT | vector as u32;
+    match local_apic.registers {
+        // the two halves are written separately, so an interrupt handler that sends an IPI mustn't come in between
+        Some(registers) => without_interrupts(|| {
+            registers.write32(ICR_HIGH, destination << 24);
+            registers.write32(ICR_LOW, command);
+            while registers.read32(ICR_LOW) & ICR_SEND_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }),
+        // This is safe because the x2APIC ICR is a single MSR in x2APIC mode, with the destination in the high half
+        None => unsafe { wrmsr(X2APIC_MSR_BASE + ICR_LOW as u32 / 16, (destination as u64) << 32 | command as u64) },
+    }
+}
+
+/// Checks that this CPU's local APIC is enabled and its id matches CPUID.
+pub fn self_check() {
+    let Some(local_apic) = LOCAL_APIC.get() else {
+        return;
+    };
+    assert_ne!(local_apic.read(SPURIOUS_INTERRUPT_VECTOR) & SVR_APIC_ENABLE, 0, "local APIC is disabled");
+    assert_eq!(id() & 0xFF, get_initial_apic_id() as u32);
+}
@@ -0,0 +1,257 @@
+//! The local APIC timer, which counts down from an initial count at a fraction of the bus clock and interrupts its CPU when it
+//! reaches zero, either once or reloading itself every time.
+//! The bus clock isn't architectural, so the timer is measured at boot against the ACPI PM timer, or against PIT channel 2
+//! when there is no PM timer. `start_tick` then makes it interrupt `hz` times a second, and each interrupt is a kernel tick.
+//! Ticks are only delivered while interrupts are enabled, which they are once initialization finishes.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::delay::{poll_until, PmTimer, PM_TIMER_FREQUENCY};
+use crate::init::InitError;
+use crate::irq::{self, IrqReturn, LineControl};
+use crate::log;
+use crate::sysctl::{self, Tunable};
+use crate::time::pit::PIT_FREQUENCY;
+use crate::timer;
+
+use super::apic::{self, local_apic, LocalApic};
+use super::port::{inb, outb};
+use super::vectors::{self, VectorClass};
+
+/// Register offsets, in the xAPIC's memory mapped registers.
+const LVT_TIMER: usize = 0x320;
+const INITIAL_COUNT: usize = 0x380;
+const CURRENT_COUNT: usize = 0x390;
+const DIVIDE_CONFIGURATION: usize = 0x3E0;
+
+/// Divides the bus clock by 16, slow enough that a 32 bit count lasts seconds.
+const DIVIDE_BY_16: u32 = 0b0011;
+const LVT_MASKED: u32 = 1 << 16;
+/// Reloads the initial count when the timer reaches zero, without it the timer is one-shot.
+const LVT_PERIODIC: u32 = 1 << 17;
+
+/// How long to measure the timer for, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+/// The tick frequency when `timer=<HZ>` isn't on the command line, `timer=0` leaves the tick stopped.
+const DEFAULT_TICK_HZ: u64 = 100;
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2, low byte then high byte, mode 0 (the output goes high when the count reaches zero).
+const PIT_CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+/// Bit 0 gates channel 2, bit 1 connects it to the speaker and bit 5 reads its output.
+const PIT_CONTROL: u16 = 0x61;
+const PIT_GATE: u8 = 1 << 0;
+const PIT_SPEAKER: u8 = 1 << 1;
+const PIT_OUTPUT: u8 = 1 << 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// `init_apic_timer` didn't run or failed.
+    NotCalibrated,
+    /// The timer can't count that fast or that slowly.
+    InvalidFrequency,
+}
+
+/// The frequency the timer counts down at, in Hz, 0 until calibrated.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The timer's vector, 0 before `init_apic_timer`.
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+/// The frequency of the running tick, 0 if it is stopped.
+static TICK_HZ: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// The highest tick frequency `timer` can be set to.
+const MAX_TICK_HZ: u64 = 10_000;
+
+/// The tick frequency, which can be changed at runtime to restart the BSP's tick at the new rate.
+static TICK_RATE: Tunable = Tunable::integer(
+    "timer",
+    "the kernel tick frequency in Hz, 0 stops the tick",
+    DEFAULT_TICK_HZ,
+    0,
+    MAX_TICK_HZ,
+)
+.with_apply(apply_tick_rate);
+
+fn apply_tick_rate(hz: u64) -> Result<(), ()> {
+    if hz == 0 {
+        stop_tick();
+        return Ok(());
+    }
+    start_tick(hz).map_err(|_| ())
+}
+
+/// Masks the timer through its LVT entry, which is where the local APIC takes it from.
+fn mask(_vector: u8) {
+    if let Some(local_apic) = local_apic() {
+        local_apic.write(LVT_TIMER, local_apic.read(LVT_TIMER) | LVT_MASKED);
+    }
+}
+
+fn unmask(_vector: u8) {
+    if let Some(local_apic) = local_apic() {
+        local_apic.write(LVT_TIMER, local_apic.read(LVT_TIMER) & !LVT_MASKED);
+    }
+}
+
+static LINE_CONTROL: LineControl = LineControl {
+    mask,
+    unmask,
+    eoi: |_| apic::eoi(),
+};
+
+/// Calibrates the BSP's local APIC timer, installs its handler and starts the tick.
+pub fn init_apic_timer() -> Result<(), InitError> {
+    let local_apic = local_apic().ok_or(InitError::new("no local APIC"))?;
+    let frequency = calibrate(local_apic);
+    if frequency == 0 {
+        return Err(InitError::new("the local APIC timer doesn't count"));
+    }
+    FREQUENCY.store(frequency, Ordering::SeqCst);
+
+    let vector = vectors::allocate(VectorClass::High).ok_or(InitError::new("no free vector"))?;
+    if irq::set_handler(vector, tick).is_err() {
+        vectors::free(vector);
+        return Err(InitError::new("the timer's vector is in use"));
+    }
+    irq::set_line_control(vector, &LINE_CONTROL);
+    VECTOR.store(vector, Ordering::SeqCst);
+    log!("apic timer: counts at {} kHz, vector {:#x}", frequency / 1000, vector);
+
+    let _ = sysctl::register(&TICK_RATE);
+    let hz = TICK_RATE.get();
+    if hz != 0 {
+        start_tick(hz).map_err(|_| InitError::new("invalid tick frequency"))?;
+    }
+    Ok(())
+}
+
+/// Measures how fast this CPU's timer counts, in Hz, against the PM timer or the PIT. Leaves the timer stopped.
+fn calibrate(local_apic: &LocalApic) -> u64 {
+    local_apic.write(DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+    local_apic.write(LVT_TIMER, LVT_MASKED);
+    let (counted, elapsed_us) = match PmTimer::find() {
+        Some(pm_timer) => {
+            let start = pm_timer.read();
+            local_apic.write(INITIAL_COUNT, u32::MAX);
+            let elapsed = pm_timer.wait_since(start, PM_TIMER_FREQUENCY * CALIBRATION_MS / 1000);
+            let counted = u32::MAX - local_apic.read(CURRENT_COUNT);
+            (counted, elapsed * 1_000_000 / PM_TIMER_FREQUENCY)
+        }
+        None => {
+            // This is safe because channel 2 only drives the speaker, which stays disconnected
+            unsafe {
+                let control = inb(PIT_CONTROL) & !(PIT_GATE | PIT_SPEAKER);
+                outb(PIT_CONTROL, control);
+                outb(PIT_COMMAND, PIT_CHANNEL_2_ONE_SHOT);
+                let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+                outb(PIT_CHANNEL_2, count as u8);
+                outb(PIT_CHANNEL_2, (count >> 8) as u8);
+                // raising the gate starts the count
+                outb(PIT_CONTROL, control | PIT_GATE);
+                local_apic.write(INITIAL_COUNT, u32::MAX);
+                while inb(PIT_CONTROL) & PIT_OUTPUT == 0 {}
+                let counted = u32::MAX - local_apic.read(CURRENT_COUNT);
+                outb(PIT_CONTROL, control);
+                (counted, CALIBRATION_MS * 1000)
+            }
+        }
+    };
+    local_apic.write(INITIAL_COUNT, 0);
+    counted as u64 * 1_000_000 / elapsed_us
+}
+
+/// Gets the timer's vector and the count that lasts `numerator / denominator` seconds.
+fn count_for(numerator: u64, denominator: u64) -> Result<(u8, u32), TimerError> {
+    let frequency = FREQUENCY.load(Ordering::SeqCst);
+    let vector = VECTOR.load(Ordering::SeqCst);
+    if frequency == 0 || vector == 0 {
+        return Err(TimerError::NotCalibrated);
+    }
+    let count = frequency.saturating_mul(numerator) / denominator;
+    match u32::try_from(count) {
+        Ok(count) if count != 0 => Ok((vector, count)),
+        _ => Err(TimerError::InvalidFrequency),
+    }
+}
+
+/// Makes this CPU's timer interrupt `hz` times a second.
+pub fn start_tick(hz: u64) -> Result<(), TimerError> {
+    if hz == 0 {
+        return Err(TimerError::InvalidFrequency);
+    }
+    let (vector, count) = count_for(1, hz)?;
+    let local_apic = local_apic().ok_or(TimerError::NotCalibrated)?;
+    local_apic.write(DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+    local_apic.write(LVT_TIMER, LVT_PERIODIC | vector as u32);
+    local_apic.write(INITIAL_COUNT, count);
+    TICK_HZ.store(hz, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Stops this CPU's timer, periodic or one-shot.
+pub fn stop_tick() {
+    if let Some(local_apic) = local_apic() {
+        local_apic.write(LVT_TIMER, LVT_MASKED);
+        local_apic.write(INITIAL_COUNT, 0);
+    }
+    TICK_HZ.store(0, Ordering::SeqCst);
+}
+
+/// Makes this CPU's timer interrupt once, after `us` microseconds. This replaces the tick until it is started again.
+pub fn one_shot(us: u64) -> Result<(), TimerError> {
+    let (vector, count) = count_for(us, 1_000_000)?;
+    let local_apic = local_apic().ok_or(TimerError::NotCalibrated)?;
+    local_apic.write(DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+    local_apic.write(LVT_TIMER, vector as u32);
+    local_apic.write(INITIAL_COUNT, count);
+    TICK_HZ.store(0, Ordering::SeqCst);
+    Ok(())
+}
+
+/// The kernel tick handler, which also runs the software timers. Like any interrupt handler it must not log.
+fn tick(_vector: u8) -> IrqReturn {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    timer::tick();
+    IrqReturn::Handled
+}
+
+/// Gets the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Gets the frequency of the running tick, 0 if it is stopped.
+pub fn tick_hz() -> u64 {
+    TICK_HZ.load(Ordering::SeqCst)
+}
+
+/// Checks that a one-shot interrupt and the periodic tick arrive while interrupts are enabled.
+pub fn self_check() {
+    if VECTOR.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let hz = tick_hz();
+
+    let before = ticks();
+    one_shot(1000).unwrap();
+    // This is safe because the vectors that can be delivered have handlers, interrupts are disabled again right after
+    unsafe { asm!("sti") };
+    let fired = poll_until(50_000, || ticks() > before);
+    unsafe { asm!("cli") };
+    assert!(fired, "one-shot timer interrupt didn't arrive");
+
+    let before = ticks();
+    start_tick(1000).unwrap();
+    unsafe { asm!("sti") };
+    let fired = poll_until(50_000, || ticks() >= before + 3);
+    unsafe { asm!("cli") };
+    assert!(fired, "periodic timer interrupts didn't arrive");
+    assert_eq!(count_for(1, 1 << 40), Err(TimerError::InvalidFrequency));
+
+    match hz {
+        0 => stop_tick(),
+        hz => start_tick(hz).unwrap(),
+    }
+}
@@ -0,0 +1,38 @@
+//! Memory barriers.
+//! x86 keeps most accesses in order by itself: loads aren't reordered with loads, stores aren't reordered with stores, and
+//! uncacheable (MMIO) accesses aren't reordered with anything. The exceptions these are for are a load passing an earlier store
+//! to a different address, write combining memory like the framebuffer, and non-temporal stores.
+//! The compiler can reorder much more than the CPU, so every barrier here is also a compiler barrier.
+
+use core::arch::asm;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// Orders all earlier loads and stores before all later ones, including a load after a store.
+pub fn mfence() {
+    // This is safe because mfence only waits for earlier memory accesses
+    unsafe { asm!("mfence", options(nostack, preserves_flags)) };
+}
+
+/// Orders all earlier loads before all later loads. Normal loads are already ordered, this is for loads from write combining memory.
+pub fn lfence() {
+    // This is safe because lfence only waits for earlier loads
+    unsafe { asm!("lfence", options(nostack, preserves_flags)) };
+}
+
+/// Orders all earlier stores before all later stores, including write combining and non-temporal stores.
+pub fn sfence() {
+    // This is safe because sfence only waits for earlier stores
+    unsafe { asm!("sfence", options(nostack, preserves_flags)) };
+}
+
+/// Makes writes to memory a device reads with DMA visible before a later MMIO write telling the device to read it.
+/// Stores to write back memory aren't reordered with a later uncacheable store, so this only has to stop the compiler.
+pub fn dma_write_barrier() {
+    compiler_fence(Ordering::Release);
+}
+
+/// Makes reads of memory a device wrote with DMA happen after an earlier MMIO read saying the device is done.
+/// Loads aren't reordered with earlier loads, so this only has to stop the compiler.
+pub fn dma_read_barrier() {
+    compiler_fence(Ordering::Acquire);
+}
@@ -1,7 +1,7 @@
 
 // all uses of cpuid in this module will cause a invalid opcode exception if cpuid is not supported
 
-use core::arch::{x86_64::__cpuid};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
 
 /// Gets the vendor string of the processor
 pub fn get_vendor_string() -> [u8; 12]{
@@ -28,4 +28,56 @@ pub fn get_vendor_string() -> [u8; 12]{
     output[11] = ecx.to_le_bytes()[3];
 
     output
-}
\ No newline at end of file
+}
+
+/// Gets the initial APIC id of the processor this runs on, which identifies the CPU
+pub fn get_initial_apic_id() -> u8 {
+    let cpuid_result = unsafe { __cpuid(1) };
+    (cpuid_result.ebx >> 24) as u8
+}
+
+/// Checks whether the processor supports 1GB pages
+pub fn supports_1gb_pages() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0001 {
+        return false;
+    }
+    let cpuid_result = unsafe { __cpuid(0x8000_0001) };
+    cpuid_result.edx & (1 << 26) != 0
+}
+
+/// Checks whether the processor supports process-context identifiers (CR4.PCIDE)
+pub fn supports_pcid() -> bool {
+    let cpuid_result = unsafe { __cpuid(1) };
+    cpuid_result.ecx & (1 << 17) != 0
+}
+
+/// Checks whether the processor has enhanced REP MOVSB/STOSB (ERMS), which makes byte string instructions as fast as wider ones
+pub fn supports_erms() -> bool {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 7 {
+        return false;
+    }
+    let cpuid_result = unsafe { __cpuid_count(7, 0) };
+    cpuid_result.ebx & (1 << 9) != 0
+}
+
+/// Checks whether the TSC is invariant, ticking at a constant rate in every P-, C- and T-state
+pub fn supports_invariant_tsc() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    let cpuid_result = unsafe { __cpuid(0x8000_0007) };
+    cpuid_result.edx & (1 << 8) != 0
+}
+
+/// Checks whether the processor supports the INVPCID instruction
+pub fn supports_invpcid() -> bool {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 7 {
+        return false;
+    }
+    let cpuid_result = unsafe { __cpuid_count(7, 0) };
+    cpuid_result.ebx & (1 << 10) != 0
+}
@@ -0,0 +1,139 @@
+//! The debug registers, which make the CPU raise a debug exception when one of up to 4 addresses is accessed.
+//! DR0 to DR3 hold the addresses, DR7 enables them and sets what kind of access and how many bytes each one covers, and DR6
+//! says which one caused the last debug exception. They are per CPU.
+
+use core::arch::asm;
+
+/// The number of breakpoints, one for each of DR0 to DR3.
+pub const BREAKPOINTS: usize = 4;
+
+/// The DR6 bits set when the breakpoint of the same number was hit.
+pub const DR6_BREAKPOINT_HIT: u64 = 0b1111;
+
+/// What kind of access triggers a breakpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointCondition {
+    Execute = 0b00,
+    Write = 0b01,
+    ReadWrite = 0b11,
+}
+
+impl BreakpointCondition {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 0b11 {
+            0b00 => BreakpointCondition::Execute,
+            0b01 => BreakpointCondition::Write,
+            // 0b10 is I/O, which needs CR4.DE and is never set here
+            _ => BreakpointCondition::ReadWrite,
+        }
+    }
+}
+
+/// A breakpoint as programmed in the debug registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u64,
+    /// 1, 2, 4 or 8 bytes, execute breakpoints are always 1.
+    pub length: u8,
+    pub condition: BreakpointCondition,
+}
+
+/// Gets the DR7 length field for `length` bytes.
+fn length_bits(length: u8) -> u64 {
+    match length {
+        1 => 0b00,
+        2 => 0b01,
+        8 => 0b10,
+        _ => 0b11,
+    }
+}
+
+fn length_from_bits(bits: u64) -> u8 {
+    match bits & 0b11 {
+        0b00 => 1,
+        0b01 => 2,
+        0b10 => 8,
+        _ => 4,
+    }
+}
+
+/// Reads the address register of breakpoint `index`.
+fn get_address(index: usize) -> u64 {
+    let x: u64;
+    unsafe {
+        match index {
+            0 => asm!("mov {}, dr0", out(reg) x),
+            1 => asm!("mov {}, dr1", out(reg) x),
+            2 => asm!("mov {}, dr2", out(reg) x),
+            _ => asm!("mov {}, dr3", out(reg) x),
+        }
+    }
+    x
+}
+
+/// Writes the address register of breakpoint `index`.
+fn set_address(index: usize, address: u64) {
+    // This is safe because the address only takes effect once the breakpoint is enabled in DR7
+    unsafe {
+        match index {
+            0 => asm!("mov dr0, {}", in(reg) address),
+            1 => asm!("mov dr1, {}", in(reg) address),
+            2 => asm!("mov dr2, {}", in(reg) address),
+            _ => asm!("mov dr3, {}", in(reg) address),
+        }
+    }
+}
+
+/// Reads the value of the dr6 register, the status of the last debug exception.
+pub fn get_dr6() -> u64 {
+    let x: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) x) }
+    x
+}
+
+/// Writes the dr6 register. The CPU never clears it, so the handler must.
+pub fn set_dr6(value: u64) {
+    unsafe { asm!("mov dr6, {}", in(reg) value) }
+}
+
+/// Reads the value of the dr7 register, which enables and configures the breakpoints.
+pub fn get_dr7() -> u64 {
+    let x: u64;
+    unsafe { asm!("mov {}, dr7", out(reg) x) }
+    x
+}
+
+/// Writes the dr7 register.
+/// Caller must ensure that the breakpoints it enables are handled by the debug exception handler.
+pub unsafe fn set_dr7(value: u64) {
+    asm!("mov dr7, {}", in(reg) value)
+}
+
+/// Gets breakpoint `index` on this CPU, or None if it isn't enabled.
+pub fn get_breakpoint(index: usize) -> Option<Breakpoint> {
+    assert!(index < BREAKPOINTS, "invalid breakpoint {}", index);
+    let dr7 = get_dr7();
+    if dr7 & (1 << (index * 2)) == 0 {
+        return None;
+    }
+    let fields = dr7 >> (16 + index * 4);
+    Some(Breakpoint {
+        address: get_address(index),
+        length: length_from_bits(fields >> 2),
+        condition: BreakpointCondition::from_bits(fields),
+    })
+}
+
+/// Sets or clears breakpoint `index` on this CPU.
+/// Caller must ensure that the debug exception handler handles the breakpoint.
+pub unsafe fn set_breakpoint(index: usize, breakpoint: Option<Breakpoint>) {
+    assert!(index < BREAKPOINTS, "invalid breakpoint {}", index);
+    let enable = 1 << (index * 2);
+    let fields = 0b1111 << (16 + index * 4);
+    let mut dr7 = get_dr7() & !enable & !fields;
+    if let Some(breakpoint) = breakpoint {
+        set_address(index, breakpoint.address);
+        dr7 |= enable | ((length_bits(breakpoint.length) << 2 | breakpoint.condition as u64) << (16 + index * 4));
+    }
+    set_dr7(dr7);
+}
@@ -0,0 +1,79 @@
+use core::fmt::Write;
+
+use spin::Mutex;
+use uart_16550::SerialPort;
+
+use super::{
+    fixup,
+    idt::{Idt, InterruptStackFrame, PageFaultErrorCode},
+    registers::{get_cr2, get_cs},
+};
+use crate::halt_loop;
+
+/// The IDT used until the kernel installs its real IDT.
+/// It lives in a static so it can be loaded before any memory has been set up.
+static EARLY_IDT: Mutex<Idt> = Mutex::new(Idt::new());
+
+/// Installs the early IDT, which reports page faults, general protection faults, and double faults over serial and halts.
+pub fn install() {
+    let mut idt = EARLY_IDT.lock();
+    let cs = get_cs();
+    idt.set_page_fault_handler(early_page_fault, cs);
+    idt.set_general_protection_fault_handler(early_general_protection_fault, cs);
+    idt.set_double_fault_handler(early_double_fault, cs);
+    // This is safe because the IDT is in a static and will never be moved
+    unsafe { idt.get_idtr().load() };
+}
+
+/// Writes a minimal report of an early exception and halts.
+/// This avoids every lock and allocation, since the fault may have happened while setting them up.
+fn report(name: &str, stack_frame: &InterruptStackFrame, error_code: u64, cr2: Option<u64>) -> ! {
+    let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    let _ = writeln!(
+        serial_port,
+        "\nEARLY EXCEPTION: {} error code: {:#x}",
+        name, error_code
+    );
+    let _ = writeln!(
+        serial_port,
+        "rip: {:#x} cs: {:#x} rflags: {:#x} rsp: {:#x} ss: {:#x}",
+        stack_frame.instruction_pointer,
+        stack_frame.code_segment,
+        stack_frame.cpu_flags,
+        stack_frame.stack_pointer,
+        stack_frame.stack_segment
+    );
+    if let Some(cr2) = cr2 {
+        let _ = writeln!(serial_port, "cr2: {:#x}", cr2);
+    }
+    halt_loop();
+}
+
+extern "x86-interrupt" fn early_page_fault(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    if fixup::apply(&mut stack_frame) {
+        return;
+    }
+    report(
+        "page fault",
+        &stack_frame,
+        error_code.bits(),
+        Some(get_cr2()),
+    );
+}
+
+extern "x86-interrupt" fn early_general_protection_fault(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if fixup::apply(&mut stack_frame) {
+        return;
+    }
+    report("general protection fault", &stack_frame, error_code, None);
+}
+
+extern "x86-interrupt" fn early_double_fault(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    report("double fault", &stack_frame, error_code, None);
+}
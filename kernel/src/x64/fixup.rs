@@ -0,0 +1,114 @@
+//! The exception fixup table lets specific instructions fault without crashing the kernel.
+//!
+//! An instruction that may fault is given a label, and an entry pointing to that label and a fixup label is placed in the
+//! `.ex_table` section. When a page fault or general protection fault occurs, the handler looks up the faulting instruction
+//! pointer in the table, and if an entry is found, resumes execution at the fixup label instead of panicking.
+//!
+//! Entries are written from inline assembly like this:
+//! ```text
+//! "2: <instruction that may fault>",
+//! "jmp 4f",
+//! "3: <fixup code, usually sets an error flag>",
+//! "4:",
+//! ".pushsection .ex_table, \"a\"",
+//! ".balign 4",
+//! ".long 2b - .",
+//! ".long 3b - .",
+//! ".popsection",
+//! ```
+//! Offsets are stored relative to the entry so the table doesn't need relocating when the kernel is loaded at a random address.
+//! Labels made up only of 0 and 1 must be avoided since LLVM parses them as binary literals in Intel syntax.
+
+use core::arch::asm;
+
+use super::idt::InterruptStackFrame;
+
+/// An entry in the exception fixup table.
+#[repr(C)]
+struct ExceptionTableEntry {
+    /// The offset of the instruction that may fault, relative to this field.
+    instruction: i32,
+    /// The offset of the code to resume at if the instruction faults, relative to this field.
+    fixup: i32,
+}
+
+impl ExceptionTableEntry {
+    fn instruction_address(&self) -> u64 {
+        (&self.instruction as *const i32 as u64).wrapping_add_signed(self.instruction as i64)
+    }
+
+    fn fixup_address(&self) -> u64 {
+        (&self.fixup as *const i32 as u64).wrapping_add_signed(self.fixup as i64)
+    }
+}
+
+extern "C" {
+    // Defined in linker.ld
+    static __start_ex_table: ExceptionTableEntry;
+    static __stop_ex_table: ExceptionTableEntry;
+}
+
+/// Returned when an instruction protected by the fixup table faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+/// Gets the entries of the exception fixup table.
+fn entries() -> &'static [ExceptionTableEntry] {
+    // This is safe because the linker places every entry between these two symbols.
+    unsafe {
+        let start = &__start_ex_table as *const ExceptionTableEntry;
+        let end = &__stop_ex_table as *const ExceptionTableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Gets the fixup address for a faulting instruction, or None if the instruction is not allowed to fault.
+pub fn search(instruction_pointer: u64) -> Option<u64> {
+    entries()
+        .iter()
+        .find(|entry| entry.instruction_address() == instruction_pointer)
+        .map(ExceptionTableEntry::fixup_address)
+}
+
+/// Redirects an exception to its fixup code if the faulting instruction has an entry in the fixup table.
+/// Returns true if the exception was fixed up (and the handler should return), or false if it should be handled normally.
+pub fn apply(stack_frame: &mut InterruptStackFrame) -> bool {
+    match search(stack_frame.instruction_pointer) {
+        Some(fixup) => {
+            // This is safe because the fixup code was written to be resumed at from this instruction.
+            unsafe { stack_frame.set_instruction_pointer(fixup) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// Reads a byte from `address`, returning `Err(Fault)` instead of crashing if the address is not mapped.
+/// Useful for inspecting memory from the debug shell.
+pub fn probe_read_u8(address: *const u8) -> Result<u8, Fault> {
+    let value: u32;
+    let faulted: u32;
+    unsafe {
+        asm!(
+            "xor {faulted:e}, {faulted:e}",
+            "2: movzx {value:e}, byte ptr [{address}]",
+            "jmp 4f",
+            "3: mov {faulted:e}, 1",
+            "xor {value:e}, {value:e}",
+            "4:",
+            ".pushsection .ex_table, \"a\"",
+            ".balign 4",
+            ".long 2b - .",
+            ".long 3b - .",
+            ".popsection",
+            address = in(reg) address,
+            value = out(reg) value,
+            faulted = out(reg) faulted,
+        );
+    }
+    if faulted == 0 {
+        Ok(value as u8)
+    } else {
+        Err(Fault)
+    }
+}
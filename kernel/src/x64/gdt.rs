@@ -2,8 +2,29 @@ use bitflags::bitflags;
 use core::{
     arch::asm,
     fmt::{Debug},
+    mem::size_of,
 };
 
+use spin::Mutex;
+
+use crate::sync::{current_cpu, MAX_CPUS};
+
+use super::registers::get_cs;
+use super::tss::TaskStateSegment;
+
+/// The index of the kernel code segment in the kernel GDT.
+pub const KERNEL_CODE_INDEX: u16 = 1;
+/// The index of the kernel data segment in the kernel GDT.
+pub const KERNEL_DATA_INDEX: u16 = 2;
+/// The index of the LDT descriptor in the kernel GDT, system descriptors take two entries.
+pub const LDT_INDEX: u16 = 3;
+/// The index of the first CPU's TSS descriptor, each CPU has its own since loading a TSS marks its descriptor busy.
+pub const TSS_INDEX: u16 = 5;
+const GDT_ENTRIES: usize = TSS_INDEX as usize + 2 * MAX_CPUS;
+
+/// The kernel's GDT, which replaces the bootloader's so the kernel controls which selectors exist.
+static GDT: Mutex<DescriptorTable<GDT_ENTRIES>> = Mutex::new(DescriptorTable::new());
+
 #[derive(Debug)]
 #[repr(packed)]
 pub struct Gdtr {
@@ -20,12 +41,12 @@ impl Gdtr {
 
     /// Gets the currently loaded gdtr.
     pub fn get() -> Self {
-        let x: *mut Gdtr;
-
+        let mut gdtr = Gdtr { size: 0, base: 0 };
+        // sgdt stores the GDTR into the memory operand
         unsafe {
-            asm!("sgdt [{gdtr}]", gdtr = out(reg) x);
-            x.read()
+            asm!("sgdt [{gdtr}]", gdtr = in(reg) &mut gdtr);
         }
+        gdtr
     }
 
     /// Gets the segment descriptor at the specified index (or none if the index is out of range)
@@ -52,6 +73,7 @@ impl Gdtr {
     }
 }
 
+#[derive(Clone, Copy)]
 #[repr(packed)]
 pub struct SegmentDescriptor {
     limit: u16,
@@ -108,8 +130,56 @@ impl SegmentDescriptor {
         self.limit2_and_flags |= flags.bits();
     }
 
+    /// Gets the descriptor privilege level.
+    pub fn get_dpl(&self) -> u8 {
+        (self.access_byte.bits() >> 5) & 0b11
+    }
+
+    /// Encodes this descriptor as the 8 bytes the CPU reads from the GDT.
+    pub fn encode(&self) -> u64 {
+        (self.limit as u64)
+            | (self.base1 as u64) << 16
+            | (self.base2 as u64) << 32
+            | (self.access_byte.bits() as u64) << 40
+            | (self.limit2_and_flags as u64) << 48
+            | (self.base3 as u64) << 56
+    }
+
+    /// Decodes a descriptor from the 8 bytes the CPU reads from the GDT. The flags' reserved bit is dropped.
+    pub fn decode(raw: u64) -> Self {
+        SegmentDescriptor {
+            limit: raw as u16,
+            base1: (raw >> 16) as u16,
+            base2: (raw >> 32) as u8,
+            access_byte: AccessByte::from_bits_retain((raw >> 40) as u8),
+            limit2_and_flags: (raw >> 48) as u8 & 0b11101111,
+            base3: (raw >> 56) as u8,
+        }
+    }
+
+    /// Checks that this is a valid code or data descriptor, see `SegmentDescriptorBuilder::build`.
+    pub fn validate(&self) -> Result<(), InvalidSegmentDescriptor> {
+        let access_byte = self.access_byte;
+        let flags = self.get_flags();
+        if !access_byte.contains(AccessByte::present) {
+            return Err(InvalidSegmentDescriptor::NotPresent);
+        }
+        if !access_byte.contains(AccessByte::descriptor_type) {
+            return Err(InvalidSegmentDescriptor::SystemSegment);
+        }
+        if flags.contains(Flags::long_mode_code) {
+            if !access_byte.contains(AccessByte::executable) {
+                return Err(InvalidSegmentDescriptor::LongModeData);
+            }
+            if flags.contains(Flags::size) {
+                return Err(InvalidSegmentDescriptor::LongModeWithSize);
+            }
+        }
+        Ok(())
+    }
+
     /// Creates a Segment Descriptor with all zeros (this is not a valid descriptor)
-    pub fn new_null_descriptor() -> Self {
+    pub const fn new_null_descriptor() -> Self {
         SegmentDescriptor {
             limit: 0,
             base1: 0,
@@ -149,6 +219,373 @@ impl SegmentDescriptor {
     }
 }
 
+/// The ways a code or data segment descriptor can be malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidSegmentDescriptor {
+    /// The limit is larger than 20 bits.
+    LimitTooLarge,
+    /// The DPL is larger than 3.
+    InvalidDpl,
+    /// The present bit is clear, loading the segment would fault.
+    NotPresent,
+    /// The descriptor type bit is clear. System segments (TSS, LDT) are 16 bytes in long mode and aren't built here.
+    SystemSegment,
+    /// The long mode flag is set on a data segment.
+    LongModeData,
+    /// The long mode and size flags are both set, which is reserved.
+    LongModeWithSize,
+}
+
+/// Builds code and data segment descriptors, checking the combination of fields is valid.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentDescriptorBuilder {
+    base: u32,
+    limit: u32,
+    dpl: u8,
+    access_byte: AccessByte,
+    flags: Flags,
+}
+
+impl SegmentDescriptorBuilder {
+    /// Starts a present code segment, or a present data segment if `executable` is false, with base and limit 0.
+    pub fn new(executable: bool) -> Self {
+        let mut access_byte = AccessByte::descriptor_type | AccessByte::present;
+        if executable {
+            access_byte |= AccessByte::executable;
+        }
+        SegmentDescriptorBuilder {
+            base: 0,
+            limit: 0,
+            dpl: 0,
+            access_byte,
+            flags: Flags::empty(),
+        }
+    }
+
+    pub fn base(mut self, base: u32) -> Self {
+        self.base = base;
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn dpl(mut self, dpl: u8) -> Self {
+        self.dpl = dpl;
+        self
+    }
+
+    /// Adds to the access byte.
+    pub fn access(mut self, access_byte: AccessByte) -> Self {
+        self.access_byte |= access_byte;
+        self
+    }
+
+    pub fn flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Builds the descriptor, checking that the limit fits in 20 bits, that the DPL is at most 3, that the descriptor is present and not a system segment,
+    /// and that the long mode flag is only set on code segments without the size flag.
+    pub fn build(self) -> Result<SegmentDescriptor, InvalidSegmentDescriptor> {
+        if self.limit & 0xFFF00000 != 0 {
+            return Err(InvalidSegmentDescriptor::LimitTooLarge);
+        }
+        if self.dpl > 3 {
+            return Err(InvalidSegmentDescriptor::InvalidDpl);
+        }
+        let mut descriptor = SegmentDescriptor::new_null_descriptor();
+        descriptor.set_base(self.base);
+        descriptor.set_limit(self.limit);
+        descriptor.access_byte = self.access_byte | AccessByte::from_bits_retain(self.dpl << 5);
+        descriptor.set_flags(self.flags);
+        descriptor.validate()?;
+        Ok(descriptor)
+    }
+}
+
+/// A table of segment descriptors, used for both the GDT and LDTs.
+#[repr(C, align(16))]
+pub struct DescriptorTable<const N: usize> {
+    descriptors: [SegmentDescriptor; N],
+}
+
+/// An LDT, a table of segment descriptors referenced by selectors with the table indicator bit set.
+/// It can only contain code and data segments.
+pub type Ldt<const N: usize> = DescriptorTable<N>;
+
+impl<const N: usize> DescriptorTable<N> {
+    /// Creates a table of null descriptors.
+    pub const fn new() -> Self {
+        DescriptorTable {
+            descriptors: [SegmentDescriptor::new_null_descriptor(); N],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    pub fn get(&self, index: u16) -> Option<&SegmentDescriptor> {
+        self.descriptors.get(index as usize)
+    }
+
+    /// Sets the code or data descriptor at `index`, panics if `index` is out of range.
+    pub fn set(&mut self, index: u16, descriptor: SegmentDescriptor) {
+        self.descriptors[index as usize] = descriptor;
+    }
+
+    /// Sets the system descriptor at `index`, which takes two entries. Panics if `index + 1` is out of range.
+    pub fn set_system(&mut self, index: u16, descriptor: SystemSegmentDescriptor) {
+        let [low, high] = descriptor.encode();
+        self.descriptors[index as usize] = SegmentDescriptor::decode(low);
+        // the upper half is not a segment descriptor, but it has the same size and decoding keeps every bit
+        self.descriptors[index as usize + 1] = SegmentDescriptor::decode(high);
+        // decode() drops the flags' reserved bit, which is part of the base in the upper half
+        self.descriptors[index as usize + 1].limit2_and_flags = (high >> 48) as u8;
+    }
+
+    /// Gets a GDTR that covers this table.
+    pub fn gdtr(&self) -> Gdtr {
+        Gdtr::from_segment_descriptors(&self.descriptors)
+    }
+
+    /// Gets the descriptor that goes in the GDT to use this table as an LDT.
+    pub fn ldt_descriptor(&self) -> SystemSegmentDescriptor {
+        SystemSegmentDescriptor::new(
+            SystemSegmentType::Ldt,
+            self.descriptors.as_ptr() as u64,
+            (N * size_of::<SegmentDescriptor>() - 1) as u32,
+        )
+    }
+}
+
+/// The types of system segment descriptor available in long mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemSegmentType {
+    Ldt = 0x2,
+    AvailableTss = 0x9,
+    BusyTss = 0xB,
+}
+
+/// A 16 byte system segment descriptor, describing an LDT or TSS.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemSegmentDescriptor {
+    base: u64,
+    limit: u32,
+    segment_type: SystemSegmentType,
+}
+
+impl SystemSegmentDescriptor {
+    /// Creates a present descriptor with DPL 0, panics if `limit` doesn't fit in 20 bits.
+    pub fn new(segment_type: SystemSegmentType, base: u64, limit: u32) -> Self {
+        assert!(limit & 0xFFF00000 == 0, "System segment limit too large");
+        SystemSegmentDescriptor {
+            base,
+            limit,
+            segment_type,
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    pub fn segment_type(&self) -> SystemSegmentType {
+        self.segment_type
+    }
+
+    /// Encodes this descriptor as the 16 bytes the CPU reads from the GDT.
+    pub fn encode(&self) -> [u64; 2] {
+        let mut low = SegmentDescriptor::new_null_descriptor();
+        low.set_base(self.base as u32);
+        low.set_limit(self.limit);
+        low.access_byte = AccessByte::from_bits_retain(self.segment_type as u8) | AccessByte::present;
+        [low.encode(), self.base >> 32]
+    }
+}
+
+/// Builds the kernel GDT and loads it on this CPU, reloading every segment register.
+/// Must be called on each CPU before the kernel IDT is used, since the IDT refers to the kernel code selector.
+pub fn load() {
+    let mut gdt = GDT.lock();
+    // the first CPU to load the GDT fills it in
+    if gdt.descriptors[KERNEL_CODE_INDEX as usize].encode() == 0 {
+        gdt.set(KERNEL_CODE_INDEX, SegmentDescriptor::new_kernel_code_descriptor());
+        gdt.set(KERNEL_DATA_INDEX, SegmentDescriptor::new_kernel_data_descriptor());
+    }
+    let code = SegmentSelector::new(KERNEL_CODE_INDEX, true, 0);
+    let data = SegmentSelector::new(KERNEL_DATA_INDEX, true, 0);
+    // This is safe because the GDT is in a static and has valid kernel code and data descriptors
+    unsafe {
+        gdt.gdtr().load();
+        // CS can only be changed with a far jump, call or return
+        asm!(
+            "push {code}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov fs, {data:x}",
+            "mov gs, {data:x}",
+            "mov ss, {data:x}",
+            code = in(reg) code.x as u64,
+            data = in(reg) data.x,
+            tmp = lateout(reg) _,
+        );
+    }
+}
+
+/// Puts `ldt` in the kernel GDT and loads it on this CPU, returning the selector that refers to it.
+/// Segment selectors with `uses_gdt` false then select from `ldt`.
+pub fn install_ldt<const N: usize>(ldt: &'static Ldt<N>) -> SegmentSelector {
+    let mut gdt = GDT.lock();
+    gdt.set_system(LDT_INDEX, ldt.ldt_descriptor());
+    let selector = SegmentSelector::new(LDT_INDEX, true, 0);
+    // This is safe because the LDT is static and its descriptor was just written to the loaded GDT
+    unsafe { load_ldt(selector) };
+    selector
+}
+
+/// Puts `tss` in this CPU's TSS descriptor in the kernel GDT and loads it, returning the selector that refers to it.
+pub fn install_tss(tss: &'static TaskStateSegment) -> SegmentSelector {
+    let index = TSS_INDEX + 2 * current_cpu() as u16;
+    let mut gdt = GDT.lock();
+    gdt.set_system(
+        index,
+        SystemSegmentDescriptor::new(
+            SystemSegmentType::AvailableTss,
+            tss as *const TaskStateSegment as u64,
+            (size_of::<TaskStateSegment>() - 1) as u32,
+        ),
+    );
+    let selector = SegmentSelector::new(index, true, 0);
+    // This is safe because the TSS is static and its descriptor was just written to the loaded GDT
+    unsafe { load_tss(selector) };
+    selector
+}
+
+/// Loads the task register, which marks the TSS descriptor busy.
+/// Caller must ensure the selector refers to an available TSS descriptor in the loaded GDT.
+pub unsafe fn load_tss(selector: SegmentSelector) {
+    asm!("ltr {selector:x}", selector = in(reg) selector.x);
+}
+
+/// Reads the task register.
+pub fn get_tr() -> SegmentSelector {
+    let x: u16;
+    unsafe { asm!("str {output:x}", output = out(reg) x) }
+    SegmentSelector { x }
+}
+
+/// Loads the LDTR, a null selector disables the LDT.
+/// Caller must ensure the selector refers to an LDT descriptor in the loaded GDT.
+pub unsafe fn load_ldt(selector: SegmentSelector) {
+    asm!("lldt {selector:x}", selector = in(reg) selector.x);
+}
+
+/// Reads the LDTR.
+pub fn get_ldtr() -> SegmentSelector {
+    let x: u16;
+    unsafe { asm!("sldt {output:x}", output = out(reg) x) }
+    SegmentSelector { x }
+}
+
+/// Checks the descriptor encoders against known good descriptors and the currently loaded code segment.
+pub fn self_check() {
+    assert_eq!(size_of::<SegmentDescriptor>(), 8);
+    assert_eq!(size_of::<Gdtr>(), 10);
+
+    let code = SegmentDescriptor::new_kernel_code_descriptor();
+    assert_eq!(code.encode(), 0x0020_9A00_0000_0000);
+    let data = SegmentDescriptor::new_kernel_data_descriptor();
+    assert_eq!(data.encode(), 0x0000_9200_0000_0000);
+
+    // a flat 32 bit user code segment exercises every field
+    let user_code = SegmentDescriptorBuilder::new(true)
+        .base(0x1234_5678)
+        .limit(0xFFFFF)
+        .dpl(3)
+        .access(AccessByte::readable_writable)
+        .flags(Flags::size | Flags::granularity)
+        .build()
+        .unwrap();
+    assert_eq!(user_code.encode(), 0x12CF_FA34_5678_FFFF);
+    let decoded = SegmentDescriptor::decode(user_code.encode());
+    assert_eq!(decoded.get_base(), 0x1234_5678);
+    assert_eq!(decoded.get_limit(), 0xFFFFF);
+    assert_eq!(decoded.get_dpl(), 3);
+    assert_eq!(decoded.get_flags(), Flags::size | Flags::granularity);
+    assert_eq!(decoded.encode(), user_code.encode());
+
+    assert_eq!(
+        SegmentDescriptorBuilder::new(false).flags(Flags::long_mode_code).build().err(),
+        Some(InvalidSegmentDescriptor::LongModeData)
+    );
+    assert_eq!(
+        SegmentDescriptorBuilder::new(true).flags(Flags::long_mode_code | Flags::size).build().err(),
+        Some(InvalidSegmentDescriptor::LongModeWithSize)
+    );
+    assert_eq!(
+        SegmentDescriptorBuilder::new(true).limit(0x100000).build().err(),
+        Some(InvalidSegmentDescriptor::LimitTooLarge)
+    );
+    assert_eq!(
+        SegmentDescriptorBuilder::new(true).dpl(4).build().err(),
+        Some(InvalidSegmentDescriptor::InvalidDpl)
+    );
+
+    let selector = SegmentSelector::new(5, true, 3);
+    assert!(selector.uses_gdt());
+    assert_eq!(selector.get_index(), 5);
+    assert_eq!(selector.privilege_level(), 3);
+    assert_eq!(selector.x, 0x2B);
+
+    // the code segment we are running in must be a valid long mode code segment
+    let gdtr = Gdtr::get();
+    let cs = get_cs();
+    assert!(cs.uses_gdt(), "CS does not select a GDT descriptor");
+    // This is safe because the loaded GDTR describes the GDT in use
+    let descriptor = unsafe { gdtr.get_segment_descriptor(cs.get_index()) }.expect("CS is outside the GDT");
+    assert_eq!(descriptor.validate(), Ok(()));
+    assert!(descriptor.get_flags().contains(Flags::long_mode_code), "CS is not a long mode code segment");
+
+    let ldt_descriptor = SystemSegmentDescriptor::new(SystemSegmentType::Ldt, 0xFFFF_FFFF_8123_4567, 0xF);
+    assert_eq!(ldt_descriptor.encode(), [0x8100_8223_4567_000F, 0xFFFF_FFFF]);
+    let tss_descriptor = SystemSegmentDescriptor::new(SystemSegmentType::AvailableTss, 0xFFFF_8000_0012_3000, 0x67);
+    assert_eq!(tss_descriptor.encode(), [0x0000_8912_3000_0067, 0xFFFF_8000]);
+
+    // load a data segment through an LDT selector, then put everything back
+    let ldt = alloc::boxed::Box::leak(alloc::boxed::Box::new(Ldt::<2>::new()));
+    ldt.set(1, SegmentDescriptor::new_kernel_data_descriptor());
+    let ldt_selector = install_ldt(ldt);
+    assert_eq!(get_ldtr().x, ldt_selector.x);
+    let selector = SegmentSelector::new(1, false, 0);
+    let loaded: u16;
+    // This is safe because the selector refers to a valid data segment and ES is restored afterwards
+    unsafe {
+        asm!(
+            "mov es, {selector:x}",
+            "mov {loaded:x}, es",
+            "mov es, {data:x}",
+            selector = in(reg) selector.x,
+            data = in(reg) SegmentSelector::new(KERNEL_DATA_INDEX, true, 0).x,
+            loaded = out(reg) loaded,
+        );
+        load_ldt(SegmentSelector { x: 0 });
+    }
+    assert_eq!(loaded, selector.x);
+}
+
 impl Debug for SegmentDescriptor {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("SegmentDescriptor")
@@ -234,7 +671,8 @@ impl SegmentSelector {
 
         let mut x: u16 = 0;
         x |= (privilege_level & 0b11) as u16;
-        if uses_gdt {
+        // the table indicator bit is set for the LDT
+        if !uses_gdt {
             x |= 0b100;
         }
         x |= index << 3;
@@ -1,6 +1,9 @@
 use core::{arch::asm, mem::size_of};
 use bitflags::bitflags;
 use super::gdt::SegmentSelector;
+use super::registers::get_cs;
+use super::tss::{DOUBLE_FAULT_IST, MACHINE_CHECK_IST, NMI_IST};
+use crate::IDT;
 
 #[derive(Debug)]
 #[repr(packed)]
@@ -16,13 +19,14 @@ impl Idtr {
         asm!("lidt [{idtr}]", idtr = in(reg) self);
     }
 
+    /// Gets the currently loaded IDTR.
     pub fn get() -> Self {
-        let x: *mut Idtr;
-
+        let mut idtr = Idtr { size: 0, base: 0 };
+        // sidt stores the IDTR into the memory operand
         unsafe {
-            asm!("sidt [{idtr}]", idtr = out(reg) x);
-            x.read()
+            asm!("sidt [{idtr}]", idtr = in(reg) &mut idtr);
         }
+        idtr
     }
 
     pub fn from_gate_descriptors(gate_descriptor: &[GateDescriptor]) -> Self {
@@ -33,11 +37,102 @@ impl Idtr {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GateType {
     InterruptGate,
     TrapGate,
 }
 
+/// The vectors of the exceptions that get special treatment in `GateOptions::for_vector`.
+pub const NMI_VECTOR: u8 = 0x2;
+pub const BREAKPOINT_VECTOR: u8 = 0x3;
+pub const OVERFLOW_VECTOR: u8 = 0x4;
+pub const DOUBLE_FAULT_VECTOR: u8 = 0x8;
+pub const MACHINE_CHECK_VECTOR: u8 = 0x12;
+/// The first vector that isn't reserved for exceptions.
+pub const FIRST_EXTERNAL_VECTOR: u8 = 0x20;
+
+/// How a gate is entered: its type, the lowest privilege level that can use `int` on it, and which IST stack it switches to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GateOptions {
+    pub gate_type: GateType,
+    pub dpl: u8,
+    /// The IST index, 0 means the stack is not switched.
+    pub ist: u8,
+}
+
+impl GateOptions {
+    /// An interrupt gate, which clears IF on entry.
+    pub const fn interrupt() -> Self {
+        GateOptions {
+            gate_type: GateType::InterruptGate,
+            dpl: 0,
+            ist: 0,
+        }
+    }
+
+    /// A trap gate, which leaves IF alone.
+    pub const fn trap() -> Self {
+        GateOptions {
+            gate_type: GateType::TrapGate,
+            dpl: 0,
+            ist: 0,
+        }
+    }
+
+    pub const fn with_dpl(mut self, dpl: u8) -> Self {
+        self.dpl = dpl;
+        self
+    }
+
+    pub const fn with_ist(mut self, ist: u8) -> Self {
+        self.ist = ist;
+        self
+    }
+
+    /// The options the kernel uses for `vector` unless told otherwise.
+    /// Exceptions use trap gates, except NMIs, double faults and machine checks, which can arrive at any time and must not be interrupted.
+    /// Those three also switch to their IST stack, since the stack they interrupted may be unusable.
+    /// External interrupts use interrupt gates. Breakpoints and overflows can be raised from user mode with `int3` and `into`.
+    pub const fn for_vector(vector: u8) -> Self {
+        let options = match vector {
+            NMI_VECTOR => Self::interrupt().with_ist(NMI_IST),
+            DOUBLE_FAULT_VECTOR => Self::interrupt().with_ist(DOUBLE_FAULT_IST),
+            MACHINE_CHECK_VECTOR => Self::interrupt().with_ist(MACHINE_CHECK_IST),
+            0..=0x1F => Self::trap(),
+            _ => Self::interrupt(),
+        };
+        match vector {
+            BREAKPOINT_VECTOR | OVERFLOW_VECTOR => options.with_dpl(3),
+            _ => options,
+        }
+    }
+}
+
+/// The ways a gate descriptor can be malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidGateDescriptor {
+    /// The type is not a 64 bit interrupt or trap gate.
+    InvalidType(u8),
+    /// The bits that must be zero aren't.
+    ReservedBitsSet,
+    /// The offset is not a canonical address.
+    NonCanonicalOffset(u64),
+    /// The segment selector is null or selects from the LDT.
+    InvalidSegmentSelector(u16),
+}
+
+/// The fields of a gate descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedGate {
+    pub offset: u64,
+    pub segment_selector: u16,
+    pub ist: u8,
+    pub gate_type: GateType,
+    pub dpl: u8,
+    pub present: bool,
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(packed)]
 pub struct GateDescriptor {
@@ -91,6 +186,7 @@ impl GateDescriptor {
 
     /// Sets the type of this gate descriptor.
     pub fn set_gate_type(&mut self, gate_type: GateType) {
+        self.flags &= !0b1111;
         match gate_type {
             GateType::InterruptGate => self.flags |= 0xE,
             GateType::TrapGate => self.flags |= 0xF,
@@ -99,6 +195,7 @@ impl GateDescriptor {
 
     pub fn set_dpl(&mut self, dpl: u8) {
         assert_eq!(dpl, dpl & 0b11);
+        self.flags &= !0b1100000;
         self.flags |= (dpl & 0b11) << 5;
     }
 
@@ -110,8 +207,96 @@ impl GateDescriptor {
         }
     }
 
+    /// Encodes this descriptor as the 16 bytes the CPU reads from the IDT, in memory order.
+    pub fn encode(&self) -> [u64; 2] {
+        let selector = self.segment_selector.x;
+        let low = (self.offset1 as u64)
+            | (selector as u64) << 16
+            | (self.ist as u64) << 32
+            | (self.flags as u64) << 40
+            | (self.offset2 as u64) << 48;
+        let high = (self.offset3 as u64) | (self.reserved as u64) << 32;
+        [low, high]
+    }
+
+    /// Creates a descriptor from the 16 bytes the CPU reads from the IDT, without checking it.
+    pub fn from_raw(raw: [u64; 2]) -> Self {
+        let [low, high] = raw;
+        Self {
+            offset1: low as u16,
+            segment_selector: SegmentSelector { x: (low >> 16) as u16 },
+            ist: (low >> 32) as u8,
+            flags: (low >> 40) as u8,
+            offset2: (low >> 48) as u16,
+            offset3: high as u32,
+            reserved: (high >> 32) as u32,
+        }
+    }
+
+    /// Decodes and checks this descriptor. The offset and segment selector are only checked if the descriptor is present.
+    pub fn decode(&self) -> Result<DecodedGate, InvalidGateDescriptor> {
+        let present = self.flags & 0b10000000 != 0;
+        let ist_reserved = self.ist & !0b111;
+        let flags_reserved = self.flags & 0b10000;
+        let reserved = self.reserved;
+        if ist_reserved != 0 || flags_reserved != 0 || reserved != 0 {
+            return Err(InvalidGateDescriptor::ReservedBitsSet);
+        }
+        let gate_type = match self.flags & 0b1111 {
+            0xE => GateType::InterruptGate,
+            0xF => GateType::TrapGate,
+            other => return Err(InvalidGateDescriptor::InvalidType(other)),
+        };
+        let offset = self.get_offset();
+        // canonical addresses have bits 48-63 equal to bit 47
+        if present && (((offset as i64) << 16) >> 16) as u64 != offset {
+            return Err(InvalidGateDescriptor::NonCanonicalOffset(offset));
+        }
+        let selector = self.segment_selector;
+        if present && (selector.get_index() == 0 || !selector.uses_gdt()) {
+            return Err(InvalidGateDescriptor::InvalidSegmentSelector(selector.x));
+        }
+        Ok(DecodedGate {
+            offset,
+            segment_selector: selector.x,
+            ist: self.get_ist(),
+            gate_type,
+            dpl: self.get_dpl(),
+            present,
+        })
+    }
+
+    /// Creates a descriptor with the given fields.
+    pub fn from_decoded(decoded: &DecodedGate) -> Self {
+        let mut descriptor = Self::create_null_descriptor();
+        descriptor.set_offset(decoded.offset);
+        descriptor.segment_selector = SegmentSelector { x: decoded.segment_selector };
+        descriptor.set_ist(decoded.ist);
+        descriptor.set_gate_type(decoded.gate_type);
+        descriptor.set_dpl(decoded.dpl);
+        descriptor.set_present(decoded.present);
+        descriptor
+    }
+
+    /// Creates a present gate descriptor for the handler at `offset`. Panics if the DPL or IST is out of range.
+    pub fn new(offset: u64, cs: SegmentSelector, options: GateOptions) -> Self {
+        let mut descriptor = Self::create_null_descriptor();
+        descriptor.set_offset(offset);
+        descriptor.segment_selector = cs;
+        descriptor.set_options(options);
+        descriptor.set_present(true);
+        descriptor
+    }
+
+    /// Sets the type, DPL and IST of this gate descriptor. Panics if the DPL or IST is out of range.
+    pub fn set_options(&mut self, options: GateOptions) {
+        self.set_gate_type(options.gate_type);
+        self.set_dpl(options.dpl);
+        self.set_ist(options.ist);
+    }
+
     /// Creates a null gate descriptor (this is an invalid descriptor).
-    pub fn create_null_descriptor() -> Self {
+    pub const fn create_null_descriptor() -> Self {
         Self {
             offset1: 0,
             segment_selector: SegmentSelector { x: 0 },
@@ -136,6 +321,28 @@ impl GateDescriptor {
     }
 }
 
+/// The values pushed onto the stack by the CPU when an interrupt occurs.
+/// This is the first parameter of every `x86-interrupt` handler.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct InterruptStackFrame {
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64,
+}
+
+impl InterruptStackFrame {
+    /// Changes the instruction the interrupted code will resume at.
+    /// `x86-interrupt` handlers receive their stack frame in place, so this takes effect when the handler returns.
+    /// Caller must ensure that the new instruction pointer is valid to resume at.
+    pub unsafe fn set_instruction_pointer(&mut self, instruction_pointer: u64) {
+        // volatile so that the write is not optimized out, nothing in the handler reads it again
+        core::ptr::write_volatile(&mut self.instruction_pointer, instruction_pointer);
+    }
+}
+
 #[repr(transparent)]
 pub struct Idt {
     gate_descriptors: [GateDescriptor; 256],
@@ -143,7 +350,7 @@ pub struct Idt {
 
 impl Idt {
     /// Creates a new IDT consisting of 256 null gate descriptors
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             gate_descriptors: [GateDescriptor::create_null_descriptor(); 256],
         }
@@ -155,25 +362,65 @@ impl Idt {
         self.gate_descriptors[interrupt_number as usize] = gate_descriptor;
     }
 
+    /// Points `vector` at the handler at `handler_address`, which must be the address of an `x86-interrupt` function of the right signature.
+    pub fn set_handler(&mut self, vector: u8, handler_address: u64, cs: SegmentSelector, options: GateOptions) {
+        self.gate_descriptors[vector as usize] = GateDescriptor::new(handler_address, cs, options);
+    }
+
+    /// Points `vector` in the kernel's IDT at the handler at `handler_address`, which must be the address of an `x86-interrupt`
+    /// function of the right signature. This can be called at any time, the IDT is loaded on every CPU so the gate takes effect
+    /// everywhere at once. The interrupt must not arrive while the gate is being written, so allocate the vector and register its
+    /// handler before the device can use it. Interrupts from devices are better handled with `irq::set_handler`, which keeps
+    /// the accounting of the vector.
+    pub fn register(vector: u8, handler_address: u64, options: GateOptions) {
+        IDT.lock().write_live(vector, GateDescriptor::new(handler_address, get_cs(), options));
+    }
+
+    /// Replaces the gate for `vector` in an IDT that may be loaded. The gate is marked not present while it is rewritten so
+    /// a CPU never uses a mix of the old and new gates.
+    fn write_live(&mut self, vector: u8, descriptor: GateDescriptor) {
+        let [low, high] = descriptor.encode();
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&low.to_le_bytes());
+        bytes[8..].copy_from_slice(&high.to_le_bytes());
+        // the flags byte has the present bit
+        const FLAGS: usize = 5;
+        let gate = &mut self.gate_descriptors[vector as usize] as *mut GateDescriptor as *mut u8;
+        // This is safe because the pointer is to the 16 bytes of the gate. Volatile writes keep them in this order.
+        unsafe {
+            gate.add(FLAGS).write_volatile(0);
+            for (i, &byte) in bytes.iter().enumerate().filter(|&(i, _)| i != FLAGS) {
+                gate.add(i).write_volatile(byte);
+            }
+            gate.add(FLAGS).write_volatile(bytes[FLAGS]);
+        }
+    }
+
+    /// Changes the type, DPL and IST of the gate for `vector`, keeping its handler.
+    pub fn set_options(&mut self, vector: u8, options: GateOptions) {
+        self.gate_descriptors[vector as usize].set_options(options);
+    }
+
     /// Sets the page fault handler, page faults push an error code, so the handler takes two parameters.
     pub fn set_page_fault_handler(
         &mut self,
-        page_fault_handler: extern "x86-interrupt" fn(u64, PageFaultErrorCode),
+        page_fault_handler: extern "x86-interrupt" fn(InterruptStackFrame, PageFaultErrorCode),
         cs: SegmentSelector,
     ) {
-        self.gate_descriptors[0xE] =
-            GateDescriptor::create_exception_handler(page_fault_handler as *const () as u64, cs);
+        self.set_handler(0xE, page_fault_handler as *const () as u64, cs, GateOptions::for_vector(0xE));
     }
 
     /// Sets the general protection fault handler, general protection faults push an error code, so the handler takes two parameters.
     pub fn set_general_protection_fault_handler(
         &mut self,
-        general_protection_fault_handler: extern "x86-interrupt" fn(u64, u64),
+        general_protection_fault_handler: extern "x86-interrupt" fn(InterruptStackFrame, u64),
         cs: SegmentSelector,
     ) {
-        self.gate_descriptors[0xD] = GateDescriptor::create_exception_handler(
+        self.set_handler(
+            0xD,
             general_protection_fault_handler as *const () as u64,
             cs,
+            GateOptions::for_vector(0xD),
         );
     }
 
@@ -181,11 +428,26 @@ impl Idt {
     /// Double faults are also unrecoverable so the handler must not return.
     pub fn set_double_fault_handler(
         &mut self,
-        double_fault_handler: extern "x86-interrupt" fn(u64, u64) -> !,
+        double_fault_handler: extern "x86-interrupt" fn(InterruptStackFrame, u64) -> !,
         cs: SegmentSelector,
     ) {
-        self.gate_descriptors[0x8] =
-            GateDescriptor::create_exception_handler(double_fault_handler as *const () as u64, cs);
+        self.set_handler(
+            DOUBLE_FAULT_VECTOR,
+            double_fault_handler as *const () as u64,
+            cs,
+            GateOptions::for_vector(DOUBLE_FAULT_VECTOR),
+        );
+    }
+
+    /// Checks every present descriptor in this IDT, returning the first invalid one's vector and problem.
+    pub fn validate(&self) -> Result<(), (u8, InvalidGateDescriptor)> {
+        for (vector, descriptor) in self.gate_descriptors.iter().enumerate() {
+            let flags = descriptor.flags;
+            if flags & 0b10000000 != 0 {
+                descriptor.decode().map_err(|error| (vector as u8, error))?;
+            }
+        }
+        Ok(())
     }
 
     /// Gets the IDTr that covers this IDT
@@ -206,4 +468,74 @@ bitflags!{
         const SHADOW_STACK = 1 << 6;
         const SOFTWARE_GUARD_EXTENSION = 1 << 15;
     }
-}
\ No newline at end of file
+}
+
+/// Checks the gate descriptor encoder against a known good descriptor, and that round trips through decoding are lossless.
+pub fn self_check() {
+    assert_eq!(size_of::<GateDescriptor>(), 16);
+    assert_eq!(size_of::<Idtr>(), 10);
+
+    let mut descriptor = GateDescriptor::create_exception_handler(0xFFFF_FFFF_8012_3456, SegmentSelector { x: 0x28 });
+    descriptor.set_ist(2);
+    descriptor.set_dpl(3);
+    assert_eq!(descriptor.encode(), [0x8012_EF02_0028_3456, 0x0000_0000_FFFF_FFFF]);
+    let decoded = descriptor.decode().unwrap();
+    assert_eq!(
+        decoded,
+        DecodedGate {
+            offset: 0xFFFF_FFFF_8012_3456,
+            segment_selector: 0x28,
+            ist: 2,
+            gate_type: GateType::TrapGate,
+            dpl: 3,
+            present: true,
+        }
+    );
+    assert_eq!(GateDescriptor::from_decoded(&decoded).encode(), descriptor.encode());
+    assert_eq!(GateDescriptor::from_raw(descriptor.encode()).encode(), descriptor.encode());
+
+    // changing the type or DPL must replace the old value rather than combining with it
+    descriptor.set_gate_type(GateType::InterruptGate);
+    descriptor.set_dpl(0);
+    assert_eq!(descriptor.get_dpl(), 0);
+    assert_eq!(descriptor.decode().unwrap().gate_type, GateType::InterruptGate);
+
+    let options = GateOptions::interrupt().with_dpl(3).with_ist(1);
+    let gate = GateDescriptor::new(0xFFFF_FFFF_8000_1000, SegmentSelector { x: 0x08 }, options);
+    let decoded = gate.decode().unwrap();
+    assert_eq!(
+        (decoded.gate_type, decoded.dpl, decoded.ist),
+        (GateType::InterruptGate, 3, 1)
+    );
+    assert_eq!(GateOptions::for_vector(BREAKPOINT_VECTOR), GateOptions::trap().with_dpl(3));
+    assert_eq!(GateOptions::for_vector(DOUBLE_FAULT_VECTOR), GateOptions::interrupt().with_ist(DOUBLE_FAULT_IST));
+    assert_eq!(GateOptions::for_vector(FIRST_EXTERNAL_VECTOR), GateOptions::interrupt());
+
+    // a gate registered after boot is used right away, and puts back the gate it replaced
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use crate::x64::vectors;
+
+    static HITS: AtomicU32 = AtomicU32::new(0);
+    extern "x86-interrupt" fn count_hit(_: InterruptStackFrame) {
+        HITS.fetch_add(1, Ordering::SeqCst);
+    }
+    const TEST_VECTOR: u8 = 0xFD;
+    assert!(vectors::reserve(TEST_VECTOR));
+    let old = IDT.lock().gate_descriptors[TEST_VECTOR as usize];
+    Idt::register(TEST_VECTOR, count_hit as *const () as u64, GateOptions::interrupt());
+    // This is safe because the handler only counts
+    unsafe { asm!("int 0xFD") };
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    IDT.lock().write_live(TEST_VECTOR, old);
+    vectors::free(TEST_VECTOR);
+
+    let mut bad = descriptor.encode();
+    bad[0] &= !(0xF << 40);
+    assert_eq!(GateDescriptor::from_raw(bad).decode(), Err(InvalidGateDescriptor::InvalidType(0)));
+    let mut bad = descriptor.encode();
+    bad[1] = 0x0000_8000;
+    assert_eq!(
+        GateDescriptor::from_raw(bad).decode(),
+        Err(InvalidGateDescriptor::NonCanonicalOffset(0x0000_8000_8012_3456))
+    );
+}
@@ -0,0 +1,260 @@
+//! The I/O APICs, which route the interrupt lines of devices (global system interrupts, GSIs) to vectors on a local APIC.
+//! Each I/O APIC handles the GSIs from its base up, with a redirection entry per input. Every entry is masked at boot, and
+//! entries are programmed when a driver registers a handler with `irq::register_irq`, except for the inputs the MADT connects
+//! NMIs to, which are set up to deliver NMIs to the BSP.
+//! Masking and unmasking are done from interrupt handlers when a line misbehaves, so the I/O APICs are only locked with
+//! interrupts disabled.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Mutex;
+
+use crate::acpi::madt::{IOApicInterruptSourceFlags, NmiSource, Polarity, TriggerMode};
+use crate::init::InitError;
+use crate::irq::LineControl;
+use crate::memory::PhysicalAddress;
+use crate::resource;
+use crate::sync::without_interrupts;
+use crate::x64::apic;
+use crate::x64::cpuid::get_initial_apic_id;
+use crate::x64::mmio::{map_mmio, Mmio};
+use crate::{log, ACPI_TABLES};
+
+const MAX_IO_APICS: usize = 8;
+const REGISTERS_SIZE: u64 = 0x20;
+/// Selects the register that `IOWIN` reads and writes.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const IOAPICVER: u32 = 0x01;
+/// The first redirection entry, each one is two registers.
+const IOREDTBL: u32 = 0x10;
+
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+/// The NMI delivery mode, which ignores the vector.
+const ENTRY_DELIVERY_NMI: u64 = 0b100 << 8;
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_DESTINATION_SHIFT: u64 = 56;
+
+/// Marks a vector that no GSI is routed to.
+const NO_GSI: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    id: u8,
+    registers: Mmio,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.registers.write32(IOREGSEL, register);
+        self.registers.read32(IOWIN)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.registers.write32(IOREGSEL, register);
+        self.registers.write32(IOWIN, value);
+    }
+
+    fn read_entry(&self, input: u32) -> u64 {
+        let low = self.read(IOREDTBL + input * 2) as u64;
+        let high = self.read(IOREDTBL + input * 2 + 1) as u64;
+        high << 32 | low
+    }
+
+    /// Writes the destination before the low half, which has the mask bit, so a half written entry is never unmasked.
+    fn write_entry(&self, input: u32, entry: u64) {
+        self.write(IOREDTBL + input * 2 + 1, (entry >> 32) as u32);
+        self.write(IOREDTBL + input * 2, entry as u32);
+        // the entry must take effect before the caller relies on it, like a handler being removed after masking
+        self.registers.flush(IOWIN);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.inputs).contains(&gsi)
+    }
+}
+
+const NONE: Option<IoApic> = None;
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([NONE; MAX_IO_APICS]);
+
+/// Finds the I/O APIC that handles `gsi` and the input it is on. The MADT only has each I/O APIC's base, the number of
+/// inputs comes from its registers, so this only finds I/O APICs that `init_io_apics` has set up.
+fn io_apic_for_gsi(io_apics: &[Option<IoApic>], gsi: u32) -> Option<(&IoApic, u32)> {
+    io_apics
+        .iter()
+        .flatten()
+        .find(|io_apic| io_apic.handles(gsi))
+        .map(|io_apic| (io_apic, gsi - io_apic.gsi_base))
+}
+
+/// The GSI routed to each vector, so line controls (which get the vector) can find the redirection entry.
+static VECTOR_GSIS: [AtomicU32; 256] = [const { AtomicU32::new(NO_GSI) }; 256];
+
+/// Masks, unmasks and ends interrupts of the lines routed with `route`.
+pub static LINE_CONTROL: LineControl = LineControl {
+    mask: |vector| set_masked(vector, true),
+    unmask: |vector| set_masked(vector, false),
+    eoi: |_| apic::eoi(),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    /// No I/O APIC handles the GSI.
+    NoIoApic,
+}
+
+/// Finds the I/O APICs in the MADT, maps their registers and masks all of their inputs.
+pub fn init_io_apics() -> Result<(), InitError> {
+    let madt = ACPI_TABLES
+        .get()
+        .and_then(|tables| tables.madt())
+        .ok_or(InitError::new("no MADT"))?;
+    let mut io_apics = IO_APICS.lock();
+    let mut slots = io_apics.iter_mut();
+    for entry in madt.io_apics() {
+        let Some(slot) = slots.next() else {
+            log!("ioapic: more than {} I/O APICs, ignoring the rest", MAX_IO_APICS);
+            break;
+        };
+        if let Err(error) = resource::claim_mmio(entry.address(), REGISTERS_SIZE, "ioapic") {
+            log!("ioapic: can't claim the registers of I/O APIC {}: {}", entry.apic_id(), error);
+            continue;
+        }
+        // This is safe because the MADT says these are the I/O APIC's registers
+        let registers = unsafe { map_mmio(PhysicalAddress::device(entry.address()), REGISTERS_SIZE) }
+            .ok_or(InitError::new("out of MMIO space"))?;
+        let mut io_apic = IoApic {
+            id: entry.apic_id(),
+            registers,
+            gsi_base: entry.global_system_interrupt_base(),
+            inputs: 0,
+        };
+        io_apic.inputs = (io_apic.read(IOAPICVER) >> 16 & 0xFF) + 1;
+        for input in 0..io_apic.inputs {
+            io_apic.write_entry(input, ENTRY_MASKED);
+        }
+        log!(
+            "ioapic: I/O APIC {} handles GSIs {}..{}",
+            io_apic.id,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.inputs
+        );
+        *slot = Some(io_apic);
+    }
+    set_nmi_inputs(&*io_apics, madt.nmi_sources());
+    Ok(())
+}
+
+/// Sets up the inputs that the MADT connects NMIs to, so they deliver NMIs to this CPU.
+fn set_nmi_inputs(io_apics: &[Option<IoApic>], nmi_sources: impl Iterator<Item = NmiSource>) {
+    for nmi in nmi_sources {
+        let NmiSource::IOApic {
+            global_system_interrupt: gsi,
+            polarity,
+            trigger_mode,
+        } = nmi
+        else {
+            continue;
+        };
+        let Some((io_apic, input)) = io_apic_for_gsi(io_apics, gsi) else {
+            log!("ioapic: no I/O APIC handles the NMI on GSI {}", gsi);
+            continue;
+        };
+        // NMIs are edge triggered and active high unless the MADT says otherwise
+        let mut entry = ENTRY_DELIVERY_NMI | (get_initial_apic_id() as u64) << ENTRY_DESTINATION_SHIFT;
+        if polarity == Polarity::ActiveLow {
+            entry |= ENTRY_ACTIVE_LOW;
+        }
+        if trigger_mode == TriggerMode::Level {
+            entry |= ENTRY_LEVEL_TRIGGERED;
+        }
+        io_apic.write_entry(input, entry);
+        log!("ioapic: GSI {} delivers NMIs", gsi);
+    }
+}
+
+/// Gets the GSI the ISA IRQ `irq` is connected to, which is the same number unless the MADT overrides it.
+pub fn isa_irq_to_gsi(irq: u8) -> u32 {
+    ACPI_TABLES
+        .get()
+        .and_then(|tables| tables.madt())
+        .and_then(|madt| {
+            madt.interrupt_source_overrides()
+                .find(|source_override| source_override.irq_source() == irq)
+        })
+        .map_or(irq as u32, |source_override| source_override.global_system_interrupt())
+}
+
+/// Gets how `gsi` signals interrupts. The MADT says how overridden ISA IRQs are wired, the other ISA IRQs are edge triggered
+/// and active high. Anything above them is assumed to be a PCI interrupt, which is level triggered and active low (the real
+/// answer is in the AML of the interrupt routing table).
+fn signalling(gsi: u32) -> (Polarity, TriggerMode) {
+    let flags = ACPI_TABLES
+        .get()
+        .and_then(|tables| tables.madt())
+        .and_then(|madt| {
+            madt.interrupt_source_overrides()
+                .find(|source_override| source_override.global_system_interrupt() == gsi)
+        })
+        .map(|source_override| source_override.flags());
+    let (default_polarity, default_trigger_mode) = if gsi < 16 || flags.is_some() {
+        (Polarity::ActiveHigh, TriggerMode::Edge)
+    } else {
+        (Polarity::ActiveLow, TriggerMode::Level)
+    };
+    let flags = flags.unwrap_or(IOApicInterruptSourceFlags::empty());
+    let polarity = match flags.polarity() {
+        Polarity::ConformsToBus => default_polarity,
+        polarity => polarity,
+    };
+    let trigger_mode = match flags.trigger_mode() {
+        TriggerMode::ConformsToBus => default_trigger_mode,
+        trigger_mode => trigger_mode,
+    };
+    (polarity, trigger_mode)
+}
+
+/// Routes `gsi` to `vector` on this CPU and unmasks it. The vector's handler must already be in place.
+pub fn route(gsi: u32, vector: u8) -> Result<(), RouteError> {
+    let (polarity, trigger_mode) = signalling(gsi);
+    let mut entry = vector as u64 | (get_initial_apic_id() as u64) << ENTRY_DESTINATION_SHIFT;
+    if polarity == Polarity::ActiveLow {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    if trigger_mode == TriggerMode::Level {
+        entry |= ENTRY_LEVEL_TRIGGERED;
+    }
+    without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let (io_apic, input) = io_apic_for_gsi(&*io_apics, gsi).ok_or(RouteError::NoIoApic)?;
+        VECTOR_GSIS[vector as usize].store(gsi, Ordering::SeqCst);
+        io_apic.write_entry(input, entry);
+        Ok(())
+    })
+}
+
+/// Masks the GSI routed to `vector` and forgets the route.
+pub fn unroute(vector: u8) {
+    set_masked(vector, true);
+    VECTOR_GSIS[vector as usize].store(NO_GSI, Ordering::SeqCst);
+}
+
+/// Masks or unmasks the GSI routed to `vector`, if there is one.
+fn set_masked(vector: u8, masked: bool) {
+    let gsi = VECTOR_GSIS[vector as usize].load(Ordering::SeqCst);
+    if gsi == NO_GSI {
+        return;
+    }
+    without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        if let Some((io_apic, input)) = io_apic_for_gsi(&*io_apics, gsi) {
+            let entry = io_apic.read_entry(input);
+            let entry = if masked { entry | ENTRY_MASKED } else { entry & !ENTRY_MASKED };
+            io_apic.write_entry(input, entry);
+        }
+    })
+}
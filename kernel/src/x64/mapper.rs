@@ -0,0 +1,70 @@
+//! An interface to page table manipulation, so code that maps memory doesn't depend on how the page tables are stored or reached.
+//! `PML4` implements it for the hardware page tables, reached through the direct map.
+
+use crate::memory::{PhysicalAddress, VirtualAddress};
+use crate::pmm::Frame;
+
+pub use super::page_table::MapError;
+use super::page_table::{PageFlags, PML4};
+
+pub trait Mapper {
+    /// Maps the 4KB page at `virtual_address` to `frame`, creating any missing paging structures.
+    /// Fails if `virtual_address` isn't page aligned or is already mapped, or if a user page would be in the kernel half.
+    fn map(&mut self, frame: Frame, virtual_address: VirtualAddress, flags: PageFlags) -> Result<(), MapError>;
+    /// Unmaps the 4KB page at `virtual_address`, returning the frame it mapped.
+    fn unmap(&mut self, virtual_address: VirtualAddress) -> Result<Frame, MapError>;
+    /// Gets the physical address `virtual_address` is mapped to, or None if it isn't mapped.
+    fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress>;
+    /// Changes the permissions and caching of the 4KB page at `virtual_address`.
+    fn update_flags(&mut self, virtual_address: VirtualAddress, flags: PageFlags) -> Result<(), MapError>;
+}
+
+impl Mapper for PML4 {
+    fn map(&mut self, frame: Frame, virtual_address: VirtualAddress, flags: PageFlags) -> Result<(), MapError> {
+        PML4::map(self, frame, virtual_address, flags)
+    }
+
+    fn unmap(&mut self, virtual_address: VirtualAddress) -> Result<Frame, MapError> {
+        PML4::unmap(self, virtual_address)
+    }
+
+    fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
+        PML4::translate(self, virtual_address)
+    }
+
+    fn update_flags(&mut self, virtual_address: VirtualAddress, flags: PageFlags) -> Result<(), MapError> {
+        PML4::update_flags(self, virtual_address, flags)
+    }
+}
+
+/// An address that nothing else maps, for `self_check`.
+const SCRATCH_ADDRESS: u64 = 0xFFFF_E000_0000_0000;
+
+/// Checks mapping, remapping and unmapping a page through `Mapper` in the current page tables.
+pub fn self_check() {
+    use crate::pmm::FrameAllocator;
+    use crate::x64::registers::get_cr3;
+    use crate::FRAME_ALLOCATOR;
+
+    let cr3 = get_cr3();
+    let mapper: &mut dyn Mapper = cr3.pml4();
+    let virtual_address = VirtualAddress::create(SCRATCH_ADDRESS);
+    let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+    let physical_address = frame.get_starting_address().get_address();
+    assert!(mapper.translate(virtual_address).is_none());
+    assert_eq!(mapper.map(frame, virtual_address, PageFlags::KERNEL_DATA), Ok(()));
+    let inside = VirtualAddress::create(SCRATCH_ADDRESS + 0x123);
+    assert_eq!(
+        mapper.translate(inside).map(|address| address.get_address()),
+        Some(physical_address + 0x123)
+    );
+    // This is safe because the page was just mapped writable
+    unsafe { (SCRATCH_ADDRESS as *mut u64).write_volatile(0x5A5A) };
+    assert_eq!(mapper.update_flags(virtual_address, PageFlags::NO_EXECUTE), Ok(()));
+    assert_eq!(unsafe { (SCRATCH_ADDRESS as *const u64).read_volatile() }, 0x5A5A);
+    let unmapped = mapper.unmap(virtual_address).unwrap();
+    assert_eq!(unmapped.get_starting_address().get_address(), physical_address);
+    assert!(mapper.translate(virtual_address).is_none());
+    assert_eq!(mapper.unmap(virtual_address).err(), Some(MapError::NotMapped));
+    FRAME_ALLOCATOR.get().unwrap().lock().free(unmapped);
+}
@@ -0,0 +1,130 @@
+//! Access to memory mapped device registers.
+//! Volatile accesses are kept in program order by the compiler relative to each other, and MMIO regions are uncacheable so the
+//! CPU doesn't reorder them either. They are also kept in order with normal memory accesses around them, so a driver can fill a
+//! buffer and then write a register telling the device to read it (see also `barrier::dma_write_barrier`).
+//!
+//! MMIO writes to PCI devices are posted: the write instruction finishes before the write reaches the device. Reading any
+//! register of the same device waits for earlier writes to arrive, which `flush` does. It is needed when a write has to take
+//! effect before something that doesn't go through the device, like selecting an IOAPIC register and then masking an interrupt,
+//! or writing a register and then starting a delay.
+//!
+//! Drivers get an `Mmio` from `map_mmio`, which maps the registers uncacheable in a region after the heap.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use spin::Mutex;
+
+use crate::heap::{HEAP_MAX_SIZE, HEAP_START};
+use crate::memory::{PhysicalAddress, VirtualAddress};
+use crate::pmm::Frame;
+use crate::x64::page_table::PageFlags;
+use crate::x64::registers::get_cr3;
+
+/// The start of the virtual region device memory is mapped into.
+/// It shares a PML4 entry with the heap, so address spaces created after boot see new mappings.
+pub const MMIO_START: u64 = HEAP_START + HEAP_MAX_SIZE;
+/// The size of the MMIO region.
+pub const MMIO_MAX_SIZE: u64 = 1 << 30;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The start of the unused part of the MMIO region. Mappings are never removed.
+static NEXT_MMIO_ADDRESS: Mutex<u64> = Mutex::new(MMIO_START);
+
+/// A block of memory mapped registers. Offsets are in bytes and must be aligned to the size of the access.
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    base: *mut u8,
+    size: usize,
+}
+
+// This is safe because the registers are only accessed with volatile accesses, whether they can be used from several CPUs
+// at once is up to the device and the driver
+unsafe impl Send for Mmio {}
+
+macro_rules! accessors {
+    ($read:ident, $write:ident, $type:ty) => {
+        pub fn $read(&self, offset: usize) -> $type {
+            let pointer = self.register::<$type>(offset);
+            // This is safe because the register is in the region, which `new` requires to be mapped
+            let value = unsafe { pointer.read_volatile() };
+            // keep later normal loads (like a buffer the device filled) after this read
+            compiler_fence(Ordering::Acquire);
+            value
+        }
+
+        pub fn $write(&self, offset: usize, value: $type) {
+            let pointer = self.register::<$type>(offset);
+            // keep earlier normal stores (like a buffer for the device) before this write
+            compiler_fence(Ordering::Release);
+            // This is safe because the register is in the region, which `new` requires to be mapped
+            unsafe { pointer.write_volatile(value) };
+        }
+    };
+}
+
+impl Mmio {
+    /// Creates accessors for the `size` bytes of registers at `base`.
+    /// Safety: the registers must be mapped uncacheable at `base` for as long as this (and its copies) are used.
+    pub const unsafe fn new(base: *mut u8, size: usize) -> Self {
+        Mmio { base, size }
+    }
+
+    pub fn base(&self) -> *mut u8 {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T {
+        let size = core::mem::size_of::<T>();
+        assert!(offset + size <= self.size && offset % size == 0, "invalid MMIO register offset {:#x}", offset);
+        // This is safe because the offset is in the region
+        unsafe { self.base.add(offset) as *mut T }
+    }
+
+    accessors!(read8, write8, u8);
+    accessors!(read16, write16, u16);
+    accessors!(read32, write32, u32);
+    accessors!(read64, write64, u64);
+
+    /// Waits for earlier posted writes to reach the device, by reading the 32 bit register at `offset`.
+    /// The register must be one that can be read without side effects.
+    pub fn flush(&self, offset: usize) {
+        self.read32(offset);
+    }
+
+    /// Writes a 32 bit register and waits for the write to reach the device.
+    pub fn write32_flushed(&self, offset: usize, value: u32) {
+        self.write32(offset, value);
+        self.flush(offset);
+    }
+}
+
+/// Maps `len` bytes of device memory at `physical` with caching disabled and returns accessors for them.
+/// `physical` doesn't need to be page aligned. Returns None if the MMIO region is full.
+/// Safety: `physical` must be device registers, mapping RAM uncacheable while the direct map caches it is undefined.
+pub unsafe fn map_mmio(physical: PhysicalAddress, len: u64) -> Option<Mmio> {
+    let offset = physical.get_address() % PAGE_SIZE;
+    let start = physical.get_address() - offset;
+    let size = (offset + len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    let mut next = NEXT_MMIO_ADDRESS.lock();
+    if *next + size > MMIO_START + MMIO_MAX_SIZE {
+        return None;
+    }
+    let virtual_start = *next;
+    *next += size;
+    drop(next);
+
+    let cr3 = get_cr3();
+    let pml4 = cr3.pml4();
+    for (i, address) in PhysicalAddress::device_frames(start, size).enumerate() {
+        let frame = Frame::from_starting_address(address);
+        // The region is only handed out once, so nothing is mapped there yet
+        pml4.map(frame, VirtualAddress::create(virtual_start + i as u64 * PAGE_SIZE), PageFlags::MMIO).unwrap();
+    }
+    Some(Mmio::new((virtual_start + offset) as *mut u8, len as usize))
+}
@@ -2,4 +2,18 @@ pub mod gdt;
 pub mod registers;
 pub mod idt;
 pub mod cpuid;
-pub mod page_table;
\ No newline at end of file
+pub mod page_table;
+pub mod mapper;
+pub mod tlb;
+pub mod port;
+pub mod early_idt;
+pub mod fixup;
+pub mod msr;
+pub mod barrier;
+pub mod mmio;
+pub mod vectors;
+pub mod apic;
+pub mod apic_timer;
+pub mod ioapic;
+pub mod pic;
+pub mod tss;pub mod debug_registers;
@@ -0,0 +1,78 @@
+use core::arch::asm;
+
+use super::fixup::Fault;
+
+/// Reads the model specific register `msr`.
+/// Causes a general protection fault if the MSR does not exist, use `try_rdmsr` when that is not known.
+/// Caller must ensure that reading the MSR does not violate memory safety.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high);
+    ((high as u64) << 32) | low as u64
+}
+
+/// Writes `value` to the model specific register `msr`.
+/// Causes a general protection fault if the MSR does not exist or the value is invalid, use `try_wrmsr` when that is not known.
+/// Caller must ensure that writing the MSR does not violate memory safety.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32);
+}
+
+/// Reads the model specific register `msr`, returning `Err(Fault)` if it does not exist.
+/// Caller must ensure that reading the MSR does not violate memory safety.
+pub unsafe fn try_rdmsr(msr: u32) -> Result<u64, Fault> {
+    let low: u32;
+    let high: u32;
+    let faulted: u32;
+    asm!(
+        "xor {faulted:e}, {faulted:e}",
+        "2: rdmsr",
+        "jmp 4f",
+        "3: mov {faulted:e}, 1",
+        "xor eax, eax",
+        "xor edx, edx",
+        "4:",
+        ".pushsection .ex_table, \"a\"",
+        ".balign 4",
+        ".long 2b - .",
+        ".long 3b - .",
+        ".popsection",
+        in("ecx") msr,
+        out("eax") low,
+        out("edx") high,
+        faulted = out(reg) faulted,
+    );
+    if faulted == 0 {
+        Ok(((high as u64) << 32) | low as u64)
+    } else {
+        Err(Fault)
+    }
+}
+
+/// Writes `value` to the model specific register `msr`, returning `Err(Fault)` if the MSR does not exist or rejected the value.
+/// Caller must ensure that writing the MSR does not violate memory safety.
+pub unsafe fn try_wrmsr(msr: u32, value: u64) -> Result<(), Fault> {
+    let faulted: u32;
+    asm!(
+        "xor {faulted:e}, {faulted:e}",
+        "2: wrmsr",
+        "jmp 4f",
+        "3: mov {faulted:e}, 1",
+        "4:",
+        ".pushsection .ex_table, \"a\"",
+        ".balign 4",
+        ".long 2b - .",
+        ".long 3b - .",
+        ".popsection",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        faulted = out(reg) faulted,
+    );
+    if faulted == 0 {
+        Ok(())
+    } else {
+        Err(Fault)
+    }
+}
@@ -1,15 +1,47 @@
 use bitfield_struct::bitfield;
+use bitflags::bitflags;
 
 use core::{
-    fmt::{Debug, Write},
+    fmt::{self, Debug, Write},
     iter,
+    ops::Range,
 };
 
 use crate::{
     memory::{DirectMappedAddress, PhysicalAddress, VirtualAddress},
-    pmm::{Frame, FrameAllocator, MemoryMapAllocator},
-    DEBUG_SERIAL_PORT, FRAME_ALLOCATOR,
+    pmm::{memzero_frame, Frame, FrameAllocator, MemoryMapAllocator},
+    x64::{cpuid::supports_1gb_pages, tlb},
+    mapping_assert, DEBUG_SERIAL_PORT, FRAME_ALLOCATOR,
 };
+bitflags! {
+    /// The permissions and caching of a page.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFlags: u64 {
+        const WRITABLE = 1 << 0;
+        /// The page can be accessed from user mode.
+        const USER = 1 << 1;
+        const WRITE_THROUGH = 1 << 2;
+        const CACHE_DISABLE = 1 << 3;
+        /// The translation is kept in the TLB across CR3 switches, for kernel pages that are the same in every address space.
+        const GLOBAL = 1 << 4;
+        /// Selects the upper half of the PAT together with the caching bits.
+        const PAT = 1 << 5;
+        const NO_EXECUTE = 1 << 6;
+    }
+}
+
+impl PageFlags {
+    /// Writable data the kernel doesn't execute.
+    pub const KERNEL_DATA: PageFlags = PageFlags::WRITABLE.union(PageFlags::NO_EXECUTE);
+    /// Device registers, which must not be cached.
+    pub const MMIO: PageFlags = PageFlags::KERNEL_DATA
+        .union(PageFlags::CACHE_DISABLE)
+        .union(PageFlags::WRITE_THROUGH);
+}
+
+/// The size of a page mapped by a Pdpt entry.
+pub const HUGE_PAGE_1GB_SIZE: u64 = 1 << 30;
+
 /// The top level paging structure, each entry references a Pdpt
 #[derive(Clone, Copy)]
 pub struct PML4 {
@@ -40,7 +72,7 @@ pub struct PageTable {
 /// An entry in PML4 that references a page directory pointer table.
 #[bitfield(u64)]
 pub struct Pml4Entry {
-    present: bool,
+    pub present: bool,
     read_write: bool,
     user_supervisor: bool,
     page_write_through: bool,
@@ -200,7 +232,7 @@ pub enum PageDirectoryEntry {
 /// An entry in a page table that maps a 4KB page.
 #[bitfield(u64)]
 pub struct PageTableEntry {
-    present: bool,
+    pub present: bool,
     read_write: bool,
     user_supervisor: bool,
     page_write_through: bool,
@@ -224,9 +256,34 @@ pub struct PageTableEntry {
     execute_disable: bool,
 }
 
-struct PageTableIterator<'a> {
+/// Iterates over the pages mapped by a PML4, in address order. Huge pages are returned once, with their first frame.
+pub struct PageTableIterator<'a> {
     page_table: &'a PML4,
-    current: VirtualAddress,
+    /// The index of the next 4KB page to look at, out of the 2^36 in the address space
+    next_page: u64,
+}
+
+/// The number of 4KB pages covered by an entry at each level.
+const PAGES_PER_PML4_ENTRY: u64 = 1 << 27;
+const PAGES_PER_PDPT_ENTRY: u64 = 1 << 18;
+const PAGES_PER_PAGE_DIRECTORY_ENTRY: u64 = 1 << 9;
+const PAGES_IN_ADDRESS_SPACE: u64 = 1 << 36;
+
+/// Why a mapping couldn't be changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// Nothing is mapped at the address.
+    NotMapped,
+    /// The address is in a huge page, which can only be changed as a whole.
+    HugePage,
+    /// Something is already mapped at the address.
+    AlreadyMapped,
+    /// The address isn't the start of a page.
+    Unaligned,
+    /// User accessible pages can't be in the kernel half.
+    KernelAddress,
+    /// The CPU doesn't support pages of that size.
+    Unsupported,
 }
 
 // Implement the basic operations of a Pml4Entry
@@ -264,6 +321,11 @@ impl PdptEntryUnion {
         }
     }
 
+    /// Creates an entry that maps a 1GB page
+    pub fn from_huge_page(huge_page: PdptEntryHugePage) -> Self {
+        Self { huge_page }
+    }
+
     /// Converts this union to its safe wrapper: `PdptEntry`
     pub fn get_entry(&self) -> PdptEntry {
         if unsafe { self.huge_page.page_size() } {
@@ -332,13 +394,13 @@ impl Debug for PageDirectoryEntryUnion {
 // Implement the basic operations of a PdptEntryPageDirectory
 impl PdptEntryPageDirectory {
     /// Gets the physical address pointed to by this entry
-    fn address(&self) -> PhysicalAddress {
+    pub fn address(&self) -> PhysicalAddress {
         PhysicalAddress::new(self.internal_addr() << 12)
     }
 
     /// Sets the physical address pointed to by this entry
     fn set_address(&mut self, physical_address: PhysicalAddress) {
-        assert!(physical_address.is_frame_aligned());
+        mapping_assert!(physical_address.is_frame_aligned());
         self.set_internal_addr(physical_address.get_address() >> 12);
     }
 
@@ -358,13 +420,39 @@ impl PdptEntryPageDirectory {
 
 // Implement the basic operations of a PdptEntryHugePage
 impl PdptEntryHugePage {
-    /// Gets the physical address referenced by this Pdpt entry
-    fn address(&self) -> PhysicalAddress {
-        PhysicalAddress::new(self.internal_addr() << 30)
+    /// Creates an entry that maps the 1GB page at `physical_address`, which must be 1GB aligned.
+    pub fn mapping(physical_address: PhysicalAddress, flags: PageFlags) -> Self {
+        let mut entry = Self::new()
+            .with_page_size(true)
+            .with_read_write(flags.contains(PageFlags::WRITABLE))
+            .with_user_supervisor(flags.contains(PageFlags::USER))
+            .with_page_write_through(flags.contains(PageFlags::WRITE_THROUGH))
+            .with_page_cache_disable(flags.contains(PageFlags::CACHE_DISABLE))
+            .with_global(flags.contains(PageFlags::GLOBAL))
+            .with_page_attribute_table(flags.contains(PageFlags::PAT))
+            .with_execute_disable(flags.contains(PageFlags::NO_EXECUTE));
+        entry.set_address(physical_address);
+        entry.set_present(true);
+        entry
     }
 
-    pub fn frame(&self) -> ! {
-        todo!("huge pages not implemented")
+    /// Gets the physical address of the 1GB page mapped by this Pdpt entry
+    pub fn address(&self) -> PhysicalAddress {
+        PhysicalAddress::device(self.internal_addr() << 30)
+    }
+
+    /// Sets the physical address of the 1GB page mapped by this Pdpt entry
+    pub fn set_address(&mut self, physical_address: PhysicalAddress) {
+        mapping_assert!(
+            physical_address.get_address() % HUGE_PAGE_1GB_SIZE == 0,
+            "Attempted to map a 1GB page to a physical address that isn't 1GB aligned"
+        );
+        self.set_internal_addr(physical_address.get_address() >> 30);
+    }
+
+    /// Gets the first frame of the 1GB page mapped by this Pdpt entry
+    pub fn frame(&self) -> Frame {
+        Frame::from_starting_address(self.address())
     }
 }
 
@@ -375,7 +463,7 @@ impl PageDirectoryEntryPageTable {
     }
 
     pub fn set_address(&mut self, physical_address: PhysicalAddress) {
-        assert!(physical_address.is_frame_aligned());
+        mapping_assert!(physical_address.is_frame_aligned());
         self.set_internal_addr(physical_address.get_address() >> 12);
     }
 
@@ -405,9 +493,9 @@ impl PageDirectoryEntryHugePage {
 
 /// Implement the basic operations of a `PageTableEntry`
 impl PageTableEntry {
-    /// Gets the address pointed to by this page table entry.
-    fn address(&self) -> PhysicalAddress {
-        PhysicalAddress::new(self.internal_addr() << 12)
+    /// Gets the address pointed to by this page table entry, which can be device memory.
+    pub fn address(&self) -> PhysicalAddress {
+        PhysicalAddress::device(self.internal_addr() << 12)
     }
 
     /// Gets the frame mapped by this page table entry.
@@ -417,7 +505,7 @@ impl PageTableEntry {
 
     /// Sets the address pointed to by this page table entry
     fn set_address(&mut self, physical_address: PhysicalAddress) {
-        assert!(
+        mapping_assert!(
             physical_address.is_frame_aligned(),
             "Attempted to map page to non-frame-aligned physical address"
         );
@@ -428,35 +516,56 @@ impl PageTableEntry {
     fn set_frame(&mut self, frame: Frame) {
         self.set_address(frame.get_starting_address());
     }
+
+    /// Sets the permission and caching bits to `flags`.
+    fn set_flags(&mut self, flags: PageFlags) {
+        self.set_read_write(flags.contains(PageFlags::WRITABLE));
+        self.set_user_supervisor(flags.contains(PageFlags::USER));
+        self.set_page_write_through(flags.contains(PageFlags::WRITE_THROUGH));
+        self.set_page_cache_disable(flags.contains(PageFlags::CACHE_DISABLE));
+        self.set_global(flags.contains(PageFlags::GLOBAL));
+        self.set_page_attribute_table(flags.contains(PageFlags::PAT));
+        self.set_execute_disable(flags.contains(PageFlags::NO_EXECUTE));
+    }
+
+    /// Gets the permission and caching bits.
+    pub fn flags(&self) -> PageFlags {
+        let mut flags = PageFlags::empty();
+        flags.set(PageFlags::WRITABLE, self.read_write());
+        flags.set(PageFlags::USER, self.user_supervisor());
+        flags.set(PageFlags::WRITE_THROUGH, self.page_write_through());
+        flags.set(PageFlags::CACHE_DISABLE, self.page_cache_disable());
+        flags.set(PageFlags::GLOBAL, self.global());
+        flags.set(PageFlags::PAT, self.page_attribute_table());
+        flags.set(PageFlags::NO_EXECUTE, self.execute_disable());
+        flags
+    }
 }
 
 impl PML4 {
     /// Creates a new empty pml4 table
     pub fn new() -> &'static mut Self {
-        let physical_address = FRAME_ALLOCATOR
-            .get()
-            .unwrap()
-            .lock()
-            .allocate()
-            .unwrap()
-            .get_starting_address();
-        let direct_address = DirectMappedAddress::from_physical(physical_address);
-        let mut pml4 = unsafe { direct_address.as_pointer::<Self>().as_mut().unwrap() };
-        for i in 0..512 {
-            pml4.entries[i] = Pml4Entry::from(0u64);
-        }
-        pml4
-    }
-
-    /// Maps `virtual_address` to `frame`
-    pub fn map(
-        &mut self,
-        frame: Frame,
-        virtual_address: VirtualAddress,
-        writable: bool,
-        no_execute: bool,
-    ) {
-        let mut pml4_entry = self.entries[virtual_address.pml4_index()];
+        let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+        // This is safe because the frame was just allocated, and all zeroes is a table of non present entries
+        unsafe {
+            memzero_frame(&frame);
+            DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<Self>().as_mut().unwrap()
+        }
+    }
+
+    /// Maps `virtual_address` to `frame`, creating any missing paging structures.
+    /// Fails if `virtual_address` isn't page aligned or is already mapped, or if a user page would be in the kernel half.
+    pub fn map(&mut self, frame: Frame, virtual_address: VirtualAddress, flags: PageFlags) -> Result<(), MapError> {
+        if !virtual_address.page_aligned() {
+            return Err(MapError::Unaligned);
+        }
+        if flags.contains(PageFlags::USER) && virtual_address.is_kernel() {
+            return Err(MapError::KernelAddress);
+        }
+        // Intermediate entries are writable and executable, the final entry decides the permissions.
+        // They are only user accessible on the way to user pages.
+        let user = flags.contains(PageFlags::USER);
+        let pml4_entry = &mut self.entries[virtual_address.pml4_index()];
         let pdpt = if pml4_entry.present() {
             unsafe { pml4_entry.pdpt().as_mut().unwrap() }
         } else {
@@ -464,111 +573,288 @@ impl PML4 {
             let new_pdpt = Pdpt::new();
             // and add it to this pml4
             pml4_entry.set_pdpt(new_pdpt as *const Pdpt);
+            pml4_entry.set_read_write(true);
             pml4_entry.set_present(true);
 
             new_pdpt
         };
 
-        let pdpt_entry = pdpt.entries[virtual_address.pdpt_index()];
+        if user {
+            pml4_entry.set_user_supervisor(true);
+        }
+
+        let pdpt_entry = &mut pdpt.entries[virtual_address.pdpt_index()];
         let page_directory = if pdpt_entry.present() {
             match pdpt_entry.get_entry() {
-                PdptEntry::PageDirectory(page_directory_pointer) => unsafe {
+                PdptEntry::PageDirectory(mut page_directory_pointer) => unsafe {
+                    if user && !page_directory_pointer.user_supervisor() {
+                        page_directory_pointer.set_user_supervisor(true);
+                        *pdpt_entry = PdptEntryUnion {
+                            page_directory: page_directory_pointer,
+                        };
+                    }
                     page_directory_pointer.page_directory().as_mut().unwrap()
                 },
-                PdptEntry::HugePage(_) => panic!("Tried to map already mapped page!"),
+                PdptEntry::HugePage(_) => return Err(MapError::HugePage),
             }
         } else {
-            PageDirectory::new()
+            let new_page_directory = PageDirectory::new();
+            let mut entry = PdptEntryPageDirectory::new();
+            entry.set_page_directory(new_page_directory as *const PageDirectory);
+            entry.set_read_write(true);
+            entry.set_user_supervisor(user);
+            entry.set_present(true);
+            *pdpt_entry = PdptEntryUnion {
+                page_directory: entry,
+            };
+            new_page_directory
         };
-        let page_directory_entry = page_directory.entries[virtual_address.page_directory_index()];
+
+        let page_directory_entry =
+            &mut page_directory.entries[virtual_address.page_directory_index()];
         let page_table = if page_directory_entry.present() {
             match page_directory_entry.get_entry() {
-                PageDirectoryEntry::PageTable(page_table_pointer) => unsafe {
+                PageDirectoryEntry::PageTable(mut page_table_pointer) => unsafe {
+                    if user && !page_table_pointer.user_supervisor() {
+                        page_table_pointer.set_user_supervisor(true);
+                        *page_directory_entry = PageDirectoryEntryUnion {
+                            page_table: page_table_pointer,
+                        };
+                    }
                     page_table_pointer.page_table().as_mut().unwrap()
                 },
-                PageDirectoryEntry::HugePage(_) => panic!("Tried to map already mapped page!"),
+                PageDirectoryEntry::HugePage(_) => return Err(MapError::HugePage),
             }
         } else {
-            PageTable::new()
+            let new_page_table = PageTable::new();
+            let mut entry = PageDirectoryEntryPageTable::new();
+            entry.set_page_table(new_page_table as *const PageTable);
+            entry.set_read_write(true);
+            entry.set_user_supervisor(user);
+            entry.set_present(true);
+            *page_directory_entry = PageDirectoryEntryUnion { page_table: entry };
+            new_page_table
         };
-        let mut page_table_entry: PageTableEntry =
-            page_table.entries[virtual_address.page_table_index()];
-        assert!(
-            !page_table_entry.present(),
-            "tried to map already mapped page"
-        );
+
+        let page_table_entry = &mut page_table.entries[virtual_address.page_table_index()];
+        if page_table_entry.present() {
+            return Err(MapError::AlreadyMapped);
+        }
         page_table_entry.set_frame(frame);
-        page_table_entry.set_read_write(writable);
-        page_table_entry.set_execute_disable(no_execute);
+        page_table_entry.set_flags(flags);
+        page_table_entry.set_present(true);
+        // The CPU may have cached the old non-present translation
+        tlb::invlpg(virtual_address);
+        Ok(())
+    }
+
+    /// Maps the 1GB page at `virtual_address` to `physical_address`, creating the Pdpt if it is missing.
+    /// Fails if the CPU doesn't support 1GB pages, either address isn't 1GB aligned, a user page would be in the kernel half,
+    /// or the Pdpt entry is already in use.
+    pub fn map_huge_1gb(
+        &mut self,
+        physical_address: PhysicalAddress,
+        virtual_address: VirtualAddress,
+        flags: PageFlags,
+    ) -> Result<(), MapError> {
+        if !supports_1gb_pages() {
+            return Err(MapError::Unsupported);
+        }
+        if virtual_address.address() % HUGE_PAGE_1GB_SIZE != 0 || physical_address.get_address() % HUGE_PAGE_1GB_SIZE != 0 {
+            return Err(MapError::Unaligned);
+        }
+        if flags.contains(PageFlags::USER) && virtual_address.is_kernel() {
+            return Err(MapError::KernelAddress);
+        }
+        let pml4_entry = &mut self.entries[virtual_address.pml4_index()];
+        let pdpt = if pml4_entry.present() {
+            unsafe { pml4_entry.pdpt().as_mut().unwrap() }
+        } else {
+            let new_pdpt = Pdpt::new();
+            pml4_entry.set_pdpt(new_pdpt as *const Pdpt);
+            pml4_entry.set_read_write(true);
+            pml4_entry.set_present(true);
+            new_pdpt
+        };
+        if flags.contains(PageFlags::USER) {
+            pml4_entry.set_user_supervisor(true);
+        }
+
+        let pdpt_entry = &mut pdpt.entries[virtual_address.pdpt_index()];
+        if pdpt_entry.present() {
+            return Err(MapError::AlreadyMapped);
+        }
+        *pdpt_entry = PdptEntryUnion::from_huge_page(PdptEntryHugePage::mapping(physical_address, flags));
+        // A single invlpg anywhere in the page drops the whole 1GB translation
+        tlb::invlpg(virtual_address);
+        Ok(())
+    }
+
+    /// Unmaps the 1GB page at `virtual_address`, returning the physical address it mapped.
+    /// Fails with `NotMapped` unless a 1GB page is mapped there, smaller pages are unmapped with `unmap`.
+    pub fn unmap_huge_1gb(&mut self, virtual_address: VirtualAddress) -> Result<PhysicalAddress, MapError> {
+        if virtual_address.address() % HUGE_PAGE_1GB_SIZE != 0 {
+            return Err(MapError::Unaligned);
+        }
+        let pml4_entry = &self.entries[virtual_address.pml4_index()];
+        if !pml4_entry.present() {
+            return Err(MapError::NotMapped);
+        }
+        // This is safe because present entries reference paging structures in the direct map
+        let pdpt = unsafe { &mut *pml4_entry.pdpt() };
+        let pdpt_entry = &mut pdpt.entries[virtual_address.pdpt_index()];
+        if !pdpt_entry.present() {
+            return Err(MapError::NotMapped);
+        }
+        let PdptEntry::HugePage(huge_page) = pdpt_entry.get_entry() else {
+            return Err(MapError::NotMapped);
+        };
+        *pdpt_entry = PdptEntryUnion::new(0);
+        tlb::invlpg(virtual_address);
+        Ok(huge_page.address())
     }
 
     /// Gets an iterator over the mappings of this PML4's page table hierarchy
     pub fn iterator(&self) -> PageTableIterator {
         PageTableIterator {
             page_table: self,
-            current: VirtualAddress::create(0),
+            next_page: 0,
         }
     }
 
+    /// Gets the page table entry mapping the 4KB page at `virtual_address`.
+    fn page_table_entry_mut(&mut self, virtual_address: VirtualAddress) -> Result<&mut PageTableEntry, MapError> {
+        let pml4_entry = &self.entries[virtual_address.pml4_index()];
+        if !pml4_entry.present() {
+            return Err(MapError::NotMapped);
+        }
+        // This is safe because present entries reference paging structures in the direct map
+        let pdpt = unsafe { &mut *pml4_entry.pdpt() };
+        let pdpt_entry = &pdpt.entries[virtual_address.pdpt_index()];
+        if !pdpt_entry.present() {
+            return Err(MapError::NotMapped);
+        }
+        let page_directory = match pdpt_entry.get_entry() {
+            PdptEntry::HugePage(_) => return Err(MapError::HugePage),
+            PdptEntry::PageDirectory(entry) => unsafe { &mut *entry.page_directory() },
+        };
+        let page_directory_entry = &page_directory.entries[virtual_address.page_directory_index()];
+        if !page_directory_entry.present() {
+            return Err(MapError::NotMapped);
+        }
+        let page_table = match page_directory_entry.get_entry() {
+            PageDirectoryEntry::HugePage(_) => return Err(MapError::HugePage),
+            PageDirectoryEntry::PageTable(entry) => unsafe { &mut *entry.page_table() },
+        };
+        let page_table_entry = &mut page_table.entries[virtual_address.page_table_index()];
+        if !page_table_entry.present() {
+            return Err(MapError::NotMapped);
+        }
+        Ok(page_table_entry)
+    }
+
+    /// Unmaps the 4KB page at `virtual_address`, returning the frame it mapped. The paging structures are kept.
+    pub fn unmap(&mut self, virtual_address: VirtualAddress) -> Result<Frame, MapError> {
+        let page_table_entry = self.page_table_entry_mut(virtual_address)?;
+        let frame = page_table_entry.frame();
+        *page_table_entry = PageTableEntry::new();
+        tlb::invlpg(virtual_address);
+        Ok(frame)
+    }
+
+    /// Changes the permissions and caching of the 4KB page at `virtual_address`.
+    /// Giving a kernel page `USER` doesn't make the paging structures above it user accessible.
+    pub fn update_flags(&mut self, virtual_address: VirtualAddress, flags: PageFlags) -> Result<(), MapError> {
+        if flags.contains(PageFlags::USER) && virtual_address.is_kernel() {
+            return Err(MapError::KernelAddress);
+        }
+        let page_table_entry = self.page_table_entry_mut(virtual_address)?;
+        page_table_entry.set_flags(flags);
+        tlb::invlpg(virtual_address);
+        Ok(())
+    }
+
+    /// Gets the frame that `virtual_address` is in, or None if it isn't mapped.
+    /// For addresses in huge pages this is the 4KB frame of the huge page that the address is in.
     pub fn get(&self, virtual_address: VirtualAddress) -> Option<Frame> {
-        todo!()
+        let physical_address = self.translate(virtual_address)?.get_address();
+        Some(Frame::from_starting_address(PhysicalAddress::device(physical_address & !0xFFF)))
+    }
+
+    /// Gets the physical address that `virtual_address` is mapped to, or None if it isn't mapped.
+    pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
+        let address = virtual_address.address();
+        let pml4_entry = &self.entries[virtual_address.pml4_index()];
+        if !pml4_entry.present() {
+            return None;
+        }
+        // This is safe because present entries reference paging structures in the direct map
+        let pdpt = unsafe { &*pml4_entry.pdpt() };
+        let pdpt_entry = &pdpt.entries[virtual_address.pdpt_index()];
+        if !pdpt_entry.present() {
+            return None;
+        }
+        let page_directory = match pdpt_entry.get_entry() {
+            PdptEntry::HugePage(huge_page) => {
+                return Some(PhysicalAddress::device(
+                    huge_page.address().get_address() + address % HUGE_PAGE_1GB_SIZE,
+                ))
+            }
+            PdptEntry::PageDirectory(entry) => unsafe { &*entry.page_directory() },
+        };
+        let page_directory_entry = &page_directory.entries[virtual_address.page_directory_index()];
+        if !page_directory_entry.present() {
+            return None;
+        }
+        let page_table = match page_directory_entry.get_entry() {
+            PageDirectoryEntry::HugePage(huge_page) => {
+                return Some(PhysicalAddress::device(huge_page.address() + address % (1 << 21)))
+            }
+            PageDirectoryEntry::PageTable(entry) => unsafe { &*entry.page_table() },
+        };
+        let page_table_entry = &page_table.entries[virtual_address.page_table_index()];
+        if !page_table_entry.present() {
+            return None;
+        }
+        Some(PhysicalAddress::device(
+            page_table_entry.address().get_address() + virtual_address.page_offset() as u64,
+        ))
     }
 }
 
 impl Pdpt {
     /// Creates a new empty pdpt.
     pub fn new() -> &'static mut Self {
-        let physical_address = FRAME_ALLOCATOR
-            .get()
-            .unwrap()
-            .lock()
-            .allocate()
-            .unwrap()
-            .get_starting_address();
-        let direct_address = DirectMappedAddress::from_physical(physical_address);
-        let mut pdpt = unsafe { direct_address.as_pointer::<Self>().as_mut().unwrap() };
-        for i in 0..512 {
-            pdpt.entries[i] = PdptEntryUnion::new(0u64);
+        let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+        // This is safe because the frame was just allocated, and all zeroes is a table of non present entries
+        unsafe {
+            memzero_frame(&frame);
+            DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<Self>().as_mut().unwrap()
         }
-        pdpt
     }
 }
 
 impl PageDirectory {
     /// Creates a new empty page directory.
     pub fn new() -> &'static mut Self {
-        let physical_address = FRAME_ALLOCATOR
-            .get()
-            .unwrap()
-            .lock()
-            .allocate()
-            .unwrap()
-            .get_starting_address();
-        let direct_address = DirectMappedAddress::from_physical(physical_address);
-        let mut page_directory = unsafe { direct_address.as_pointer::<Self>().as_mut().unwrap() };
-        for i in 0..512 {
-            page_directory.entries[i] = PageDirectoryEntryUnion::new(0u64);
+        let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+        // This is safe because the frame was just allocated, and all zeroes is a table of non present entries
+        unsafe {
+            memzero_frame(&frame);
+            DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<Self>().as_mut().unwrap()
         }
-        page_directory
     }
 }
 
 impl PageTable {
     /// Creates a new empty page table
     pub fn new() -> &'static mut Self {
-        let physical_address = FRAME_ALLOCATOR
-            .get()
-            .unwrap()
-            .lock()
-            .allocate()
-            .unwrap()
-            .get_starting_address();
-        let direct_address = DirectMappedAddress::from_physical(physical_address);
-        let mut page_table = unsafe { direct_address.as_pointer::<Self>().as_mut().unwrap() };
-        for i in 0..512 {
-            page_table.entries[i] = PageTableEntry::from(0u64);
+        let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+        // This is safe because the frame was just allocated, and all zeroes is a table of non present entries
+        unsafe {
+            memzero_frame(&frame);
+            DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<Self>().as_mut().unwrap()
         }
-        page_table
     }
 }
 
@@ -576,95 +862,254 @@ impl Iterator for PageTableIterator<'_> {
     type Item = (VirtualAddress, Frame);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current.page_table_index() == 1 << 9 {
-            self.current.set_page_table_index(0);
-            self.next_page_table()?;
-        } else {
-            self.current
-                .set_page_table_index(self.current.page_table_index() + 1);
-            return match self.page_table.get(self.current) {
-                Some(frame) => Some((self.current, frame)),
-                None => self.next(), // Note, this algorithm should be easy if you use recursion
+        while self.next_page < PAGES_IN_ADDRESS_SPACE {
+            let page = self.next_page;
+            let virtual_address = page_address(page);
+            // skip whole non-present structures at a time
+            let pml4_entry = &self.page_table.entries[virtual_address.pml4_index()];
+            if !pml4_entry.present() {
+                self.next_page = align_up(page + 1, PAGES_PER_PML4_ENTRY);
+                continue;
+            }
+            let pdpt = unsafe { &*pml4_entry.pdpt() };
+            let pdpt_entry = &pdpt.entries[virtual_address.pdpt_index()];
+            if !pdpt_entry.present() {
+                self.next_page = align_up(page + 1, PAGES_PER_PDPT_ENTRY);
+                continue;
+            }
+            let page_directory = match pdpt_entry.get_entry() {
+                PdptEntry::HugePage(huge_page) => {
+                    self.next_page = align_up(page + 1, PAGES_PER_PDPT_ENTRY);
+                    return Some((virtual_address, huge_page.frame()));
+                }
+                PdptEntry::PageDirectory(entry) => unsafe { &*entry.page_directory() },
             };
+            let page_directory_entry = &page_directory.entries[virtual_address.page_directory_index()];
+            if !page_directory_entry.present() {
+                self.next_page = align_up(page + 1, PAGES_PER_PAGE_DIRECTORY_ENTRY);
+                continue;
+            }
+            let page_table = match page_directory_entry.get_entry() {
+                PageDirectoryEntry::HugePage(huge_page) => {
+                    self.next_page = align_up(page + 1, PAGES_PER_PAGE_DIRECTORY_ENTRY);
+                    let frame = Frame::from_starting_address(PhysicalAddress::device(huge_page.address()));
+                    return Some((virtual_address, frame));
+                }
+                PageDirectoryEntry::PageTable(entry) => unsafe { &*entry.page_table() },
+            };
+            self.next_page = page + 1;
+            let page_table_entry = &page_table.entries[virtual_address.page_table_index()];
+            if page_table_entry.present() {
+                return Some((virtual_address, page_table_entry.frame()));
+            }
         }
-
         None
     }
 }
 
-impl PageTableIterator<'_> {
-    /// Moves `current` to the next present page table.
-    /// Returns None if no present page tables are left.
-    fn next_page_table(&mut self) -> Option<()>{
-        if self.current.page_directory_index() == 1 << 9 {
-            // If we are at the end of the current page directory, we go to the next page directory
-            self.current.set_page_directory_index(0);
-            self.next_page_directory()?;
-        } else {
-            self.current
-                .set_page_directory_index(self.current.page_directory_index() + 1);
-        }
-
-        while let None = self.page_table.get_page_table(self.current) {
-            // We continue through the page tables until we find one that is present.
-            if self.current.page_directory_index() == 1 << 9 {
-                // If we are at the end of the current page directory, we jump to the next one.
-                self.next_page_directory()?;
-                self.current.set_page_table_index(0);
-            } else {
-                // Otherwise, we try the next page table
-                self.current
-                    .set_page_table_index(self.current.page_directory_index() + 1);
-            }
-        }
-        return Some(());
+/// The number of pages of each size mapped in part of an address space, and the paging structures used to map them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MappingStats {
+    pub pages_4kb: u64,
+    pub pages_2mb: u64,
+    pub pages_1gb: u64,
+    pub pdpts: u64,
+    pub page_directories: u64,
+    pub page_tables: u64,
+}
+
+impl MappingStats {
+    /// Gets the number of bytes mapped.
+    pub fn mapped_bytes(&self) -> u64 {
+        self.pages_4kb * 0x1000 + self.pages_2mb * (1 << 21) + self.pages_1gb * HUGE_PAGE_1GB_SIZE
     }
 
-    /// Moves `current` to the next present page directory.
-    /// Returns None if no present page directories are left.
-    fn next_page_directory(&mut self) -> Option<()>{
-        if self.current.pdpt_index() == 1 << 9 {
-            // If we are at the end of the current pdpt, we go to the next one.
-            self.current.set_pdpt_index(0);
-            self.next_pdpt()?;
-        } else {
-            // Otherwise, go to the next page directory
-            self.current.set_pdpt_index(self.current.pdpt_index() + 1);
-        }
-
-        while let None = self.page_table.get_page_directory(self.current) {
-            // We continue through page directories until we find one that is present.
-            if self.current.pdpt_index() == 1 << 9 {
-                // If we reach the end of the current pdpt, we go to the next present one.
-                self.next_pdpt();
-                self.current.set_pdpt_index(0);
-            } else {
-                self.current.set_pdpt_index(self.current.pdpt_index() + 1);
+    /// Gets the number of frames used by paging structures, not counting the PML4.
+    pub fn table_frames(&self) -> u64 {
+        self.pdpts + self.page_directories + self.page_tables
+    }
+}
+
+/// The structure `PML4::walk` found, either a paging structure or a page.
+enum WalkItem {
+    Pdpt,
+    PageDirectory,
+    PageTable,
+    Page {
+        virtual_address: u64,
+        physical_address: u64,
+        size: u64,
+    },
+}
+
+impl PML4 {
+    /// Calls `f` with every paging structure and page reachable from the PML4 entries in `pml4_entries`, in address order.
+    fn walk(&self, pml4_entries: Range<usize>, mut f: impl FnMut(WalkItem)) {
+        for pml4_index in pml4_entries {
+            let pml4_entry = &self.entries[pml4_index];
+            if !pml4_entry.present() {
+                continue;
+            }
+            f(WalkItem::Pdpt);
+            let base = page_address(pml4_index as u64 * PAGES_PER_PML4_ENTRY).address();
+            // This is safe because present entries reference paging structures in the direct map
+            let pdpt = unsafe { &*pml4_entry.pdpt() };
+            for (pdpt_index, pdpt_entry) in pdpt.entries.iter().enumerate() {
+                if !pdpt_entry.present() {
+                    continue;
+                }
+                let pdpt_base = base + pdpt_index as u64 * HUGE_PAGE_1GB_SIZE;
+                let page_directory = match pdpt_entry.get_entry() {
+                    PdptEntry::HugePage(huge_page) => {
+                        f(WalkItem::Page {
+                            virtual_address: pdpt_base,
+                            physical_address: huge_page.address().get_address(),
+                            size: HUGE_PAGE_1GB_SIZE,
+                        });
+                        continue;
+                    }
+                    PdptEntry::PageDirectory(entry) => unsafe { &*entry.page_directory() },
+                };
+                f(WalkItem::PageDirectory);
+                for (page_directory_index, page_directory_entry) in page_directory.entries.iter().enumerate() {
+                    if !page_directory_entry.present() {
+                        continue;
+                    }
+                    let page_directory_base = pdpt_base + ((page_directory_index as u64) << 21);
+                    let page_table = match page_directory_entry.get_entry() {
+                        PageDirectoryEntry::HugePage(huge_page) => {
+                            f(WalkItem::Page {
+                                virtual_address: page_directory_base,
+                                physical_address: huge_page.address(),
+                                size: 1 << 21,
+                            });
+                            continue;
+                        }
+                        PageDirectoryEntry::PageTable(entry) => unsafe { &*entry.page_table() },
+                    };
+                    f(WalkItem::PageTable);
+                    for (page_table_index, page_table_entry) in page_table.entries.iter().enumerate() {
+                        if page_table_entry.present() {
+                            f(WalkItem::Page {
+                                virtual_address: page_directory_base + ((page_table_index as u64) << 12),
+                                physical_address: page_table_entry.address().get_address(),
+                                size: 0x1000,
+                            });
+                        }
+                    }
+                }
             }
         }
-        return Some(())
     }
 
-    /// Moves `current` to the next present pdpt.
-    /// Returns None if no present pdpt's are left.
-    fn next_pdpt(&mut self) -> Option<()>{
-        if self.current.pml4_index() == 1 << 9 {
-            // If we are at the end of the current pml4, there are no pdpts left
-            return None;
-        } else {
-            // Otherwise, go to the next page directory
-            self.current.set_pml4_index(self.current.pml4_index() + 1);
-        }
+    /// Counts the pages and paging structures reachable from the PML4 entries in `pml4_entries`.
+    /// `0..256` is the user half and `256..512` the kernel half.
+    pub fn stats(&self, pml4_entries: Range<usize>) -> MappingStats {
+        let mut stats = MappingStats::default();
+        self.walk(pml4_entries, |item| match item {
+            WalkItem::Pdpt => stats.pdpts += 1,
+            WalkItem::PageDirectory => stats.page_directories += 1,
+            WalkItem::PageTable => stats.page_tables += 1,
+            WalkItem::Page { size: 0x1000, .. } => stats.pages_4kb += 1,
+            WalkItem::Page { size: HUGE_PAGE_1GB_SIZE, .. } => stats.pages_1gb += 1,
+            WalkItem::Page { .. } => stats.pages_2mb += 1,
+        });
+        stats
+    }
 
-        while let None = self.page_table.get_page_directory(self.current) {
-            // We continue through page directories until we find one that is present.
-            if self.current.pml4_index() == 1 << 9 {
-                // If we reach the end of the current pdpt, we go to the next present one.
-                return None;
-            } else {
-                self.current.set_page_directory_index(self.current.page_directory_index() + 1);
+    /// Writes the mappings reachable from the PML4 entries in `pml4_entries`, merging pages that are contiguous both virtually
+    /// and physically into one line.
+    pub fn dump(&self, pml4_entries: Range<usize>, output: &mut impl fmt::Write) -> fmt::Result {
+        // the virtual start, physical start and length of the range being merged
+        let mut current: Option<(u64, u64, u64)> = None;
+        let mut result = Ok(());
+        fn write_range(output: &mut impl fmt::Write, (virtual_start, physical_start, length): (u64, u64, u64)) -> fmt::Result {
+            writeln!(
+                output,
+                "{:016x}-{:016x} -> {:x} ({} KiB)",
+                virtual_start,
+                virtual_start + length,
+                physical_start,
+                length / 1024
+            )
+        }
+        self.walk(pml4_entries, |item| {
+            let WalkItem::Page {
+                virtual_address,
+                physical_address,
+                size,
+            } = item
+            else {
+                return;
+            };
+            match current.as_mut() {
+                Some((virtual_start, physical_start, length))
+                    if *virtual_start + *length == virtual_address && *physical_start + *length == physical_address =>
+                {
+                    *length += size;
+                }
+                _ => {
+                    if let Some(range) = current.replace((virtual_address, physical_address, size)) {
+                        result = result.and(write_range(output, range));
+                    }
+                }
             }
+        });
+        if let Some(range) = current {
+            result = result.and(write_range(output, range));
         }
-        return Some(())
+        result
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Gets the canonical address of the 4KB page with index `page`.
+fn page_address(page: u64) -> VirtualAddress {
+    let address = page << 12;
+    // sign extend bit 47
+    let address = if address & (1 << 47) != 0 { address | 0xFFFF << 48 } else { address };
+    VirtualAddress::create(address)
+}
+
+/// Checks mapping a 1GB page and reading memory through it.
+pub fn self_check() {
+    use crate::x64::registers::get_cr3;
+
+    /// A 1GB aligned address that nothing else maps.
+    const SCRATCH_1GB_ADDRESS: u64 = 0xFFFF_E000_4000_0000;
+
+    let cr3 = get_cr3();
+    let pml4 = cr3.pml4();
+    let virtual_address = VirtualAddress::create(SCRATCH_1GB_ADDRESS);
+    let frame = FRAME_ALLOCATOR.get().unwrap().lock().allocate().unwrap();
+    let physical_address = frame.get_starting_address().get_address();
+    let page_start = physical_address & !(HUGE_PAGE_1GB_SIZE - 1);
+    let result = pml4.map_huge_1gb(PhysicalAddress::device(page_start), virtual_address, PageFlags::NO_EXECUTE);
+    if !supports_1gb_pages() {
+        assert_eq!(result, Err(MapError::Unsupported));
+        FRAME_ALLOCATOR.get().unwrap().lock().free(frame);
+        return;
+    }
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        pml4.map_huge_1gb(PhysicalAddress::device(page_start), virtual_address, PageFlags::NO_EXECUTE),
+        Err(MapError::AlreadyMapped)
+    );
+    let through_huge_page = SCRATCH_1GB_ADDRESS + physical_address - page_start;
+    assert_eq!(
+        pml4.translate(VirtualAddress::create(through_huge_page)).map(|address| address.get_address()),
+        Some(physical_address)
+    );
+    // This is safe because the frame was just allocated, and the 1GB page maps it read only
+    unsafe {
+        let direct_mapped = DirectMappedAddress::from_physical(frame.get_starting_address()).as_pointer::<u64>();
+        direct_mapped.write_volatile(0x1234_5678_9ABC_DEF0);
+        assert_eq!((through_huge_page as *const u64).read_volatile(), 0x1234_5678_9ABC_DEF0);
     }
+    assert_eq!(pml4.unmap_huge_1gb(virtual_address).map(|address| address.get_address()), Ok(page_start));
+    assert!(pml4.translate(virtual_address).is_none());
+    FRAME_ALLOCATOR.get().unwrap().lock().free(frame);
 }
@@ -0,0 +1,107 @@
+//! The legacy 8259 PICs, which are only remapped and masked since interrupts go through the APICs.
+//! At boot the master PIC delivers its IRQs on vectors 0x08 to 0x0F, where the exceptions are, so an interrupt from it would
+//! look like a double fault or a page fault. Remapping moves them to the first external vectors.
+//! A masked PIC can still deliver a spurious IRQ 7 or 15 when a line drops before it is acknowledged, so those two vectors get
+//! a handler that counts them.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::irq::{self, IrqReturn};
+use crate::log;
+
+use super::idt::FIRST_EXTERNAL_VECTOR;
+use super::port::{inb, outb};
+use super::vectors;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+/// Starts initialization, with an ICW4 following.
+const ICW1_INIT: u8 = 0x11;
+/// The master has the slave on IRQ 2.
+const ICW3_SLAVE_ON_IRQ2: u8 = 1 << 2;
+/// The slave's cascade identity.
+const ICW3_CASCADE_IDENTITY: u8 = 2;
+const ICW4_8086_MODE: u8 = 0x01;
+/// Makes the next command port read return the in-service register.
+const OCW3_READ_ISR: u8 = 0x0B;
+const EOI: u8 = 0x20;
+
+/// The vectors the PICs' IRQs are remapped to, IRQ 0 to 7 and IRQ 8 to 15.
+pub const PIC1_VECTOR_BASE: u8 = FIRST_EXTERNAL_VECTOR;
+pub const PIC2_VECTOR_BASE: u8 = FIRST_EXTERNAL_VECTOR + 8;
+/// The IRQ a PIC reports when the line went away before the CPU acknowledged it.
+const SPURIOUS_IRQ: u8 = 7;
+
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// Writes to a PIC port, then gives the PIC time to act on it with a write to the unused POST code port.
+unsafe fn write(port: u16, value: u8) {
+    outb(port, value);
+    outb(0x80, 0);
+}
+
+/// Masks every IRQ of both PICs.
+pub fn mask_all() {
+    // This is safe because masking only stops interrupts
+    unsafe {
+        outb(PIC1_DATA, 0xFF);
+        outb(PIC2_DATA, 0xFF);
+    }
+}
+
+/// Moves the PICs' vectors away from the exceptions and masks all of their IRQs. The interrupt entry stubs must be installed.
+pub fn remap_and_mask() {
+    // This is safe because the PICs are reinitialized with every IRQ masked, on vectors that have entry stubs
+    unsafe {
+        write(PIC1_COMMAND, ICW1_INIT);
+        write(PIC2_COMMAND, ICW1_INIT);
+        write(PIC1_DATA, PIC1_VECTOR_BASE);
+        write(PIC2_DATA, PIC2_VECTOR_BASE);
+        write(PIC1_DATA, ICW3_SLAVE_ON_IRQ2);
+        write(PIC2_DATA, ICW3_CASCADE_IDENTITY);
+        write(PIC1_DATA, ICW4_8086_MODE);
+        write(PIC2_DATA, ICW4_8086_MODE);
+    }
+    mask_all();
+    for vector in [PIC1_VECTOR_BASE + SPURIOUS_IRQ, PIC2_VECTOR_BASE + SPURIOUS_IRQ] {
+        if !vectors::reserve(vector) || irq::set_handler(vector, spurious).is_err() {
+            log!("pic: vector {:#x} is in use, spurious PIC interrupts will look unhandled", vector);
+        }
+    }
+}
+
+/// Reads the in-service register of the PIC with command port `command`.
+fn in_service(command: u16) -> u8 {
+    // This is safe because OCW3 only selects which register the command port reads
+    unsafe {
+        outb(command, OCW3_READ_ISR);
+        inb(command)
+    }
+}
+
+/// Handles IRQ 7 and 15. A real one is in service and needs an EOI like any other IRQ, a spurious one isn't and doesn't,
+/// except that the master did see the slave's cascade IRQ for a spurious IRQ 15.
+fn spurious(vector: u8) -> IrqReturn {
+    let slave = vector == PIC2_VECTOR_BASE + SPURIOUS_IRQ;
+    let command = if slave { PIC2_COMMAND } else { PIC1_COMMAND };
+    // This is safe because these EOIs end an interrupt the PIC delivered
+    unsafe {
+        if in_service(command) & 1 << SPURIOUS_IRQ != 0 {
+            outb(command, EOI);
+        } else {
+            SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        }
+        if slave {
+            outb(PIC1_COMMAND, EOI);
+        }
+    }
+    IrqReturn::Handled
+}
+
+/// Gets the number of spurious interrupts from the PICs.
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
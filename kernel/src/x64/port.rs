@@ -0,0 +1,83 @@
+use core::arch::asm;
+
+use super::fixup::Fault;
+
+/// Writes a byte to the given I/O port.
+/// Caller must ensure that writing to the port does not violate memory safety.
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value);
+}
+
+/// Reads a byte from the given I/O port.
+/// Caller must ensure that reading from the port does not violate memory safety.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port);
+    value
+}
+
+/// Writes a word to the given I/O port.
+/// Caller must ensure that writing to the port does not violate memory safety.
+pub unsafe fn outw(port: u16, value: u16) {
+    asm!("out dx, ax", in("dx") port, in("ax") value);
+}
+
+/// Reads a word from the given I/O port.
+/// Caller must ensure that reading from the port does not violate memory safety.
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    asm!("in ax, dx", out("ax") value, in("dx") port);
+    value
+}
+
+/// Writes a double word to the given I/O port.
+/// Caller must ensure that writing to the port does not violate memory safety.
+pub unsafe fn outl(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value);
+}
+
+/// Reads a double word from the given I/O port.
+/// Caller must ensure that reading from the port does not violate memory safety.
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", out("eax") value, in("dx") port);
+    value
+}
+
+/// Generates a function that reads an I/O port, returning `Err(Fault)` instead of crashing if the access faults.
+/// Port accesses at CPL 0 normally can't fault, but some hypervisors inject a general protection fault for ports they don't emulate.
+macro_rules! probe_in {
+    ($name:ident, $type:ty, $instruction:literal, $register:tt) => {
+        /// Reads the given I/O port, returning `Err(Fault)` if the access faulted.
+        /// A port with no device behind it usually reads as all ones rather than faulting.
+        /// Caller must ensure that reading from the port does not violate memory safety.
+        pub unsafe fn $name(port: u16) -> Result<$type, Fault> {
+            let value: $type;
+            let faulted: u32;
+            asm!(
+                "xor {faulted:e}, {faulted:e}",
+                concat!("2: ", $instruction),
+                "jmp 4f",
+                "3: mov {faulted:e}, 1",
+                "4:",
+                ".pushsection .ex_table, \"a\"",
+                ".balign 4",
+                ".long 2b - .",
+                ".long 3b - .",
+                ".popsection",
+                in("dx") port,
+                out($register) value,
+                faulted = out(reg) faulted,
+            );
+            if faulted == 0 {
+                Ok(value)
+            } else {
+                Err(Fault)
+            }
+        }
+    };
+}
+
+probe_in!(probe_inb, u8, "in al, dx", "al");
+probe_in!(probe_inw, u16, "in ax, dx", "ax");
+probe_in!(probe_inl, u32, "in eax, dx", "eax");
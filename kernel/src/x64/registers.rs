@@ -74,7 +74,15 @@ pub fn get_cr0() -> Cr0 {
     Cr0::from_bits_retain(x)
 }
 
+/// Reads the value of the cr2 register (the address that caused the last page fault).
+pub fn get_cr2() -> u64 {
+    let x: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) x) }
+    x
+}
+
 #[repr(transparent)]
+#[derive(Clone, Copy)]
 pub struct Cr3 {
     x: u64,
 }
@@ -91,6 +99,11 @@ impl Cr3 {
         self.x & (!0xFFF) & (!(u64::MAX << M))
     }
 
+    /// Gets the PCID in the low bits, which is only meaningful if CR4.PCIDE is set
+    pub fn pcid(&self) -> u16 {
+        (self.x & 0xFFF) as u16
+    }
+
     /// Gets the PML4 pointed to by cr3 (requires physical memory to be mapped at some offset)
     pub fn pml4(&self) -> &mut PML4 {
         let ptr = (self.address() + DIRECT_MAP_START.get().unwrap()) as *mut PML4;
@@ -105,6 +118,12 @@ pub fn get_cr3() -> Cr3 {
     Cr3::new(x)
 }
 
+/// Writes the CR3 register, switching page tables and flushing the TLB (except for global pages).
+/// Caller must ensure the new page tables map everything in use, including the running code and the stack.
+pub unsafe fn set_cr3(cr3: Cr3) {
+    asm!("mov cr3, {c}", c = in(reg) cr3.x, options(nostack));
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy)]
     pub struct Cr4: u64{
@@ -136,9 +155,45 @@ bitflags! {
     }
 }
 
-/// Reads the value of the CR3 register.
+/// Reads the value of the CR4 register.
 pub fn get_cr4() -> Cr4 {
     let x: u64;
     unsafe { asm!("mov {c}, cr4", c = out(reg) x) }
     Cr4::from_bits_retain(x)
 }
+
+/// Writes the CR4 register.
+/// Caller must ensure the new value is supported by the CPU and doesn't break anything in use.
+pub unsafe fn set_cr4(cr4: Cr4) {
+    asm!("mov cr4, {c}", c = in(reg) cr4.bits(), options(nostack));
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct RFlags: u64{
+        const carry = 1;
+        const parity = 1 << 2;
+        const auxiliary_carry = 1 << 4;
+        const zero = 1 << 6;
+        const sign = 1 << 7;
+        const trap = 1 << 8;
+        /// Set if maskable interrupts are enabled.
+        const interrupt_enable = 1 << 9;
+        const direction = 1 << 10;
+        const overflow = 1 << 11;
+        const nested_task = 1 << 14;
+        const resume = 1 << 16;
+        const virtual_8086_mode = 1 << 17;
+        const alignment_check = 1 << 18;
+        const virtual_interrupt = 1 << 19;
+        const virtual_interrupt_pending = 1 << 20;
+        const id = 1 << 21;
+    }
+}
+
+/// Reads the value of the RFLAGS register.
+pub fn get_rflags() -> RFlags {
+    let x: u64;
+    unsafe { asm!("pushfq", "pop {}", out(reg) x) }
+    RFlags::from_bits_retain(x)
+}
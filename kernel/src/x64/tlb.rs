@@ -0,0 +1,206 @@
+//! TLB invalidation. Changing a present page table entry must be followed by one of these, or the CPU may keep using the old
+//! translation. Only the running CPU is flushed, except by `shootdown`, which also has every other started CPU flush a range
+//! with an IPI and waits until they have. Memory that was mapped must not be reused before that.
+//!
+//! With process-context identifiers (PCIDs) the TLB keeps translations for several address spaces at once, tagged with the
+//! PCID in the low bits of CR3. They are only used if `enable_pcid` was called and the CPU supports them.
+
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use spin::Mutex;
+
+use super::apic;
+use super::cpuid::{supports_invpcid, supports_pcid};
+use super::registers::{get_cr3, get_cr4, set_cr3, set_cr4, Cr4};
+use super::vectors::{self, VectorClass};
+use crate::init::InitError;
+use crate::irq::{self, IrqReturn};
+use crate::memory::VirtualAddress;
+use crate::smp::{cpu_state, CpuState};
+use crate::sync::{current_cpu, MAX_CPUS};
+
+/// The INVPCID invalidation types.
+const INVPCID_ADDRESS: u64 = 0;
+const INVPCID_SINGLE_CONTEXT: u64 = 1;
+const INVPCID_ALL_INCLUDING_GLOBAL: u64 = 2;
+
+const PAGE_SIZE: u64 = 0x1000;
+/// Ranges of more pages than this are flushed with `flush_everything` instead of page by page.
+const FLUSH_EVERYTHING_PAGES: u64 = 64;
+
+/// The vector of the shootdown IPI, 0 until `init_shootdown` allocates it.
+static SHOOTDOWN_VECTOR: AtomicU8 = AtomicU8::new(0);
+/// Serializes shootdowns, so there is only one range to flush at a time.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+/// The range of the current shootdown, written before `SHOOTDOWN_GENERATION` is incremented.
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+/// Incremented for each shootdown.
+static SHOOTDOWN_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// The last shootdown each CPU has flushed.
+static FLUSHED_GENERATION: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Flushes the translation of the page containing `virtual_address`, for the current PCID.
+/// This flushes the translation even if it is global, and the whole page if it is a huge page.
+pub fn invlpg(virtual_address: VirtualAddress) {
+    // This is safe because invlpg only drops cached translations
+    unsafe { asm!("invlpg [{}]", in(reg) virtual_address.address(), options(nostack, preserves_flags)) };
+}
+
+/// Flushes every translation of the current PCID except global ones, by reloading CR3.
+pub fn flush_all() {
+    // This is safe because the page tables don't change
+    unsafe { set_cr3(get_cr3()) };
+}
+
+/// Flushes every translation, including global ones and those of every PCID, with INVPCID or by toggling CR4.PGE.
+pub fn flush_everything() {
+    if supports_invpcid() {
+        invpcid(INVPCID_ALL_INCLUDING_GLOBAL, 0, 0);
+        return;
+    }
+    // any change to CR4.PGE flushes every PCID while reloading CR3 only flushes the current one, so PGE is toggled even when
+    // it is clear
+    let cr4 = get_cr4();
+    // This is safe because turning global pages off and on again (or on and off) only drops cached translations
+    unsafe {
+        set_cr4(cr4 ^ Cr4::page_global_enable);
+        set_cr4(cr4);
+    }
+}
+
+/// Checks whether PCIDs are in use on this CPU.
+pub fn pcid_enabled() -> bool {
+    get_cr4().contains(Cr4::pcid_enable)
+}
+
+/// Turns on PCIDs on this CPU if it supports them, returns whether they are on.
+/// The current CR3 must have PCID 0, which it does unless PCIDs were already in use.
+pub fn enable_pcid() -> bool {
+    if !supports_pcid() {
+        return false;
+    }
+    let cr4 = get_cr4();
+    if !cr4.contains(Cr4::pcid_enable) {
+        assert_eq!(get_cr3().pcid(), 0, "Attempted to enable PCIDs with a PCID in CR3");
+        // This is safe because all existing translations are tagged with PCID 0, which is the current one
+        unsafe { set_cr4(cr4 | Cr4::pcid_enable) };
+    }
+    true
+}
+
+/// Runs INVPCID with the given type and descriptor.
+fn invpcid(invalidation_type: u64, pcid: u16, address: u64) {
+    let descriptor: [u64; 2] = [pcid as u64, address];
+    // This is safe because INVPCID only drops cached translations, callers check that the CPU supports it
+    unsafe {
+        asm!("invpcid {}, [{}]", in(reg) invalidation_type, in(reg) &descriptor, options(nostack, preserves_flags));
+    }
+}
+
+/// Flushes the translations (except global ones) of `pcid`, which doesn't have to be the current one.
+pub fn flush_pcid(pcid: u16) {
+    if !pcid_enabled() {
+        flush_all();
+    } else if supports_invpcid() {
+        invpcid(INVPCID_SINGLE_CONTEXT, pcid, 0);
+    } else if get_cr3().pcid() == pcid {
+        flush_all();
+    } else {
+        // without INVPCID another PCID can only be flushed by flushing everything
+        flush_everything();
+    }
+}
+
+/// Flushes the translation of the page containing `virtual_address` in `pcid`, which doesn't have to be the current one.
+pub fn flush_address_pcid(pcid: u16, virtual_address: VirtualAddress) {
+    if !pcid_enabled() || get_cr3().pcid() == pcid {
+        invlpg(virtual_address);
+    } else if supports_invpcid() {
+        invpcid(INVPCID_ADDRESS, pcid, virtual_address.address());
+    } else {
+        flush_everything();
+    }
+}
+
+/// Flushes `pages` pages starting at `start` on this CPU, in every PCID.
+fn flush_range(start: VirtualAddress, pages: u64) {
+    if pages > FLUSH_EVERYTHING_PAGES || pcid_enabled() {
+        // invlpg only flushes the current PCID
+        flush_everything();
+        return;
+    }
+    for page in 0..pages {
+        invlpg(VirtualAddress::create(start.address() + page * PAGE_SIZE));
+    }
+}
+
+/// Flushes the range of the current shootdown if this CPU hasn't yet, and reports that it has.
+fn flush_requested() {
+    let generation = SHOOTDOWN_GENERATION.load(Ordering::SeqCst);
+    let flushed = &FLUSHED_GENERATION[current_cpu()];
+    if flushed.load(Ordering::SeqCst) == generation {
+        return;
+    }
+    let start = VirtualAddress::create(SHOOTDOWN_START.load(Ordering::SeqCst));
+    flush_range(start, SHOOTDOWN_PAGES.load(Ordering::SeqCst));
+    flushed.store(generation, Ordering::SeqCst);
+}
+
+fn shootdown_interrupt(_vector: u8) -> IrqReturn {
+    flush_requested();
+    IrqReturn::Handled
+}
+
+/// Allocates the shootdown IPI's vector. Called before the APs are started, so they can't miss a shootdown.
+pub fn init_shootdown() -> Result<(), InitError> {
+    let vector = vectors::allocate(VectorClass::Ipi).ok_or(InitError::new("no free vector for TLB shootdowns"))?;
+    // the vector was just allocated, so nothing else handles it
+    let _ = irq::set_handler(vector, shootdown_interrupt);
+    irq::set_line_control(vector, &apic::IPI_LINE_CONTROL);
+    SHOOTDOWN_VECTOR.store(vector, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Flushes `pages` pages starting at `start` on every started CPU, and returns once they all have.
+/// The other CPUs flush from an IPI, so they have to take interrupts eventually: this must not be called while holding a lock
+/// that another CPU may wait for with interrupts disabled.
+pub fn shootdown(start: VirtualAddress, pages: u64) {
+    flush_range(start, pages);
+    let vector = SHOOTDOWN_VECTOR.load(Ordering::SeqCst);
+    if vector == 0 {
+        // the APs haven't been started
+        return;
+    }
+    let this_cpu = current_cpu();
+    // a CPU waiting here with interrupts disabled would hold up the shootdown in progress, so it flushes for it instead
+    let _lock = loop {
+        if let Some(lock) = SHOOTDOWN_LOCK.try_lock() {
+            break lock;
+        }
+        flush_requested();
+        spin_loop();
+    };
+    SHOOTDOWN_START.store(start.address(), Ordering::SeqCst);
+    SHOOTDOWN_PAGES.store(pages, Ordering::SeqCst);
+    let generation = SHOOTDOWN_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    FLUSHED_GENERATION[this_cpu].store(generation, Ordering::SeqCst);
+    // only the CPUs that were sent the IPI are waited for, one that starts meanwhile has nothing cached
+    let mut targets = [0u64; MAX_CPUS / 64];
+    for cpu in 0..MAX_CPUS {
+        if cpu != this_cpu && cpu_state(cpu) != CpuState::Offline {
+            targets[cpu / 64] |= 1 << (cpu % 64);
+            apic::send_ipi(cpu as u32, vector);
+        }
+    }
+    for cpu in 0..MAX_CPUS {
+        if targets[cpu / 64] & (1 << (cpu % 64)) == 0 {
+            continue;
+        }
+        while FLUSHED_GENERATION[cpu].load(Ordering::SeqCst) < generation {
+            spin_loop();
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Task state segments, which in long mode only hold the stacks the CPU switches to.
+//! Each CPU gets a TSS with an interrupt stack table entry for each exception that can't trust the stack it interrupted:
+//! a double fault is usually a stack overflow, and NMIs and machine checks can arrive anywhere, including in the middle of
+//! switching stacks. The IST stacks are kernel stacks with guard pages, so an overflow of one is still caught.
+
+use core::mem::size_of;
+
+use alloc::boxed::Box;
+
+use crate::stack::{self, KernelStack, StackError};
+use crate::sync::current_cpu;
+
+use super::gdt;
+
+/// The IST entries of the exceptions that switch stacks, 0 means the stack isn't switched.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+pub const MACHINE_CHECK_IST: u8 = 3;
+const IST_STACKS: [u8; 3] = [DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST];
+const IST_STACK_PAGES: usize = 4;
+
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStateSegment {
+    reserved1: u32,
+    /// The stacks used when an interrupt comes from a lower privilege level, indexed by the level switched to.
+    pub privilege_stack_table: [u64; 3],
+    reserved2: u64,
+    /// The stacks selected by the IST field of gates, entry 0 is IST 1.
+    pub interrupt_stack_table: [u64; 7],
+    reserved3: u64,
+    reserved4: u16,
+    /// The offset of the I/O permission bitmap, a value past the end of the segment means there is none.
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    /// Creates a TSS without stacks or an I/O permission bitmap.
+    pub const fn new() -> Self {
+        TaskStateSegment {
+            reserved1: 0,
+            privilege_stack_table: [0; 3],
+            reserved2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved3: 0,
+            reserved4: 0,
+            iomap_base: size_of::<TaskStateSegment>() as u16,
+        }
+    }
+
+    /// Sets the stack the CPU switches to for gates with IST `ist`, which must be 1 to 7.
+    pub fn set_ist(&mut self, ist: u8, stack_top: u64) {
+        assert!((1..=7).contains(&ist), "invalid IST index {}", ist);
+        self.interrupt_stack_table[ist as usize - 1] = stack_top;
+    }
+}
+
+/// Allocates this CPU's IST stacks and loads a TSS with them, and reserves the CPU's emergency stack. Must be called on each
+/// CPU before it loads the kernel IDT, whose gates for double faults, NMIs and machine checks use the IST.
+pub fn load() -> Result<(), StackError> {
+    stack::reserve_emergency_stack()?;
+    let mut tss = TaskStateSegment::new();
+    for ist in IST_STACKS {
+        let stack = KernelStack::new(IST_STACK_PAGES)?;
+        tss.set_ist(ist, stack.top());
+        // the stack is used for as long as the CPU runs
+        core::mem::forget(stack);
+    }
+    gdt::install_tss(Box::leak(Box::new(tss)));
+    Ok(())
+}
+
+/// Checks the layout of the TSS, and that this CPU has one with its IST stacks.
+pub fn self_check() {
+    assert_eq!(size_of::<TaskStateSegment>(), 104);
+    assert_eq!(core::mem::offset_of!(TaskStateSegment, interrupt_stack_table), 0x24);
+    assert_eq!(core::mem::offset_of!(TaskStateSegment, iomap_base), 0x66);
+    assert_ne!(gdt::get_tr().x, 0, "no TSS is loaded");
+    assert!(stack::emergency_stack_top(current_cpu()).is_some(), "this CPU has no emergency stack");
+}
@@ -0,0 +1,143 @@
+//! Allocation of IDT vectors for interrupts, so drivers, MSI and the I/O APIC don't pick colliding vectors.
+//! The local APIC's priority of a vector is its upper 4 bits, so vectors are handed out from ranges of whole priority classes:
+//! the higher the range, the sooner the interrupt is delivered when several are pending.
+
+use core::ops::Range;
+
+use spin::Mutex;
+
+use super::idt::FIRST_EXTERNAL_VECTOR;
+
+/// The vector the local APIC uses for spurious interrupts, which is reserved here so nothing else gets it.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The kinds of interrupts, from the highest priority to the lowest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorClass {
+    /// The vectors the CPU reserves for exceptions, which can't be allocated.
+    Exception,
+    /// Interrupts between CPUs, like TLB shootdowns and reschedules.
+    Ipi,
+    /// Devices that need to be serviced quickly, like timers.
+    High,
+    Normal,
+    /// Devices that can wait, like the legacy ISA devices.
+    Low,
+}
+
+impl VectorClass {
+    pub const ALL: [VectorClass; 5] = [
+        VectorClass::Exception,
+        VectorClass::Ipi,
+        VectorClass::High,
+        VectorClass::Normal,
+        VectorClass::Low,
+    ];
+
+    /// Gets the vectors of this class, each range is made of whole priority classes (16 vectors).
+    pub const fn vectors(&self) -> Range<u8> {
+        match self {
+            VectorClass::Exception => 0..FIRST_EXTERNAL_VECTOR,
+            VectorClass::Ipi => 0xE0..0xFF,
+            VectorClass::High => 0xB0..0xE0,
+            VectorClass::Normal => 0x40..0xB0,
+            VectorClass::Low => FIRST_EXTERNAL_VECTOR..0x40,
+        }
+    }
+
+    /// Gets the class `vector` is in.
+    pub fn of(vector: u8) -> Self {
+        VectorClass::ALL
+            .into_iter()
+            .find(|class| class.vectors().contains(&vector))
+            // only the spurious vector is in no range
+            .unwrap_or(VectorClass::Ipi)
+    }
+}
+
+/// A bit for each vector, set if it is in use.
+struct Vectors([u64; 4]);
+
+impl Vectors {
+    fn is_used(&self, vector: u8) -> bool {
+        self.0[vector as usize / 64] & (1 << (vector % 64)) != 0
+    }
+
+    fn set(&mut self, vector: u8, used: bool) {
+        if used {
+            self.0[vector as usize / 64] |= 1 << (vector % 64);
+        } else {
+            self.0[vector as usize / 64] &= !(1 << (vector % 64));
+        }
+    }
+}
+
+/// The exceptions and the spurious vector are always used.
+static VECTORS: Mutex<Vectors> = Mutex::new(Vectors([u32::MAX as u64, 0, 0, 1 << 63]));
+
+/// Allocates a vector of class `class`, returns None if they are all in use.
+pub fn allocate(class: VectorClass) -> Option<u8> {
+    allocate_block(class, 1)
+}
+
+/// Allocates `count` consecutive vectors of class `class`, aligned to `count` as multiple message MSI needs.
+/// `count` must be a power of two. Returns the first vector, or None if there is no free block.
+pub fn allocate_block(class: VectorClass, count: u8) -> Option<u8> {
+    assert!(count.is_power_of_two(), "vector block size must be a power of two");
+    if class == VectorClass::Exception {
+        return None;
+    }
+    let range = class.vectors();
+    let mut vectors = VECTORS.lock();
+    let first = (range.start..range.end)
+        .step_by(count as usize)
+        .filter(|&first| first % count == 0 && first as u16 + count as u16 <= range.end as u16)
+        .find(|&first| (first..first + count).all(|vector| !vectors.is_used(vector)))?;
+    for vector in first..first + count {
+        vectors.set(vector, true);
+    }
+    Some(first)
+}
+
+/// Claims a specific vector, for vectors that are fixed like the syscall gate. Returns false if it is already in use.
+pub fn reserve(vector: u8) -> bool {
+    let mut vectors = VECTORS.lock();
+    if vectors.is_used(vector) {
+        return false;
+    }
+    vectors.set(vector, true);
+    true
+}
+
+/// Gives back a vector from `allocate` or `reserve`.
+pub fn free(vector: u8) {
+    assert!(vector >= FIRST_EXTERNAL_VECTOR && vector != SPURIOUS_VECTOR, "vector {:#x} can't be freed", vector);
+    VECTORS.lock().set(vector, false);
+}
+
+/// Returns whether `vector` is in use.
+pub fn is_used(vector: u8) -> bool {
+    VECTORS.lock().is_used(vector)
+}
+
+/// Checks that vectors come from their class's range, blocks are aligned, and freed vectors can be allocated again.
+pub fn self_check() {
+    assert_eq!(allocate(VectorClass::Exception), None);
+    assert!(is_used(0x0E) && is_used(SPURIOUS_VECTOR));
+    for class in VectorClass::ALL {
+        assert_eq!(class.vectors().start % 16, 0);
+        assert_eq!(VectorClass::of(class.vectors().start), class);
+    }
+    let vector = allocate(VectorClass::High).unwrap();
+    assert!(VectorClass::High.vectors().contains(&vector));
+    assert!(!reserve(vector));
+    let block = allocate_block(VectorClass::Normal, 8).unwrap();
+    assert_eq!(block % 8, 0);
+    assert!((block..block + 8).all(|vector| VectorClass::of(vector) == VectorClass::Normal && is_used(vector)));
+    free(vector);
+    for vector in block..block + 8 {
+        free(vector);
+    }
+    assert_eq!(allocate(VectorClass::High), Some(vector));
+    free(vector);
+}
@@ -0,0 +1,199 @@
+//! Sending files over the debug serial port with XMODEM, to get logs and dumps off machines that have no network or storage.
+//! The receiver (like `sx`/`rx` from lrzsz, or a terminal emulator) starts the transfer by sending `C` for XMODEM-1K with CRCs,
+//! or NAK for the original XMODEM with 128 byte blocks and checksums. Each block is resent until it is acknowledged.
+//! The serial port is locked for the whole transfer, so log messages from other CPUs wait instead of corrupting it.
+
+use spin::MutexGuard;
+use uart_16550::SerialPort;
+
+use crate::delay::Deadline;
+use crate::drivers::serial;
+use crate::DEBUG_SERIAL_PORT;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC_MODE: u8 = b'C';
+/// Pads the last block.
+const PADDING: u8 = 0x1A;
+
+/// How long to wait for the receiver to start, in microseconds.
+const START_TIMEOUT_US: u64 = 60_000_000;
+/// How long to wait for a block to be acknowledged, in microseconds.
+const RESPONSE_TIMEOUT_US: u64 = 10_000_000;
+/// How many times a block is sent before giving up.
+const MAX_RETRIES: usize = 10;
+
+/// A byte stream to a receiver.
+pub trait Link {
+    fn send(&mut self, byte: u8);
+    /// Waits up to `timeout_us` microseconds for a byte.
+    fn receive(&mut self, timeout_us: u64) -> Option<u8>;
+}
+
+/// The debug serial port, locked for as long as this exists.
+pub struct SerialLink {
+    serial_port: MutexGuard<'static, SerialPort>,
+}
+
+impl SerialLink {
+    pub fn new() -> Self {
+        SerialLink {
+            serial_port: DEBUG_SERIAL_PORT.lock(),
+        }
+    }
+}
+
+impl Link for SerialLink {
+    fn send(&mut self, byte: u8) {
+        // `send()` would expand backspaces, which are data here
+        self.serial_port.send_raw(byte);
+    }
+
+    fn receive(&mut self, timeout_us: u64) -> Option<u8> {
+        let deadline = Deadline::after_us(timeout_us);
+        loop {
+            if let Some(byte) = serial::try_receive() {
+                return Some(byte);
+            }
+            if deadline.has_passed() {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmodemError {
+    /// The receiver never asked for the transfer to start.
+    NoReceiver,
+    /// The receiver cancelled the transfer.
+    Cancelled,
+    /// A block was not acknowledged after `MAX_RETRIES` tries.
+    TooManyRetries,
+}
+
+/// The CRC-16/XMODEM of `data`.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Sends `data` to the receiver at the other end of `link`. The last block is padded, so the receiver may get a little more.
+pub fn send(link: &mut impl Link, data: &[u8]) -> Result<(), XmodemError> {
+    let crc = loop {
+        match link.receive(START_TIMEOUT_US) {
+            Some(CRC_MODE) => break true,
+            Some(NAK) => break false,
+            Some(CAN) => return Err(XmodemError::Cancelled),
+            // anything else is noise from before the receiver started
+            Some(_) => continue,
+            None => return Err(XmodemError::NoReceiver),
+        }
+    };
+    let block_size = if crc { 1024 } else { 128 };
+    let mut block = [PADDING; 1024];
+    for (index, chunk) in data.chunks(block_size).enumerate() {
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()..block_size].fill(PADDING);
+        // block numbers start at 1 and wrap
+        let number = (index + 1) as u8;
+        send_with_retries(link, |link| {
+            link.send(if crc { STX } else { SOH });
+            link.send(number);
+            link.send(!number);
+            for &byte in &block[..block_size] {
+                link.send(byte);
+            }
+            if crc {
+                let [high, low] = crc16(&block[..block_size]).to_be_bytes();
+                link.send(high);
+                link.send(low);
+            } else {
+                link.send(block[..block_size].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+            }
+        })?;
+    }
+    send_with_retries(link, |link| link.send(EOT))
+}
+
+/// Sends something with `send` until the receiver acknowledges it.
+fn send_with_retries<L: Link>(link: &mut L, mut send: impl FnMut(&mut L)) -> Result<(), XmodemError> {
+    for _ in 0..MAX_RETRIES {
+        send(link);
+        match link.receive(RESPONSE_TIMEOUT_US) {
+            Some(ACK) => return Ok(()),
+            // one CAN could be line noise, two in a row can't
+            Some(CAN) if link.receive(RESPONSE_TIMEOUT_US) == Some(CAN) => return Err(XmodemError::Cancelled),
+            _ => {}
+        }
+    }
+    Err(XmodemError::TooManyRetries)
+}
+
+/// Checks the framing and CRCs of a transfer against a receiver that NAKs the first try of every block.
+pub fn self_check() {
+    use alloc::collections::VecDeque;
+    use alloc::vec::Vec;
+
+    assert_eq!(crc16(b"123456789"), 0x31C3);
+
+    /// A receiver that checks each block as it arrives.
+    struct TestReceiver {
+        block: Vec<u8>,
+        responses: VecDeque<u8>,
+        received: Vec<u8>,
+        nak_next: bool,
+    }
+
+    impl Link for TestReceiver {
+        fn send(&mut self, byte: u8) {
+            self.block.push(byte);
+            if self.block == [EOT] {
+                self.block.clear();
+                self.responses.push_back(ACK);
+                return;
+            }
+            if self.block.len() < 1024 + 5 {
+                return;
+            }
+            let block = core::mem::take(&mut self.block);
+            assert_eq!(block[0], STX);
+            assert_eq!(block[1], !block[2]);
+            assert_eq!(u16::from_be_bytes([block[1027], block[1028]]), crc16(&block[3..1027]));
+            if self.nak_next {
+                self.responses.push_back(NAK);
+            } else {
+                self.received.extend_from_slice(&block[3..1027]);
+                self.responses.push_back(ACK);
+            }
+            self.nak_next = !self.nak_next;
+        }
+
+        fn receive(&mut self, _: u64) -> Option<u8> {
+            self.responses.pop_front()
+        }
+    }
+
+    let data: Vec<u8> = (0..3000u32).map(|i| (i * 7) as u8).collect();
+    let mut receiver = TestReceiver {
+        block: Vec::new(),
+        responses: VecDeque::from([b'x', CRC_MODE]),
+        received: Vec::new(),
+        nak_next: true,
+    };
+    assert_eq!(send(&mut receiver, &data), Ok(()));
+    assert_eq!(receiver.received.len(), 3 * 1024);
+    assert_eq!(&receiver.received[..data.len()], &data[..]);
+    assert!(receiver.received[data.len()..].iter().all(|&byte| byte == PADDING));
+}